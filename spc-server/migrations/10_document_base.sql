@@ -0,0 +1,4 @@
+-- the article version a pad was created from, to merge on saving back
+ALTER TABLE document ADD COLUMN base_text TEXT;
+ALTER TABLE document ADD COLUMN base_hash VARCHAR;
+ALTER TABLE document ADD COLUMN base_updated_at INTEGER;
@@ -0,0 +1,5 @@
+CREATE TABLE pad_owners (
+  doc_id VARCHAR PRIMARY KEY NOT NULL,
+  node VARCHAR NOT NULL,          -- url of the owner process
+  expires_at INTEGER NOT NULL     -- lease, renewed while the pad is live
+);
@@ -0,0 +1,9 @@
+-- comment threads anchored to a range of a document, on_ty = 'document'
+ALTER TABLE comments ADD COLUMN key VARCHAR;          -- unique on its document
+ALTER TABLE comments ADD COLUMN parent_key VARCHAR;   -- first comment of the thread
+ALTER TABLE comments ADD COLUMN name VARCHAR NOT NULL DEFAULT '';
+ALTER TABLE comments ADD COLUMN anchor_start INTEGER;
+ALTER TABLE comments ADD COLUMN anchor_end INTEGER;
+ALTER TABLE comments ADD COLUMN is_resolved BOOLEAN NOT NULL DEFAULT FALSE;
+
+CREATE UNIQUE INDEX comments_on_key ON comments (on_ty, on_id, key);
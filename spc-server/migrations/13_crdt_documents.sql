@@ -0,0 +1,7 @@
+-- documents using the CRDT backend, for offline editing, with its state;
+-- their text is still persisted in documents, or notes
+CREATE TABLE crdt_documents (
+  doc_id VARCHAR PRIMARY KEY NOT NULL,
+  data BLOB NOT NULL,
+  updated_at INTEGER NOT NULL
+);
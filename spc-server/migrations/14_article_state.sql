@@ -0,0 +1,8 @@
+-- publishing state: draft -> scheduled -> published -> archived
+ALTER TABLE articles ADD COLUMN state VARCHAR NOT NULL DEFAULT 'published';
+-- when published, or to be published if scheduled
+ALTER TABLE articles ADD COLUMN publish_at INTEGER NOT NULL DEFAULT 0;
+
+UPDATE articles SET publish_at = created_at;
+
+CREATE INDEX idx_articles_state ON articles (state, publish_at);
@@ -0,0 +1,15 @@
+-- pieces could only be hidden so far
+ALTER TABLE pieces ADD COLUMN is_locked BOOLEAN DEFAULT FALSE;
+
+-- audit trail of moderator actions on content
+CREATE TABLE mod_actions (
+  id INTEGER PRIMARY KEY AUTOINCREMENT,
+  on_ty VARCHAR NOT NULL, -- article|piece
+  on_id INTEGER NOT NULL,
+  act VARCHAR NOT NULL, -- hide|unhide|lock|unlock
+  reason TEXT NOT NULL DEFAULT '',
+  mod_by VARCHAR NOT NULL,
+  mod_at INTEGER NOT NULL
+);
+
+CREATE INDEX idx_mod_actions_on ON mod_actions (on_ty, on_id);
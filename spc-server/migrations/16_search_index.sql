@@ -0,0 +1,95 @@
+-- full-text search over articles, pieces, notes, feeds and tags,
+-- kept in sync by the triggers below
+CREATE VIRTUAL TABLE search_index USING fts5(
+  title,
+  body,
+  on_ty UNINDEXED, -- article|piece|note|feed|tag
+  on_id UNINDEXED,
+  uname UNINDEXED, -- author, owner of notes
+  tokenize = 'unicode61 remove_diacritics 2'
+);
+
+-- articles
+INSERT INTO search_index (title, body, on_ty, on_id, uname)
+  SELECT title, content, 'article', id, uname FROM articles;
+
+CREATE TRIGGER search_articles_ai AFTER INSERT ON articles BEGIN
+  INSERT INTO search_index (title, body, on_ty, on_id, uname)
+  VALUES (new.title, new.content, 'article', new.id, new.uname);
+END;
+CREATE TRIGGER search_articles_au AFTER UPDATE OF title, content, uname ON articles BEGIN
+  DELETE FROM search_index WHERE on_ty = 'article' AND on_id = old.id;
+  INSERT INTO search_index (title, body, on_ty, on_id, uname)
+  VALUES (new.title, new.content, 'article', new.id, new.uname);
+END;
+CREATE TRIGGER search_articles_ad AFTER DELETE ON articles BEGIN
+  DELETE FROM search_index WHERE on_ty = 'article' AND on_id = old.id;
+END;
+
+-- pieces
+INSERT INTO search_index (title, body, on_ty, on_id, uname)
+  SELECT '', content, 'piece', id, uname FROM pieces;
+
+CREATE TRIGGER search_pieces_ai AFTER INSERT ON pieces BEGIN
+  INSERT INTO search_index (title, body, on_ty, on_id, uname)
+  VALUES ('', new.content, 'piece', new.id, new.uname);
+END;
+CREATE TRIGGER search_pieces_au AFTER UPDATE OF content, uname ON pieces BEGIN
+  DELETE FROM search_index WHERE on_ty = 'piece' AND on_id = old.id;
+  INSERT INTO search_index (title, body, on_ty, on_id, uname)
+  VALUES ('', new.content, 'piece', new.id, new.uname);
+END;
+CREATE TRIGGER search_pieces_ad AFTER DELETE ON pieces BEGIN
+  DELETE FROM search_index WHERE on_ty = 'piece' AND on_id = old.id;
+END;
+
+-- notes, searched by their owner only
+INSERT INTO search_index (title, body, on_ty, on_id, uname)
+  SELECT title, content, 'note', id, uname FROM notes;
+
+CREATE TRIGGER search_notes_ai AFTER INSERT ON notes BEGIN
+  INSERT INTO search_index (title, body, on_ty, on_id, uname)
+  VALUES (new.title, new.content, 'note', new.id, new.uname);
+END;
+CREATE TRIGGER search_notes_au AFTER UPDATE OF title, content, uname ON notes BEGIN
+  DELETE FROM search_index WHERE on_ty = 'note' AND on_id = old.id;
+  INSERT INTO search_index (title, body, on_ty, on_id, uname)
+  VALUES (new.title, new.content, 'note', new.id, new.uname);
+END;
+CREATE TRIGGER search_notes_ad AFTER DELETE ON notes BEGIN
+  DELETE FROM search_index WHERE on_ty = 'note' AND on_id = old.id;
+END;
+
+-- feeds, titles and intros
+INSERT INTO search_index (title, body, on_ty, on_id, uname)
+  SELECT title, IFNULL(intro, ''), 'feed', id, IFNULL(author, '') FROM feeds;
+
+CREATE TRIGGER search_feeds_ai AFTER INSERT ON feeds BEGIN
+  INSERT INTO search_index (title, body, on_ty, on_id, uname)
+  VALUES (new.title, IFNULL(new.intro, ''), 'feed', new.id, IFNULL(new.author, ''));
+END;
+CREATE TRIGGER search_feeds_au AFTER UPDATE OF title, intro, author ON feeds BEGIN
+  DELETE FROM search_index WHERE on_ty = 'feed' AND on_id = old.id;
+  INSERT INTO search_index (title, body, on_ty, on_id, uname)
+  VALUES (new.title, IFNULL(new.intro, ''), 'feed', new.id, IFNULL(new.author, ''));
+END;
+CREATE TRIGGER search_feeds_ad AFTER DELETE ON feeds BEGIN
+  DELETE FROM search_index WHERE on_ty = 'feed' AND on_id = old.id;
+END;
+
+-- tags
+INSERT INTO search_index (title, body, on_ty, on_id, uname)
+  SELECT tname, content, 'tag', id, '' FROM tags;
+
+CREATE TRIGGER search_tags_ai AFTER INSERT ON tags BEGIN
+  INSERT INTO search_index (title, body, on_ty, on_id, uname)
+  VALUES (new.tname, new.content, 'tag', new.id, '');
+END;
+CREATE TRIGGER search_tags_au AFTER UPDATE OF tname, content ON tags BEGIN
+  DELETE FROM search_index WHERE on_ty = 'tag' AND on_id = old.id;
+  INSERT INTO search_index (title, body, on_ty, on_id, uname)
+  VALUES (new.tname, new.content, 'tag', new.id, '');
+END;
+CREATE TRIGGER search_tags_ad AFTER DELETE ON tags BEGIN
+  DELETE FROM search_index WHERE on_ty = 'tag' AND on_id = old.id;
+END;
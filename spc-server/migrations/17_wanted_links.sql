@@ -0,0 +1,8 @@
+-- wikilinks to titles with no article yet, by the linking article
+CREATE TABLE wanted_links (
+  title VARCHAR NOT NULL,
+  in_id INTEGER NOT NULL,
+  UNIQUE(title, in_id)
+);
+
+CREATE INDEX idx_wanted_links_in ON wanted_links (in_id);
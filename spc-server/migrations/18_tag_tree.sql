@@ -0,0 +1,26 @@
+-- nested tags: `a/b` is a child of `a`, 0 for a root tag
+ALTER TABLE tags ADD COLUMN parent_id INTEGER NOT NULL DEFAULT 0;
+
+CREATE INDEX idx_tags_parent ON tags (parent_id);
+
+-- other names of a tag, tagging with one tags with the tag
+CREATE TABLE tag_aliases (
+  alias VARCHAR PRIMARY KEY,
+  tag_id INTEGER NOT NULL
+);
+
+-- the missing parents of the nested tags so far
+WITH RECURSIVE parent(name) AS (
+  SELECT rtrim(rtrim(tname, replace(tname, '/', '')), '/') 
+  FROM tags WHERE instr(tname, '/') > 0
+  UNION
+  SELECT rtrim(rtrim(name, replace(name, '/', '')), '/') 
+  FROM parent WHERE instr(name, '/') > 0
+)
+INSERT OR IGNORE INTO tags (tname) SELECT name FROM parent WHERE name != '';
+
+UPDATE tags SET parent_id = COALESCE((
+  SELECT p.id FROM tags p 
+  WHERE p.tname = rtrim(rtrim(tags.tname, replace(tags.tname, '/', '')), '/')
+), 0)
+WHERE instr(tname, '/') > 0;
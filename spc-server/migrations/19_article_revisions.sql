@@ -0,0 +1,22 @@
+-- every edit of an article is a revision, even back to an earlier content;
+-- snapshots of documents stay keyed by their text
+CREATE TABLE revisions_new (
+  id INTEGER PRIMARY KEY AUTOINCREMENT,
+  content TEXT NOT NULL,
+  on_ty VARCHAR NOT NULL,
+  on_id INTEGER NOT NULL,
+  rev_by VARCHAR NOT NULL,
+  rev_at INTEGER NOT NULL,
+  is_current BOOLEAN NOT NULL DEFAULT FALSE,
+  name VARCHAR NOT NULL DEFAULT ''
+);
+
+INSERT INTO revisions_new (id, content, on_ty, on_id, rev_by, rev_at, is_current, name)
+SELECT id, content, on_ty, on_id, rev_by, rev_at, is_current, name FROM revisions;
+
+DROP TABLE revisions;
+ALTER TABLE revisions_new RENAME TO revisions;
+
+CREATE UNIQUE INDEX idx_revisions_document ON revisions (content, on_ty, on_id)
+WHERE on_ty = 'document';
+CREATE INDEX idx_revisions_on ON revisions (on_ty, on_id);
@@ -0,0 +1,5 @@
+-- the author owns the pads of an article created before they had owners
+INSERT OR IGNORE INTO document_acl (doc_id, uname, role)
+SELECT d.id, a.uname, 'owner' FROM document d
+JOIN articles a ON a.id = d.article_id
+WHERE NOT EXISTS (SELECT 1 FROM document_acl acl WHERE acl.doc_id = d.id);
@@ -0,0 +1,161 @@
+-- key the search index on rowid, given per row in `search_rows`, for the
+-- triggers to update it by rowid rather than scanning the unindexed columns
+DROP TRIGGER search_articles_ai;
+DROP TRIGGER search_articles_au;
+DROP TRIGGER search_articles_ad;
+DROP TRIGGER search_pieces_ai;
+DROP TRIGGER search_pieces_au;
+DROP TRIGGER search_pieces_ad;
+DROP TRIGGER search_notes_ai;
+DROP TRIGGER search_notes_au;
+DROP TRIGGER search_notes_ad;
+DROP TRIGGER search_feeds_ai;
+DROP TRIGGER search_feeds_au;
+DROP TRIGGER search_feeds_ad;
+DROP TRIGGER search_tags_ai;
+DROP TRIGGER search_tags_au;
+DROP TRIGGER search_tags_ad;
+DROP TABLE search_index;
+
+CREATE TABLE search_rows (
+  id INTEGER PRIMARY KEY AUTOINCREMENT,
+  on_ty VARCHAR NOT NULL,
+  on_id NOT NULL, -- an integer, a text for notes
+  UNIQUE(on_ty, on_id)
+);
+
+CREATE VIRTUAL TABLE search_index USING fts5(
+  title,
+  body,
+  on_ty UNINDEXED, -- article|piece|note|feed|tag
+  on_id UNINDEXED,
+  uname UNINDEXED, -- author, owner of notes
+  tokenize = 'unicode61 remove_diacritics 2'
+);
+
+-- articles
+INSERT INTO search_rows (on_ty, on_id) SELECT 'article', id FROM articles;
+INSERT INTO search_index (rowid, title, body, on_ty, on_id, uname)
+  SELECT r.id, t.title, t.content, 'article', t.id, t.uname FROM articles t
+  JOIN search_rows r ON r.on_ty = 'article' AND r.on_id = t.id;
+
+CREATE TRIGGER search_articles_ai AFTER INSERT ON articles BEGIN
+  INSERT OR IGNORE INTO search_rows (on_ty, on_id) VALUES ('article', new.id);
+  INSERT INTO search_index (rowid, title, body, on_ty, on_id, uname)
+  SELECT id, new.title, new.content, on_ty, on_id, new.uname
+  FROM search_rows WHERE on_ty = 'article' AND on_id = new.id;
+END;
+CREATE TRIGGER search_articles_au AFTER UPDATE OF title, content, uname ON articles BEGIN
+  UPDATE search_index SET title = new.title, body = new.content, uname = new.uname
+  WHERE rowid = (
+    SELECT id FROM search_rows WHERE on_ty = 'article' AND on_id = old.id
+  );
+END;
+CREATE TRIGGER search_articles_ad AFTER DELETE ON articles BEGIN
+  DELETE FROM search_index WHERE rowid = (
+    SELECT id FROM search_rows WHERE on_ty = 'article' AND on_id = old.id
+  );
+  DELETE FROM search_rows WHERE on_ty = 'article' AND on_id = old.id;
+END;
+
+-- pieces
+INSERT INTO search_rows (on_ty, on_id) SELECT 'piece', id FROM pieces;
+INSERT INTO search_index (rowid, title, body, on_ty, on_id, uname)
+  SELECT r.id, '', t.content, 'piece', t.id, t.uname FROM pieces t
+  JOIN search_rows r ON r.on_ty = 'piece' AND r.on_id = t.id;
+
+CREATE TRIGGER search_pieces_ai AFTER INSERT ON pieces BEGIN
+  INSERT OR IGNORE INTO search_rows (on_ty, on_id) VALUES ('piece', new.id);
+  INSERT INTO search_index (rowid, title, body, on_ty, on_id, uname)
+  SELECT id, '', new.content, on_ty, on_id, new.uname
+  FROM search_rows WHERE on_ty = 'piece' AND on_id = new.id;
+END;
+CREATE TRIGGER search_pieces_au AFTER UPDATE OF content, uname ON pieces BEGIN
+  UPDATE search_index SET title = '', body = new.content, uname = new.uname
+  WHERE rowid = (
+    SELECT id FROM search_rows WHERE on_ty = 'piece' AND on_id = old.id
+  );
+END;
+CREATE TRIGGER search_pieces_ad AFTER DELETE ON pieces BEGIN
+  DELETE FROM search_index WHERE rowid = (
+    SELECT id FROM search_rows WHERE on_ty = 'piece' AND on_id = old.id
+  );
+  DELETE FROM search_rows WHERE on_ty = 'piece' AND on_id = old.id;
+END;
+
+-- notes, searched by their owner only
+INSERT INTO search_rows (on_ty, on_id) SELECT 'note', id FROM notes;
+INSERT INTO search_index (rowid, title, body, on_ty, on_id, uname)
+  SELECT r.id, t.title, t.content, 'note', t.id, t.uname FROM notes t
+  JOIN search_rows r ON r.on_ty = 'note' AND r.on_id = t.id;
+
+CREATE TRIGGER search_notes_ai AFTER INSERT ON notes BEGIN
+  INSERT OR IGNORE INTO search_rows (on_ty, on_id) VALUES ('note', new.id);
+  INSERT INTO search_index (rowid, title, body, on_ty, on_id, uname)
+  SELECT id, new.title, new.content, on_ty, on_id, new.uname
+  FROM search_rows WHERE on_ty = 'note' AND on_id = new.id;
+END;
+CREATE TRIGGER search_notes_au AFTER UPDATE OF title, content, uname ON notes BEGIN
+  UPDATE search_index SET title = new.title, body = new.content, uname = new.uname
+  WHERE rowid = (
+    SELECT id FROM search_rows WHERE on_ty = 'note' AND on_id = old.id
+  );
+END;
+CREATE TRIGGER search_notes_ad AFTER DELETE ON notes BEGIN
+  DELETE FROM search_index WHERE rowid = (
+    SELECT id FROM search_rows WHERE on_ty = 'note' AND on_id = old.id
+  );
+  DELETE FROM search_rows WHERE on_ty = 'note' AND on_id = old.id;
+END;
+
+-- feeds, titles and intros
+INSERT INTO search_rows (on_ty, on_id) SELECT 'feed', id FROM feeds;
+INSERT INTO search_index (rowid, title, body, on_ty, on_id, uname)
+  SELECT r.id, t.title, IFNULL(t.intro, ''), 'feed', t.id, IFNULL(t.author, '')
+  FROM feeds t
+  JOIN search_rows r ON r.on_ty = 'feed' AND r.on_id = t.id;
+
+CREATE TRIGGER search_feeds_ai AFTER INSERT ON feeds BEGIN
+  INSERT OR IGNORE INTO search_rows (on_ty, on_id) VALUES ('feed', new.id);
+  INSERT INTO search_index (rowid, title, body, on_ty, on_id, uname)
+  SELECT id, new.title, IFNULL(new.intro, ''), on_ty, on_id, IFNULL(new.author, '')
+  FROM search_rows WHERE on_ty = 'feed' AND on_id = new.id;
+END;
+CREATE TRIGGER search_feeds_au AFTER UPDATE OF title, intro, author ON feeds BEGIN
+  UPDATE search_index
+  SET title = new.title, body = IFNULL(new.intro, ''), uname = IFNULL(new.author, '')
+  WHERE rowid = (
+    SELECT id FROM search_rows WHERE on_ty = 'feed' AND on_id = old.id
+  );
+END;
+CREATE TRIGGER search_feeds_ad AFTER DELETE ON feeds BEGIN
+  DELETE FROM search_index WHERE rowid = (
+    SELECT id FROM search_rows WHERE on_ty = 'feed' AND on_id = old.id
+  );
+  DELETE FROM search_rows WHERE on_ty = 'feed' AND on_id = old.id;
+END;
+
+-- tags
+INSERT INTO search_rows (on_ty, on_id) SELECT 'tag', id FROM tags;
+INSERT INTO search_index (rowid, title, body, on_ty, on_id, uname)
+  SELECT r.id, t.tname, t.content, 'tag', t.id, '' FROM tags t
+  JOIN search_rows r ON r.on_ty = 'tag' AND r.on_id = t.id;
+
+CREATE TRIGGER search_tags_ai AFTER INSERT ON tags BEGIN
+  INSERT OR IGNORE INTO search_rows (on_ty, on_id) VALUES ('tag', new.id);
+  INSERT INTO search_index (rowid, title, body, on_ty, on_id, uname)
+  SELECT id, new.tname, new.content, on_ty, on_id, ''
+  FROM search_rows WHERE on_ty = 'tag' AND on_id = new.id;
+END;
+CREATE TRIGGER search_tags_au AFTER UPDATE OF tname, content ON tags BEGIN
+  UPDATE search_index SET title = new.tname, body = new.content, uname = ''
+  WHERE rowid = (
+    SELECT id FROM search_rows WHERE on_ty = 'tag' AND on_id = old.id
+  );
+END;
+CREATE TRIGGER search_tags_ad AFTER DELETE ON tags BEGIN
+  DELETE FROM search_index WHERE rowid = (
+    SELECT id FROM search_rows WHERE on_ty = 'tag' AND on_id = old.id
+  );
+  DELETE FROM search_rows WHERE on_ty = 'tag' AND on_id = old.id;
+END;
@@ -0,0 +1,8 @@
+CREATE TABLE document_ops (
+  doc_id VARCHAR NOT NULL,
+  revision INTEGER NOT NULL, -- index of the operation in history
+  user_id INTEGER NOT NULL,  -- connection id, -1 for server-made
+  operation TEXT NOT NULL,   -- json serialized OperationSeq
+  created_at INTEGER NOT NULL,
+  PRIMARY KEY (doc_id, revision)
+);
@@ -0,0 +1,6 @@
+CREATE TABLE document_checkpoints (
+  doc_id VARCHAR PRIMARY KEY,
+  revision INTEGER NOT NULL, -- number of operations composed into text
+  text TEXT NOT NULL,
+  created_at INTEGER NOT NULL
+);
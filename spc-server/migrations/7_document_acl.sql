@@ -0,0 +1,14 @@
+CREATE TABLE document_acl (
+  doc_id VARCHAR NOT NULL,
+  uname VARCHAR NOT NULL,
+  role VARCHAR NOT NULL, -- owner|editor|viewer
+  UNIQUE(doc_id, uname)
+);
+
+CREATE TABLE document_shares (
+  token VARCHAR PRIMARY KEY,
+  doc_id VARCHAR NOT NULL,
+  role VARCHAR NOT NULL, -- editor|viewer
+  created_by VARCHAR NOT NULL,
+  expire_at INTEGER NOT NULL
+);
@@ -0,0 +1,11 @@
+CREATE TABLE document_chats (
+  id INTEGER PRIMARY KEY AUTOINCREMENT,
+  doc_id VARCHAR NOT NULL,
+  user_id INTEGER NOT NULL,  -- connection id
+  uname VARCHAR NOT NULL,    -- signed-in username, empty for anonymous
+  name VARCHAR NOT NULL,     -- display name
+  message TEXT NOT NULL,
+  created_at INTEGER NOT NULL
+);
+
+CREATE INDEX document_chats_doc_id ON document_chats (doc_id);
@@ -0,0 +1,2 @@
+-- name of a snapshot, revisions of documents are keyed by the text id
+ALTER TABLE revisions ADD COLUMN name VARCHAR NOT NULL DEFAULT '';
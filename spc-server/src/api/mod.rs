@@ -1,2 +1,3 @@
 pub mod feed;
-pub mod note;
\ No newline at end of file
+pub mod note;
+pub mod search;
\ No newline at end of file
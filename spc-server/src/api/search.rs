@@ -0,0 +1,26 @@
+use axum::{extract::{State, Query}, response::IntoResponse, Json};
+use axum::http::StatusCode;
+
+use crate::{
+  AppState as Ctx,
+  db::{
+    search::SearchQuery,
+    user::{ClaimCan, READ_PERMIT}
+  }
+};
+
+/// Handler for the GET `/api/search?q=&ty=&tag=&author=&perpage=&page=`
+/// endpoint, what the user can see, public content if anonymous.
+pub async fn search(
+  State(ctx): State<Ctx>,
+  Query(query): Query<SearchQuery>,
+  check: ClaimCan<READ_PERMIT>,
+) -> Result<impl IntoResponse, StatusCode> {
+  let viewer = check.claim.unwrap_or_default().uname;
+  let hits = query
+    .get(&ctx, &viewer)
+    .await
+    .map_err(|_e| StatusCode::BAD_REQUEST)?;
+
+  Ok(Json(hits))
+}
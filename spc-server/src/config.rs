@@ -10,6 +10,7 @@ use tracing::{error, warn};
 use validator::Validate;
 
 use crate::error::AppError;
+use crate::pad::limit::Limits;
 
 /// App Config
 pub(crate) static CONFIG: Lazy<Config> = Lazy::new(Config::load);
@@ -45,6 +46,13 @@ pub(crate) struct Config {
   pub(crate) admin_name: String,
   /// hours to clean up documents after collaboration inactivity.
   pub(crate) expiry_hours: u32,
+  /// url the other spc processes sharing the db reach this one at,
+  /// e.g. http://10.0.0.2:8080; empty to run a single process.
+  #[serde(default)]
+  pub(crate) node_url: String,
+  /// rate limits on collaborative writing
+  #[serde(default)]
+  pub(crate) pad_limits: Limits,
 }
 
 impl Default for Config {
@@ -67,6 +75,8 @@ impl Default for Config {
       key: "".into(),
       admin_name: "".into(),
       expiry_hours: 1,
+      node_url: "".into(),
+      pad_limits: Limits::default(),
     }
   }
 }
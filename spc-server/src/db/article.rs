@@ -1,12 +1,18 @@
 //! models for article
 
 use chrono::Utc;
-use spc_util::capture_element;
-use sqlx::{FromRow, SqlitePool};
+use spc_util::{
+  diff::merge3,
+  wikilink::{parse_wikilinks, rename_wikilinks, wikilink_targets},
+  LinkResolver, MAX_EMBED_DEPTH,
+};
+use sqlx::{FromRow, SqliteConnection, SqlitePool};
+use std::collections::{BTreeSet, HashMap};
 use serde::Serialize;
 
 use super::{feed::Feed, tag::Tag, sled::gen_expirable_id};
-use crate::{error::AppError, util::md::md2html, AppState, pad::document::PersistedDocument};
+use crate::{error::AppError, util::md::md2html, AppState};
+use crate::pad::acl::{Access, AclEntry};
 
 #[derive(FromRow, Serialize, Debug, Default)]
 pub struct Article {
@@ -19,16 +25,185 @@ pub struct Article {
   pub updated_at: i64,
   pub is_locked: bool,
   pub is_hidden: bool,
+  /// one of `DRAFT`, `SCHEDULED`, `PUBLISHED` or `ARCHIVED`
+  pub state: String,
+  /// when published, or to be published if scheduled
+  pub publish_at: i64,
 }
 
+// publishing states of an article
+pub const DRAFT: &str = "draft";
+pub const SCHEDULED: &str = "scheduled";
+pub const PUBLISHED: &str = "published";
+pub const ARCHIVED: &str = "archived";
+
+/// if an article can go from state `from` to `to`:
+/// draft -> scheduled -> published -> archived, or back to draft.
+pub fn can_transit(from: &str, to: &str) -> bool {
+  from == to
+    || matches!(
+      (from, to),
+      (DRAFT, SCHEDULED)
+        | (DRAFT, PUBLISHED)
+        | (SCHEDULED, DRAFT)
+        | (SCHEDULED, PUBLISHED)
+        | (PUBLISHED, ARCHIVED)
+        | (PUBLISHED, DRAFT)
+        | (ARCHIVED, PUBLISHED)
+        | (ARCHIVED, DRAFT)
+    )
+}
+
+/// result of saving a pad to its article
+pub enum PadSave {
+  /// saved, with the merged text 
+  Saved { article: Article, text: String },
+  /// not saved, the text with conflict markers
+  Conflict { text: String, conflicts: usize },
+}
+
+/// the article version a pad is based on
+#[derive(FromRow, Debug)]
+struct PadBase {
+  id: String,
+  article_id: Option<u32>,
+  base_text: Option<String>,
+  base_hash: Option<String>,
+  base_updated_at: Option<i64>,
+}
+
+/// The wikilinks of an article when rendered: the missing targets, and the
+/// articles to transclude, up to [`MAX_EMBED_DEPTH`] deep.
+#[derive(Debug, Default)]
+pub struct ArticleLinks {
+  names: Vec<String>,
+  missing: BTreeSet<String>,
+  embeds: HashMap<String, String>,
+}
+
+impl ArticleLinks {
+  /// load the links of `article` and of the articles it embeds, as seen by
+  /// `viewer`.
+  pub async fn load(ctx: &AppState, article: &Article, viewer: &str) -> Result<Self, AppError> {
+    let mut links = ArticleLinks {
+      names: vec![article.title.clone(), article.id.to_string()],
+      missing: Article::missing_targets(ctx, &article.content).await?,
+      embeds: HashMap::new(),
+    };
+
+    let mut level = vec![article.content.clone()];
+    for _ in 0..MAX_EMBED_DEPTH {
+      let mut next = Vec::new();
+      for content in &level {
+        for link in parse_wikilinks(content) {
+          if !link.embed
+            || link.target.is_empty()
+            || links.names.iter().any(|n| n == link.target)
+            || links.embeds.contains_key(link.target)
+          {
+            continue;
+          }
+          let embedded = Article::get_by_id_or_title(ctx, link.target)
+            .await
+            .ok()
+            .filter(|a| a.visible_to(viewer) && !a.is_hidden);
+          if let Some(embedded) = embedded {
+            links.missing.extend(Article::missing_targets(ctx, &embedded.content).await?);
+            links.embeds.insert(link.target.to_owned(), embedded.content.clone());
+            next.push(embedded.content);
+          }
+        }
+      }
+      level = next;
+    }
+
+    Ok(links)
+  }
+}
+
+impl LinkResolver for ArticleLinks {
+  fn exists(&self, target: &str) -> bool {
+    !self.missing.contains(target)
+  }
+
+  fn embed(&self, target: &str) -> Option<String> {
+    self.embeds.get(target).cloned()
+  }
+
+  fn page_names(&self) -> Vec<String> {
+    self.names.clone()
+  }
+}
+
+/// An article linking to or mentioning another.
+#[derive(FromRow, Serialize, Debug, Default)]
+pub struct LinkedArticle {
+  pub id: u32,
+  pub uname: String,
+  pub title: String,
+}
+
+/// A missing title, with the count of the published articles linking to it.
 #[derive(FromRow, Serialize, Debug, Default)]
-pub struct ArticleIn {
-  pub article_id: u32,
-  pub in_ty: String,
-  pub in_id: u32,
+pub struct Wanted {
+  pub title: String,
+  pub refs: u32,
+}
+
+impl Wanted {
+  /// the missing titles, most wanted first
+  pub async fn get_list(ctx: &AppState, perpage: i64, page: i64) -> Result<Vec<Wanted>, AppError> {
+    let page_offset = std::cmp::max(0, page - 1);
+    let wanted: Vec<Wanted> = sqlx::query_as(
+      r#"
+      SELECT w.title AS title, COUNT(*) AS refs FROM wanted_links w
+      JOIN articles a ON a.id = w.in_id
+      WHERE a.state IN ('published', 'archived') AND a.is_hidden = FALSE
+        AND NOT EXISTS (SELECT 1 FROM articles WHERE title = w.title)
+      GROUP BY w.title
+      ORDER BY refs DESC, w.title
+      LIMIT $1
+      OFFSET $2;
+      "#,
+    )
+    .bind(perpage)
+    .bind(perpage * page_offset)
+    .fetch_all(&ctx.pool)
+    .await?;
+
+    Ok(wanted)
+  }
 }
 
 impl Article {
+  /// if the article can be seen by `uname`: drafts and scheduled articles
+  /// by their author only.
+  pub fn visible_to(&self, uname: &str) -> bool {
+    self.state == PUBLISHED || self.state == ARCHIVED || self.uname == uname
+  }
+
+  /// check the article is not hidden by a moderator, but to its author and
+  /// moderators.
+  pub fn check_unhidden(&self, uname: &str, is_mod: bool) -> Result<(), AppError> {
+    if self.is_hidden && self.uname != uname && !is_mod {
+      return Err(AppError::Moderated);
+    }
+    Ok(())
+  }
+
+  /// check the article is not locked by a moderator, before editing it.
+  pub fn check_unlocked(&self) -> Result<(), AppError> {
+    if self.is_locked {
+      return Err(AppError::Moderated);
+    }
+    Ok(())
+  }
+
+  /// when published, or created if never published, to order by.
+  pub fn published_at(&self) -> i64 {
+    if self.publish_at > 0 { self.publish_at } else { self.created_at }
+  }
+
   pub async fn get(ctx: &AppState, id: u32) -> Result<Article, AppError> {
     let article: Article = sqlx::query_as(
       r#"
@@ -61,6 +236,28 @@ impl Article {
   }
 
   pub async fn new(&self, ctx: &AppState) -> Result<Article, AppError> {
+    let mut tx = ctx.pool.begin().await?;
+    let new_article = self.save(&mut tx).await?;
+    tx.commit().await?;
+
+    Ok(new_article)
+  }
+
+  /// save a retitled article, with the wikilinks to its `old_title`
+  /// rewritten, in one transaction.
+  pub async fn rename(
+    &self, ctx: &AppState, old_title: &str,
+  ) -> Result<Article, AppError> {
+    let mut tx = ctx.pool.begin().await?;
+    let new_article = self.save(&mut tx).await?;
+    Article::update_wikilinks(&mut tx, old_title, &new_article.title, &self.uname).await?;
+    tx.commit().await?;
+
+    Ok(new_article)
+  }
+
+  /// insert or update the article, along with its revisions.
+  async fn save(&self, conn: &mut SqliteConnection) -> Result<Article, AppError> {
     let id = self.id;
     let now = Utc::now().timestamp();
     // insert
@@ -68,9 +265,9 @@ impl Article {
       sqlx::query_as(
         r#"
         INSERT OR IGNORE INTO articles 
-        (title, uname, cover, content, created_at, updated_at)
+        (title, uname, cover, content, created_at, updated_at, state, publish_at)
         VALUES
-        ($1, $2, $3, $4, $5, $6)
+        ($1, $2, $3, $4, $5, $6, $7, $8)
         RETURNING *;
         "#,
       )
@@ -78,31 +275,85 @@ impl Article {
       .bind(&self.uname)
       .bind(&self.cover)
       .bind(&self.content)
-      .bind(&now)
-      .bind(&now)
-      .fetch_one(&ctx.pool)
+      .bind(now)
+      .bind(now)
+      .bind(&self.state)
+      .bind(self.publish_at)
+      .fetch_one(&mut *conn)
       .await?
     } else {
+      let old_article: Article = sqlx::query_as(
+        r#"
+        SELECT * FROM articles WHERE id = $1;
+        "#,
+      )
+      .bind(id)
+      .fetch_one(&mut *conn)
+      .await?;
+      keep_revision(&mut *conn, &old_article).await?;
       sqlx::query_as(
         r#"
         UPDATE articles 
-        SET title = $1, cover = $2, content = $3, updated_at = $4
-        WHERE id = $5
+        SET title = $1, cover = $2, content = $3, updated_at = $4,
+          state = $5, publish_at = $6
+        WHERE id = $7
         RETURNING *;
         "#,
       )
       .bind(&self.title)
       .bind(&self.cover)
       .bind(&self.content)
-      .bind(&now)
-      .bind(&self.id)
-      .fetch_one(&ctx.pool)
+      .bind(now)
+      .bind(&self.state)
+      .bind(self.publish_at)
+      .bind(self.id)
+      .fetch_one(&mut *conn)
       .await?
     };
+    // the editor, not necessarily the author
+    add_revision(&mut *conn, &new_article, &self.uname).await?;
+    Article::relink(&mut *conn, new_article.id, &new_article.content).await?;
 
     Ok(new_article)
   }
 
+  /// roll an article back to one of its revisions, recorded as a new
+  /// revision by `uname`.
+  pub async fn rollback(
+    ctx: &AppState, id: u32, rid: u32, uname: &str,
+  ) -> Result<Article, AppError> {
+    let revision = Revision::get(ctx, id, rid).await?;
+    let mut tx = ctx.pool.begin().await?;
+    let article: Article = sqlx::query_as(
+      r#"
+      SELECT * FROM articles WHERE id = $1;
+      "#,
+    )
+    .bind(id)
+    .fetch_one(&mut tx)
+    .await?;
+    article.check_unlocked()?;
+    keep_revision(&mut tx, &article).await?;
+    let article: Article = sqlx::query_as(
+      r#"
+      UPDATE articles 
+      SET content = $1, updated_at = $2
+      WHERE id = $3
+      RETURNING *;
+      "#,
+    )
+    .bind(&revision.content)
+    .bind(Utc::now().timestamp())
+    .bind(id)
+    .fetch_one(&mut tx)
+    .await?;
+    add_revision(&mut tx, &article, uname).await?;
+    Article::relink(&mut tx, id, &article.content).await?;
+    tx.commit().await?;
+
+    Ok(article)
+  }
+
   pub async fn del(ctx: &AppState, id: u32) -> Result<Article, AppError> {
     let article: Article = sqlx::query_as(
       r#"
@@ -135,6 +386,7 @@ impl Article {
     if article.uname != uname {
       return Err(AppError::NoPermission);
     }
+    article.check_unlocked()?;
     // num_char "\r" issue, leading OTError
     // operational_transform::OperationSeq
     // pub fn apply(&self, s: &str) -> Result<String, OTError>
@@ -144,154 +396,490 @@ impl Article {
     let esc_content = article.content.replace("\r", "\n");
     // create a document, UNIQUE(article_id) if not NULL
     // check if existing already
-    let doc_res: Result<PersistedDocument, AppError> = sqlx::query_as(
+    let doc_res: Result<PadBase, AppError> = sqlx::query_as(
       r#"
-      SELECT * FROM document WHERE article_id = $1;
+      SELECT id, article_id, base_text, base_hash, base_updated_at 
+      FROM document WHERE article_id = $1;
       "#
     )
-    .bind(&article.id)
+    .bind(article.id)
     .fetch_one(&ctx.pool)
     .await
     .map_err(|_| AppError::NotFound);
     
-    let generated_id = gen_expirable_id(60 * 60 * 24, None);
     let doc_id = match doc_res {
-      Ok(doc) if doc.id.is_some() => doc.id.unwrap_or(generated_id),
-      _ => generated_id
+      // keep the existing pad as is, the edits are merged on saving
+      Ok(doc) => {
+        if doc.base_text.is_none() {
+          set_pad_base(&mut *ctx.pool.acquire().await?, &doc.id, &article).await?;
+        }
+        doc.id
+      }
+      Err(_) => {
+        let doc_id = gen_expirable_id(60 * 60 * 24, None);
+        // record the version of article the pad starts from
+        let result = sqlx::query(
+          r#"
+          INSERT INTO
+            document (id, text, article_id, base_text, base_hash, base_updated_at)
+          VALUES
+            ($1, $2, $3, $4, $5, $6)
+          "#,
+        )
+        .bind(&doc_id)
+        .bind(&esc_content)
+        .bind(article.id)
+        .bind(&esc_content)
+        .bind(content_hash(&article.content))
+        .bind(article.updated_at)
+        .execute(&ctx.pool)
+        .await?;
+
+        if result.rows_affected() != 1 {
+          return Err(AppError::NotFound);
+        }
+        doc_id
+      }
     };
-    
-    let result = sqlx::query(
+
+    // the author owns the pad, others join via invitation or share link
+    AclEntry::grant(&ctx.pool, &doc_id, &article.uname, Access::Own).await?;
+
+    Ok(format!("/app/editor#{doc_id}"))
+  }
+
+  /// save the collaborative editing result `text` to article, as a revision.
+  /// the param `uname` is used to check author matched.   
+  /// 
+  /// if the article changed since the pad was created, the changes on both 
+  /// sides are three-way merged; on conflict nothing is saved and the text 
+  /// with conflict markers is returned, to resolve in the pad.
+  pub async fn save_doc_to_article(
+    pool: &SqlitePool, doc_id: &str, uname: &str, text: &str,
+  ) -> Result<PadSave, AppError> {
+    let mut tx = pool.begin().await?;
+    // a write first, holding the write lock till committed: the article
+    // cannot change between being read, merged and saved
+    let doc: PadBase = sqlx::query_as(
       r#"
-      INSERT INTO
-        document (id, text, article_id)
-      VALUES
-        ($1, $2, $3)
-      ON CONFLICT(id) DO UPDATE SET
-        text = excluded.text
-      "#,
+      UPDATE document SET base_hash = base_hash WHERE id = $1
+      RETURNING id, article_id, base_text, base_hash, base_updated_at;
+      "#
     )
-    .bind(&doc_id)
-    .bind(&esc_content)
-    .bind(&article.id)
-    .execute(&ctx.pool)
+    .bind(doc_id)
+    .fetch_one(&mut tx)
     .await?;
+    let article_id = doc.article_id.ok_or(AppError::NotFound)?;
 
-    if result.rows_affected() != 1 {
-      return Err(AppError::NotFound);
+    let article: Article = sqlx::query_as(
+      r#"
+      SELECT * FROM articles WHERE id = $1;
+      "#,
+    )
+    .bind(article_id)
+    .fetch_one(&mut tx)
+    .await?;
+    // check Author uname matched
+    if article.uname != uname {
+      return Err(AppError::NoPermission);
     }
+    article.check_unlocked()?;
 
-    Ok(format!("/app/editor#{doc_id}"))
+    let unchanged = doc.base_updated_at == Some(article.updated_at)
+      && doc.base_hash == Some(content_hash(&article.content));
+    let merged = match doc.base_text {
+      Some(base_text) if !unchanged => {
+        let current = article.content.replace("\r", "\n");
+        let merge = merge3(&base_text, text, &current, ("pad", "article"));
+        if merge.conflicts > 0 {
+          // rebase the pad on the current article, resolve in the pad
+          set_pad_base(&mut tx, doc_id, &article).await?;
+          tx.commit().await?;
+          return Ok(PadSave::Conflict {
+            text: merge.text,
+            conflicts: merge.conflicts,
+          });
+        }
+        merge.text
+      }
+      // unchanged, or the pad was created before tracking its base
+      _ => text.to_owned(),
+    };
+
+    keep_revision(&mut tx, &article).await?;
+
+    let article: Article = sqlx::query_as(
+      r#"
+      UPDATE articles 
+      SET content = $1, updated_at = $2
+      WHERE id = $3 AND uname = $4 AND updated_at = $5
+      RETURNING *;
+      "#,
+    )
+    .bind(&merged)
+    .bind(Utc::now().timestamp())
+    .bind(article_id) // to get article
+    .bind(uname)       // to check author matched
+    .bind(article.updated_at) // to check it is the article merged
+    .fetch_one(&mut tx)
+    .await?;
+
+    add_revision(&mut tx, &article, uname).await?;
+    Article::relink(&mut tx, article.id, &article.content).await?;
+    set_pad_base(&mut tx, doc_id, &article).await?;
+    tx.commit().await?;
+
+    Ok(PadSave::Saved { article, text: merged })
   }
 
-  /// save the collaborative editing result to article. 
-  /// the param `uname` is used to check author matched.   
-  pub async fn save_doc_to_article(
-    pool: &SqlitePool, doc_id: &str, uname: &str,
-  ) -> Result<Article, AppError> {
-    let doc = PersistedDocument::load(pool, doc_id).await?;
-    
-    // create a document
-    if let Some(article_id) = doc.article_id {
-      let article: Article = sqlx::query_as(
+  /// record the wikilinks in the `content` of article `id` anew, so links
+  /// removed by an edit no longer count as backlinks, and the links to
+  /// missing titles as wanted.
+  async fn relink(
+    conn: &mut SqliteConnection, id: u32, content: &str,
+  ) -> Result<(), AppError> {
+    sqlx::query(
+      r#"
+      DELETE FROM article_in WHERE in_ty = 'article' AND in_id = $1;
+      "#,
+    )
+    .bind(id)
+    .execute(&mut *conn)
+    .await?;
+    sqlx::query(
+      r#"
+      DELETE FROM wanted_links WHERE in_id = $1;
+      "#,
+    )
+    .bind(id)
+    .execute(&mut *conn)
+    .await?;
+
+    for target in wikilink_targets(content) {
+      let target_id: u32 = target.parse().unwrap_or(0);
+      sqlx::query(
         r#"
-        UPDATE articles 
-        SET content = $1, updated_at = $2
-        WHERE id = $3 AND uname = $4 
-        RETURNING *;
+        INSERT OR IGNORE INTO article_in (article_id, in_ty, in_id)
+        SELECT id, 'article', $1 FROM articles 
+        WHERE (id = $2 OR title = $3) AND id != $1
+        LIMIT 1;
         "#,
       )
-      .bind(&doc.text)
-      .bind(&Utc::now().timestamp())
-      .bind(&article_id) // to get article
-      .bind(&uname)      // to check author matched
-      .fetch_one(pool)
+      .bind(id)
+      .bind(target_id)
+      .bind(target)
+      .execute(&mut *conn)
       .await?;
-      
-      // del all the documents with this article_id
+
       sqlx::query(
         r#"
-        DELETE FROM document WHERE article_id = $1;
-        "#
+        INSERT OR IGNORE INTO wanted_links (title, in_id)
+        SELECT $3, $1 
+        WHERE NOT EXISTS (SELECT 1 FROM articles WHERE id = $2 OR title = $3);
+        "#,
       )
-      .bind(article_id)
-      .execute(pool)
-      .await
-      .unwrap_or_default();
+      .bind(id)
+      .bind(target_id)
+      .bind(target)
+      .execute(&mut *conn)
+      .await?;
+    }
 
-      return Ok(article);
-    } else {
-      return Err(AppError::NotFound);
+    Ok(())
+  }
+
+  /// article `id` now has the `title`: the wanted links to the title
+  /// become its backlinks.
+  pub async fn resolve_wanted(pool: &SqlitePool, id: u32, title: &str) -> Result<(), AppError> {
+    let mut tx = pool.begin().await?;
+    sqlx::query(
+      r#"
+      INSERT OR IGNORE INTO article_in (article_id, in_ty, in_id)
+      SELECT $1, 'article', in_id FROM wanted_links 
+      WHERE title = $2 AND in_id != $1;
+      "#,
+    )
+    .bind(id)
+    .bind(title)
+    .execute(&mut tx)
+    .await?;
+
+    sqlx::query(
+      r#"
+      DELETE FROM wanted_links WHERE title = $1;
+      "#,
+    )
+    .bind(title)
+    .execute(&mut tx)
+    .await?;
+    tx.commit().await?;
+
+    Ok(())
+  }
+
+  /// the wikilink targets in `content` with no article, for rendering
+  /// red links.
+  pub async fn missing_targets(
+    ctx: &AppState, content: &str,
+  ) -> Result<BTreeSet<String>, AppError> {
+    let mut missing = BTreeSet::new();
+    for target in wikilink_targets(content) {
+      let target_id: u32 = target.parse().unwrap_or(0);
+      let found: Option<(u32,)> = sqlx::query_as(
+        r#"
+        SELECT id FROM articles WHERE id = $1 OR title = $2 LIMIT 1;
+        "#,
+      )
+      .bind(target_id)
+      .bind(target)
+      .fetch_optional(&ctx.pool)
+      .await?;
+      if found.is_none() {
+        missing.insert(target.to_owned());
+      }
     }
+
+    Ok(missing)
   }
 
-  pub async fn include(
-    ctx: &AppState, id: u32, in_ty: &str, in_id: u32
-  ) -> Result<u64, AppError> {
-    let res = sqlx::query(
+  /// the published articles linking to article `id`
+  pub async fn backlinks(ctx: &AppState, id: u32) -> Result<Vec<LinkedArticle>, AppError> {
+    let linked: Vec<LinkedArticle> = sqlx::query_as(
       r#"
-      INSERT OR IGNORE INTO article_in
-      (article_id, in_ty, in_id)
-      VALUES
-      ($1, $2, $3)
-      RETURNING *;
+      SELECT a.id, a.uname, a.title FROM article_in ai
+      JOIN articles a ON a.id = ai.in_id
+      WHERE ai.article_id = $1 AND ai.in_ty = 'article'
+        AND a.state IN ('published', 'archived') AND a.is_hidden = FALSE
+      ORDER BY a.title;
       "#,
     )
-    .bind(&id)
-    .bind(in_ty)
-    .bind(&in_id)
-    .execute(&ctx.pool)
-    .await
-    .unwrap_or_default();
+    .bind(id)
+    .fetch_all(&ctx.pool)
+    .await?;
 
-    Ok(res.rows_affected())
+    Ok(linked)
   }
 
-  pub async fn update_wikilinks(
-    ctx: &AppState, id: u32, old_title: &str, new_title: &str
-  ) -> Result<u8, AppError> {
-    let wikilinks: Vec<ArticleIn> = sqlx::query_as(
+  /// the published articles mentioning the `title` of article `id` in
+  /// their text without linking to it, at most 20, found in the search index.
+  pub async fn unlinked_mentions(
+    ctx: &AppState, id: u32, title: &str,
+  ) -> Result<Vec<LinkedArticle>, AppError> {
+    if title.trim().is_empty() {
+      return Ok(vec![]);
+    }
+    let phrase = format!("body : \"{}\"", title.trim().replace('"', "\"\""));
+    let mentions: Vec<LinkedArticle> = sqlx::query_as(
       r#"
-      SELECT * FROM article_in 
-      WHERE article_id = $1 AND in_ty = $2; 
+      SELECT a.id, a.uname, a.title FROM search_index si
+      JOIN articles a ON si.on_ty = 'article' AND a.id = si.on_id
+      WHERE search_index MATCH $1 AND a.id != $2
+        AND a.state IN ('published', 'archived') AND a.is_hidden = FALSE
+        AND NOT EXISTS (
+          SELECT 1 FROM article_in ai 
+          WHERE ai.article_id = $2 AND ai.in_ty = 'article' AND ai.in_id = a.id
+        )
+      ORDER BY rank
+      LIMIT 20;
       "#,
     )
+    .bind(&phrase)
     .bind(id)
-    .bind("article")
     .fetch_all(&ctx.pool)
-    .await
-    .unwrap_or_default(); 
+    .await?;
+
+    Ok(mentions)
+  }
+
+  /// set the content of an article for a change it had no part in, a
+  /// rename or a tag merge, recorded as a revision by `uname`.
+  pub(crate) async fn rewrite(
+    conn: &mut SqliteConnection, article: &Article, content: &str, now: i64, uname: &str,
+  ) -> Result<Article, AppError> {
+    keep_revision(&mut *conn, article).await?;
+    let updated: Article = sqlx::query_as(
+      r#"
+      UPDATE articles 
+      SET content = $1, updated_at = $2
+      WHERE id = $3
+      RETURNING *;
+      "#,
+    )
+    .bind(content)
+    .bind(now)
+    .bind(article.id)
+    .fetch_one(&mut *conn)
+    .await?;
+    add_revision(&mut *conn, &updated, uname).await?;
+
+    Ok(updated)
+  }
+
+  /// rewrite the wikilinks to a renamed article, each rewrite recorded as
+  /// a revision by `uname`, who renamed it. Aliases and headings are kept:
+  /// `[[text|old#heading]]` to `[[text|new#heading]]`.
+  async fn update_wikilinks(
+    conn: &mut SqliteConnection, old_title: &str, new_title: &str, uname: &str,
+  ) -> Result<u32, AppError> {
+    // the articles maybe linking by the old title, the tokenizer tells
+    let articles: Vec<Article> = sqlx::query_as(
+      r#"
+      SELECT * FROM articles WHERE instr(content, $1) > 0;
+      "#,
+    )
+    .bind(old_title.trim())
+    .fetch_all(&mut *conn)
+    .await?;
 
     let now = Utc::now().timestamp();
-    for wikilink in wikilinks {
-      let articleid = wikilink.in_id;
-      if let Ok(article) = Article::get(&ctx, articleid).await {
-        let mut content = article.content;
-        // cases: [[src_title|tar_title]] or [[tar_title]]
-        let re = format!(r"\[\[[^\[\]]*{old_title}\]\]");
-        // println!("re: {}", re);
-        let old_links = capture_element(&content, &re);
-        for old_link in old_links {
-          let new_link = old_link.replace(old_title, new_title);
-          content = content.replace(&old_link, &new_link);
-        }
-        
-        sqlx::query(
-          r#"
-          UPDATE articles 
-          SET content = $1, updated_at = $2
-          WHERE id = $3;
-          "#,
-        )
-        .bind(&content)
-        .bind(&now)
-        .bind(&article.id)
-        .execute(&ctx.pool)
-        .await
-        .unwrap_or_default();
-      }
+    let mut count = 0;
+    for article in articles {
+      let content = match rename_wikilinks(&article.content, old_title, new_title) {
+        Some(content) => content,
+        None => continue,
+      };
+
+      Article::rewrite(&mut *conn, &article, &content, now, uname).await?;
+      count += 1;
     }
 
-    Ok(1)
+    Ok(count)
+  }
+}
+
+
+/// sha256 hex digest of content 
+fn content_hash(content: &str) -> String {
+  ring::digest::digest(&ring::digest::SHA256, content.as_bytes())
+    .as_ref()
+    .iter()
+    .map(|b| format!("{b:02x}"))
+    .collect()
+}
+
+/// record the article version a pad is based on
+async fn set_pad_base(
+  conn: &mut SqliteConnection, doc_id: &str, article: &Article,
+) -> Result<(), AppError> {
+  sqlx::query(
+    r#"
+    UPDATE document 
+    SET base_text = $1, base_hash = $2, base_updated_at = $3
+    WHERE id = $4;
+    "#,
+  )
+  .bind(article.content.replace("\r", "\n"))
+  .bind(content_hash(&article.content))
+  .bind(article.updated_at)
+  .bind(doc_id)
+  .execute(&mut *conn)
+  .await?;
+
+  Ok(())
+}
+
+/// keep the version of article about to be overwritten in revisions, if
+/// it was not recorded, e.g. saved before revisions were.
+async fn keep_revision(
+  conn: &mut SqliteConnection, article: &Article,
+) -> Result<(), AppError> {
+  sqlx::query(
+    r#"
+    INSERT INTO
+      revisions (content, on_ty, on_id, rev_by, rev_at)
+    SELECT $1, 'article', $2, $3, $4
+    WHERE NOT EXISTS (
+      SELECT 1 FROM revisions
+      WHERE on_ty = 'article' AND on_id = $2 AND is_current AND content = $1
+    )
+    "#,
+  )
+  .bind(&article.content)
+  .bind(article.id)
+  .bind(&article.uname)
+  .bind(article.updated_at)
+  .execute(&mut *conn)
+  .await?;
+
+  Ok(())
+}
+
+/// add the content of article as the current revision, a new one even if
+/// back to an earlier content, e.g. on rollback
+async fn add_revision(
+  conn: &mut SqliteConnection, article: &Article, rev_by: &str,
+) -> Result<(), AppError> {
+  let rev_id: u32 = sqlx::query_scalar(
+    r#"
+    INSERT INTO
+      revisions (content, on_ty, on_id, rev_by, rev_at, is_current)
+    VALUES
+      ($1, 'article', $2, $3, $4, TRUE)
+    RETURNING id;
+    "#,
+  )
+  .bind(&article.content)
+  .bind(article.id)
+  .bind(rev_by)
+  .bind(article.updated_at)
+  .fetch_one(&mut *conn)
+  .await?;
+
+  sqlx::query(
+    r#"
+    UPDATE revisions SET is_current = FALSE 
+    WHERE on_ty = 'article' AND on_id = $1 AND id <> $2;
+    "#,
+  )
+  .bind(article.id)
+  .bind(rev_id)
+  .execute(&mut *conn)
+  .await?;
+
+  Ok(())
+}
+
+/// a recorded version of an article
+#[derive(FromRow, Serialize, Debug, Default)]
+pub struct Revision {
+  pub id: u32,
+  pub content: String,
+  pub rev_by: String,
+  pub rev_at: i64,
+  pub is_current: bool,
+}
+
+impl Revision {
+  /// the revisions of an article, newest first
+  pub async fn list(ctx: &AppState, article_id: u32) -> Result<Vec<Revision>, AppError> {
+    let revisions: Vec<Revision> = sqlx::query_as(
+      r#"
+      SELECT id, content, rev_by, rev_at, is_current FROM revisions
+      WHERE on_ty = 'article' AND on_id = $1
+      ORDER BY rev_at DESC, id DESC;
+      "#,
+    )
+    .bind(article_id)
+    .fetch_all(&ctx.pool)
+    .await?;
+
+    Ok(revisions)
+  }
+
+  /// a revision of the article
+  pub async fn get(ctx: &AppState, article_id: u32, id: u32) -> Result<Revision, AppError> {
+    let revision: Revision = sqlx::query_as(
+      r#"
+      SELECT id, content, rev_by, rev_at, is_current FROM revisions
+      WHERE on_ty = 'article' AND on_id = $1 AND id = $2;
+      "#,
+    )
+    .bind(article_id)
+    .bind(id)
+    .fetch_one(&ctx.pool)
+    .await?;
+
+    Ok(revision)
   }
 }
 
@@ -302,9 +890,18 @@ pub struct Piece {
   pub content: String,
   pub created_at: i64,
   pub is_hidden: bool,
+  pub is_locked: bool,
 }
 
 impl Piece {
+  /// check the piece is not locked by a moderator, before editing it.
+  pub fn check_unlocked(&self) -> Result<(), AppError> {
+    if self.is_locked {
+      return Err(AppError::Moderated);
+    }
+    Ok(())
+  }
+
   pub async fn get(ctx: &AppState, id: u32) -> Result<Piece, AppError> {
     let piece: Piece = sqlx::query_as(
       r#"
@@ -389,6 +986,8 @@ pub struct Entry {
   pub content: String,
   pub uname: String,
   pub created_at: i64,
+  /// when published, to sort articles and pieces together
+  pub published_at: i64,
   pub link: String,
 }
 
@@ -397,6 +996,7 @@ impl From<Article> for Entry {
     Entry {
       ty: String::from("article"),
       id: a.id,
+      published_at: a.published_at(),
       title: a.title,
       cover: a.cover,
       content: a.content,
@@ -417,6 +1017,7 @@ impl From<Piece> for Entry {
       content: md2html(&p.content, "articlepage", "tag"),
       uname: p.uname,
       created_at: p.created_at,
+      published_at: p.created_at,
       link: format!("/piece/{}", p.id),
     }
   }
@@ -432,6 +1033,7 @@ impl From<Feed> for Entry {
       content: f.content,
       uname: f.channel_link, // channel as uname
       created_at: f.published,
+      published_at: f.published,
       link: f.feed_url,
     }
   }
@@ -443,20 +1045,40 @@ impl From<Tag> for Entry {
       ty: String::from("tag"),
       id: t.id,
       title: t.tname.clone(),
-      cover: String::from(""),
+      link: t.link(),
+      cover: t.cover,
       content: t.content,
       uname: String::from(""),
       created_at: 0,
-      link: format!("/tag/{}", t.tname),
+      published_at: 0,
     }
   }
 }
 
+/// publish the scheduled articles which are due, run periodically.
+pub async fn publish_scheduled_job(ctx: &AppState) -> Result<u64, AppError> {
+  let published = sqlx::query(
+    r#"
+    UPDATE articles SET state = $1
+    WHERE state = $2 AND publish_at <= $3;
+    "#,
+  )
+  .bind(PUBLISHED)
+  .bind(SCHEDULED)
+  .bind(Utc::now().timestamp())
+  .execute(&ctx.pool)
+  .await?
+  .rows_affected();
+
+  Ok(published)
+}
+
 // #[derive(Debug, Clone)]
+// the last field is the viewer, who sees its own drafts and scheduled ones
 pub enum QueryArticles {
-  Index(String, i64, i64), // ord, perpage, page
-  Tag(String, i64, i64),   // tag, ..
-  User(String, u8, i64, i64), // uname, action:1-by|2-like
+  Index(String, i64, i64, String), // ord, perpage, page, viewer
+  Tag(String, i64, i64, String),   // tag, ..
+  User(String, u8, i64, i64, String), // uname, action:1-by|2-like
   // Item(u32, String, i64, i64), // item id, ord, ..
   // Kw(String, i64, i64), // kw, ..
 }
@@ -466,19 +1088,26 @@ impl QueryArticles {
     let mut article_list: Vec<Article> = Vec::new();
     // let mut article_count: i64 = 0;
     match self {
-      QueryArticles::Index(ord, perpage, page) => {
+      QueryArticles::Index(ord, perpage, page, viewer) => {
         let page_offset = std::cmp::max(0, page - 1);
         article_list = if ord.to_lowercase().trim() == "desc" {
           sqlx::query_as(
             r#"
             SELECT * FROM articles 
-            ORDER BY id DESC
+            WHERE is_hidden = FALSE AND (
+              state = 'published' 
+              OR (uname = $3 AND state IN ('draft', 'scheduled'))
+            )
+            ORDER BY
+              CASE WHEN publish_at > 0 THEN publish_at ELSE created_at END DESC,
+              id DESC
             LIMIT $1 
             OFFSET $2; 
             "#,
           )
           .bind(perpage)
           .bind(perpage * page_offset)
+          .bind(&viewer)
           .fetch_all(&ctx.pool)
           .await
           .unwrap_or_default()
@@ -486,49 +1115,67 @@ impl QueryArticles {
           sqlx::query_as(
             r#"
             SELECT * FROM articles 
-            ORDER BY id ASC
+            WHERE is_hidden = FALSE AND (
+              state = 'published' 
+              OR (uname = $3 AND state IN ('draft', 'scheduled'))
+            )
+            ORDER BY
+              CASE WHEN publish_at > 0 THEN publish_at ELSE created_at END ASC,
+              id ASC
             LIMIT $1 
             OFFSET $2; 
             "#,
           )
           .bind(perpage)
           .bind(perpage * page_offset)
+          .bind(&viewer)
           .fetch_all(&ctx.pool)
           .await
           .unwrap_or_default()
         };
       }
-      QueryArticles::Tag(tname, perpage, page) => {
+      QueryArticles::Tag(tname, perpage, page, viewer) => {
         let page_offset = std::cmp::max(0, page - 1);
         article_list = sqlx::query_as(
           r#"
           SELECT * FROM articles
           WHERE id IN (
             SELECT on_id FROM tag_entry
-            WHERE on_ty = 'article' AND tag_id = (
-              SELECT id FROM tags WHERE tname = $1
+            WHERE on_ty = 'article' AND tag_id IN (
+              SELECT id FROM tags 
+              WHERE tname = $1 OR substr(tname, 1, length($1) + 1) = $1 || '/'
             )
-            ORDER BY on_id DESC
-            LIMIT $2 
-            OFFSET $3 
-          );
+          ) AND is_hidden = FALSE AND (
+            state = 'published' 
+            OR (uname = $4 AND state IN ('draft', 'scheduled'))
+          )
+          ORDER BY
+            CASE WHEN publish_at > 0 THEN publish_at ELSE created_at END DESC,
+            id DESC
+          LIMIT $2 
+          OFFSET $3;
           "#,
         )
         .bind(&tname)
         .bind(perpage)
         .bind(perpage * page_offset)
+        .bind(&viewer)
         .fetch_all(&ctx.pool)
         .await
         .unwrap_or_default();
       }
-      QueryArticles::User(uname, act, perpage, page) => {
+      QueryArticles::User(uname, act, perpage, page, viewer) => {
         let page_offset = std::cmp::max(0, page - 1);
         if act == 1 {
+          // all of them on one's own profile
           article_list = sqlx::query_as(
             r#"
             SELECT * FROM articles 
-            WHERE uname = $1
-            ORDER BY id DESC
+            WHERE uname = $1 
+              AND ((state = 'published' AND is_hidden = FALSE) OR uname = $4)
+            ORDER BY
+              CASE WHEN publish_at > 0 THEN publish_at ELSE created_at END DESC,
+              id DESC
             LIMIT $2 
             OFFSET $3; 
             "#,
@@ -536,6 +1183,7 @@ impl QueryArticles {
           .bind(&uname)
           .bind(perpage)
           .bind(perpage * page_offset)
+          .bind(&viewer)
           .fetch_all(&ctx.pool)
           .await
           .unwrap_or_default()
@@ -555,7 +1203,7 @@ impl QueryArticles {
 pub enum QueryPieces {
   Index(String, i64, i64), // ord, perpage, page
   Tag(String, i64, i64),   // tag, ..
-  User(String, u8, i64, i64), // uname, action:1-by|2-like
+  User(String, u8, i64, i64, String), // uname, action:1-by|2-like, viewer
   // Kw(String, i64, i64), // kw, ..
 }
 
@@ -570,6 +1218,7 @@ impl QueryPieces {
           sqlx::query_as(
             r#"
             SELECT * FROM pieces 
+            WHERE is_hidden = FALSE
             ORDER BY id DESC
             LIMIT $1 
             OFFSET $2; 
@@ -584,6 +1233,7 @@ impl QueryPieces {
           sqlx::query_as(
             r#"
             SELECT * FROM pieces 
+            WHERE is_hidden = FALSE
             ORDER BY id ASC
             LIMIT $1 
             OFFSET $2; 
@@ -603,13 +1253,14 @@ impl QueryPieces {
           SELECT * FROM pieces
           WHERE id IN (
             SELECT on_id FROM tag_entry
-            WHERE on_ty = 'piece' AND tag_id = (
-              SELECT id FROM tags WHERE tname = $1
+            WHERE on_ty = 'piece' AND tag_id IN (
+              SELECT id FROM tags 
+              WHERE tname = $1 OR substr(tname, 1, length($1) + 1) = $1 || '/'
             )
-            ORDER BY on_id DESC
-            LIMIT $2 
-            OFFSET $3 
-          );
+          ) AND is_hidden = FALSE
+          ORDER BY id DESC
+          LIMIT $2 
+          OFFSET $3;
           "#,
         )
         .bind(&tname)
@@ -619,13 +1270,13 @@ impl QueryPieces {
         .await
         .unwrap_or_default();
       }
-      QueryPieces::User(uname, act, perpage, page) => {
+      QueryPieces::User(uname, act, perpage, page, viewer) => {
         let page_offset = std::cmp::max(0, page - 1);
         if act == 1 {
           piece_list = sqlx::query_as(
             r#"
             SELECT * FROM pieces 
-            WHERE uname = $1
+            WHERE uname = $1 AND (is_hidden = FALSE OR uname = $4)
             ORDER BY id DESC
             LIMIT $2 
             OFFSET $3; 
@@ -634,6 +1285,7 @@ impl QueryPieces {
           .bind(&uname)
           .bind(perpage)
           .bind(perpage * page_offset)
+          .bind(&viewer)
           .fetch_all(&ctx.pool)
           .await
           .unwrap_or_default()
@@ -648,3 +1300,201 @@ impl QueryPieces {
     Ok((piece_list, piece_count))
   }
 }
+
+#[cfg(test)]
+mod tests {
+  use std::collections::BTreeSet;
+
+  use super::*;
+  use crate::db::tag::TagEntry;
+  use crate::db::{test_pool, test_state};
+
+  #[test]
+  fn test_moderation_checks() {
+    let article = Article {
+      uname: "alice".into(),
+      is_hidden: true,
+      ..Article::default()
+    };
+    assert!(article.check_unhidden("alice", false).is_ok());
+    assert!(matches!(article.check_unhidden("bob", false), Err(AppError::Moderated)));
+    assert!(matches!(article.check_unhidden("", false), Err(AppError::Moderated)));
+    assert!(article.check_unhidden("bob", true).is_ok());
+    assert!(article.check_unlocked().is_ok());
+
+    let article = Article { is_locked: true, ..article };
+    assert!(matches!(article.check_unlocked(), Err(AppError::Moderated)));
+    let piece = Piece { is_locked: true, ..Piece::default() };
+    assert!(matches!(piece.check_unlocked(), Err(AppError::Moderated)));
+    assert!(Piece::default().check_unlocked().is_ok());
+  }
+
+  #[tokio::test]
+  async fn test_revisions_back_and_forth() {
+    let (_dir, pool) = test_pool().await;
+    // saved before revisions were recorded
+    let article: Article = sqlx::query_as(
+      r#"
+      INSERT INTO articles (title, uname, content, created_at, updated_at)
+      VALUES ('Title', 'alice', 'one', 1, 1)
+      RETURNING *;
+      "#,
+    )
+    .fetch_one(&pool)
+    .await
+    .unwrap();
+
+    let mut tx = pool.begin().await.unwrap();
+    let article = Article::rewrite(&mut tx, &article, "two", 2, "bob").await.unwrap();
+    // back to the first content, as on rollback
+    Article::rewrite(&mut tx, &article, "one", 3, "alice").await.unwrap();
+    tx.commit().await.unwrap();
+
+    let revisions: Vec<(String, String, i64, bool)> = sqlx::query_as(
+      r#"
+      SELECT content, rev_by, rev_at, is_current FROM revisions
+      WHERE on_ty = 'article' AND on_id = $1
+      ORDER BY id;
+      "#,
+    )
+    .bind(article.id)
+    .fetch_all(&pool)
+    .await
+    .unwrap();
+    assert_eq!(
+      revisions,
+      vec![
+        ("one".into(), "alice".into(), 1, false),
+        ("two".into(), "bob".into(), 2, false),
+        ("one".into(), "alice".into(), 3, true),
+      ]
+    );
+  }
+
+  #[tokio::test]
+  async fn test_update_wikilinks() {
+    let (_dir, pool) = test_pool().await;
+    sqlx::query(
+      r#"
+      INSERT INTO articles (title, uname, content, created_at, updated_at)
+      VALUES
+        ('Linking', 'alice', 'see [[Old]] and [[more|Old#Part]]', 1, 1),
+        ('Other', 'alice', 'Old but no link', 1, 1);
+      "#,
+    )
+    .execute(&pool)
+    .await
+    .unwrap();
+
+    let mut tx = pool.begin().await.unwrap();
+    let count = Article::update_wikilinks(&mut tx, "Old", "New", "bob").await.unwrap();
+    tx.commit().await.unwrap();
+    assert_eq!(count, 1);
+
+    let contents: Vec<String> =
+      sqlx::query_scalar("SELECT content FROM articles ORDER BY id")
+        .fetch_all(&pool)
+        .await
+        .unwrap();
+    assert_eq!(contents, vec!["see [[New]] and [[more|New#Part]]", "Old but no link"]);
+  }
+
+  #[tokio::test]
+  async fn test_save_doc_to_article() {
+    let (_dir, pool) = test_pool().await;
+    let article: Article = sqlx::query_as(
+      r#"
+      INSERT INTO articles (title, uname, content, created_at, updated_at)
+      VALUES ('Title', 'alice', $1, 1, 1)
+      RETURNING *;
+      "#,
+    )
+    .bind("title\n\none\ntwo\nthree\n")
+    .fetch_one(&pool)
+    .await
+    .unwrap();
+    sqlx::query(
+      r#"
+      INSERT INTO
+        document (id, text, article_id, base_text, base_hash, base_updated_at)
+      VALUES
+        ('doc', $1, $2, $1, $3, $4)
+      "#,
+    )
+    .bind(&article.content)
+    .bind(article.id)
+    .bind(content_hash(&article.content))
+    .bind(article.updated_at)
+    .execute(&pool)
+    .await
+    .unwrap();
+    // edited apart from the pad meanwhile
+    let mut tx = pool.begin().await.unwrap();
+    Article::rewrite(&mut tx, &article, "title\n\none\ntwo\nthree\nfour\n", 2, "alice").await.unwrap();
+    tx.commit().await.unwrap();
+
+    let saved = Article::save_doc_to_article(&pool, "doc", "alice", "title\n\nONE\ntwo\nthree\n")
+      .await
+      .unwrap();
+    let (article, text) = match saved {
+      PadSave::Saved { article, text } => (article, text),
+      PadSave::Conflict { text, .. } => panic!("expected the edits merged: {text:?}"),
+    };
+    assert_eq!(text, "title\n\nONE\ntwo\nthree\nfour\n");
+    assert_eq!(article.content, text);
+
+    // the next save is based on the saved article
+    let base: (String, i64) = sqlx::query_as(
+      "SELECT base_text, base_updated_at FROM document WHERE id = 'doc'",
+    )
+    .fetch_one(&pool)
+    .await
+    .unwrap();
+    assert_eq!(base, (text, article.updated_at));
+
+    let denied = Article::save_doc_to_article(&pool, "doc", "bob", "").await;
+    assert!(matches!(denied, Err(AppError::NoPermission)));
+  }
+
+  #[tokio::test]
+  async fn test_query_by_publish_time() {
+    let (_dir, ctx) = test_state().await;
+    // written in turn, published otherwise
+    sqlx::query(
+      r#"
+      INSERT INTO articles (title, uname, content, created_at, updated_at, state, publish_at)
+      VALUES
+        ('First', 'alice', '#rs', 1, 1, 'published', 30),
+        ('Second', 'alice', '#rs', 2, 2, 'published', 20),
+        ('Draft', 'alice', '#rs', 25, 25, 'draft', 0);
+      "#,
+    )
+    .execute(&ctx.pool)
+    .await
+    .unwrap();
+    for id in [1, 2, 3] {
+      TagEntry::tag(&ctx, BTreeSet::from([String::from("rs")]), "article", id)
+        .await
+        .unwrap();
+    }
+    let titles = |articles: Vec<Article>| -> Vec<String> {
+      articles.into_iter().map(|a| a.title).collect()
+    };
+
+    let (articles, _) = QueryArticles::Index("desc".into(), 10, 1, "alice".into())
+      .get(&ctx)
+      .await
+      .unwrap();
+    assert_eq!(titles(articles), ["First", "Draft", "Second"]);
+    let (articles, _) = QueryArticles::Index("asc".into(), 10, 1, String::new())
+      .get(&ctx)
+      .await
+      .unwrap();
+    assert_eq!(titles(articles), ["Second", "First"]);
+    let (articles, _) = QueryArticles::Tag("rs".into(), 10, 1, String::new())
+      .get(&ctx)
+      .await
+      .unwrap();
+    assert_eq!(titles(articles), ["First", "Second"]);
+  }
+}
@@ -0,0 +1,171 @@
+//! the graph of published articles, their tags and the wikilinks between
+//! them, for the graph page and its JSON.
+
+use serde::Serialize;
+use sqlx::FromRow;
+use std::collections::BTreeSet;
+
+use crate::{error::AppError, AppState};
+
+/// most recently updated articles in the graph, at most
+const GRAPH_ARTICLES: i64 = 1000;
+
+/// A node, `ty` is `article` or `tag`, `id` is `ty:id` or `tag:tname`.
+#[derive(Serialize, Debug, PartialEq)]
+pub struct Node {
+  pub id: String,
+  pub ty: &'static str,
+  pub label: String,
+  pub link: String,
+}
+
+/// An edge, `ty` is `link` for a wikilink from `source` to `target`, or
+/// `tag` from an article to its tag.
+#[derive(Serialize, Debug, PartialEq)]
+pub struct Edge {
+  pub source: String,
+  pub target: String,
+  pub ty: &'static str,
+}
+
+#[derive(Serialize, Debug, Default)]
+pub struct Graph {
+  pub nodes: Vec<Node>,
+  pub edges: Vec<Edge>,
+}
+
+#[derive(FromRow)]
+struct ArticleRow {
+  id: u32,
+  title: String,
+}
+
+#[derive(FromRow)]
+struct LinkRow {
+  article_id: u32,
+  in_id: u32,
+}
+
+#[derive(FromRow)]
+struct TagRow {
+  on_id: u32,
+  tname: String,
+}
+
+impl Graph {
+  pub async fn get(ctx: &AppState) -> Result<Graph, AppError> {
+    let articles: Vec<ArticleRow> = sqlx::query_as(
+      r#"
+      SELECT id, title FROM articles
+      WHERE state IN ('published', 'archived') AND is_hidden = FALSE
+      ORDER BY updated_at DESC
+      LIMIT $1;
+      "#,
+    )
+    .bind(GRAPH_ARTICLES)
+    .fetch_all(&ctx.pool)
+    .await?;
+
+    let links: Vec<LinkRow> = sqlx::query_as(
+      r#"
+      SELECT article_id, in_id FROM article_in WHERE in_ty = 'article';
+      "#,
+    )
+    .fetch_all(&ctx.pool)
+    .await?;
+
+    let tags: Vec<TagRow> = sqlx::query_as(
+      r#"
+      SELECT te.on_id AS on_id, t.tname AS tname FROM tag_entry te
+      JOIN tags t ON t.id = te.tag_id
+      WHERE te.on_ty = 'article';
+      "#,
+    )
+    .fetch_all(&ctx.pool)
+    .await?;
+
+    Ok(build_graph(articles, links, tags))
+  }
+}
+
+/// the nodes and edges among the given articles, links and tags outside
+/// them left out.
+fn build_graph(articles: Vec<ArticleRow>, links: Vec<LinkRow>, tags: Vec<TagRow>) -> Graph {
+  let ids: BTreeSet<u32> = articles.iter().map(|a| a.id).collect();
+  let mut graph = Graph::default();
+  for a in articles {
+    graph.nodes.push(Node {
+      id: format!("article:{}", a.id),
+      ty: "article",
+      label: a.title,
+      link: format!("/article/{}/view", a.id),
+    });
+  }
+
+  for l in links {
+    if ids.contains(&l.in_id) && ids.contains(&l.article_id) {
+      graph.edges.push(Edge {
+        source: format!("article:{}", l.in_id),
+        target: format!("article:{}", l.article_id),
+        ty: "link",
+      });
+    }
+  }
+
+  let mut tag_names: BTreeSet<String> = BTreeSet::new();
+  for t in tags {
+    if !ids.contains(&t.on_id) {
+      continue;
+    }
+    graph.edges.push(Edge {
+      source: format!("article:{}", t.on_id),
+      target: format!("tag:{}", t.tname),
+      ty: "tag",
+    });
+    tag_names.insert(t.tname);
+  }
+  for tname in tag_names {
+    graph.nodes.push(Node {
+      id: format!("tag:{}", tname),
+      ty: "tag",
+      link: format!("/tag/{}", urlencoding::encode(&tname)),
+      label: format!("#{}", tname),
+    });
+  }
+
+  graph
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_build_graph() {
+    let articles = vec![
+      ArticleRow { id: 1, title: "One".into() },
+      ArticleRow { id: 2, title: "Two".into() },
+    ];
+    // 2 links to 1, 3 is a draft linking to 1
+    let links = vec![
+      LinkRow { article_id: 1, in_id: 2 },
+      LinkRow { article_id: 1, in_id: 3 },
+    ];
+    let tags = vec![
+      TagRow { on_id: 1, tname: "rust".into() },
+      TagRow { on_id: 2, tname: "rust".into() },
+      TagRow { on_id: 3, tname: "draft".into() },
+    ];
+    let graph = build_graph(articles, links, tags);
+
+    let ids: Vec<&str> = graph.nodes.iter().map(|n| n.id.as_str()).collect();
+    assert_eq!(ids, vec!["article:1", "article:2", "tag:rust"]);
+    assert_eq!(graph.nodes[2].link, "/tag/rust");
+    assert_eq!(graph.edges.len(), 3);
+    assert_eq!(
+      graph.edges[0],
+      Edge { source: "article:2".into(), target: "article:1".into(), ty: "link" }
+    );
+    assert!(graph.edges[1..].iter().all(|e| e.ty == "tag" && e.target == "tag:rust"));
+  }
+}
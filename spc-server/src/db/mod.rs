@@ -1,6 +1,35 @@
 pub mod article;
 pub mod feed;
+pub mod graph;
+pub mod moderation;
 pub mod note;
+pub mod search;
 pub mod sled;
 pub mod tag;
 pub mod user;
+
+/// A database with the migrations run, in a temporary directory removed
+/// along with the returned guard, for the tests.
+#[cfg(test)]
+pub(crate) async fn test_pool() -> (tempfile::TempDir, sqlx::SqlitePool) {
+  use sqlx::sqlite::SqliteConnectOptions;
+  use std::str::FromStr;
+
+  let dir = tempfile::tempdir().unwrap();
+  let uri = format!("sqlite://{}", dir.path().join("test.db").display());
+  let options = SqliteConnectOptions::from_str(&uri)
+    .unwrap()
+    .create_if_missing(true);
+  let pool = sqlx::SqlitePool::connect_with(options).await.unwrap();
+  sqlx::migrate!("./migrations").run(&pool).await.unwrap();
+  (dir, pool)
+}
+
+/// The app state over `test_pool` and a temporary sled db, for the tests.
+#[cfg(test)]
+pub(crate) async fn test_state() -> (tempfile::TempDir, crate::AppState) {
+  let (dir, pool) = test_pool().await;
+  let sled = ::sled::Config::new().temporary(true).open().unwrap();
+  let pads = Default::default();
+  (dir, crate::AppState { pool, sled, pads })
+}
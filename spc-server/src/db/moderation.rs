@@ -0,0 +1,148 @@
+//! models for moderation: hide or lock content, with an audit trail
+
+use chrono::Utc;
+use serde::Serialize;
+use sqlx::FromRow;
+
+use crate::{error::AppError, AppState};
+
+/// a moderator action on an article or piece
+#[derive(FromRow, Serialize, Debug, Default)]
+pub struct ModAction {
+  pub id: u32,
+  pub on_ty: String,
+  pub on_id: u32,
+  pub act: String,
+  pub reason: String,
+  pub mod_by: String,
+  pub mod_at: i64,
+}
+
+impl ModAction {
+  /// hide, unhide, lock or unlock the content `on_ty` `on_id`, recorded
+  /// with the reason and moderator.
+  pub async fn apply(
+    ctx: &AppState, on_ty: &str, on_id: u32, act: &str, reason: &str, mod_by: &str,
+  ) -> Result<ModAction, AppError> {
+    // never format the request into sql, match the known names only
+    let table = match on_ty {
+      "article" => "articles",
+      "piece" => "pieces",
+      _ => return Err(AppError::InvalidInput),
+    };
+    let set = match act {
+      "hide" => "is_hidden = TRUE",
+      "unhide" => "is_hidden = FALSE",
+      "lock" => "is_locked = TRUE",
+      "unlock" => "is_locked = FALSE",
+      _ => return Err(AppError::InvalidInput),
+    };
+
+    let mut tx = ctx.pool.begin().await?;
+    let updated = sqlx::query(&format!("UPDATE {table} SET {set} WHERE id = $1"))
+      .bind(on_id)
+      .execute(&mut tx)
+      .await?
+      .rows_affected();
+    if updated != 1 {
+      return Err(AppError::NotFound);
+    }
+    let action: ModAction = sqlx::query_as(
+      r#"
+      INSERT INTO mod_actions (on_ty, on_id, act, reason, mod_by, mod_at)
+      VALUES ($1, $2, $3, $4, $5, $6)
+      RETURNING *;
+      "#,
+    )
+    .bind(on_ty)
+    .bind(on_id)
+    .bind(act)
+    .bind(reason)
+    .bind(mod_by)
+    .bind(Utc::now().timestamp())
+    .fetch_one(&mut tx)
+    .await?;
+    tx.commit().await?;
+
+    Ok(action)
+  }
+
+  /// the actions, newest first
+  pub async fn get_list(
+    ctx: &AppState, perpage: i64, page: i64,
+  ) -> Result<Vec<ModAction>, AppError> {
+    let page_offset = std::cmp::max(0, page - 1);
+    let actions: Vec<ModAction> = sqlx::query_as(
+      r#"
+      SELECT * FROM mod_actions
+      ORDER BY id DESC
+      LIMIT $1
+      OFFSET $2;
+      "#,
+    )
+    .bind(perpage)
+    .bind(perpage * page_offset)
+    .fetch_all(&ctx.pool)
+    .await?;
+
+    Ok(actions)
+  }
+
+  /// the actions on the content `on_ty` `on_id`, newest first
+  pub async fn get_on(
+    ctx: &AppState, on_ty: &str, on_id: u32,
+  ) -> Result<Vec<ModAction>, AppError> {
+    let actions: Vec<ModAction> = sqlx::query_as(
+      r#"
+      SELECT * FROM mod_actions
+      WHERE on_ty = $1 AND on_id = $2
+      ORDER BY id DESC;
+      "#,
+    )
+    .bind(on_ty)
+    .bind(on_id)
+    .fetch_all(&ctx.pool)
+    .await?;
+
+    Ok(actions)
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::db::test_state;
+
+  #[tokio::test]
+  async fn test_apply() {
+    let (_dir, ctx) = test_state().await;
+    sqlx::query(
+      r#"
+      INSERT INTO pieces (uname, content, created_at) VALUES ('alice', 'hi', 1);
+      "#,
+    )
+    .execute(&ctx.pool)
+    .await
+    .unwrap();
+
+    let action = ModAction::apply(&ctx, "piece", 1, "lock", "spam", "mod").await.unwrap();
+    assert_eq!((action.act.as_str(), action.mod_by.as_str()), ("lock", "mod"));
+    let locked: bool = sqlx::query_scalar("SELECT is_locked FROM pieces WHERE id = 1")
+      .fetch_one(&ctx.pool)
+      .await
+      .unwrap();
+    assert!(locked);
+
+    // only the known acts and types, on existing content
+    let unknown_act = ModAction::apply(&ctx, "piece", 1, "is_hidden = TRUE", "", "mod").await;
+    assert!(matches!(unknown_act, Err(AppError::InvalidInput)));
+    let unknown_ty = ModAction::apply(&ctx, "users", 1, "hide", "", "mod").await;
+    assert!(matches!(unknown_ty, Err(AppError::InvalidInput)));
+    let missing = ModAction::apply(&ctx, "article", 1, "hide", "", "mod").await;
+    assert!(matches!(missing, Err(AppError::NotFound)));
+
+    let actions = ModAction::get_on(&ctx, "piece", 1).await.unwrap();
+    assert_eq!(actions.len(), 1);
+    assert!(ModAction::get_on(&ctx, "article", 1).await.unwrap().is_empty());
+  }
+}
@@ -0,0 +1,244 @@
+//! full-text search, on the FTS5 table `search_index` kept in sync by
+//! triggers on articles, pieces, notes, feeds and tags, each row keyed by
+//! its rowid in `search_rows`.
+
+use serde::{Deserialize, Serialize};
+use sqlx::FromRow;
+
+use crate::{error::AppError, AppState};
+
+/// marks the highlighted terms, before the html is escaped
+const MARK_START: char = '\u{e000}';
+const MARK_END: char = '\u{e001}';
+
+/// A search, the params of the search page and API.
+#[derive(Deserialize, Debug, Default, Clone)]
+pub struct SearchQuery {
+  /// the words to search, all of them, a trailing `*` for a prefix
+  pub q: String,
+  /// article|piece|note|feed|tag, all if none
+  pub ty: Option<String>,
+  /// articles and pieces with the tag
+  pub tag: Option<String>,
+  /// by the author
+  pub author: Option<String>,
+  pub perpage: Option<i64>,
+  pub page: Option<i64>,
+}
+
+/// A search result, `title` and `snippet` are escaped html with the
+/// matched terms in `<mark>`.
+#[derive(FromRow, Serialize, Debug, Default)]
+pub struct SearchHit {
+  pub on_ty: String,
+  pub on_id: String,
+  pub uname: String,
+  pub title: String,
+  pub snippet: String,
+  /// the feed url for feeds, the name for tags
+  #[serde(skip)]
+  target: Option<String>,
+  #[sqlx(default)]
+  pub link: String,
+}
+
+impl SearchQuery {
+  /// hits per page, 20 by default, at most 100
+  pub fn perpage(&self) -> i64 {
+    self.perpage.unwrap_or(20).clamp(1, 100)
+  }
+
+  /// search what `viewer` can see: published articles, unhidden content,
+  /// its own notes and drafts.
+  pub async fn get(&self, ctx: &AppState, viewer: &str) -> Result<Vec<SearchHit>, AppError> {
+    let terms = match_terms(&self.q);
+    if terms.is_empty() {
+      return Ok(vec![]);
+    }
+    let perpage = self.perpage();
+    let page_offset = std::cmp::max(0, self.page.unwrap_or(1) - 1);
+
+    let mut hits: Vec<SearchHit> = sqlx::query_as(
+      r#"
+      SELECT
+        si.on_ty AS on_ty,
+        CAST(si.on_id AS TEXT) AS on_id,
+        si.uname AS uname,
+        highlight(search_index, 0, $1, $2) AS title,
+        snippet(search_index, 1, $1, $2, '...', 24) AS snippet,
+        CASE si.on_ty
+          WHEN 'feed' THEN f.feed_url
+          WHEN 'tag' THEN si.title
+        END AS target
+      FROM search_index si
+      LEFT JOIN articles a ON si.on_ty = 'article' AND a.id = si.on_id
+      LEFT JOIN pieces p ON si.on_ty = 'piece' AND p.id = si.on_id
+      LEFT JOIN feeds f ON si.on_ty = 'feed' AND f.id = si.on_id
+      WHERE search_index MATCH $3
+        AND (
+          (si.on_ty = 'article' AND a.is_hidden = FALSE AND (
+            a.state = 'published'
+            OR (a.uname = $4 AND a.state IN ('draft', 'scheduled'))
+          ))
+          OR (si.on_ty = 'piece' AND p.is_hidden = FALSE)
+          OR (si.on_ty = 'note' AND si.uname = $4 AND $4 != '')
+          OR si.on_ty IN ('feed', 'tag')
+        )
+        AND ($5 = '' OR si.on_ty = $5)
+        AND ($6 = '' OR si.uname = $6)
+        AND ($7 = '' OR EXISTS (
+          SELECT 1 FROM tag_entry te JOIN tags t ON t.id = te.tag_id
+          WHERE t.tname = $7 AND te.on_ty = si.on_ty AND te.on_id = si.on_id
+        ))
+      ORDER BY rank
+      LIMIT $8
+      OFFSET $9;
+      "#,
+    )
+    .bind(MARK_START.to_string())
+    .bind(MARK_END.to_string())
+    .bind(&terms)
+    .bind(viewer)
+    .bind(self.ty.as_deref().unwrap_or_default().trim())
+    .bind(self.author.as_deref().unwrap_or_default().trim())
+    .bind(self.tag.as_deref().unwrap_or_default().trim())
+    .bind(perpage)
+    .bind(perpage * page_offset)
+    .fetch_all(&ctx.pool)
+    .await?;
+
+    for hit in hits.iter_mut() {
+      hit.title = mark_html(&hit.title);
+      hit.snippet = mark_html(&hit.snippet);
+      hit.link = match hit.on_ty.as_str() {
+        "article" => format!("/article/{}/view", hit.on_id),
+        "piece" => format!("/user/{}", hit.uname),
+        "note" => format!("/app/write/{}", hit.on_id),
+        "feed" => hit.target.take().unwrap_or_default(),
+        "tag" => format!(
+          "/tag/{}",
+          urlencoding::encode(&hit.target.take().unwrap_or_default())
+        ),
+        _ => String::new(),
+      };
+    }
+
+    Ok(hits)
+  }
+}
+
+/// the user input as an FTS5 query: all the words, quoted so that no
+/// syntax gets in, with a trailing `*` kept for prefix search.
+fn match_terms(q: &str) -> String {
+  q.split_whitespace()
+    .filter_map(|word| {
+      let (word, prefix) = match word.strip_suffix('*') {
+        Some(w) => (w, "*"),
+        None => (word, ""),
+      };
+      if word.is_empty() {
+        return None;
+      }
+      Some(format!("\"{}\"{}", word.replace('"', "\"\""), prefix))
+    })
+    .collect::<Vec<_>>()
+    .join(" ")
+}
+
+/// escape the text as html, then turn the marks into `<mark>`.
+fn mark_html(text: &str) -> String {
+  let mut html = String::with_capacity(text.len());
+  for c in text.chars() {
+    match c {
+      '&' => html.push_str("&amp;"),
+      '<' => html.push_str("&lt;"),
+      '>' => html.push_str("&gt;"),
+      '"' => html.push_str("&quot;"),
+      '\'' => html.push_str("&#39;"),
+      MARK_START => html.push_str("<mark>"),
+      MARK_END => html.push_str("</mark>"),
+      c => html.push(c),
+    }
+  }
+  html
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::db::test_state;
+
+  /// what `viewer` finds searching `q`, sorted
+  async fn found(ctx: &AppState, q: &str, viewer: &str) -> Vec<(String, String)> {
+    let query = SearchQuery { q: q.into(), ..SearchQuery::default() };
+    let mut hits: Vec<_> = query
+      .get(ctx, viewer)
+      .await
+      .unwrap()
+      .into_iter()
+      .map(|hit| (hit.on_ty, hit.on_id))
+      .collect();
+    hits.sort();
+    hits
+  }
+
+  fn hit(on_ty: &str, on_id: &str) -> (String, String) {
+    (on_ty.into(), on_id.into())
+  }
+
+  #[tokio::test]
+  async fn test_visibility() {
+    let (_dir, ctx) = test_state().await;
+    sqlx::query(
+      r#"
+      INSERT INTO articles (id, title, uname, content, created_at, updated_at, state, is_hidden)
+      VALUES
+        (1, 'Public', 'alice', 'apple pie', 1, 1, 'published', FALSE),
+        (2, 'Draft', 'alice', 'apple tart', 1, 1, 'draft', FALSE),
+        (3, 'Hidden', 'alice', 'apple jam', 1, 1, 'published', TRUE);
+      INSERT INTO pieces (id, uname, content, created_at, is_hidden)
+      VALUES (1, 'alice', 'an apple a day', 1, FALSE), (2, 'alice', 'apple spam', 1, TRUE);
+      INSERT INTO notes (id, uname, title, content, created_at, updated_at)
+      VALUES ('n1', 'alice', 'Note', 'apple seeds', 1, 1);
+      "#,
+    )
+    .execute(&ctx.pool)
+    .await
+    .unwrap();
+
+    let public = vec![hit("article", "1"), hit("piece", "1")];
+    assert_eq!(found(&ctx, "apple", "").await, public);
+    assert_eq!(found(&ctx, "apple", "bob").await, public);
+    assert_eq!(
+      found(&ctx, "apple", "alice").await,
+      vec![hit("article", "1"), hit("article", "2"), hit("note", "n1"), hit("piece", "1")]
+    );
+
+    // the index follows the edits and deletions
+    sqlx::query(
+      r#"
+      UPDATE articles SET content = 'pear pie' WHERE id = 1;
+      DELETE FROM pieces WHERE id = 1;
+      "#,
+    )
+    .execute(&ctx.pool)
+    .await
+    .unwrap();
+    assert!(found(&ctx, "apple", "").await.is_empty());
+    assert_eq!(found(&ctx, "pear", "").await, vec![hit("article", "1")]);
+  }
+
+  #[test]
+  fn test_match_terms() {
+    assert_eq!(match_terms("rust  wasm"), r#""rust" "wasm""#);
+    assert_eq!(match_terms("crdt* *"), r#""crdt"*"#);
+    assert_eq!(match_terms(r#"say "hi" OR"#), r#""say" """hi""" "OR""#);
+    assert_eq!(match_terms("  "), "");
+  }
+
+  #[test]
+  fn test_mark_html() {
+    let text = format!("a {}<b>{} & c", MARK_START, MARK_END);
+    assert_eq!(mark_html(&text), "a <mark>&lt;b&gt;</mark> &amp; c");
+  }
+}
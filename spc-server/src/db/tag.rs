@@ -1,25 +1,52 @@
 //! models for tag
 
-use sqlx::FromRow;
+use chrono::Utc;
+use spc_util::rename_hashtag;
+use sqlx::{FromRow, SqliteConnection};
 use std::collections::BTreeSet;
 
 use crate::{error::AppError, AppState};
 
-// use super::article::{Article, Entry, Piece};
+use super::article::{Article, Piece};
 
 #[derive(FromRow, Debug, Default)]
 pub struct Tag {
   pub id: u32,
   pub tname: String,
+  pub cover: String,
   pub content: String,
+  /// the parent of a nested tag, `a` of `a/b`, 0 for a root tag
+  pub parent_id: u32,
+}
+
+/// the tag name as stored: no `#`, no empty segments, `a/b` nested in `a`
+pub fn normalize_tname(tname: &str) -> String {
+  tname
+    .trim()
+    .trim_start_matches('#')
+    .split('/')
+    .map(|s| s.trim())
+    .filter(|s| !s.is_empty())
+    .collect::<Vec<_>>()
+    .join("/")
 }
 
 impl Tag {
+  /// the tag page, the `/` of a nested tag encoded to stay in one segment
+  pub fn link(&self) -> String {
+    format!("/tag/{}", urlencoding::encode(&self.tname))
+  }
+
+  /// the tag by id, name or alias
   pub async fn get(ctx: &AppState, name: &str) -> Result<Tag, AppError> {
     let id: u32 = name.parse().unwrap_or(0);
     let tag: Tag = sqlx::query_as(
       r#"
-      SELECT * FROM tags WHERE id = $1 OR tname = $2;
+      SELECT * FROM tags 
+      WHERE id = $1 OR tname = $2 
+        OR id = (SELECT tag_id FROM tag_aliases WHERE alias = $2)
+      ORDER BY tname = $2 DESC
+      LIMIT 1;
       "#,
     )
     .bind(id)
@@ -70,80 +97,334 @@ impl Tag {
     Ok(tags)
   }
 
-  pub async fn new(
-    ctx: &AppState,
-    id: u32,
-    tname: &str,
-    content: &str,
-  ) -> Result<Tag, AppError> {
-    // insert
-    let _new_tag: Tag = if id == 0 {
-      sqlx::query_as(
+  /// the tag named `tname` or by the alias, created with the missing
+  /// parents if new.
+  pub async fn ensure(conn: &mut SqliteConnection, tname: &str) -> Result<Tag, AppError> {
+    let found: Option<Tag> = sqlx::query_as(
+      r#"
+      SELECT * FROM tags 
+      WHERE tname = $1 OR id = (SELECT tag_id FROM tag_aliases WHERE alias = $1)
+      ORDER BY tname = $1 DESC
+      LIMIT 1;
+      "#,
+    )
+    .bind(tname)
+    .fetch_optional(&mut *conn)
+    .await?;
+    if let Some(tag) = found {
+      return Ok(tag);
+    }
+
+    // from the root down: `a`, `a/b`, `a/b/c`
+    let mut tag = Tag::default();
+    let names = tname
+      .match_indices('/')
+      .map(|(i, _)| &tname[..i])
+      .chain(std::iter::once(tname));
+    for name in names {
+      let inserted = sqlx::query(
         r#"
-        INSERT INTO tags (tname, content)
+        INSERT INTO tags (tname, parent_id)
         VALUES ($1, $2)
-        ON CONFLICT (tname) DO NOTHING 
-        RETURNING *;
+        ON CONFLICT (tname) DO NOTHING;
         "#,
       )
-      .bind(tname)
-      .bind(content)
-      .fetch_one(&ctx.pool)
-      .await
-      .unwrap_or_default()
-    } else {
-      sqlx::query_as(
+      .bind(name)
+      .bind(tag.id)
+      .execute(&mut *conn)
+      .await?
+      .rows_affected();
+
+      tag = sqlx::query_as(
         r#"
-        UPDATE tags 
-        SET tname = $1, content = $2
-        WHERE id = $3
-        RETURNING *;
+        SELECT * FROM tags WHERE tname = $1;
         "#,
       )
-      .bind(tname)
-      .bind(content)
-      .bind(id)
-      .fetch_one(&ctx.pool)
-      .await
-      .unwrap_or_default()
-    };
+      .bind(name)
+      .fetch_one(&mut *conn)
+      .await?;
+
+      // a new parent adopts the children left by a deleted one
+      if inserted > 0 {
+        sqlx::query(
+          r#"
+          UPDATE tags SET parent_id = $1 
+          WHERE substr(tname, 1, length($2) + 1) = $2 || '/' 
+            AND instr(substr(tname, length($2) + 2), '/') = 0;
+          "#,
+        )
+        .bind(tag.id)
+        .bind(name)
+        .execute(&mut *conn)
+        .await?;
+      }
+    }
+
+    Ok(tag)
+  }
 
+  pub async fn update(
+    ctx: &AppState,
+    id: u32,
+    content: &str,
+    cover: &str,
+  ) -> Result<Tag, AppError> {
     let tag: Tag = sqlx::query_as(
       r#"
-      SELECT * FROM tags WHERE tname = $1;
+      UPDATE tags 
+      SET content = $1, cover = $2
+      WHERE id = $3
+      RETURNING *;
       "#,
     )
-    .bind(tname)
+    .bind(content)
+    .bind(cover)
+    .bind(id)
     .fetch_one(&ctx.pool)
     .await?;
 
     Ok(tag)
   }
 
-  pub async fn del(ctx: &AppState, id: u32) -> Result<Tag, AppError> {
-    let tag: Tag = sqlx::query_as(
+  /// the tags nested right in the tag
+  pub async fn children(ctx: &AppState, id: u32) -> Result<Vec<Tag>, AppError> {
+    let tags: Vec<Tag> = sqlx::query_as(
       r#"
-      DELETE FROM tags WHERE id = $1 RETURNING *;
+      SELECT * FROM tags WHERE parent_id = $1 ORDER BY tname;
       "#,
     )
     .bind(id)
-    .fetch_one(&ctx.pool)
+    .fetch_all(&ctx.pool)
     .await?;
 
-    // del TagEntry
+    Ok(tags)
+  }
+
+  pub async fn aliases(ctx: &AppState, id: u32) -> Result<Vec<String>, AppError> {
+    let aliases: Vec<(String,)> = sqlx::query_as(
+      r#"
+      SELECT alias FROM tag_aliases WHERE tag_id = $1 ORDER BY alias;
+      "#,
+    )
+    .bind(id)
+    .fetch_all(&ctx.pool)
+    .await?;
+
+    Ok(aliases.into_iter().map(|a| a.0).collect())
+  }
+
+  /// replace the aliases of the tag, skip the names of tags and the
+  /// aliases of other tags.
+  pub async fn set_aliases(
+    ctx: &AppState,
+    id: u32,
+    aliases: BTreeSet<String>,
+  ) -> Result<(), AppError> {
+    let mut tx = ctx.pool.begin().await?;
     sqlx::query(
       r#"
-      DELETE FROM tag_entry WHERE tag_id = $1;
+      DELETE FROM tag_aliases WHERE tag_id = $1;
       "#,
     )
     .bind(id)
-    .execute(&ctx.pool)
+    .execute(&mut tx)
     .await?;
 
+    for alias in aliases {
+      sqlx::query(
+        r#"
+        INSERT OR IGNORE INTO tag_aliases (alias, tag_id)
+        SELECT $1, $2 WHERE NOT EXISTS (SELECT 1 FROM tags WHERE tname = $1);
+        "#,
+      )
+      .bind(&alias)
+      .bind(id)
+      .execute(&mut tx)
+      .await?;
+    }
+    tx.commit().await?;
+
+    Ok(())
+  }
+
+  /// merge the tag `from` and its nested tags into `into`: `from/x` into
+  /// `into/x`. The entries move, the old names become aliases, and the
+  /// hashtags in the content are rewritten if `rewrite`, each article
+  /// rewrite recorded as a revision by `uname`. Return the tag merged into.
+  pub async fn merge(
+    ctx: &AppState,
+    from: u32,
+    into: u32,
+    rewrite: bool,
+    uname: &str,
+  ) -> Result<Tag, AppError> {
+    let from = Tag::get(ctx, &from.to_string()).await?;
+    let into = Tag::get(ctx, &into.to_string()).await?;
+    if from.id == into.id || into.tname.starts_with(&format!("{}/", from.tname)) {
+      return Err(AppError::InvalidInput);
+    }
+
+    // the tag and its nested tags, parents first
+    let merging: Vec<Tag> = sqlx::query_as(
+      r#"
+      SELECT * FROM tags 
+      WHERE tname = $1 OR substr(tname, 1, length($1) + 1) = $1 || '/'
+      ORDER BY length(tname);
+      "#,
+    )
+    .bind(&from.tname)
+    .fetch_all(&ctx.pool)
+    .await?;
+
+    let now = Utc::now().timestamp();
+    let mut tx = ctx.pool.begin().await?;
+    for src in merging {
+      let dst_name = format!("{}{}", into.tname, &src.tname[from.tname.len()..]);
+      let dst = Tag::ensure(&mut tx, &dst_name).await?;
+      // the new name is an alias of the tag itself
+      if dst.id == src.id {
+        continue;
+      }
+
+      if rewrite {
+        let articles: Vec<Article> = sqlx::query_as(
+          r#"
+          SELECT * FROM articles WHERE id IN (
+            SELECT on_id FROM tag_entry WHERE on_ty = 'article' AND tag_id = $1
+          );
+          "#,
+        )
+        .bind(src.id)
+        .fetch_all(&mut tx)
+        .await?;
+        // locked content is left as is, its entries move along
+        for article in articles {
+          if article.check_unlocked().is_err() {
+            continue;
+          }
+          if let Some(content) = rename_hashtag(&article.content, &src.tname, &dst.tname) {
+            Article::rewrite(&mut tx, &article, &content, now, uname).await?;
+          }
+        }
+
+        let pieces: Vec<Piece> = sqlx::query_as(
+          r#"
+          SELECT * FROM pieces WHERE id IN (
+            SELECT on_id FROM tag_entry WHERE on_ty = 'piece' AND tag_id = $1
+          );
+          "#,
+        )
+        .bind(src.id)
+        .fetch_all(&mut tx)
+        .await?;
+        for piece in pieces {
+          if piece.check_unlocked().is_err() {
+            continue;
+          }
+          if let Some(content) = rename_hashtag(&piece.content, &src.tname, &dst.tname) {
+            sqlx::query(
+              r#"
+              UPDATE pieces SET content = $1 WHERE id = $2;
+              "#,
+            )
+            .bind(content)
+            .bind(piece.id)
+            .execute(&mut tx)
+            .await?;
+          }
+        }
+      }
+
+      // the entries tagged with both stay once
+      sqlx::query(
+        r#"
+        UPDATE OR IGNORE tag_entry SET tag_id = $1 WHERE tag_id = $2;
+        "#,
+      )
+      .bind(dst.id)
+      .bind(src.id)
+      .execute(&mut tx)
+      .await?;
+
+      sqlx::query(
+        r#"
+        UPDATE tag_aliases SET tag_id = $1 WHERE tag_id = $2;
+        "#,
+      )
+      .bind(dst.id)
+      .bind(src.id)
+      .execute(&mut tx)
+      .await?;
+
+      del_tag(&mut tx, src.id).await?;
+
+      sqlx::query(
+        r#"
+        INSERT OR IGNORE INTO tag_aliases (alias, tag_id) VALUES ($1, $2);
+        "#,
+      )
+      .bind(&src.tname)
+      .bind(dst.id)
+      .execute(&mut tx)
+      .await?;
+    }
+    tx.commit().await?;
+
+    Ok(into)
+  }
+
+  pub async fn del(ctx: &AppState, id: u32) -> Result<Tag, AppError> {
+    let mut tx = ctx.pool.begin().await?;
+    let tag = del_tag(&mut tx, id).await?;
+    tx.commit().await?;
+
     Ok(tag)
   }
 }
 
+/// delete the tag, its entries and aliases; the nested tags are kept, as
+/// roots till the parent is back.
+async fn del_tag(conn: &mut SqliteConnection, id: u32) -> Result<Tag, AppError> {
+  let tag: Tag = sqlx::query_as(
+    r#"
+    DELETE FROM tags WHERE id = $1 RETURNING *;
+    "#,
+  )
+  .bind(id)
+  .fetch_one(&mut *conn)
+  .await?;
+
+  // del TagEntry
+  sqlx::query(
+    r#"
+    DELETE FROM tag_entry WHERE tag_id = $1;
+    "#,
+  )
+  .bind(id)
+  .execute(&mut *conn)
+  .await?;
+
+  sqlx::query(
+    r#"
+    DELETE FROM tag_aliases WHERE tag_id = $1;
+    "#,
+  )
+  .bind(id)
+  .execute(&mut *conn)
+  .await?;
+
+  sqlx::query(
+    r#"
+    UPDATE tags SET parent_id = 0 WHERE parent_id = $1;
+    "#,
+  )
+  .bind(id)
+  .execute(&mut *conn)
+  .await?;
+
+  Ok(tag)
+}
+
 #[derive(FromRow, Debug, Default)]
 pub struct TagEntry {
   pub tag_id: u32,
@@ -251,7 +532,7 @@ impl TagEntry {
     sqlx::query(
       r#"
       DELETE FROM tag_entry 
-      WHERE tag_id = $1 AND on_ty = $2 AND on_id = $1 
+      WHERE tag_id = $1 AND on_ty = $2 AND on_id = $3 
       RETURNING *;
       "#,
     )
@@ -264,35 +545,91 @@ impl TagEntry {
     Ok(())
   }
 
+  /// tag the entry with the tags named, by the names or aliases; untag
+  /// it from the other tags.
   pub async fn tag(
     ctx: &AppState,
     tnames: BTreeSet<String>,
     on_ty: &str,
     on_id: u32,
   ) -> Result<(), AppError> {
-    let old_tags: Vec<Tag> = TagEntry::get_tags(&ctx, on_ty, on_id).await?;
-    let old_tnames: Vec<String> = old_tags.into_iter().map(|t| t.tname).collect();
-    let old_set: BTreeSet<String> = old_tnames
-      .into_iter()
-      .map(|s| s.trim().to_string())
-      .filter(|s| !s.is_empty())
-      .collect();
+    let old_tags: Vec<Tag> = TagEntry::get_tags(ctx, on_ty, on_id).await?;
+    let old_set: BTreeSet<u32> = old_tags.into_iter().map(|t| t.id).collect();
 
-    let to_del: Vec<String> = old_set.difference(&tnames).cloned().collect();
-    let to_add: Vec<String> = tnames.difference(&old_set).cloned().collect();
+    let mut new_set: BTreeSet<u32> = BTreeSet::new();
+    for t in tnames.iter().map(|t| normalize_tname(t)) {
+      if t.is_empty() {
+        continue;
+      }
+      let tag = Tag::ensure(&mut *ctx.pool.acquire().await?, &t).await?;
+      new_set.insert(tag.id);
+    }
 
     // add
-    for t in to_add {
-      let new_tag = Tag::new(&ctx, 0, &t, "").await?;
-      TagEntry::new(&ctx, new_tag.id, on_ty, on_id).await?;
+    for tag_id in new_set.difference(&old_set) {
+      TagEntry::new(ctx, *tag_id, on_ty, on_id).await?;
     }
 
     // del
-    for t in to_del {
-      let del_tag = Tag::get(&ctx, &t).await?;
-      TagEntry::del(&ctx, del_tag.id, on_ty, on_id).await?;
+    for tag_id in old_set.difference(&new_set) {
+      TagEntry::del(ctx, *tag_id, on_ty, on_id).await?;
     }
 
     Ok(())
   }
 }
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::db::test_state;
+
+  #[test]
+  fn test_normalize_tname() {
+    assert_eq!(normalize_tname(" #rust/async "), "rust/async");
+    assert_eq!(normalize_tname("rust//async/"), "rust/async");
+    assert_eq!(normalize_tname("/"), "");
+  }
+
+  #[tokio::test]
+  async fn test_merge_skips_locked() {
+    let (_dir, ctx) = test_state().await;
+    sqlx::query(
+      r#"
+      INSERT INTO articles (title, uname, content, created_at, updated_at, is_locked)
+      VALUES
+        ('Open', 'alice', 'on #rs', 1, 1, FALSE),
+        ('Locked', 'alice', 'on #rs', 1, 1, TRUE);
+      "#,
+    )
+    .execute(&ctx.pool)
+    .await
+    .unwrap();
+    for id in [1, 2] {
+      TagEntry::tag(&ctx, BTreeSet::from([String::from("rs")]), "article", id)
+        .await
+        .unwrap();
+    }
+    let from = Tag::get(&ctx, "rs").await.unwrap();
+    let mut tx = ctx.pool.begin().await.unwrap();
+    let into = Tag::ensure(&mut tx, "rust").await.unwrap();
+    tx.commit().await.unwrap();
+
+    Tag::merge(&ctx, from.id, into.id, true, "mod").await.unwrap();
+    let contents: Vec<String> =
+      sqlx::query_scalar("SELECT content FROM articles ORDER BY id")
+        .fetch_all(&ctx.pool)
+        .await
+        .unwrap();
+    assert_eq!(contents, vec!["on #rust", "on #rs"]);
+    // both entries moved, the locked content kept as is
+    let tagged: Vec<u32> = sqlx::query_scalar(
+      "SELECT on_id FROM tag_entry WHERE tag_id = $1 ORDER BY on_id",
+    )
+    .bind(into.id)
+    .fetch_all(&ctx.pool)
+    .await
+    .unwrap();
+    assert_eq!(tagged, vec![1, 2]);
+  }
+}
@@ -40,6 +40,8 @@ pub enum AppError {
   DecodeClaimError,
   #[error("hash password error")]
   HashPasswordError,
+  #[error("lost the lease on the document")]
+  LeaseLost,
   // 4XX
   #[error("Captcha Error")]
   CaptchaError,
@@ -67,6 +69,8 @@ pub enum AppError {
   ReadOnly,
   #[error("Not found")]
   NotFound,
+  #[error("The pad is not owned by this process")]
+  NotOwnedHere,
   #[error(transparent)]
   ValidationError(#[from] validator::ValidationErrors),
   #[error(transparent)]
@@ -99,7 +103,7 @@ impl From<AppError> for SsrError {
       | AppError::ValidationError(_)
       | AppError::InvalidInput
       | AppError::AxumFormRejection(_) => StatusCode::BAD_REQUEST,
-      AppError::NotFound => StatusCode::NOT_FOUND,
+      AppError::NotFound | AppError::NotOwnedHere => StatusCode::NOT_FOUND,
       AppError::WriteInterval => StatusCode::TOO_MANY_REQUESTS,
       AppError::Unauthorized | AppError::NoPermission => StatusCode::UNAUTHORIZED,
       AppError::Banned => StatusCode::FORBIDDEN,
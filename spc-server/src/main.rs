@@ -10,10 +10,12 @@ mod ssr;
 mod util;
 
 use crate::{
-  db::feed::refresh_feeds_job, db::sled::clear_invalid_job, router::router,
+  db::article::publish_scheduled_job, db::feed::refresh_feeds_job,
+  db::sled::clear_invalid_job, router::router,
 };
 use config::CONFIG;
 use error::AppError;
+use pad::Pads;
 use sled::Db as Sledb;
 use sqlx::{sqlite::SqliteConnectOptions, ConnectOptions, SqlitePool};
 use std::{fs, path::Path, str::FromStr};
@@ -26,6 +28,8 @@ use tracing_subscriber::{layer::SubscriberExt, util::SubscriberInitExt};
 pub struct AppState {
   pub pool: SqlitePool,
   pub sled: Sledb,
+  /// live collaborative pads
+  pub pads: Pads,
 }
 
 impl AppState {
@@ -49,6 +53,7 @@ impl AppState {
     Ok(AppState {
       pool: SqlitePool::connect(uri).await?,
       sled: sled_db,
+      pads: Pads::default(),
     })
   }
 }
@@ -85,6 +90,19 @@ async fn main() -> Result<(), AppError> {
     }
   });
 
+  // publish the scheduled articles, checked every minute
+  let ctx2 = ctx.clone();
+  tokio::spawn(async move {
+    loop {
+      match publish_scheduled_job(&ctx2).await {
+        Ok(n) if n > 0 => info!("published {} scheduled articles", n),
+        Ok(_) => {}
+        Err(e) => error!(%e),
+      }
+      sleep_seconds(60).await;
+    }
+  });
+
   let app = router(ctx).await;
   let addr = CONFIG.addr.parse().expect("addr parse error");
 
@@ -0,0 +1,318 @@
+//! Access control of collaborative documents.
+//!
+//! A document without any ACL entry is open to everyone, as anonymous pads
+//! are, but read-only if it is the pad of an article. Once it has an owner,
+//! only the listed users or holders of a valid share token can join, the
+//! latter until the token expires.
+
+use chrono::Utc;
+use nanoid::nanoid;
+use serde::{Deserialize, Serialize};
+use sqlx::SqlitePool;
+
+use crate::error::AppError;
+
+/// Access level on a document, ordered from none to owner.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+pub enum Access {
+  /// Cannot join the document.
+  None,
+  /// Can follow the edits but not make any.
+  View,
+  /// Can edit the document.
+  Edit,
+  /// Can edit, manage access and save the document to its article.
+  Own,
+}
+
+impl Access {
+  /// Parse from the role stored in database.
+  pub fn from_role(role: &str) -> Self {
+    match role {
+      "owner" => Access::Own,
+      "editor" => Access::Edit,
+      "viewer" => Access::View,
+      _ => Access::None,
+    }
+  }
+
+  /// The role stored in database.
+  pub fn as_role(&self) -> &'static str {
+    match self {
+      Access::Own => "owner",
+      Access::Edit => "editor",
+      Access::View => "viewer",
+      Access::None => "",
+    }
+  }
+
+  /// Returns if edits are allowed.
+  pub fn can_edit(&self) -> bool {
+    *self >= Access::Edit
+  }
+}
+
+/// A user's role on a document.
+#[derive(sqlx::FromRow, Serialize, Clone, Debug)]
+pub struct AclEntry {
+  /// id of the document
+  pub doc_id: String,
+  /// username of the member
+  pub uname: String,
+  /// owner|editor|viewer
+  pub role: String,
+}
+
+impl AclEntry {
+  /// List the members of a document.
+  pub async fn list(
+    pool: &SqlitePool,
+    document_id: &str,
+  ) -> Result<Vec<AclEntry>, AppError> {
+    let entries: Vec<AclEntry> = sqlx::query_as(
+      r#"
+      SELECT * FROM document_acl WHERE doc_id = $1;
+      "#
+    )
+    .bind(document_id)
+    .fetch_all(pool)
+    .await?;
+
+    Ok(entries)
+  }
+
+  /// Set the access of a user on a document, `Access::None` to remove.
+  pub async fn grant(
+    pool: &SqlitePool,
+    document_id: &str,
+    uname: &str,
+    access: Access,
+  ) -> Result<(), AppError> {
+    if access == Access::None {
+      sqlx::query(
+        r#"
+        DELETE FROM document_acl WHERE doc_id = $1 AND uname = $2;
+        "#
+      )
+      .bind(document_id)
+      .bind(uname)
+      .execute(pool)
+      .await?;
+    } else {
+      sqlx::query(
+        r#"
+        INSERT INTO
+          document_acl (doc_id, uname, role)
+        VALUES
+          ($1, $2, $3)
+        ON CONFLICT(doc_id, uname) DO UPDATE SET
+          role = excluded.role
+        "#
+      )
+      .bind(document_id)
+      .bind(uname)
+      .bind(access.as_role())
+      .execute(pool)
+      .await?;
+    }
+
+    Ok(())
+  }
+
+  /// Resolve the access of a visitor, who may be anonymous (empty `uname`)
+  /// and may hold a share token. Returns along the expiry timestamp of the
+  /// token, if the access comes from it.
+  pub async fn access(
+    pool: &SqlitePool,
+    document_id: &str,
+    uname: &str,
+    token: Option<&str>,
+  ) -> Result<(Access, Option<i64>), AppError> {
+    let entries = AclEntry::list(pool, document_id).await?;
+    if entries.is_empty() {
+      let linked: bool = sqlx::query_scalar(
+        r#"
+        SELECT EXISTS (
+          SELECT 1 FROM document WHERE id = $1 AND article_id IS NOT NULL
+        );
+        "#
+      )
+      .bind(document_id)
+      .fetch_one(pool)
+      .await?;
+      let access = if linked { Access::View } else { Access::Edit };
+      return Ok((access, None));
+    }
+
+    let by_member = entries
+      .iter()
+      .find(|entry| !uname.is_empty() && entry.uname == uname)
+      .map(|entry| Access::from_role(&entry.role))
+      .unwrap_or(Access::None);
+
+    let by_token = match token {
+      Some(token) => ShareToken::get(pool, document_id, token)
+        .await?
+        .map(|share| (Access::from_role(&share.role), share.expire_at)),
+      None => None,
+    };
+
+    // a share link never grants ownership
+    Ok(match by_token {
+      Some((access, expire_at)) if access.min(Access::Edit) > by_member => {
+        (access.min(Access::Edit), Some(expire_at))
+      }
+      _ => (by_member, None),
+    })
+  }
+}
+
+/// A share link token granting access on a document until it expires.
+#[derive(sqlx::FromRow, Serialize, Clone, Debug)]
+pub struct ShareToken {
+  /// the token, used as `?token=` in the share link
+  pub token: String,
+  /// id of the document
+  pub doc_id: String,
+  /// editor|viewer
+  pub role: String,
+  /// username of who created it
+  pub created_by: String,
+  /// expiry timestamp
+  pub expire_at: i64,
+}
+
+impl ShareToken {
+  /// Create a token valid for `hours`, purging the expired ones.
+  pub async fn new(
+    pool: &SqlitePool,
+    document_id: &str,
+    access: Access,
+    hours: i64,
+    created_by: &str,
+  ) -> Result<ShareToken, AppError> {
+    if !(Access::View..=Access::Edit).contains(&access) {
+      return Err(AppError::InvalidInput);
+    }
+    let now = Utc::now().timestamp();
+    sqlx::query(
+      r#"
+      DELETE FROM document_shares WHERE expire_at < $1;
+      "#
+    )
+    .bind(now)
+    .execute(pool)
+    .await?;
+
+    let share = ShareToken {
+      token: nanoid!(),
+      doc_id: document_id.to_owned(),
+      role: access.as_role().to_owned(),
+      created_by: created_by.to_owned(),
+      expire_at: now + hours * 3600,
+    };
+    // executed to completion, so that the token is committed once returned
+    sqlx::query(
+      r#"
+      INSERT INTO
+        document_shares (token, doc_id, role, created_by, expire_at)
+      VALUES
+        ($1, $2, $3, $4, $5)
+      "#
+    )
+    .bind(&share.token)
+    .bind(&share.doc_id)
+    .bind(&share.role)
+    .bind(&share.created_by)
+    .bind(share.expire_at)
+    .execute(pool)
+    .await?;
+
+    Ok(share)
+  }
+
+  /// Get a token of the document if it is not expired.
+  pub async fn get(
+    pool: &SqlitePool,
+    document_id: &str,
+    token: &str,
+  ) -> Result<Option<ShareToken>, AppError> {
+    let share: Option<ShareToken> = sqlx::query_as(
+      r#"
+      SELECT * FROM document_shares
+      WHERE token = $1 AND doc_id = $2 AND expire_at > $3;
+      "#
+    )
+    .bind(token)
+    .bind(document_id)
+    .bind(Utc::now().timestamp())
+    .fetch_optional(pool)
+    .await?;
+
+    Ok(share)
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::db::test_pool;
+
+  #[test]
+  fn test_access_role() {
+    for access in [Access::View, Access::Edit, Access::Own] {
+      assert_eq!(Access::from_role(access.as_role()), access);
+    }
+    assert_eq!(Access::from_role("admin"), Access::None);
+    assert!(Access::Own.can_edit());
+    assert!(!Access::View.can_edit());
+  }
+
+  #[tokio::test]
+  async fn test_access() {
+    let (_dir, pool) = test_pool().await;
+    sqlx::query(
+      r#"
+      INSERT INTO document (id, text, article_id) VALUES ('pad', '', NULL), ('linked', '', 1);
+      "#
+    )
+    .execute(&pool)
+    .await
+    .unwrap();
+    assert_eq!(
+      AclEntry::access(&pool, "pad", "", None).await.unwrap(),
+      (Access::Edit, None)
+    );
+    assert_eq!(
+      AclEntry::access(&pool, "linked", "bob", None).await.unwrap(),
+      (Access::View, None)
+    );
+
+    AclEntry::grant(&pool, "linked", "alice", Access::Own).await.unwrap();
+    AclEntry::grant(&pool, "linked", "carol", Access::View).await.unwrap();
+    assert_eq!(
+      AclEntry::access(&pool, "linked", "bob", None).await.unwrap(),
+      (Access::None, None)
+    );
+    let share = ShareToken::new(&pool, "linked", Access::Edit, 1, "alice").await.unwrap();
+    let token = Some(share.token.as_str());
+    assert_eq!(
+      AclEntry::access(&pool, "linked", "carol", token).await.unwrap(),
+      (Access::Edit, Some(share.expire_at))
+    );
+    // the owner does not depend on the token
+    assert_eq!(
+      AclEntry::access(&pool, "linked", "alice", token).await.unwrap(),
+      (Access::Own, None)
+    );
+
+    sqlx::query("UPDATE document_shares SET expire_at = 0")
+      .execute(&pool)
+      .await
+      .unwrap();
+    assert_eq!(
+      AclEntry::access(&pool, "linked", "carol", token).await.unwrap(),
+      (Access::View, None)
+    );
+  }
+}
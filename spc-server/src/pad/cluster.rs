@@ -0,0 +1,481 @@
+//! Coordination of pads across spc processes sharing the same database.
+//!
+//! Each document is owned by a single process, which holds its `Pad` and
+//! orders its edits. The other processes relay their connections, and
+//! forward their requests on the document, to the owner.
+
+use std::time::Duration;
+
+use anyhow::{Context, Result};
+use axum::body::{boxed, Body, Full};
+use axum::http::{header, HeaderValue, Request};
+use axum::response::Response;
+use chrono::Utc;
+use futures::future::BoxFuture;
+use futures::prelude::*;
+use once_cell::sync::Lazy;
+use ring::hmac;
+use sqlx::SqlitePool;
+use tokio_tungstenite::tungstenite::Message as WsMessage;
+
+use super::acl::Access;
+use super::transport::Connection;
+
+/// Lifetime of an ownership lease, renewed while the pad is live.
+const LEASE_TTL: i64 = 30;
+/// Interval to renew the leases, well within their lifetime.
+pub const LEASE_RENEW: Duration = Duration::from_secs(10);
+/// Lifetime of a signed relay url, in seconds.
+const RELAY_TTL: i64 = 60;
+/// Header marking a request forwarded by another process.
+pub const FORWARDED_HEADER: &str = "x-spc-forwarded";
+
+/// The process owning a document.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Owner {
+  /// This process owns the document.
+  Local,
+  /// Another process owns the document, reachable at this url.
+  Remote(String),
+}
+
+/// Decides which process owns each document.
+///
+/// Implementations must hand a document to a single process at a time.
+pub trait Coordinator: Send + Sync {
+  /// Find the owner of a document, taking it over if nobody owns it.
+  fn acquire<'a>(&'a self, id: &'a str) -> BoxFuture<'a, Result<Owner>>;
+
+  /// Keep owning the documents, called every `LEASE_RENEW` with the ids of
+  /// the live pads. Returns the ids no longer owned by this process.
+  fn renew<'a>(&'a self, ids: &'a [String]) -> BoxFuture<'a, Result<Vec<String>>>;
+
+  /// Give up a document, once its pad is dropped.
+  fn release<'a>(&'a self, id: &'a str) -> BoxFuture<'a, Result<()>>;
+
+  /// The node holding the leases of this process in the `pad_owners`
+  /// table, checked when persisting so that a process which lost a
+  /// document never writes over the history of its new owner. `None` if
+  /// documents are not leased.
+  fn fence(&self) -> Option<&str> {
+    None
+  }
+}
+
+/// A single process owning every document, the default.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Standalone;
+
+impl Coordinator for Standalone {
+  fn acquire<'a>(&'a self, _id: &'a str) -> BoxFuture<'a, Result<Owner>> {
+    future::ok(Owner::Local).boxed()
+  }
+
+  fn renew<'a>(&'a self, _ids: &'a [String]) -> BoxFuture<'a, Result<Vec<String>>> {
+    future::ok(Vec::new()).boxed()
+  }
+
+  fn release<'a>(&'a self, _id: &'a str) -> BoxFuture<'a, Result<()>> {
+    future::ok(()).boxed()
+  }
+}
+
+/// Processes sharing a SQLite database, owning documents through leases
+/// in the `pad_owners` table.
+///
+/// A lease not renewed, e.g. when its process died, can be taken over once
+/// it expires.
+#[derive(Clone, Debug)]
+pub struct SqliteLeases {
+  pool: SqlitePool,
+  /// url of this process, as reached by the others
+  node: String,
+}
+
+impl SqliteLeases {
+  /// Create the coordinator of the process reachable at `node`.
+  pub fn new(pool: SqlitePool, node: &str) -> Self {
+    Self {
+      pool,
+      node: node.trim_end_matches('/').to_owned(),
+    }
+  }
+
+  async fn acquire_lease(&self, id: &str) -> Result<Owner> {
+    let now = Utc::now().timestamp();
+    sqlx::query(
+      r#"
+      INSERT INTO pad_owners (doc_id, node, expires_at)
+      VALUES ($1, $2, $3)
+      ON CONFLICT(doc_id) DO UPDATE
+      SET node = excluded.node, expires_at = excluded.expires_at
+      WHERE pad_owners.node = excluded.node OR pad_owners.expires_at < $4
+      "#,
+    )
+    .bind(id)
+    .bind(&self.node)
+    .bind(now + LEASE_TTL)
+    .bind(now)
+    .execute(&self.pool)
+    .await?;
+
+    let node: String =
+      sqlx::query_scalar("SELECT node FROM pad_owners WHERE doc_id = $1")
+        .bind(id)
+        .fetch_one(&self.pool)
+        .await?;
+    Ok(if node == self.node {
+      Owner::Local
+    } else {
+      Owner::Remote(node)
+    })
+  }
+
+  async fn renew_leases(&self, ids: &[String]) -> Result<Vec<String>> {
+    let expires_at = Utc::now().timestamp() + LEASE_TTL;
+    let mut lost = Vec::new();
+    for id in ids {
+      let res = sqlx::query(
+        r#"
+        UPDATE pad_owners SET expires_at = $1
+        WHERE doc_id = $2 AND node = $3
+        "#,
+      )
+      .bind(expires_at)
+      .bind(id)
+      .bind(&self.node)
+      .execute(&self.pool)
+      .await?;
+      if res.rows_affected() == 0 {
+        lost.push(id.clone());
+      }
+    }
+    Ok(lost)
+  }
+
+  async fn release_lease(&self, id: &str) -> Result<()> {
+    sqlx::query("DELETE FROM pad_owners WHERE doc_id = $1 AND node = $2")
+      .bind(id)
+      .bind(&self.node)
+      .execute(&self.pool)
+      .await?;
+    Ok(())
+  }
+}
+
+impl Coordinator for SqliteLeases {
+  fn acquire<'a>(&'a self, id: &'a str) -> BoxFuture<'a, Result<Owner>> {
+    self.acquire_lease(id).boxed()
+  }
+
+  fn renew<'a>(&'a self, ids: &'a [String]) -> BoxFuture<'a, Result<Vec<String>>> {
+    self.renew_leases(ids).boxed()
+  }
+
+  fn release<'a>(&'a self, id: &'a str) -> BoxFuture<'a, Result<()>> {
+    self.release_lease(id).boxed()
+  }
+
+  fn fence(&self) -> Option<&str> {
+    Some(&self.node)
+  }
+}
+
+/// A pad live in another process, as leased.
+#[derive(Debug, Clone, sqlx::FromRow)]
+pub struct RemotePad {
+  /// id of the document
+  pub doc_id: String,
+  /// url of the owner process
+  pub node: String,
+  /// timestamp the lease ends at, if not renewed
+  pub expires_at: i64,
+}
+
+/// Returns the pads live in the processes other than the one at `node`,
+/// none when running a single process.
+pub async fn remote_pads(pool: &SqlitePool, node: &str) -> Result<Vec<RemotePad>> {
+  if node.is_empty() {
+    return Ok(vec![]);
+  }
+  let pads = sqlx::query_as(
+    r#"
+    SELECT doc_id, node, expires_at FROM pad_owners
+    WHERE node != $1 AND expires_at >= $2
+    ORDER BY node, doc_id
+    "#,
+  )
+  .bind(node.trim_end_matches('/'))
+  .bind(Utc::now().timestamp())
+  .fetch_all(pool)
+  .await?;
+  Ok(pads)
+}
+
+/// Returns the process other than the one at `node` owning the document,
+/// if any.
+pub async fn remote_owner(pool: &SqlitePool, node: &str, id: &str) -> Result<Option<String>> {
+  if node.is_empty() {
+    return Ok(None);
+  }
+  let owner = sqlx::query_scalar(
+    r#"
+    SELECT node FROM pad_owners
+    WHERE doc_id = $1 AND node != $2 AND expires_at >= $3
+    "#,
+  )
+  .bind(id)
+  .bind(node.trim_end_matches('/'))
+  .bind(Utc::now().timestamp())
+  .fetch_optional(pool)
+  .await?;
+  Ok(owner)
+}
+
+/// Query params of the `/api/relay/:id` endpoint, signed by the relaying
+/// process, which checked the access of the visitor.
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+pub struct RelayQuery {
+  /// role of the visitor, see `Access::as_role`
+  pub role: String,
+  /// username of the visitor, empty if not signed in
+  pub uname: String,
+  /// expiration timestamp of the url
+  pub exp: i64,
+  /// key of the SSE session relayed, its messages posted to the owner
+  #[serde(default)]
+  pub session: String,
+  /// hex encoded signature
+  pub sig: String,
+}
+
+impl RelayQuery {
+  /// Sign the access of a visitor on a document, connected over SSE in
+  /// `session` if not empty.
+  pub fn new(secret: &str, id: &str, access: Access, uname: &str, session: &str) -> Self {
+    let role = access.as_role().to_owned();
+    let exp = Utc::now().timestamp() + RELAY_TTL;
+    let sig = sign(secret, id, &role, uname, session, exp);
+    Self {
+      role,
+      uname: uname.to_owned(),
+      exp,
+      session: session.to_owned(),
+      sig,
+    }
+  }
+
+  /// Returns the signed access, `None` if the signature is invalid or
+  /// expired.
+  pub fn verify(&self, secret: &str, id: &str) -> Option<Access> {
+    if self.exp < Utc::now().timestamp() {
+      return None;
+    }
+    let expected = sign(secret, id, &self.role, &self.uname, &self.session, self.exp);
+    ring::constant_time::verify_slices_are_equal(
+      expected.as_bytes(),
+      self.sig.as_bytes(),
+    )
+    .ok()?;
+    Some(Access::from_role(&self.role))
+  }
+}
+
+/// hex encoded HMAC of the relayed access
+fn sign(secret: &str, id: &str, role: &str, uname: &str, session: &str, exp: i64) -> String {
+  let key = hmac::Key::new(hmac::HMAC_SHA256, secret.as_bytes());
+  let msg = format!("{id}\n{role}\n{uname}\n{session}\n{exp}");
+  hmac::sign(&key, msg.as_bytes())
+    .as_ref()
+    .iter()
+    .map(|b| format!("{b:02x}"))
+    .collect()
+}
+
+/// Url of the `/api/relay/:id` endpoint of the process at `node`.
+pub fn relay_url(node: &str, id: &str, query: &RelayQuery) -> String {
+  let node = node
+    .replacen("https://", "wss://", 1)
+    .replacen("http://", "ws://", 1);
+  format!(
+    "{}/api/relay/{}?role={}&uname={}&exp={}&session={}&sig={}",
+    node,
+    urlencoding::encode(id),
+    query.role,
+    urlencoding::encode(&query.uname),
+    query.exp,
+    urlencoding::encode(&query.session),
+    query.sig,
+  )
+}
+
+/// Relay a client connection to the owner of the document at `url`, until
+/// either side disconnects.
+pub async fn relay(mut conn: Connection, url: &str) -> Result<()> {
+  let (mut remote, _) = tokio_tungstenite::connect_async(url)
+    .await
+    .context("failed to connect to the owner")?;
+  loop {
+    tokio::select! {
+      msg = conn.recv() => match msg {
+        None => break,
+        Some(msg) => {
+          let text = serde_json::to_string(&msg?).context("failed to serialize message")?;
+          remote.send(WsMessage::Text(text)).await?;
+        }
+      },
+      msg = remote.next() => match msg {
+        None => break,
+        Some(msg) => match msg? {
+          WsMessage::Text(text) => {
+            let msg = serde_json::from_str(&text).context("failed to deserialize message")?;
+            conn.send(msg).await?;
+          }
+          WsMessage::Close(frame) => {
+            if let Some(frame) = frame {
+              conn.close(frame.reason.into_owned()).await.ok();
+            }
+            break;
+          }
+          _ => {}
+        },
+      },
+    }
+  }
+  remote.close(None).await.ok();
+  Ok(())
+}
+
+/// Client forwarding the requests, the redirects returned as is.
+static CLIENT: Lazy<reqwest::Client> = Lazy::new(|| {
+  reqwest::Client::builder()
+    .redirect(reqwest::redirect::Policy::none())
+    .build()
+    .expect("failed to build the forwarding client")
+});
+
+/// Forward a request to the owner process at `node`, returning its response.
+pub async fn forward(node: &str, req: Request<Body>) -> Result<Response> {
+  let (parts, body) = req.into_parts();
+  let body = hyper::body::to_bytes(body).await?;
+  let path = parts
+    .uri
+    .path_and_query()
+    .map(|p| p.as_str())
+    .unwrap_or("/");
+  let mut headers = parts.headers;
+  headers.remove(header::HOST);
+  headers.insert(FORWARDED_HEADER, HeaderValue::from_static("1"));
+  let res = CLIENT
+    .request(parts.method, format!("{node}{path}"))
+    .headers(headers)
+    .body(body)
+    .send()
+    .await
+    .context("failed to forward to the owner")?;
+
+  let mut builder = Response::builder().status(res.status());
+  for (name, value) in res.headers() {
+    if name != header::TRANSFER_ENCODING && name != header::CONNECTION {
+      builder = builder.header(name, value);
+    }
+  }
+  let bytes = res.bytes().await?;
+  Ok(builder.body(boxed(Full::from(bytes)))?)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::db::test_pool;
+  use crate::pad::document::PersistedDocument;
+  use crate::pad::mdpad::Pad;
+
+  #[test]
+  fn test_relay_query() {
+    let query = RelayQuery::new("secret", "doc1", Access::Edit, "alice", "");
+    assert_eq!(query.verify("secret", "doc1"), Some(Access::Edit));
+    assert_eq!(query.verify("secret", "doc2"), None);
+    assert_eq!(query.verify("other", "doc1"), None);
+
+    let hijacked = RelayQuery {
+      session: String::from("other"),
+      ..query.clone()
+    };
+    assert_eq!(hijacked.verify("secret", "doc1"), None);
+    let forged = RelayQuery {
+      role: String::from("owner"),
+      ..query
+    };
+    assert_eq!(forged.verify("secret", "doc1"), None);
+
+    let url = relay_url("http://10.0.0.2:8080", "doc 1", &forged);
+    assert!(url.starts_with("ws://10.0.0.2:8080/api/relay/doc%201?role=owner&uname=alice"));
+  }
+
+  #[tokio::test]
+  async fn test_contended_lease() {
+    let (_dir, pool) = test_pool().await;
+    let a = SqliteLeases::new(pool.clone(), "http://a:8080/");
+    let b = SqliteLeases::new(pool.clone(), "http://b:8080");
+    let ids = vec![String::from("doc1")];
+
+    let (owner_a, owner_b) = tokio::join!(a.acquire("doc1"), b.acquire("doc1"));
+    let (owner_a, owner_b) = (owner_a.unwrap(), owner_b.unwrap());
+    assert!(
+      (owner_a == Owner::Local) != (owner_b == Owner::Local),
+      "{owner_a:?} and {owner_b:?}"
+    );
+    let (a, b) = if owner_a == Owner::Local { (a, b) } else { (b, a) };
+    assert_eq!(b.acquire("doc1").await.unwrap(), Owner::Remote(a.node.clone()));
+    assert!(a.renew(&ids).await.unwrap().is_empty());
+
+    // a stalls, its lease expires and b takes the document over
+    sqlx::query("UPDATE pad_owners SET expires_at = 0")
+      .execute(&pool)
+      .await
+      .unwrap();
+    assert_eq!(b.acquire("doc1").await.unwrap(), Owner::Local);
+    assert_eq!(a.acquire("doc1").await.unwrap(), Owner::Remote(b.node.clone()));
+    assert_eq!(a.renew(&ids).await.unwrap(), ids);
+
+    let pad_a = Pad::default();
+    pad_a.replace_text("late flush of a").unwrap();
+    let pad_b = Pad::default();
+    pad_b.replace_text("edited on b").unwrap();
+    pad_b.snapshot_since(0).store(&pool, "doc1", b.fence()).await.unwrap();
+    // the final flush of a is fenced off, whatever its order
+    assert!(pad_a.snapshot_since(0).store(&pool, "doc1", a.fence()).await.is_err());
+    a.release("doc1").await.unwrap();
+    assert!(b.renew(&ids).await.unwrap().is_empty());
+
+    let document = PersistedDocument::load(&pool, "doc1").await.unwrap();
+    assert_eq!(document.text, "edited on b");
+  }
+
+  #[tokio::test]
+  async fn test_remote_pads() {
+    let (_dir, pool) = test_pool().await;
+    let a = SqliteLeases::new(pool.clone(), "http://a:8080");
+    let b = SqliteLeases::new(pool.clone(), "http://b:8080");
+    a.acquire("doc1").await.unwrap();
+    b.acquire("doc2").await.unwrap();
+    b.acquire("doc3").await.unwrap();
+    sqlx::query("UPDATE pad_owners SET expires_at = 0 WHERE doc_id = 'doc3'")
+      .execute(&pool)
+      .await
+      .unwrap();
+
+    let remote = remote_pads(&pool, "http://a:8080/").await.unwrap();
+    let remote: Vec<_> = remote.iter().map(|p| (p.doc_id.as_str(), p.node.as_str())).collect();
+    assert_eq!(remote, vec![("doc2", "http://b:8080")]);
+    assert_eq!(
+      remote_owner(&pool, "http://a:8080", "doc2").await.unwrap().as_deref(),
+      Some("http://b:8080")
+    );
+    // owned here, expired, or a single process
+    assert_eq!(remote_owner(&pool, "http://a:8080", "doc1").await.unwrap(), None);
+    assert_eq!(remote_owner(&pool, "http://a:8080", "doc3").await.unwrap(), None);
+    assert!(remote_pads(&pool, "").await.unwrap().is_empty());
+  }
+}
@@ -0,0 +1,349 @@
+//! Documents using the CRDT backend, built with the `crdt` feature.
+//!
+//! Such a document can be edited offline, in the browser, then merged here
+//! into its live pad. The merged text is thus persisted as for any other
+//! pad, in `documents` or `notes`, and the CRDT state in `crdt_documents`.
+//! Edits made meanwhile on the pad are folded into the CRDT before merging.
+//!
+//! The live pads still run on OT; the CRDT only carries the offline edits.
+
+use std::sync::Arc;
+
+use axum::body::Bytes;
+use axum::extract::{Path, Query, State};
+use axum::http::{header, StatusCode};
+use axum::response::IntoResponse;
+use axum::Json;
+use chrono::Utc;
+use dashmap::DashMap;
+use log::{error, warn};
+use once_cell::sync::Lazy;
+use serde::Deserialize;
+use spc_util::crdt::CrdtText;
+use sqlx::SqlitePool;
+use tokio::sync::{Mutex, OwnedMutexGuard};
+
+use super::acl::Access;
+use super::{get_pad, resolve_access, PadQuery, ServerState};
+use crate::db::user::{ClaimCan, BASIC_PERMIT};
+use crate::error::AppError;
+
+/// Locks on the CRDT state of the documents, each merge loading and
+/// storing the whole state.
+static MERGES: Lazy<DashMap<String, Arc<Mutex<()>>>> = Lazy::new(Default::default);
+
+/// The lock on the CRDT state of a document, dropped from `MERGES` by its
+/// last holder.
+struct MergeLock {
+  id: String,
+  _guard: OwnedMutexGuard<()>,
+}
+
+impl MergeLock {
+  async fn acquire(id: &str) -> Self {
+    let lock = Arc::clone(&MERGES.entry(id.to_owned()).or_default());
+    Self {
+      id: id.to_owned(),
+      _guard: lock.lock_owned().await,
+    }
+  }
+}
+
+impl Drop for MergeLock {
+  fn drop(&mut self) {
+    // held by the map and this guard only, none waiting
+    MERGES.remove_if(&self.id, |_, lock| Arc::strong_count(lock) == 2);
+  }
+}
+
+/// The CRDT state of a document, stored in the `crdt_documents` table.
+#[derive(sqlx::FromRow, Clone, Debug)]
+pub struct PersistedCrdt {
+  /// Saved automerge document.
+  pub data: Vec<u8>,
+}
+
+impl PersistedCrdt {
+  /// Load the state of a document, `None` if it does not use the backend.
+  pub async fn load(
+    pool: &SqlitePool,
+    document_id: &str,
+  ) -> Result<Option<PersistedCrdt>, AppError> {
+    let crdt = sqlx::query_as(
+      r#"
+      SELECT data FROM crdt_documents WHERE doc_id = $1;
+      "#
+    )
+    .bind(document_id)
+    .fetch_optional(pool)
+    .await?;
+
+    Ok(crdt)
+  }
+
+  /// Store the state of a document.
+  pub async fn store(
+    pool: &SqlitePool,
+    document_id: &str,
+    data: &[u8],
+  ) -> Result<(), AppError> {
+    sqlx::query(
+      r#"
+      INSERT INTO crdt_documents (doc_id, data, updated_at)
+      VALUES ($1, $2, $3)
+      ON CONFLICT(doc_id) DO UPDATE
+      SET data = excluded.data, updated_at = excluded.updated_at
+      "#
+    )
+    .bind(document_id)
+    .bind(data)
+    .bind(Utc::now().timestamp())
+    .execute(pool)
+    .await?;
+
+    Ok(())
+  }
+
+  /// Switch a document back to the OT backend, dropping its state.
+  pub async fn delete(
+    pool: &SqlitePool,
+    document_id: &str,
+  ) -> Result<(), AppError> {
+    sqlx::query("DELETE FROM crdt_documents WHERE doc_id = $1")
+      .bind(document_id)
+      .execute(pool)
+      .await?;
+
+    Ok(())
+  }
+}
+
+/// Load the CRDT of a document, caught up with the text of its pad.
+async fn load_synced(
+  state: &ServerState,
+  id: &str,
+  text: &str,
+) -> Result<CrdtText, StatusCode> {
+  let persisted = PersistedCrdt::load(&state.pool, id)
+    .await
+    .map_err(|_e| StatusCode::INTERNAL_SERVER_ERROR)?
+    .ok_or(StatusCode::NOT_FOUND)?;
+  let mut doc = CrdtText::load(&persisted.data).map_err(|e| {
+    error!("when loading crdt of {}: {}", id, e);
+    StatusCode::INTERNAL_SERVER_ERROR
+  })?;
+  doc.update(text).map_err(|_e| StatusCode::INTERNAL_SERVER_ERROR)?;
+  doc.commit();
+  Ok(doc)
+}
+
+/// Respond with a saved CRDT.
+fn octets(data: Vec<u8>) -> impl IntoResponse {
+  ([(header::CONTENT_TYPE, "application/octet-stream")], data)
+}
+
+/// Handler for the GET `/api/crdt/:id` endpoint, the CRDT to edit offline
+/// as of the last merge. Edits made on the pad since come along with the
+/// next merge, so that posting no changes catches up.
+pub(super) async fn crdt_handler(
+  State(state): State<ServerState>,
+  Path(id): Path<String>,
+  Query(query): Query<PadQuery>,
+  check: ClaimCan<BASIC_PERMIT>,
+) -> Result<impl IntoResponse, StatusCode> {
+  let uname = check.claim.unwrap_or_default().uname;
+  let access =
+    resolve_access(&state.pool, &id, &uname, query.token.as_deref()).await;
+  if access == Access::None {
+    return Err(StatusCode::FORBIDDEN);
+  }
+  let persisted = PersistedCrdt::load(&state.pool, &id)
+    .await
+    .map_err(|_e| StatusCode::INTERNAL_SERVER_ERROR)?
+    .ok_or(StatusCode::NOT_FOUND)?;
+
+  Ok(octets(persisted.data))
+}
+
+/// Handler for the POST `/api/crdt/:id` endpoint.
+///
+/// Merges the changes made offline, as a saved CRDT or the changes saved
+/// after some heads, into the live pad. Returns the merged CRDT.
+pub(super) async fn merge_handler(
+  State(state): State<ServerState>,
+  Path(id): Path<String>,
+  Query(query): Query<PadQuery>,
+  check: ClaimCan<BASIC_PERMIT>,
+  body: Bytes,
+) -> Result<impl IntoResponse, StatusCode> {
+  let uname = check.claim.unwrap_or_default().uname;
+  let access =
+    resolve_access(&state.pool, &id, &uname, query.token.as_deref()).await;
+  if !access.can_edit() {
+    return Err(StatusCode::FORBIDDEN);
+  }
+  let data = merge(&state, &id, &uname, &body).await?;
+
+  Ok(octets(data))
+}
+
+/// Merge changes into the CRDT and the live pad of a document, returning
+/// the merged CRDT as saved.
+async fn merge(
+  state: &ServerState,
+  id: &str,
+  uname: &str,
+  changes: &[u8],
+) -> Result<Vec<u8>, StatusCode> {
+  let _merging = MergeLock::acquire(id).await;
+  let pad = get_pad(state, id, uname).await?;
+  let (revision, text) = pad.revision_text();
+  let mut doc = load_synced(state, id, &text).await?;
+  doc.merge(changes).map_err(|e| {
+    warn!("invalid changes merged into {}: {}", id, e);
+    StatusCode::BAD_REQUEST
+  })?;
+  // edits made on the pad since are kept, as for any server edit
+  pad.edit_text(revision, &text, &doc.text()).map_err(|e| {
+    error!("when applying merged text to {}: {}", id, e);
+    StatusCode::INTERNAL_SERVER_ERROR
+  })?;
+  let data = doc.save();
+  PersistedCrdt::store(&state.pool, id, &data)
+    .await
+    .map_err(|_e| StatusCode::INTERNAL_SERVER_ERROR)?;
+
+  Ok(data)
+}
+
+/// Payload of the PUT `/api/crdt/:id` endpoint.
+#[derive(Deserialize)]
+pub(super) struct BackendRequest {
+  /// use the CRDT backend, else the OT one only
+  crdt: bool,
+}
+
+/// Handler for the PUT `/api/crdt/:id` endpoint, select the backend of a
+/// document, owner only.
+pub(super) async fn backend_handler(
+  State(state): State<ServerState>,
+  Path(id): Path<String>,
+  check: ClaimCan<BASIC_PERMIT>,
+  Json(req): Json<BackendRequest>,
+) -> Result<impl IntoResponse, StatusCode> {
+  let uname = check.claim.unwrap_or_default().uname;
+  if resolve_access(&state.pool, &id, &uname, None).await < Access::Own {
+    return Err(StatusCode::FORBIDDEN);
+  }
+  let _merging = MergeLock::acquire(&id).await;
+  if !req.crdt {
+    PersistedCrdt::delete(&state.pool, &id)
+      .await
+      .map_err(|_e| StatusCode::INTERNAL_SERVER_ERROR)?;
+    return Ok(StatusCode::NO_CONTENT);
+  }
+  let exists = PersistedCrdt::load(&state.pool, &id)
+    .await
+    .map_err(|_e| StatusCode::INTERNAL_SERVER_ERROR)?
+    .is_some();
+  if !exists {
+    let pad = get_pad(&state, &id, &uname).await?;
+    let mut doc = CrdtText::new(&pad.text())
+      .map_err(|_e| StatusCode::INTERNAL_SERVER_ERROR)?;
+    PersistedCrdt::store(&state.pool, &id, &doc.save())
+      .await
+      .map_err(|_e| StatusCode::INTERNAL_SERVER_ERROR)?;
+  }
+
+  Ok(StatusCode::NO_CONTENT)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::db::test_pool;
+  use crate::pad::cluster::Standalone;
+  use crate::pad::limit::Limits;
+
+  fn server_state(pool: SqlitePool) -> ServerState {
+    ServerState {
+      documents: Default::default(),
+      pool,
+      sessions: Default::default(),
+      persisting: Default::default(),
+      coordinator: Arc::new(Standalone),
+      secret: "secret".into(),
+      limits: Limits::default(),
+      start_time: 0,
+    }
+  }
+
+  /// Switch a live pad to the CRDT backend, returning the saved CRDT.
+  async fn enable(state: &ServerState, id: &str, text: &str) -> Vec<u8> {
+    let pad = get_pad(state, id, "").await.unwrap();
+    pad.replace_text(text).unwrap();
+    let data = CrdtText::new(&pad.text()).unwrap().save();
+    PersistedCrdt::store(&state.pool, id, &data).await.unwrap();
+    data
+  }
+
+  #[tokio::test]
+  async fn test_merge_into_pad() {
+    let (_dir, pool) = test_pool().await;
+    let state = server_state(pool);
+    let data = enable(&state, "crdt1", "hello world").await;
+
+    let mut alice = CrdtText::load(&data).unwrap();
+    alice.set_actor(b"alice");
+    let heads = alice.heads();
+    alice.splice(0, 5, "howdy").unwrap();
+    alice.commit();
+    // edited on the pad meanwhile
+    let pad = get_pad(&state, "crdt1", "").await.unwrap();
+    pad.replace_text("hello world!").unwrap();
+
+    let changes = alice.save_after(&heads).unwrap();
+    let merged = merge(&state, "crdt1", "alice", &changes).await.unwrap();
+    assert_eq!(pad.text(), "howdy world!");
+    assert_eq!(CrdtText::load(&merged).unwrap().text(), "howdy world!");
+    let persisted = PersistedCrdt::load(&state.pool, "crdt1").await.unwrap().unwrap();
+    assert_eq!(persisted.data, merged);
+
+    // a retried merge changes nothing
+    merge(&state, "crdt1", "alice", &changes).await.unwrap();
+    assert_eq!(pad.text(), "howdy world!");
+    assert!(!MERGES.contains_key("crdt1"));
+  }
+
+  #[tokio::test]
+  async fn test_concurrent_merges() {
+    let (_dir, pool) = test_pool().await;
+    let state = server_state(pool);
+    let data = enable(&state, "crdt2", "shared text").await;
+    // not merged into by the others
+    enable(&state, "crdt3", "other").await;
+
+    let mut changes = Vec::new();
+    for (actor, pos, word) in [(b"bob", 0, "bob "), (b"eve", 11, " eve")] {
+      let mut doc = CrdtText::load(&data).unwrap();
+      doc.set_actor(actor);
+      let heads = doc.heads();
+      doc.splice(pos, 0, word).unwrap();
+      doc.commit();
+      changes.push(doc.save_after(&heads).unwrap());
+    }
+    let (bob, eve) = tokio::join!(
+      merge(&state, "crdt2", "bob", &changes[0]),
+      merge(&state, "crdt2", "eve", &changes[1]),
+    );
+    bob.unwrap();
+    eve.unwrap();
+
+    let pad = get_pad(&state, "crdt2", "").await.unwrap();
+    assert_eq!(pad.text(), "bob shared text eve");
+    let persisted = PersistedCrdt::load(&state.pool, "crdt2").await.unwrap().unwrap();
+    assert_eq!(CrdtText::load(&persisted.data).unwrap().text(), "bob shared text eve");
+    let other = PersistedCrdt::load(&state.pool, "crdt3").await.unwrap().unwrap();
+    assert_eq!(CrdtText::load(&other.data).unwrap().text(), "other");
+  }
+}
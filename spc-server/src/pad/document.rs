@@ -1,6 +1,7 @@
 //! Backend SQLite database handlers for persisting documents.
 
 use chrono::Utc;
+use serde::Serialize;
 use sqlx::SqlitePool;
 
 use crate::error::AppError;
@@ -37,12 +38,144 @@ impl PersistedDocument {
     .map_err(|e| e.into())
   }
 
-  /// Store the text of a document in the database.
+  /// Count the number of documents in the database.
+  pub async fn count(pool: &SqlitePool) -> Result<usize, AppError> {
+    let row: (i64,) = sqlx::query_as("SELECT count(*) FROM document;")
+      .fetch_one(pool)
+      .await?;
+    Ok(row.0 as usize)
+  }
+}
+
+/// Represents one operation in the persisted history of a document.
+#[derive(sqlx::FromRow, PartialEq, Eq, Clone, Debug)]
+pub struct PersistedOperation {
+  /// Revision number, i.e. the index of the operation in the history.
+  pub revision: i64,
+  /// id of the connection which made the operation, -1 if made by server.
+  pub user_id: i64,
+  /// The operation serialized as JSON.
+  pub operation: String,
+  /// Timestamp when the operation was applied.
+  pub created_at: i64,
+}
+
+impl PersistedOperation {
+  /// Load the operation history of a document, ordered by revision.
+  pub async fn load_all(
+    pool: &SqlitePool,
+    document_id: &str,
+  ) -> Result<Vec<PersistedOperation>, AppError> {
+    let operations: Vec<PersistedOperation> = sqlx::query_as(
+      r#"
+      SELECT revision, user_id, operation, created_at
+      FROM document_ops
+      WHERE doc_id = $1
+      ORDER BY revision ASC;
+      "#
+    )
+    .bind(document_id)
+    .fetch_all(pool)
+    .await?;
+
+    Ok(operations)
+  }
+}
+
+/// The text of a document at some revision, with all the earlier operations
+/// composed into it. History before `revision` is not kept.
+#[derive(sqlx::FromRow, PartialEq, Eq, Clone, Debug)]
+pub struct PersistedCheckpoint {
+  /// Number of operations composed into the text.
+  pub revision: i64,
+  /// Text content of the document at the revision.
+  pub text: String,
+  /// Timestamp when the checkpoint was made.
+  pub created_at: i64,
+}
+
+impl PersistedCheckpoint {
+  /// Load the latest checkpoint of a document, if any.
+  pub async fn load(
+    pool: &SqlitePool,
+    document_id: &str,
+  ) -> Result<Option<PersistedCheckpoint>, AppError> {
+    let checkpoint: Option<PersistedCheckpoint> = sqlx::query_as(
+      r#"
+      SELECT revision, text, created_at
+      FROM document_checkpoints
+      WHERE doc_id = $1;
+      "#
+    )
+    .bind(document_id)
+    .fetch_optional(pool)
+    .await?;
+
+    Ok(checkpoint)
+  }
+}
+
+/// The history of a document to persist, taken consistently from its pad:
+/// the operations from some revision on, the text they lead to, and the
+/// checkpoint the older operations are composed into.
+#[derive(Clone, Debug)]
+pub struct PersistedHistory {
+  /// Text content of the document after the operations.
+  pub document: PersistedDocument,
+  /// Operations to append to the history.
+  pub operations: Vec<PersistedOperation>,
+  /// Checkpoint of the compacted history, if any.
+  pub checkpoint: Option<PersistedCheckpoint>,
+}
+
+impl PersistedHistory {
+  /// Store the operations, the text, and the checkpoint if it moved forward
+  /// dropping the operations composed into it, in one transaction.
+  ///
+  /// With a `fence`, the transaction only commits while that node holds the
+  /// lease on the document, so that a process which lost it never writes
+  /// over the history of the new owner.
   pub async fn store(
+    &self,
     pool: &SqlitePool,
     document_id: &str,
-    document: &PersistedDocument,
+    fence: Option<&str>,
   ) -> Result<(), AppError> {
+    let mut tx = pool.begin().await?;
+    if let Some(node) = fence {
+      // a write, to hold the database lock until the commit
+      let held = sqlx::query(
+        r#"
+        UPDATE pad_owners SET node = node WHERE doc_id = $1 AND node = $2
+        "#
+      )
+      .bind(document_id)
+      .bind(node)
+      .execute(&mut tx)
+      .await?;
+      if held.rows_affected() == 0 {
+        return Err(AppError::LeaseLost);
+      }
+    }
+
+    for op in &self.operations {
+      sqlx::query(
+        r#"
+        INSERT INTO
+          document_ops (doc_id, revision, user_id, operation, created_at)
+        VALUES
+          ($1, $2, $3, $4, $5)
+        "#
+      )
+      .bind(document_id)
+      .bind(op.revision)
+      .bind(op.user_id)
+      .bind(&op.operation)
+      .bind(op.created_at)
+      .execute(&mut tx)
+      .await?;
+    }
+
     let now = Utc::now().timestamp();
     let result = sqlx::query(
       r#"
@@ -53,28 +186,383 @@ impl PersistedDocument {
       ON CONFLICT(id) DO UPDATE SET
         text = excluded.text,
         language = excluded.language,
-        updated_at = $5 
+        updated_at = excluded.updated_at
       "#
     )
     .bind(document_id)
-    .bind(&document.text)
-    .bind(&document.language)
-    .bind(&now)
-    .bind(&now)
-    .execute(pool)
+    .bind(&self.document.text)
+    .bind(&self.document.language)
+    .bind(now)
+    .execute(&mut tx)
     .await?;
-
     if result.rows_affected() != 1 {
       return Err(AppError::NotFound);
     }
+
+    if let Some(checkpoint) = &self.checkpoint {
+      sqlx::query(
+        r#"
+        INSERT INTO
+          document_checkpoints (doc_id, revision, text, created_at)
+        VALUES
+          ($1, $2, $3, $4)
+        ON CONFLICT(doc_id) DO UPDATE SET
+          revision = excluded.revision,
+          text = excluded.text,
+          created_at = excluded.created_at
+        WHERE excluded.revision > document_checkpoints.revision
+        "#
+      )
+      .bind(document_id)
+      .bind(checkpoint.revision)
+      .bind(&checkpoint.text)
+      .bind(checkpoint.created_at)
+      .execute(&mut tx)
+      .await?;
+
+      sqlx::query(
+        r#"
+        DELETE FROM document_ops WHERE doc_id = $1 AND revision < $2;
+        "#
+      )
+      .bind(document_id)
+      .bind(checkpoint.revision)
+      .execute(&mut tx)
+      .await?;
+    }
+    tx.commit().await?;
+
     Ok(())
   }
+}
 
-  /// Count the number of documents in the database.
-  pub async fn count(pool: &SqlitePool) -> Result<usize, AppError> {
-    let row: (i64,) = sqlx::query_as("SELECT count(*) FROM document;")
-      .fetch_one(pool)
+/// Represents one message in the persisted chat log of a document.
+#[derive(sqlx::FromRow, PartialEq, Eq, Clone, Debug)]
+pub struct PersistedChat {
+  /// id of the connection which sent the message.
+  pub user_id: i64,
+  /// Username of the sender if signed in, empty for anonymous.
+  pub uname: String,
+  /// Display name of the sender.
+  pub name: String,
+  /// Text of the message.
+  pub message: String,
+  /// Timestamp when the message was sent.
+  pub created_at: i64,
+}
+
+impl PersistedChat {
+  /// Load the latest messages in the chat log of a document, oldest first.
+  pub async fn load_recent(
+    pool: &SqlitePool,
+    document_id: &str,
+    limit: usize,
+  ) -> Result<Vec<PersistedChat>, AppError> {
+    let mut chats: Vec<PersistedChat> = sqlx::query_as(
+      r#"
+      SELECT user_id, uname, name, message, created_at
+      FROM document_chats
+      WHERE doc_id = $1
+      ORDER BY id DESC
+      LIMIT $2;
+      "#
+    )
+    .bind(document_id)
+    .bind(limit as i64)
+    .fetch_all(pool)
+    .await?;
+    chats.reverse();
+
+    Ok(chats)
+  }
+
+  /// Append messages to the chat log of a document, in one transaction.
+  pub async fn store_batch(
+    pool: &SqlitePool,
+    document_id: &str,
+    chats: &[PersistedChat],
+  ) -> Result<(), AppError> {
+    let mut tx = pool.begin().await?;
+    for chat in chats {
+      sqlx::query(
+        r#"
+        INSERT INTO
+          document_chats (doc_id, user_id, uname, name, message, created_at)
+        VALUES
+          ($1, $2, $3, $4, $5, $6)
+        "#
+      )
+      .bind(document_id)
+      .bind(chat.user_id)
+      .bind(&chat.uname)
+      .bind(&chat.name)
+      .bind(&chat.message)
+      .bind(chat.created_at)
+      .execute(&mut tx)
       .await?;
-    Ok(row.0 as usize)
+    }
+    tx.commit().await?;
+
+    Ok(())
+  }
+}
+
+/// A named snapshot of a document, stored in the `revisions` table.
+#[derive(sqlx::FromRow, Serialize, PartialEq, Eq, Clone, Debug)]
+pub struct PersistedSnapshot {
+  /// id of the revision row.
+  pub id: i64,
+  /// Name given to the snapshot.
+  pub name: String,
+  /// Username of who took the snapshot.
+  pub rev_by: String,
+  /// Timestamp when the snapshot was taken.
+  pub rev_at: i64,
+}
+
+impl PersistedSnapshot {
+  /// List the snapshots of a document, newest first.
+  pub async fn list(
+    pool: &SqlitePool,
+    document_id: &str,
+  ) -> Result<Vec<PersistedSnapshot>, AppError> {
+    let snapshots: Vec<PersistedSnapshot> = sqlx::query_as(
+      r#"
+      SELECT id, name, rev_by, rev_at
+      FROM revisions
+      WHERE on_ty = 'document' AND on_id = $1
+      ORDER BY rev_at DESC, id DESC;
+      "#
+    )
+    .bind(document_id)
+    .fetch_all(pool)
+    .await?;
+
+    Ok(snapshots)
+  }
+
+  /// Load the text of a snapshot of a document.
+  pub async fn load_text(
+    pool: &SqlitePool,
+    document_id: &str,
+    snapshot_id: i64,
+  ) -> Result<String, AppError> {
+    let row: (String,) = sqlx::query_as(
+      r#"
+      SELECT content FROM revisions
+      WHERE id = $1 AND on_ty = 'document' AND on_id = $2;
+      "#
+    )
+    .bind(snapshot_id)
+    .bind(document_id)
+    .fetch_one(pool)
+    .await?;
+
+    Ok(row.0)
+  }
+
+  /// Store a named snapshot of the text of a document.
+  ///
+  /// Taking a snapshot of an already stored text renames that snapshot.
+  pub async fn store(
+    pool: &SqlitePool,
+    document_id: &str,
+    name: &str,
+    text: &str,
+    uname: &str,
+  ) -> Result<PersistedSnapshot, AppError> {
+    let now = Utc::now().timestamp();
+    let snapshot: PersistedSnapshot = sqlx::query_as(
+      r#"
+      INSERT INTO
+        revisions (content, on_ty, on_id, rev_by, rev_at, name)
+      VALUES
+        ($1, 'document', $2, $3, $4, $5)
+      ON CONFLICT(content, on_ty, on_id) WHERE on_ty = 'document' DO UPDATE SET
+        rev_by = excluded.rev_by,
+        rev_at = excluded.rev_at,
+        name = excluded.name
+      RETURNING id, name, rev_by, rev_at;
+      "#
+    )
+    .bind(text)
+    .bind(document_id)
+    .bind(uname)
+    .bind(now)
+    .bind(name)
+    .fetch_one(pool)
+    .await?;
+
+    Ok(snapshot)
+  }
+}
+
+/// A comment on a document, stored in the `comments` table.
+///
+/// The first comment of a thread is anchored to a range of the text, the
+/// replies refer to it by `parent_key`.
+#[derive(sqlx::FromRow, PartialEq, Eq, Clone, Debug)]
+pub struct PersistedComment {
+  /// Unique key of the comment on its document.
+  pub key: String,
+  /// Key of the first comment of the thread, `None` for the first itself.
+  pub parent_key: Option<String>,
+  /// Start of the anchored range, in characters.
+  pub anchor_start: Option<i64>,
+  /// End of the anchored range, in characters.
+  pub anchor_end: Option<i64>,
+  /// Username of the author if signed in, empty for anonymous.
+  pub uname: String,
+  /// Display name of the author.
+  pub name: String,
+  /// Text of the comment.
+  pub content: String,
+  /// Timestamp when the comment was posted.
+  pub post_at: i64,
+  /// If the thread is resolved.
+  pub is_resolved: bool,
+}
+
+impl PersistedComment {
+  /// Load the comments of a document, oldest first.
+  pub async fn load(
+    pool: &SqlitePool,
+    document_id: &str,
+  ) -> Result<Vec<PersistedComment>, AppError> {
+    let comments = sqlx::query_as(
+      r#"
+      SELECT key, parent_key, anchor_start, anchor_end, uname, name, content,
+        post_at, is_resolved
+      FROM comments
+      WHERE on_ty = 'document' AND on_id = $1 AND key IS NOT NULL
+        AND is_hidden = FALSE
+      ORDER BY id;
+      "#
+    )
+    .bind(document_id)
+    .fetch_all(pool)
+    .await?;
+
+    Ok(comments)
+  }
+
+  /// Store the comments of a document, updating the anchors and the state
+  /// of those already stored, in one transaction.
+  pub async fn store_batch(
+    pool: &SqlitePool,
+    document_id: &str,
+    comments: &[PersistedComment],
+  ) -> Result<(), AppError> {
+    let mut tx = pool.begin().await?;
+    for comment in comments {
+      sqlx::query(
+        r#"
+        INSERT INTO
+          comments (uname, content, on_ty, on_id, post_at, key, parent_key,
+            name, anchor_start, anchor_end, is_resolved)
+        VALUES
+          ($1, $2, 'document', $3, $4, $5, $6, $7, $8, $9, $10)
+        ON CONFLICT(on_ty, on_id, key) DO UPDATE SET
+          content = excluded.content,
+          anchor_start = excluded.anchor_start,
+          anchor_end = excluded.anchor_end,
+          is_resolved = excluded.is_resolved
+        "#
+      )
+      .bind(&comment.uname)
+      .bind(&comment.content)
+      .bind(document_id)
+      .bind(comment.post_at)
+      .bind(&comment.key)
+      .bind(&comment.parent_key)
+      .bind(&comment.name)
+      .bind(comment.anchor_start)
+      .bind(comment.anchor_end)
+      .bind(comment.is_resolved)
+      .execute(&mut tx)
+      .await?;
+    }
+    tx.commit().await?;
+
+    Ok(())
+  }
+
+  /// Carry the comments of a document over to its article, replacing those
+  /// carried on the last save.
+  pub async fn carry_to_article(
+    pool: &SqlitePool,
+    document_id: &str,
+    article_id: u32,
+  ) -> Result<(), AppError> {
+    let mut tx = pool.begin().await?;
+    sqlx::query(
+      r#"
+      DELETE FROM comments
+      WHERE on_ty = 'article' AND on_id = $1 AND key IS NOT NULL;
+      "#
+    )
+    .bind(article_id)
+    .execute(&mut tx)
+    .await?;
+    sqlx::query(
+      r#"
+      INSERT INTO
+        comments (uname, title, content, on_ty, on_id, post_at, is_hidden, key,
+          parent_key, name, anchor_start, anchor_end, is_resolved)
+      SELECT uname, title, content, 'article', $1, post_at, is_hidden, key,
+        parent_key, name, anchor_start, anchor_end, is_resolved
+      FROM comments
+      WHERE on_ty = 'document' AND on_id = $2 AND key IS NOT NULL;
+      "#
+    )
+    .bind(article_id)
+    .bind(document_id)
+    .execute(&mut tx)
+    .await?;
+    tx.commit().await?;
+
+    Ok(())
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::db::test_pool;
+  use crate::pad::mdpad::Pad;
+
+  fn document(text: &str) -> PersistedDocument {
+    PersistedDocument {
+      id: None,
+      text: text.into(),
+      language: None,
+      updated_at: None,
+      article_id: None,
+    }
+  }
+
+  #[tokio::test]
+  async fn test_store_history() {
+    let (_dir, pool) = test_pool().await;
+    let pad = Pad::from(document(""));
+    let mut persisted = 0;
+    // past the compaction of the history, persisted now and then
+    for i in 0..1100 {
+      pad.replace_text(&format!("text {i}")).unwrap();
+      if i % 300 == 0 || i == 1099 {
+        let history = pad.snapshot_since(persisted);
+        history.store(&pool, "doc1", None).await.unwrap();
+        persisted = history.operations.last().unwrap().revision as usize + 1;
+      }
+    }
+    assert_eq!(persisted, pad.revision());
+
+    let checkpoint = PersistedCheckpoint::load(&pool, "doc1").await.unwrap().unwrap();
+    let operations = PersistedOperation::load_all(&pool, "doc1").await.unwrap();
+    assert_eq!(operations[0].revision, checkpoint.revision);
+    let document = PersistedDocument::load(&pool, "doc1").await.unwrap();
+    let restored = Pad::restore(document, Some(checkpoint), operations).unwrap();
+    assert_eq!(restored.text(), "text 1099");
+    assert_eq!(restored.revision(), pad.revision());
   }
 }
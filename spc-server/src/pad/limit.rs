@@ -0,0 +1,118 @@
+//! Rate limits protecting pads from flooding clients.
+
+use std::fmt;
+use std::time::Instant;
+
+use serde::{Deserialize, Serialize};
+
+/// A rate limit: a sustained rate, with bursts up to `burst`.
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Rate {
+  /// number of messages allowed per second, on average
+  pub per_sec: f64,
+  /// number of messages allowed at once
+  pub burst: f64,
+}
+
+impl Rate {
+  const fn new(per_sec: f64, burst: f64) -> Self {
+    Self { per_sec, burst }
+  }
+}
+
+/// Limits of pads, set in `Config`.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Limits {
+  /// edits of a connection, undo and redo included
+  pub edits: Rate,
+  /// cursor updates of a connection
+  pub cursors: Rate,
+  /// info, language and chat messages of a connection
+  pub infos: Rate,
+  /// edits on a document, from all connections
+  pub doc_edits: Rate,
+  /// total number of operations of a document, compacted ones included
+  pub max_doc_ops: usize,
+}
+
+impl Default for Limits {
+  fn default() -> Self {
+    Self {
+      edits: Rate::new(30.0, 120.0),
+      cursors: Rate::new(30.0, 120.0),
+      infos: Rate::new(2.0, 10.0),
+      doc_edits: Rate::new(200.0, 600.0),
+      max_doc_ops: 1_000_000,
+    }
+  }
+}
+
+/// A token bucket, refilled at the sustained rate of a limit.
+#[derive(Clone, Copy, Debug)]
+pub struct Bucket {
+  tokens: f64,
+  last: Instant,
+}
+
+impl Bucket {
+  /// Create a full bucket.
+  pub fn new(rate: Rate) -> Self {
+    Self {
+      tokens: rate.burst,
+      last: Instant::now(),
+    }
+  }
+
+  /// Take a token at `now`, returns `false` if the bucket is empty.
+  pub fn take_at(&mut self, rate: Rate, now: Instant) -> bool {
+    let elapsed = now.saturating_duration_since(self.last).as_secs_f64();
+    self.tokens = (self.tokens + elapsed * rate.per_sec).min(rate.burst);
+    self.last = now;
+    if self.tokens < 1.0 {
+      return false;
+    }
+    self.tokens -= 1.0;
+    true
+  }
+
+  /// Take a token now, returns `false` if the bucket is empty.
+  pub fn take(&mut self, rate: Rate) -> bool {
+    self.take_at(rate, Instant::now())
+  }
+}
+
+/// A limit exceeded by a client, which gets disconnected.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct LimitExceeded(pub &'static str);
+
+impl fmt::Display for LimitExceeded {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    f.write_str(self.0)
+  }
+}
+
+impl std::error::Error for LimitExceeded {}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use std::time::Duration;
+
+  #[test]
+  fn test_bucket() {
+    let rate = Rate::new(2.0, 3.0);
+    let mut bucket = Bucket::new(rate);
+    let now = bucket.last;
+    assert!((0..3).all(|_| bucket.take_at(rate, now)));
+    assert!(!bucket.take_at(rate, now));
+    // refilled at the sustained rate
+    let later = now + Duration::from_millis(500);
+    assert!(bucket.take_at(rate, later));
+    assert!(!bucket.take_at(rate, later));
+    // up to the burst
+    let much_later = later + Duration::from_secs(60);
+    assert!((0..3).all(|_| bucket.take_at(rate, much_later)));
+    assert!(!bucket.take_at(rate, much_later));
+  }
+}
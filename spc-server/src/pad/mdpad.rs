@@ -1,20 +1,40 @@
 //! Eventually consistent server-side logic.
 
-use std::collections::HashMap;
-use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
 
 use anyhow::{bail, Context, Result};
-use axum::extract::ws::{Message, WebSocket};
-use futures::prelude::*;
+use chrono::Utc;
 use log::{info, warn};
 use operational_transform::OperationSeq;
-use parking_lot::{RwLock, RwLockUpgradableReadGuard};
+use parking_lot::{Mutex, RwLock, RwLockUpgradableReadGuard};
 use serde::{Deserialize, Serialize};
 use tokio::sync::{broadcast, Notify};
 
 use crate::db::note::Note;
-use super::document::PersistedDocument;
-use super::ot::transform_index;
+use super::acl::Access;
+use super::limit::{Bucket, LimitExceeded, Limits};
+use super::document::{
+  PersistedChat, PersistedCheckpoint, PersistedComment, PersistedDocument,
+  PersistedHistory, PersistedOperation,
+};
+use super::ot::{diff_operation, transform_index};
+use super::transport::Connection;
+
+/// Compact the history once it holds more operations than this.
+const COMPACT_THRESHOLD: usize = 1024;
+/// Number of recent operations kept in history after compaction.
+const HISTORY_WINDOW: usize = 256;
+/// Number of recent chat messages kept in memory and sent on connection.
+pub const CHAT_BACKLOG: usize = 100;
+/// Maximum length of a chat message, in characters.
+const CHAT_MAX_LEN: usize = 2000;
+/// Number of edits each user can undo.
+const UNDO_DEPTH: usize = 100;
+/// Maximum number of comments on a document.
+const COMMENT_MAX: usize = 1000;
+/// Maximum number of pending suggestions on a document.
+const SUGGESTION_MAX: usize = 100;
 
 /// The main object representing a collaborative session.
 pub struct Pad {
@@ -22,45 +42,264 @@ pub struct Pad {
   state: RwLock<State>,
   /// Incremented to obtain unique user IDs.
   count: AtomicU64,
+  /// Number of clients currently connected.
+  online: AtomicUsize,
   /// Used to notify clients of new text operations.
   notify: Notify,
   /// Used to inform all clients of metadata updates.
   update: broadcast::Sender<ServerMsg>,
   /// Set to true when the document is destroyed.
   killed: AtomicBool,
+  /// Rate limits of the clients.
+  limits: Limits,
+  /// Rate limit of the edits on the document, from all connections.
+  doc_edits: Mutex<Bucket>,
+}
+
+/// Rate limits of a connection.
+struct ConnBuckets {
+  edits: Bucket,
+  cursors: Bucket,
+  infos: Bucket,
+}
+
+impl ConnBuckets {
+  fn new(limits: &Limits) -> Self {
+    Self {
+      edits: Bucket::new(limits.edits),
+      cursors: Bucket::new(limits.cursors),
+      infos: Bucket::new(limits.infos),
+    }
+  }
 }
 
 /// Shared state involving multiple users, protected by a lock.
 #[derive(Default)]
 struct State {
+  /// Revision of the first operation kept in `operations`.
+  base: usize,
+  /// Text at revision `base`, with the older operations composed into it.
+  base_text: String,
   operations: Vec<UserOperation>,
   text: String,
   language: Option<String>,
   users: HashMap<u64, UserInfo>,
   cursors: HashMap<u64, CursorData>,
+  /// Recent chat messages, at most `CHAT_BACKLOG`.
+  chats: VecDeque<ChatMessage>,
+  /// Number of chat messages so far, counting the loaded backlog.
+  num_chats: usize,
+  /// Undo and redo stacks of each user, by connection id.
+  undo: HashMap<u64, UndoStacks>,
+  /// Comments on the document, oldest first.
+  comments: Vec<Comment>,
+  /// Incremented when comments are added, changed or moved.
+  comments_version: usize,
+  /// Connections in suggesting mode, whose edits are only proposed.
+  suggesting: HashSet<u64>,
+  /// Pending suggestions, rebased on each edit to the current revision.
+  suggestions: Vec<Suggestion>,
+}
+
+/// The inverse of an operation, applying to the text at `revision`.
+#[derive(Clone, Debug)]
+struct Inverse {
+  revision: usize,
+  operation: OperationSeq,
+}
+
+/// Inverses of the edits of a user, to undo and redo them.
+#[derive(Default)]
+struct UndoStacks {
+  undo: Vec<Inverse>,
+  redo: Vec<Inverse>,
+}
+
+/// How an edit came to be, to keep the undo stacks of its author.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum EditKind {
+  /// A new edit, which can be undone.
+  Edit,
+  /// Undoes an edit, which can be redone.
+  Undo,
+  /// Redoes an undone edit, which can be undone again.
+  Redo,
+}
+
+impl UndoStacks {
+  /// Record the inverse of an edit of the given kind.
+  fn push(&mut self, kind: EditKind, inverse: Inverse) {
+    let stack = match kind {
+      EditKind::Edit => {
+        self.redo.clear();
+        &mut self.undo
+      }
+      EditKind::Undo => &mut self.redo,
+      EditKind::Redo => &mut self.undo,
+    };
+    if stack.len() >= UNDO_DEPTH {
+      stack.remove(0);
+    }
+    stack.push(inverse);
+  }
+
+  /// Take the inverse to apply to undo, or to redo, the last edit.
+  fn pop(&mut self, kind: EditKind) -> Option<Inverse> {
+    match kind {
+      EditKind::Edit => None,
+      EditKind::Undo => self.undo.pop(),
+      EditKind::Redo => self.redo.pop(),
+    }
+  }
+}
+
+impl State {
+  /// Returns the current revision.
+  fn revision(&self) -> usize {
+    self.base + self.operations.len()
+  }
+
+  /// Returns the name shown for user `id`: their username if signed in,
+  /// else the name they chose.
+  fn name_of(&self, id: u64, uname: &str) -> String {
+    if uname.is_empty() {
+      self
+        .users
+        .get(&id)
+        .map(|info| info.name.clone())
+        .unwrap_or_else(|| String::from("Anonymous"))
+    } else {
+      uname.to_owned()
+    }
+  }
+
+  /// Returns the checkpoint of compacted history, if any.
+  fn checkpoint(&self) -> Option<PersistedCheckpoint> {
+    if self.base == 0 {
+      return None;
+    }
+    Some(PersistedCheckpoint {
+      revision: self.base as i64,
+      text: self.base_text.clone(),
+      created_at: Utc::now().timestamp(),
+    })
+  }
+
+  /// Compose the oldest operations into the checkpoint text, keeping only a
+  /// recent window of history. Revision numbers are left unchanged.
+  fn compact(&mut self) -> Result<()> {
+    if self.operations.len() <= COMPACT_THRESHOLD {
+      return Ok(());
+    }
+    let num_old = self.operations.len() - HISTORY_WINDOW;
+    let mut checkpoint = self.operations[0].operation.clone();
+    for old_op in &self.operations[1..num_old] {
+      checkpoint = checkpoint.compose(&old_op.operation)?;
+    }
+    self.base_text = checkpoint.apply(&self.base_text)?;
+    self.operations.drain(..num_old);
+    self.base += num_old;
+    // inverses are transformed through the history after them
+    let base = self.base;
+    for stacks in self.undo.values_mut() {
+      stacks.undo.retain(|inverse| inverse.revision >= base);
+      stacks.redo.retain(|inverse| inverse.revision >= base);
+    }
+    info!("compacted history up to revision {}", self.base);
+    Ok(())
+  }
 }
 
+/// An operation in the history, along with the id of its author.
 #[derive(Clone, Debug, Serialize, Deserialize)]
-struct UserOperation {
+pub struct UserOperation {
   id: u64,
   operation: OperationSeq,
+  /// Timestamp when applied, kept for persistence only.
+  #[serde(skip)]
+  created_at: i64,
 }
 
+/// Information about a user, set by the client.
 #[derive(Clone, Debug, Serialize, Deserialize)]
-struct UserInfo {
+pub struct UserInfo {
   name: String,
   hue: u32,
 }
 
+/// Cursor and selection positions of a user.
 #[derive(Clone, Debug, Serialize, Deserialize)]
-struct CursorData {
+pub struct CursorData {
   cursors: Vec<u32>,
   selections: Vec<(u32, u32)>,
 }
 
-/// A message received from the client over WebSocket.
+/// A chat message sent on the document.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct ChatMessage {
+  id: u64,
+  /// Username of the sender if signed in, empty for anonymous.
+  uname: String,
+  name: String,
+  text: String,
+  time: i64,
+}
+
+/// A comment on the document: the first of a thread, anchored to a range
+/// of the text, or a reply in a thread.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Comment {
+  /// Unique key of the comment.
+  key: String,
+  /// Key of the first comment of the thread, `None` for the first itself.
+  parent: Option<String>,
+  /// Anchored range in characters, moved along the edits.
+  start: u32,
+  end: u32,
+  /// Username of the author if signed in, empty for anonymous.
+  uname: String,
+  name: String,
+  text: String,
+  time: i64,
+  /// Set on the first comment once the thread is resolved.
+  resolved: bool,
+}
+
+/// An edit proposed in suggesting mode, pending until the owner of the
+/// document accepts or rejects it.
 #[derive(Clone, Debug, Serialize, Deserialize)]
-enum ClientMsg {
+pub struct Suggestion {
+  /// Unique key of the suggestion.
+  key: String,
+  /// Connection id of the author.
+  id: u64,
+  /// Username of the author if signed in, empty for anonymous.
+  uname: String,
+  name: String,
+  /// Revision the operation applies to, the current one.
+  revision: usize,
+  operation: OperationSeq,
+  time: i64,
+}
+
+/// Activity on a pad, as seen by the admin.
+#[derive(Clone, Debug, Default, Serialize)]
+pub struct Activity {
+  /// Number of clients currently connected.
+  pub online: usize,
+  /// Names of the connected users, as set by their clients.
+  pub users: Vec<String>,
+  /// Timestamp of the last edit, 0 if none is kept in history.
+  pub last_edit: i64,
+  /// Current revision, i.e. the number of operations so far.
+  pub revision: usize,
+  /// Estimated size in memory of the text, history and chat, in bytes.
+  pub memory: usize,
+}
+
+/// A message received from the client.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub enum ClientMsg {
   /// Represents a sequence of local edits from the user.
   Edit {
     revision: usize,
@@ -72,13 +311,36 @@ enum ClientMsg {
   ClientInfo(UserInfo),
   /// Sets the user's cursor and selection positions.
   CursorData(CursorData),
+  /// Sends a chat message to everyone on the document.
+  Chat(String),
+  /// Undoes the last edit of the user, keeping the edits of others.
+  Undo,
+  /// Redoes the last edit undone by the user.
+  Redo,
+  /// Starts a comment thread on a range of the text.
+  Comment { start: u32, end: u32, text: String },
+  /// Replies in the comment thread of `parent`.
+  Reply { parent: String, text: String },
+  /// Resolves, or reopens, the comment thread of `key`.
+  Resolve { key: String, resolved: bool },
+  /// Turns suggesting mode on or off, in which edits are only proposed.
+  Suggesting(bool),
+  /// Accepts the suggestion of `key`, applying it, owner only.
+  Accept(String),
+  /// Rejects the suggestion of `key`, by the owner or its author.
+  Reject(String),
 }
 
-/// A message sent to the client over WebSocket.
+/// A message sent to the client.
 #[derive(Clone, Debug, Serialize, Deserialize)]
-enum ServerMsg {
+pub enum ServerMsg {
   /// Informs the client of their unique socket ID.
   Identity(u64),
+  /// Informs the client that its edits will be rejected.
+  ReadOnly(bool),
+  /// The text at a checkpoint revision, sent before the history when older
+  /// operations have been compacted.
+  Snapshot { revision: usize, text: String },
   /// Broadcasts text operations to all clients.
   History {
     start: usize,
@@ -90,24 +352,30 @@ enum ServerMsg {
   UserInfo { id: u64, info: Option<UserInfo> },
   /// Broadcasts a user's cursor position.
   UserCursor { id: u64, data: CursorData },
-}
-
-impl From<ServerMsg> for Message {
-  fn from(msg: ServerMsg) -> Self {
-    let serialized = serde_json::to_string(&msg).expect("failed serialize");
-    Message::Text(serialized)
-  }
+  /// Broadcasts a chat message, also sent for the backlog on connection.
+  Chat(ChatMessage),
+  /// Broadcasts a new or changed comment, also sent for all comments on
+  /// connection. Clients move the anchors along the edits themselves.
+  Comment(Comment),
+  /// Sends a pending suggestion, right after the history it applies to.
+  Suggestion(Suggestion),
+  /// Broadcasts that a suggestion was accepted or rejected.
+  SuggestionDone { key: String, accepted: bool },
 }
 
 impl Default for Pad {
   fn default() -> Self {
     let (tx, _) = broadcast::channel(16);
+    let limits = Limits::default();
     Self {
       state: Default::default(),
       count: Default::default(),
+      online: Default::default(),
       notify: Default::default(),
       update: tx,
       killed: AtomicBool::new(false),
+      doc_edits: Mutex::new(Bucket::new(limits.doc_edits)),
+      limits,
     }
   }
 }
@@ -125,6 +393,7 @@ impl From<PersistedDocument> for Pad {
       state.operations.push(UserOperation {
         id: u64::MAX,
         operation,
+        created_at: Utc::now().timestamp(),
       })
     }
     pad
@@ -144,6 +413,7 @@ impl From<Note> for Pad {
       state.operations.push(UserOperation {
         id: u64::MAX,
         operation,
+        created_at: Utc::now().timestamp(),
       })
     }
     pad
@@ -151,16 +421,94 @@ impl From<Note> for Pad {
 }
 
 impl Pad {
-  /// Handle a connection from a WebSocket.
-  pub async fn on_connection(&self, socket: WebSocket) {
+  /// Restore a pad from a persisted document and its operation history,
+  /// starting from the checkpoint if the history has been compacted.
+  ///
+  /// Fails if the history is not contiguous or does not replay to the
+  /// persisted text, e.g. when the text was overwritten out of band.
+  pub fn restore(
+    document: PersistedDocument,
+    checkpoint: Option<PersistedCheckpoint>,
+    history: Vec<PersistedOperation>,
+  ) -> Result<Self> {
+    let (base, base_text) = match checkpoint {
+      Some(checkpoint) => (checkpoint.revision as usize, checkpoint.text),
+      None if history.is_empty() => bail!("empty operation history"),
+      None => (0, String::new()),
+    };
+    let mut text = base_text.clone();
+    let mut operations = Vec::with_capacity(history.len());
+    for persisted in history {
+      if persisted.revision < base as i64 {
+        continue; // already composed into the checkpoint
+      }
+      let revision = base + operations.len();
+      if persisted.revision != revision as i64 {
+        bail!("missing revision {} in history", revision);
+      }
+      let operation: OperationSeq = serde_json::from_str(&persisted.operation)
+        .context("failed to deserialize operation")?;
+      text = operation.apply(&text)?;
+      operations.push(UserOperation {
+        id: persisted.user_id as u64,
+        operation,
+        created_at: persisted.created_at,
+      });
+    }
+    if text != document.text {
+      bail!("history does not match the persisted text");
+    }
+
+    // keep connection ids unique across restarts, so a reconnecting client
+    // never mistakes an old operation for an acknowledgement of its own
+    let next_id = operations
+      .iter()
+      .filter(|op| op.id != u64::MAX)
+      .map(|op| op.id + 1)
+      .max()
+      .unwrap_or(0);
+
+    let pad = Self::default();
+    pad.count.store(next_id, Ordering::Relaxed);
+    {
+      let mut state = pad.state.write();
+      state.base = base;
+      state.base_text = base_text;
+      state.text = text;
+      state.language = document.language;
+      state.operations = operations;
+      state.compact()?;
+    }
+    Ok(pad)
+  }
+
+  /// Set the rate limits of the clients.
+  pub fn with_limits(mut self, limits: Limits) -> Self {
+    self.doc_edits = Mutex::new(Bucket::new(limits.doc_edits));
+    self.limits = limits;
+    self
+  }
+
+  /// Handle a connection from a client over any transport, with the access
+  /// of its visitor and their username, empty if not signed in.
+  pub async fn on_connection(
+    &self,
+    conn: Connection,
+    access: Access,
+    uname: String,
+  ) {
     let id = self.count.fetch_add(1, Ordering::Relaxed);
-    info!("connection! id = {}", id);
-    if let Err(e) = self.handle_connection(id, socket).await {
+    info!("connection! id = {}, access = {:?}", id, access);
+    self.online.fetch_add(1, Ordering::Relaxed);
+    if let Err(e) = self.handle_connection(id, conn, access, &uname).await {
       warn!("connection terminated early: {}", e);
     }
+    self.online.fetch_sub(1, Ordering::Relaxed);
     info!("disconnection, id = {}", id);
     self.state.write().users.remove(&id);
     self.state.write().cursors.remove(&id);
+    self.state.write().undo.remove(&id);
+    self.state.write().suggesting.remove(&id);
     self
       .update
       .send(ServerMsg::UserInfo { id, info: None })
@@ -185,10 +533,190 @@ impl Pad {
     }
   }
 
+  /// Returns a snapshot of the current document along with the operations
+  /// from revision `start` and the checkpoint, taken consistently for
+  /// persistence.
+  ///
+  /// Operations already composed into the checkpoint are not returned.
+  pub fn snapshot_since(&self, start: usize) -> PersistedHistory {
+    let state = self.state.read();
+    let operations = state
+      .operations
+      .iter()
+      .enumerate()
+      .skip(start.saturating_sub(state.base))
+      .map(|(index, op)| PersistedOperation {
+        revision: (state.base + index) as i64,
+        user_id: op.id as i64,
+        operation: serde_json::to_string(&op.operation).expect("failed serialize"),
+        created_at: op.created_at,
+      })
+      .collect();
+    let document = PersistedDocument {
+      text: state.text.clone(),
+      language: state.language.clone(),
+      updated_at: None,
+      article_id: None,
+      id: None,
+    };
+    PersistedHistory {
+      document,
+      operations,
+      checkpoint: state.checkpoint(),
+    }
+  }
+
   /// Returns the current revision.
   pub fn revision(&self) -> usize {
     let state = self.state.read();
-    state.operations.len()
+    state.revision()
+  }
+
+  /// Returns the current activity on the pad.
+  pub fn activity(&self) -> Activity {
+    let state = self.state.read();
+    let mut users: Vec<String> =
+      state.users.values().map(|info| info.name.clone()).collect();
+    users.sort();
+    let history: usize = state
+      .operations
+      .iter()
+      .flat_map(|op| op.operation.ops())
+      .map(|op| match op {
+        operational_transform::Operation::Insert(s) => s.len() + 24,
+        _ => 24,
+      })
+      .sum();
+    let chats: usize = state.chats.iter().map(|chat| chat.text.len() + 64).sum();
+    Activity {
+      online: self.online.load(Ordering::Relaxed),
+      users,
+      last_edit: state.operations.last().map_or(0, |op| op.created_at),
+      revision: state.revision(),
+      memory: state.text.len() + state.base_text.len() + history + chats,
+    }
+  }
+
+  /// Returns a snapshot of the latest text, along with its revision.
+  pub fn revision_text(&self) -> (usize, String) {
+    let state = self.state.read();
+    (state.revision(), state.text.clone())
+  }
+
+  /// Replace the text with `text` through a server-made edit, so connected
+  /// clients converge as for any other edit. Returns the new revision.
+  ///
+  /// Edits racing with this one are transformed as usual, thus kept.
+  pub fn replace_text(&self, text: &str) -> Result<usize> {
+    let (revision, old) = self.revision_text();
+    self.edit_text(revision, &old, text)
+  }
+
+  /// Edit the text from `old` at `revision` into `new`, as with
+  /// `replace_text`, keeping the edits made since `revision`.
+  pub fn edit_text(&self, revision: usize, old: &str, new: &str) -> Result<usize> {
+    let operation = diff_operation(old, new);
+    if !operation.is_noop() {
+      self.apply_edit(u64::MAX, revision, operation)?;
+      self.notify.notify_waiters();
+    }
+    Ok(self.revision())
+  }
+
+  /// Load the chat backlog persisted for this document, oldest first.
+  pub fn load_chats(&self, chats: Vec<PersistedChat>) {
+    let mut state = self.state.write();
+    state.num_chats = chats.len();
+    state.chats = chats
+      .into_iter()
+      .rev()
+      .take(CHAT_BACKLOG)
+      .rev()
+      .map(|chat| ChatMessage {
+        id: chat.user_id as u64,
+        uname: chat.uname,
+        name: chat.name,
+        text: chat.message,
+        time: chat.created_at,
+      })
+      .collect();
+  }
+
+  /// Returns the number of chat messages so far, counting the loaded backlog.
+  pub fn num_chats(&self) -> usize {
+    let state = self.state.read();
+    state.num_chats
+  }
+
+  /// Returns the chat messages from index `start` for persistence, along
+  /// with the number of messages so far.
+  ///
+  /// Messages already dropped from the backlog are not returned.
+  pub fn chats_since(&self, start: usize) -> (Vec<PersistedChat>, usize) {
+    let state = self.state.read();
+    let first = state.num_chats - state.chats.len();
+    let chats = state
+      .chats
+      .iter()
+      .skip(start.saturating_sub(first))
+      .map(|chat| PersistedChat {
+        user_id: chat.id as i64,
+        uname: chat.uname.clone(),
+        name: chat.name.clone(),
+        message: chat.text.clone(),
+        created_at: chat.time,
+      })
+      .collect();
+    (chats, state.num_chats)
+  }
+
+  /// Load the comments persisted for this document, oldest first.
+  pub fn load_comments(&self, comments: Vec<PersistedComment>) {
+    let mut state = self.state.write();
+    state.comments = comments
+      .into_iter()
+      .map(|comment| Comment {
+        key: comment.key,
+        parent: comment.parent_key,
+        start: comment.anchor_start.unwrap_or_default() as u32,
+        end: comment.anchor_end.unwrap_or_default() as u32,
+        uname: comment.uname,
+        name: comment.name,
+        text: comment.content,
+        time: comment.post_at,
+        resolved: comment.is_resolved,
+      })
+      .collect();
+  }
+
+  /// Returns the version of the comments, incremented on any change.
+  pub fn comments_version(&self) -> usize {
+    let state = self.state.read();
+    state.comments_version
+  }
+
+  /// Returns the comments for persistence, along with their version.
+  pub fn comments(&self) -> (Vec<PersistedComment>, usize) {
+    let state = self.state.read();
+    let comments = state
+      .comments
+      .iter()
+      .map(|comment| {
+        let anchored = comment.parent.is_none();
+        PersistedComment {
+          key: comment.key.clone(),
+          parent_key: comment.parent.clone(),
+          anchor_start: anchored.then_some(comment.start as i64),
+          anchor_end: anchored.then_some(comment.end as i64),
+          uname: comment.uname.clone(),
+          name: comment.name.clone(),
+          content: comment.text.clone(),
+          post_at: comment.time,
+          is_resolved: comment.resolved,
+        }
+      })
+      .collect();
+    (comments, state.comments_version)
   }
 
   /// Kill this object immediately, dropping all current connections.
@@ -202,10 +730,28 @@ impl Pad {
     self.killed.load(Ordering::Relaxed)
   }
 
-  async fn handle_connection(&self, id: u64, mut socket: WebSocket) -> Result<()> {
+  /// Wait until this Pad object is killed.
+  pub async fn until_killed(&self) {
+    loop {
+      let notified = self.notify.notified();
+      if self.killed() {
+        return;
+      }
+      notified.await;
+    }
+  }
+
+  async fn handle_connection(
+    &self,
+    id: u64,
+    mut conn: Connection,
+    access: Access,
+    uname: &str,
+  ) -> Result<()> {
     let mut update_rx = self.update.subscribe();
 
-    let mut revision: usize = self.send_initial(id, &mut socket).await?;
+    let mut revision: usize = self.send_initial(id, &mut conn, access).await?;
+    let mut buckets = ConnBuckets::new(&self.limits);
 
     loop {
       // In order to avoid the "lost wakeup" problem, we first request a
@@ -216,19 +762,37 @@ impl Pad {
         break;
       }
       if self.revision() > revision {
-        revision = self.send_history(revision, &mut socket).await?
+        revision = self.send_history(revision, &mut conn).await?
       }
 
       tokio::select! {
           _ = notified => {}
           update = update_rx.recv() => {
-              socket.send(update?.into()).await?;
+              match update? {
+                  ServerMsg::Suggestion(suggestion) => {
+                      revision = self
+                          .send_suggestion(&suggestion.key, revision, &mut conn)
+                          .await?;
+                  }
+                  update => conn.send(update).await?,
+              }
           }
-          result = socket.next() => {
+          result = conn.recv() => {
               match result {
                   None => break,
-                  Some(message) => {
-                      self.handle_message(id, message?).await?;
+                  Some(msg) => {
+                      let msg = msg?;
+                      let handled = match self.check_rate(&mut buckets, &msg) {
+                          Ok(()) => self.handle_message(id, msg, access, uname).await,
+                          Err(e) => Err(e.into()),
+                      };
+                      if let Err(e) = handled {
+                          // tell the offender why it gets disconnected
+                          if let Some(limit) = e.downcast_ref::<LimitExceeded>() {
+                              conn.close(limit.to_string()).await.ok();
+                          }
+                          return Err(e);
+                      }
                   }
               }
           }
@@ -238,14 +802,28 @@ impl Pad {
     Ok(())
   }
 
-  async fn send_initial(&self, id: u64, socket: &mut WebSocket) -> Result<usize> {
-    socket.send(ServerMsg::Identity(id).into()).await?;
+  async fn send_initial(
+    &self,
+    id: u64,
+    conn: &mut Connection,
+    access: Access,
+  ) -> Result<usize> {
+    conn.send(ServerMsg::Identity(id)).await?;
+    if !access.can_edit() {
+      conn.send(ServerMsg::ReadOnly(true)).await?;
+    }
     let mut messages = Vec::new();
     let revision = {
       let state = self.state.read();
+      if state.base > 0 {
+        messages.push(ServerMsg::Snapshot {
+          revision: state.base,
+          text: state.base_text.clone(),
+        });
+      }
       if !state.operations.is_empty() {
         messages.push(ServerMsg::History {
-          start: 0,
+          start: state.base,
           operations: state.operations.clone(),
         });
       }
@@ -264,10 +842,19 @@ impl Pad {
           data: data.clone(),
         });
       }
-      state.operations.len()
+      for chat in &state.chats {
+        messages.push(ServerMsg::Chat(chat.clone()));
+      }
+      for comment in &state.comments {
+        messages.push(ServerMsg::Comment(comment.clone()));
+      }
+      for suggestion in &state.suggestions {
+        messages.push(ServerMsg::Suggestion(suggestion.clone()));
+      }
+      state.revision()
     };
     for msg in messages {
-      socket.send(msg.into()).await?;
+      conn.send(msg).await?;
     }
     Ok(revision)
   }
@@ -275,33 +862,129 @@ impl Pad {
   async fn send_history(
     &self,
     start: usize,
-    socket: &mut WebSocket,
+    conn: &mut Connection,
   ) -> Result<usize> {
-    let operations = {
+    let (snapshot, start, operations) = {
       let state = self.state.read();
-      let len = state.operations.len();
-      if start < len {
-        state.operations[start..].to_owned()
+      // the client fell behind the checkpoint, bootstrap it from there
+      let snapshot = if start < state.base {
+        Some(ServerMsg::Snapshot {
+          revision: state.base,
+          text: state.base_text.clone(),
+        })
       } else {
-        Vec::new()
-      }
+        None
+      };
+      let start = start.max(state.base);
+      let operations = state.operations[start - state.base..].to_owned();
+      (snapshot, start, operations)
     };
+    if let Some(msg) = snapshot {
+      conn.send(msg).await?;
+    }
     let num_ops = operations.len();
     if num_ops > 0 {
       let msg = ServerMsg::History { start, operations };
-      socket.send(msg.into()).await?;
+      conn.send(msg).await?;
     }
     Ok(start + num_ops)
   }
 
-  async fn handle_message(&self, id: u64, message: Message) -> Result<()> {
-    let msg: ClientMsg = match message.to_text() {
-      Ok(text) => {
-        serde_json::from_str(text).context("failed to deserialize message")?
+  /// Send the current state of a suggestion, after the history up to the
+  /// revision it was rebased on. Returns the revision sent so far.
+  async fn send_suggestion(
+    &self,
+    key: &str,
+    mut revision: usize,
+    conn: &mut Connection,
+  ) -> Result<usize> {
+    loop {
+      let suggestion = {
+        let state = self.state.read();
+        state.suggestions.iter().find(|s| s.key == key).cloned()
+      };
+      match suggestion {
+        // already accepted or rejected
+        None => return Ok(revision),
+        Some(suggestion) if suggestion.revision > revision => {
+          revision = self.send_history(revision, conn).await?;
+        }
+        Some(suggestion) => {
+          conn.send(ServerMsg::Suggestion(suggestion)).await?;
+          return Ok(revision);
+        }
       }
-      Err(_) => return Ok(()), // Ignore non-text messages
-    };
+    }
+  }
+
+  /// Take a token from the buckets limiting the message.
+  fn check_rate(
+    &self,
+    buckets: &mut ConnBuckets,
+    msg: &ClientMsg,
+  ) -> Result<(), LimitExceeded> {
+    let limits = &self.limits;
+    match msg {
+      ClientMsg::Edit { .. }
+      | ClientMsg::Undo
+      | ClientMsg::Redo
+      | ClientMsg::Accept(_) => {
+        if !buckets.edits.take(limits.edits) {
+          return Err(LimitExceeded("too many edits"));
+        }
+        if !self.doc_edits.lock().take(limits.doc_edits) {
+          return Err(LimitExceeded("too many edits on the document"));
+        }
+      }
+      ClientMsg::CursorData(_) => {
+        if !buckets.cursors.take(limits.cursors) {
+          return Err(LimitExceeded("too many cursor updates"));
+        }
+      }
+      ClientMsg::ClientInfo(_)
+      | ClientMsg::SetLanguage(_)
+      | ClientMsg::Chat(_)
+      | ClientMsg::Comment { .. }
+      | ClientMsg::Reply { .. }
+      | ClientMsg::Resolve { .. }
+      | ClientMsg::Suggesting(_)
+      | ClientMsg::Reject(_) => {
+        if !buckets.infos.take(limits.infos) {
+          return Err(LimitExceeded("too many info changes"));
+        }
+      }
+    }
+    Ok(())
+  }
+
+  async fn handle_message(
+    &self,
+    id: u64,
+    msg: ClientMsg,
+    access: Access,
+    uname: &str,
+  ) -> Result<()> {
     match msg {
+      // proposed edits are allowed to viewers as well
+      ClientMsg::Edit {
+        revision,
+        operation,
+      } if self.is_suggesting(id) => {
+        let suggestion = self
+          .suggest(id, uname, revision, operation)
+          .context("invalid suggested operation")?;
+        if let Some(suggestion) = suggestion {
+          self.update.send(ServerMsg::Suggestion(suggestion)).ok();
+        }
+      }
+      ClientMsg::Edit { .. }
+      | ClientMsg::SetLanguage(_)
+      | ClientMsg::Undo
+      | ClientMsg::Redo
+        if !access.can_edit() =>
+      {
+        warn!("rejected change from read-only connection, id = {}", id);
+      }
       ClientMsg::Edit {
         revision,
         operation,
@@ -328,18 +1011,291 @@ impl Pad {
         let msg = ServerMsg::UserCursor { id, data };
         self.update.send(msg).ok();
       }
+      ClientMsg::Chat(text) => {
+        if let Some(chat) = self.add_chat(id, uname, text) {
+          self.update.send(ServerMsg::Chat(chat)).ok();
+        }
+      }
+      ClientMsg::Comment { start, end, text } => {
+        let comment = self.add_comment(id, uname, None, (start, end), text);
+        if let Some(comment) = comment {
+          self.update.send(ServerMsg::Comment(comment)).ok();
+        }
+      }
+      ClientMsg::Reply { parent, text } => {
+        let comment = self.add_comment(id, uname, Some(parent), (0, 0), text);
+        if let Some(comment) = comment {
+          self.update.send(ServerMsg::Comment(comment)).ok();
+        }
+      }
+      ClientMsg::Resolve { key, resolved } => {
+        if let Some(comment) = self.resolve(uname, access, &key, resolved) {
+          self.update.send(ServerMsg::Comment(comment)).ok();
+        }
+      }
+      ClientMsg::Suggesting(on) => {
+        let mut state = self.state.write();
+        if on {
+          state.suggesting.insert(id);
+        } else {
+          state.suggesting.remove(&id);
+        }
+      }
+      ClientMsg::Accept(key) => {
+        if self.accept(access, &key)? {
+          self.notify.notify_waiters();
+          let done = ServerMsg::SuggestionDone { key, accepted: true };
+          self.update.send(done).ok();
+        }
+      }
+      ClientMsg::Reject(key) => {
+        if self.reject(id, uname, access, &key) {
+          let done = ServerMsg::SuggestionDone { key, accepted: false };
+          self.update.send(done).ok();
+        }
+      }
+      ClientMsg::Undo => {
+        if self.undo(id, EditKind::Undo)? {
+          self.notify.notify_waiters();
+        }
+      }
+      ClientMsg::Redo => {
+        if self.undo(id, EditKind::Redo)? {
+          self.notify.notify_waiters();
+        }
+      }
     }
     Ok(())
   }
 
+  /// Append a chat message to the backlog, named after the signed-in user
+  /// if any, or else the name the client set. Empty messages are dropped.
+  fn add_chat(&self, id: u64, uname: &str, text: String) -> Option<ChatMessage> {
+    let text = text.trim();
+    if text.is_empty() {
+      return None;
+    }
+    let text: String = text.chars().take(CHAT_MAX_LEN).collect();
+    let mut state = self.state.write();
+    let name = state.name_of(id, uname);
+    let chat = ChatMessage {
+      id,
+      uname: uname.to_owned(),
+      name,
+      text,
+      time: Utc::now().timestamp(),
+    };
+    if state.chats.len() >= CHAT_BACKLOG {
+      state.chats.pop_front();
+    }
+    state.chats.push_back(chat.clone());
+    state.num_chats += 1;
+    Some(chat)
+  }
+
+  /// Add a comment, starting a thread on the `range` of the text, or else
+  /// replying in the thread of `parent`. Named as chat messages are.
+  ///
+  /// Empty comments, replies to unknown threads and comments beyond the
+  /// limit are dropped.
+  fn add_comment(
+    &self,
+    id: u64,
+    uname: &str,
+    parent: Option<String>,
+    range: (u32, u32),
+    text: String,
+  ) -> Option<Comment> {
+    let text = text.trim();
+    if text.is_empty() {
+      return None;
+    }
+    let text: String = text.chars().take(CHAT_MAX_LEN).collect();
+    let mut state = self.state.write();
+    if state.comments.len() >= COMMENT_MAX {
+      warn!("dropped comment beyond the limit, id = {}", id);
+      return None;
+    }
+    let (start, end) = match &parent {
+      Some(key) => {
+        let thread = state
+          .comments
+          .iter()
+          .any(|c| c.parent.is_none() && &c.key == key);
+        if !thread {
+          return None;
+        }
+        (0, 0)
+      }
+      None => {
+        let len = state.text.chars().count() as u32;
+        let (start, end) = (range.0.min(range.1), range.0.max(range.1));
+        (start.min(len), end.min(len))
+      }
+    };
+    let name = state.name_of(id, uname);
+    let comment = Comment {
+      key: nanoid::nanoid!(12),
+      parent,
+      start,
+      end,
+      uname: uname.to_owned(),
+      name,
+      text,
+      time: Utc::now().timestamp(),
+      resolved: false,
+    };
+    state.comments.push(comment.clone());
+    state.comments_version += 1;
+    Some(comment)
+  }
+
+  /// Resolve, or reopen, a comment thread, by an editor or its author.
+  fn resolve(
+    &self,
+    uname: &str,
+    access: Access,
+    key: &str,
+    resolved: bool,
+  ) -> Option<Comment> {
+    let mut state = self.state.write();
+    let comment = state
+      .comments
+      .iter_mut()
+      .find(|c| c.parent.is_none() && c.key == key)?;
+    let is_author = !uname.is_empty() && comment.uname == uname;
+    if !access.can_edit() && !is_author {
+      return None;
+    }
+    comment.resolved = resolved;
+    let comment = comment.clone();
+    state.comments_version += 1;
+    Some(comment)
+  }
+
+  fn is_suggesting(&self, id: u64) -> bool {
+    self.state.read().suggesting.contains(&id)
+  }
+
+  /// Store an edit of user `id` as a suggestion, rebased on the current
+  /// revision. No-op edits and suggestions beyond the limit are dropped.
+  fn suggest(
+    &self,
+    id: u64,
+    uname: &str,
+    revision: usize,
+    mut operation: OperationSeq,
+  ) -> Result<Option<Suggestion>> {
+    let mut state = self.state.write();
+    let len = state.revision();
+    if revision > len {
+      bail!("got revision {}, but current is {}", revision, len);
+    }
+    if revision < state.base {
+      bail!("got revision {}, but history starts at {}", revision, state.base);
+    }
+    for history_op in &state.operations[revision - state.base..] {
+      operation = operation.transform(&history_op.operation)?.0;
+    }
+    // checks the operation applies to the text
+    operation.apply(&state.text)?;
+    if operation.target_len() > 100000 {
+      bail!(
+        "target length {} is greater than 100 KB maximum",
+        operation.target_len()
+      );
+    }
+    if operation.is_noop() {
+      return Ok(None);
+    }
+    if state.suggestions.len() >= SUGGESTION_MAX {
+      warn!("dropped suggestion beyond the limit, id = {}", id);
+      return Ok(None);
+    }
+    let suggestion = Suggestion {
+      key: nanoid::nanoid!(12),
+      id,
+      uname: uname.to_owned(),
+      name: state.name_of(id, uname),
+      revision: len,
+      operation,
+      time: Utc::now().timestamp(),
+    };
+    state.suggestions.push(suggestion.clone());
+    Ok(Some(suggestion))
+  }
+
+  /// Accept a suggestion, applying it as an edit of the server, so that it
+  /// is not taken for an edit of whoever accepted it. Owner only.
+  fn accept(&self, access: Access, key: &str) -> Result<bool> {
+    if access < Access::Own {
+      return Ok(false);
+    }
+    let suggestion = {
+      let mut state = self.state.write();
+      match state.suggestions.iter().position(|s| s.key == key) {
+        Some(index) => state.suggestions.remove(index),
+        None => return Ok(false),
+      }
+    };
+    self
+      .apply_edit(u64::MAX, suggestion.revision, suggestion.operation)
+      .context("invalid suggestion")?;
+    Ok(true)
+  }
+
+  /// Reject a suggestion, by the owner or its author.
+  fn reject(&self, id: u64, uname: &str, access: Access, key: &str) -> bool {
+    let mut state = self.state.write();
+    let index = state.suggestions.iter().position(|s| {
+      let is_author = s.id == id || (!uname.is_empty() && s.uname == uname);
+      s.key == key && (access >= Access::Own || is_author)
+    });
+    match index {
+      Some(index) => {
+        state.suggestions.remove(index);
+        true
+      }
+      None => false,
+    }
+  }
+
+  /// Undo, or redo, the last edit of user `id`: apply the inverse of their
+  /// edit, transformed through the edits made after it by everyone.
+  ///
+  /// Returns `false` if there is nothing to undo.
+  fn undo(&self, id: u64, kind: EditKind) -> Result<bool> {
+    let inverse = {
+      let mut state = self.state.write();
+      match state.undo.get_mut(&id).and_then(|stacks| stacks.pop(kind)) {
+        Some(inverse) => inverse,
+        None => return Ok(false),
+      }
+    };
+    self
+      .apply(id, inverse.revision, inverse.operation, kind)
+      .context("invalid undo operation")?;
+    Ok(true)
+  }
+
   fn apply_edit(
+    &self,
+    id: u64,
+    revision: usize,
+    operation: OperationSeq,
+  ) -> Result<()> {
+    self.apply(id, revision, operation, EditKind::Edit)
+  }
+
+  fn apply(
     &self,
     id: u64,
     revision: usize,
     mut operation: OperationSeq,
+    kind: EditKind,
   ) -> Result<()> {
     let state = self.state.upgradable_read();
-    let len = state.operations.len();
+    let len = state.revision();
     info!(
       "edit: id = {}, revision = {}, op_len = {}, base_len = {}, target_len = {}",
       id,
@@ -351,7 +1307,14 @@ impl Pad {
     if revision > len {
       bail!("got revision {}, but current is {}", revision, len);
     }
-    for history_op in &state.operations[revision..] {
+    if revision < state.base {
+      bail!("got revision {}, but history starts at {}", revision, state.base);
+    }
+    // edits made by the server, e.g. saving, are kept anyway
+    if id != u64::MAX && len >= self.limits.max_doc_ops {
+      return Err(LimitExceeded("document reached its operation limit").into());
+    }
+    for history_op in &state.operations[revision - state.base..] {
       operation = operation.transform(&history_op.operation)?.0;
     }
     if operation.target_len() > 100000 {
@@ -362,6 +1325,39 @@ impl Pad {
     }
     let new_text = operation.apply(&state.text)?;
     let mut state = RwLockUpgradableReadGuard::upgrade(state);
+    // edits made by the server are not undone by anyone
+    if id != u64::MAX {
+      let inverse = Inverse {
+        revision: state.revision() + 1,
+        operation: operation.invert(&state.text),
+      };
+      state.undo.entry(id).or_default().push(kind, inverse);
+    }
+    // pending suggestions follow the edits, at the revision after this one
+    let revision = state.revision() + 1;
+    state.suggestions.retain_mut(|suggestion| {
+      match suggestion.operation.transform(&operation) {
+        Ok((rebased, _)) => {
+          suggestion.operation = rebased;
+          suggestion.revision = revision;
+          true
+        }
+        Err(_) => false,
+      }
+    });
+    let mut moved = false;
+    for comment in state.comments.iter_mut().filter(|c| c.parent.is_none()) {
+      let (start, end) = (
+        transform_index(&operation, comment.start),
+        transform_index(&operation, comment.end),
+      );
+      moved |= (start, end) != (comment.start, comment.end);
+      (comment.start, comment.end) = (start, end);
+    }
+    // persisted again only if an anchor moved
+    if moved {
+      state.comments_version += 1;
+    }
     for (_, data) in state.cursors.iter_mut() {
       for cursor in data.cursors.iter_mut() {
         *cursor = transform_index(&operation, *cursor);
@@ -371,8 +1367,301 @@ impl Pad {
         *end = transform_index(&operation, *end);
       }
     }
-    state.operations.push(UserOperation { id, operation });
+    // undoing is made by the server, the client of the user would take
+    // an operation of its own as the acknowledgement of its pending edit
+    let author = if kind == EditKind::Edit { id } else { u64::MAX };
+    state.operations.push(UserOperation {
+      id: author,
+      operation,
+      created_at: Utc::now().timestamp(),
+    });
     state.text = new_text;
+    state.compact()?;
     Ok(())
   }
 }
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn document(text: &str) -> PersistedDocument {
+    PersistedDocument {
+      id: None,
+      text: text.into(),
+      language: None,
+      updated_at: None,
+      article_id: None,
+    }
+  }
+
+  #[test]
+  fn test_restore_history() {
+    let pad = Pad::from(document("abc"));
+    let mut op = OperationSeq::default();
+    op.retain(3);
+    op.insert("def");
+    pad.apply_edit(7, 1, op).unwrap();
+
+    let history = pad.snapshot_since(0);
+    assert_eq!(history.operations.len(), 2);
+    assert!(history.checkpoint.is_none());
+    let restored = Pad::restore(history.document, None, history.operations).unwrap();
+    assert_eq!(restored.text(), "abcdef");
+    assert_eq!(restored.revision(), 2);
+    assert_eq!(restored.count.load(Ordering::Relaxed), 8);
+  }
+
+  #[test]
+  fn test_restore_mismatch() {
+    let pad = Pad::from(document("abc"));
+    let history = pad.snapshot_since(0).operations;
+    assert!(Pad::restore(document("xyz"), None, history).is_err());
+    assert!(Pad::restore(document(""), None, Vec::new()).is_err());
+  }
+
+  #[test]
+  fn test_compact_history() {
+    let pad = Pad::from(document(""));
+    for revision in 1..=COMPACT_THRESHOLD {
+      let mut op = OperationSeq::default();
+      op.retain(revision as u64 - 1);
+      op.insert("a");
+      pad.apply_edit(0, revision, op).unwrap();
+    }
+    let revision = COMPACT_THRESHOLD + 1;
+    assert_eq!(pad.revision(), revision);
+    let history = pad.snapshot_since(0);
+    let checkpoint = history.checkpoint.unwrap();
+    assert_eq!(checkpoint.revision as usize, revision - HISTORY_WINDOW);
+    assert_eq!(checkpoint.text.len(), revision - HISTORY_WINDOW - 1);
+
+    // edits older than the checkpoint are refused
+    let mut op = OperationSeq::default();
+    op.insert("b");
+    assert!(pad.apply_edit(0, 1, op).is_err());
+
+    assert_eq!(history.operations.len(), HISTORY_WINDOW);
+    let restored =
+      Pad::restore(history.document, Some(checkpoint), history.operations).unwrap();
+    assert_eq!(restored.text(), pad.text());
+    assert_eq!(restored.revision(), revision);
+  }
+
+  #[test]
+  fn test_replace_text() {
+    let pad = Pad::from(document("hello wörld"));
+    assert_eq!(pad.replace_text("hello wörld").unwrap(), 1);
+    assert_eq!(pad.replace_text("hello, my wörld!").unwrap(), 2);
+    assert_eq!(pad.text(), "hello, my wörld!");
+    assert_eq!(pad.replace_text("").unwrap(), 3);
+    assert_eq!(pad.text(), "");
+
+    // diffs against the latest text
+    let mut op = OperationSeq::default();
+    op.insert("abc");
+    pad.apply_edit(0, 3, op).unwrap();
+    pad.replace_text("abd").unwrap();
+    assert_eq!(pad.text(), "abd");
+
+    // keeps the edits made since the revision of the old text
+    let (revision, old) = pad.revision_text();
+    let mut op = OperationSeq::default();
+    op.insert("x");
+    op.retain(3);
+    pad.apply_edit(0, revision, op).unwrap();
+    pad.edit_text(revision, &old, "abc").unwrap();
+    assert_eq!(pad.text(), "xabc");
+  }
+
+  #[test]
+  fn test_undo_redo() {
+    let pad = Pad::from(document(""));
+    let mut op = OperationSeq::default();
+    op.insert("hello");
+    pad.apply_edit(0, 1, op).unwrap();
+    // another user edits around it, concurrently
+    let mut op = OperationSeq::default();
+    op.insert("> ");
+    op.insert("world");
+    pad.apply_edit(1, 1, op).unwrap();
+    assert_eq!(pad.text(), "> worldhello");
+
+    // only the edit of user 0 is undone
+    assert!(pad.undo(0, EditKind::Undo).unwrap());
+    assert_eq!(pad.text(), "> world");
+    assert_eq!(pad.snapshot_since(3).operations[0].user_id as u64, u64::MAX);
+    assert!(!pad.undo(0, EditKind::Undo).unwrap());
+    assert!(pad.undo(0, EditKind::Redo).unwrap());
+    assert_eq!(pad.text(), "> worldhello");
+    assert!(!pad.undo(0, EditKind::Redo).unwrap());
+    assert!(pad.undo(1, EditKind::Undo).unwrap());
+    assert_eq!(pad.text(), "hello");
+
+    // a new edit clears the redo stack
+    assert!(pad.undo(0, EditKind::Undo).unwrap());
+    let mut op = OperationSeq::default();
+    op.insert("!");
+    pad.apply_edit(0, pad.revision(), op).unwrap();
+    assert!(!pad.undo(0, EditKind::Redo).unwrap());
+    assert!(pad.undo(0, EditKind::Undo).unwrap());
+    assert_eq!(pad.text(), "");
+
+    // server edits are not undoable
+    pad.replace_text("abc").unwrap();
+    assert!(!pad.undo(u64::MAX, EditKind::Undo).unwrap());
+  }
+
+  #[test]
+  fn test_limits() {
+    use super::super::limit::Rate;
+
+    let limits = Limits {
+      edits: Rate { per_sec: 0.0, burst: 2.0 },
+      doc_edits: Rate { per_sec: 0.0, burst: 3.0 },
+      max_doc_ops: 3,
+      ..Limits::default()
+    };
+    let pad = Pad::from(document("")).with_limits(limits.clone());
+    let mut first = ConnBuckets::new(&limits);
+    let mut second = ConnBuckets::new(&limits);
+    let edit = ClientMsg::Edit {
+      revision: 0,
+      operation: OperationSeq::default(),
+    };
+    assert!(pad.check_rate(&mut first, &edit).is_ok());
+    assert!(pad.check_rate(&mut first, &ClientMsg::Undo).is_ok());
+    assert_eq!(
+      pad.check_rate(&mut first, &edit),
+      Err(LimitExceeded("too many edits"))
+    );
+    // shared by all connections on the document
+    assert!(pad.check_rate(&mut second, &edit).is_ok());
+    assert_eq!(
+      pad.check_rate(&mut second, &edit),
+      Err(LimitExceeded("too many edits on the document"))
+    );
+
+    for revision in 1..3 {
+      let mut op = OperationSeq::default();
+      op.retain(revision as u64 - 1);
+      op.insert("a");
+      pad.apply_edit(0, revision, op).unwrap();
+    }
+    let mut op = OperationSeq::default();
+    op.retain(2);
+    op.insert("a");
+    let e = pad.apply_edit(0, 3, op).unwrap_err();
+    assert!(e.downcast_ref::<LimitExceeded>().is_some());
+    assert_eq!(pad.replace_text("b").unwrap(), 4);
+  }
+
+  #[test]
+  fn test_activity() {
+    let pad = Pad::from(document("abc"));
+    pad.state.write().users.insert(0, UserInfo { name: "bob".into(), hue: 0 });
+    pad.state.write().users.insert(1, UserInfo { name: "ann".into(), hue: 0 });
+    let activity = pad.activity();
+    assert_eq!(activity.users, vec!["ann", "bob"]);
+    assert_eq!(activity.revision, 1);
+    assert!(activity.last_edit > 0);
+    assert!(activity.memory >= 6);
+  }
+
+  #[test]
+  fn test_comments() {
+    let pad = Pad::from(document("hello world"));
+    let thread = pad
+      .add_comment(0, "alice", None, (11, 6), String::from("which world?"))
+      .unwrap();
+    assert_eq!((thread.start, thread.end), (6, 11));
+    let reply = pad.add_comment(1, "", Some(thread.key.clone()), (0, 0), "ours".into());
+    assert_eq!(reply.unwrap().name, "Anonymous");
+    assert!(pad.add_comment(1, "", Some("nope".into()), (0, 0), "hi".into()).is_none());
+
+    // anchors move along the edits
+    let mut op = OperationSeq::default();
+    op.insert(">> ");
+    op.retain(11);
+    pad.apply_edit(1, 1, op).unwrap();
+    let (comments, version) = pad.comments();
+    assert_eq!(comments[0].anchor_start, Some(9));
+    assert_eq!(comments[0].anchor_end, Some(14));
+    assert_eq!(comments[1].anchor_start, None);
+    assert_eq!(version, 3);
+
+    // only editors and the author resolve
+    assert!(pad.resolve("bob", Access::View, &thread.key, true).is_none());
+    assert!(pad.resolve("alice", Access::View, &thread.key, true).unwrap().resolved);
+
+    let restored = Pad::from(document(">> hello world"));
+    restored.load_comments(comments);
+    let (comments, _) = restored.comments();
+    assert_eq!(comments.len(), 2);
+    assert_eq!(comments[0].anchor_end, Some(14));
+
+    // edits after the anchors leave them, and the version, as is
+    let pad = Pad::from(document("hello world"));
+    pad.add_comment(0, "alice", None, (0, 5), String::from("hi")).unwrap();
+    let version = pad.comments_version();
+    let mut op = OperationSeq::default();
+    op.retain(11);
+    op.insert("!");
+    pad.apply_edit(1, 1, op).unwrap();
+    assert_eq!(pad.comments_version(), version);
+  }
+
+  #[test]
+  fn test_suggestions() {
+    let pad = Pad::from(document("hello"));
+    let mut op = OperationSeq::default();
+    op.retain(5);
+    op.insert(" world");
+    let suggestion = pad.suggest(1, "bob", 1, op).unwrap().unwrap();
+    assert_eq!(pad.text(), "hello");
+
+    // rebased against newer edits
+    let mut op = OperationSeq::default();
+    op.insert("> ");
+    op.retain(5);
+    pad.apply_edit(0, 1, op).unwrap();
+    let mut op = OperationSeq::default();
+    op.retain(7);
+    op.insert("!");
+    let other = pad.suggest(2, "", 2, op).unwrap().unwrap();
+    assert_eq!(other.revision, 2);
+
+    // only the owner accepts, and the author or the owner rejects
+    assert!(!pad.accept(Access::Edit, &suggestion.key).unwrap());
+    assert!(!pad.reject(3, "carol", Access::Edit, &other.key));
+    assert!(pad.accept(Access::Own, &suggestion.key).unwrap());
+    assert_eq!(pad.text(), "> hello world");
+    assert!(pad.reject(2, "", Access::View, &other.key));
+    assert!(!pad.accept(Access::Own, &other.key).unwrap());
+    assert_eq!(pad.text(), "> hello world");
+  }
+
+  #[test]
+  fn test_chat_backlog() {
+    let pad = Pad::default();
+    assert!(pad.add_chat(0, "", String::from("  ")).is_none());
+    let chat = pad.add_chat(0, "", String::from(" hi ")).unwrap();
+    assert_eq!((chat.name.as_str(), chat.text.as_str()), ("Anonymous", "hi"));
+    let chat = pad.add_chat(1, "alice", String::from("hey")).unwrap();
+    assert_eq!(chat.name, "alice");
+
+    for _ in 0..CHAT_BACKLOG {
+      pad.add_chat(1, "alice", String::from("spam"));
+    }
+    assert_eq!(pad.num_chats(), CHAT_BACKLOG + 2);
+    let (chats, num_chats) = pad.chats_since(1);
+    assert_eq!(chats.len(), CHAT_BACKLOG);
+    assert_eq!(num_chats, CHAT_BACKLOG + 2);
+    let (chats, _) = pad.chats_since(CHAT_BACKLOG + 1);
+    assert_eq!(chats.len(), 1);
+
+    let restored = Pad::default();
+    restored.load_chats(chats);
+    assert_eq!(restored.num_chats(), 1);
+  }
+}
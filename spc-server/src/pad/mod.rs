@@ -3,35 +3,60 @@
 #![forbid(unsafe_code)]
 #![warn(missing_docs)]
 
+use std::convert::Infallible;
 use std::sync::Arc;
 use std::time::{Duration, SystemTime};
 
-use axum::http::StatusCode;
-use axum::routing::get;
+use std::collections::HashMap;
+
+use axum::body::Body;
+use axum::http::{Request, StatusCode};
+use axum::middleware::{self, Next};
+use axum::routing::{get, post};
 use axum::Router;
 use axum::{
-  extract::{ws::WebSocketUpgrade, Path, State},
-  response::IntoResponse,
+  extract::{ws::WebSocketUpgrade, Path, Query, State},
+  response::sse::{Event, KeepAlive, Sse},
+  response::{IntoResponse, Response},
   Json,
 };
 use sqlx::SqlitePool;
 
 use dashmap::DashMap;
-use log::{error, info};
+use futures::channel::mpsc;
+use futures::prelude::*;
+use log::{error, info, warn};
+use nanoid::nanoid;
 use rand::Rng;
-use serde::Serialize;
+use serde::{Deserialize, Serialize};
+use tokio::sync::OwnedMutexGuard;
 use tokio::time::{self, Instant};
 
-use document::PersistedDocument as StoreDoc;
-use mdpad::Pad;
+use document::{
+  PersistedChat as StoreChat, PersistedCheckpoint as StoreCheckpoint,
+  PersistedComment as StoreComment, PersistedDocument as StoreDoc, PersistedOperation as StoreOp,
+  PersistedSnapshot as StoreSnapshot,
+};
+use acl::{Access, AclEntry, ShareToken};
+use limit::Limits;
+use cluster::{Coordinator, Owner, RelayQuery, FORWARDED_HEADER, LEASE_RENEW};
+use mdpad::{Activity, ClientMsg, Pad, CHAT_BACKLOG};
+use transport::{Connection, Encoding, Outgoing};
 
-use crate::db::article::Article;
+use crate::db::article::{Article, PadSave};
 use crate::db::note::Note;
-use crate::db::user::{ClaimCan, CREATE_PERMIT, BASIC_PERMIT};
+use crate::db::user::{ClaimCan, ADMIN_PERMIT, CREATE_PERMIT, BASIC_PERMIT};
+use crate::error::AppError;
 
+pub mod acl;
+pub mod cluster;
+#[cfg(feature = "crdt")]
+pub mod crdt;
 pub mod document;
+pub mod limit;
 pub mod mdpad;
 pub mod ot;
+pub mod transport;
 
 /// An entry stored in the global server map.
 ///
@@ -41,17 +66,70 @@ pub mod ot;
 struct Document {
   last_accessed: Instant,
   pad: Arc<Pad>,
+  /// id of the linked article, if any
+  article_id: Option<u32>,
 }
 
 impl Document {
-  fn new(pad: Arc<Pad>) -> Self {
+  fn new(pad: Arc<Pad>, article_id: Option<u32>) -> Self {
     Self {
       last_accessed: Instant::now(),
       pad,
+      article_id,
     }
   }
 }
 
+/// The in-memory pads of the server, shared with the admin pages.
+#[derive(Clone, Default)]
+pub struct Pads(Arc<DashMap<String, Document>>);
+
+/// Activity on a live pad, listed for the admin.
+#[derive(Clone, Debug, Serialize)]
+pub struct PadActivity {
+  /// id of the document
+  pub id: String,
+  /// id of the linked article, if any
+  pub article_id: Option<u32>,
+  /// seconds since a client last connected or a request used the pad
+  pub idle_secs: u64,
+  /// activity on the pad
+  #[serde(flatten)]
+  pub activity: Activity,
+}
+
+impl Pads {
+  /// Returns the activity on the live pads, most recently edited first.
+  pub fn activity(&self) -> Vec<PadActivity> {
+    let mut pads: Vec<PadActivity> = self
+      .0
+      .iter()
+      .map(|entry| PadActivity {
+        id: entry.key().clone(),
+        article_id: entry.article_id,
+        idle_secs: entry.last_accessed.elapsed().as_secs(),
+        activity: entry.pad.activity(),
+      })
+      .collect();
+    pads.sort_by_key(|pad| std::cmp::Reverse(pad.activity.last_edit));
+    pads
+  }
+
+  /// Force-close a pad, dropping all its connections. The edits are
+  /// persisted, and the pad is loaded again on the next connection.
+  ///
+  /// Returns `false` if the pad is not live.
+  pub fn kill(&self, id: &str) -> bool {
+    self.0.remove(id).is_some()
+  }
+}
+
+impl std::fmt::Debug for Pads {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    f.debug_tuple("Pads").field(&self.0.len()).finish()
+  }
+}
+
 impl Drop for Document {
   fn drop(&mut self) {
     self.pad.kill();
@@ -65,10 +143,28 @@ struct ServerState {
   documents: Arc<DashMap<String, Document>>,
   /// Connection to the database pool, if persistence is enabled.
   pool: SqlitePool,
+  /// Senders of the client messages posted to SSE connections, by session.
+  sessions: Arc<DashMap<String, EventSession>>,
+  /// Held by the persister of each pad until it is flushed and released.
+  persisting: Arc<DashMap<String, Arc<tokio::sync::Mutex<()>>>>,
+  /// Decides which process owns each document.
+  coordinator: Arc<dyn Coordinator>,
+  /// Key to sign the connections relayed between processes.
+  secret: Arc<str>,
+  /// Rate limits of the pads.
+  limits: Limits,
   /// System time when the server started, in seconds since Unix epoch.
   start_time: u64,
 }
 
+/// A connection over Server-Sent Events, the client posts its messages to.
+struct EventSession {
+  /// id of the document connected to.
+  doc_id: String,
+  /// Sender of the messages from the client.
+  sender: mpsc::Sender<ClientMsg>,
+}
+
 /// Statistics about the server, returned from an API endpoint.
 #[derive(Serialize)]
 struct Stats {
@@ -81,12 +177,20 @@ struct Stats {
 }
 
 /// Server configuration.
-#[derive(Clone, Debug)]
+#[derive(Clone)]
 pub struct WsConfig {
   /// Number of hours to clean up documents after inactivity.
   pub expiry_hours: u32,
   /// Database object, for persistence if desired.
   pub pool: SqlitePool,
+  /// Decides which process owns each document, see `cluster`.
+  pub coordinator: Arc<dyn Coordinator>,
+  /// Key shared by the processes to sign relayed connections.
+  pub secret: String,
+  /// Rate limits of the pads.
+  pub limits: Limits,
+  /// The in-memory pads, shared with the admin pages.
+  pub pads: Pads,
 }
 
 /// router
@@ -96,73 +200,404 @@ pub async fn ws_server(config: WsConfig) -> Router {
     .expect("SystemTime returned before UNIX_EPOCH")
     .as_secs();
   let state = ServerState {
-    documents: Default::default(),
+    documents: Arc::clone(&config.pads.0),
     pool: config.pool,
+    sessions: Default::default(),
+    persisting: Default::default(),
+    coordinator: config.coordinator,
+    secret: config.secret.into(),
+    limits: config.limits,
     start_time,
   };
   tokio::spawn(cleaner(state.clone(), config.expiry_hours));
+  tokio::spawn(lease_keeper(state.clone()));
+
+  // endpoints on the live pad, served by the process owning the document
+  let router_owned = Router::new()
+    .route("/api/text/:id", get(text_handler))
+    .route(
+      "/api/snapshot/:id",
+      get(snapshot_list_handler).post(snapshot_handler),
+    )
+    .route("/api/snapshot/:id/:sid/restore", post(restore_handler))
+    .route("/api/savetoarticle/:id", get(save_handler))
+    .route("/api/events/:id/:session", post(post_handler));
+  // documents edited offline, merged into their live pad
+  #[cfg(feature = "crdt")]
+  let router_owned = router_owned.route(
+    "/api/crdt/:id",
+    get(crdt::crdt_handler)
+      .post(crdt::merge_handler)
+      .put(crdt::backend_handler),
+  );
+  let router_owned = router_owned
+    .route_layer(middleware::from_fn_with_state(
+      state.clone(),
+      forward_to_owner,
+    ));
 
   let router_ws = Router::new()
     .route("/api/socket/:id", get(socket_handler)) // WEBSOCKET
-    .route("/api/text/:id", get(text_handler))
+    .route("/api/events/:id", get(events_handler)) // SSE, fallback
+    .route("/api/relay/:id", get(relay_handler)) // between processes
+    .route("/api/snapshot/:id/:sid", get(snapshot_text_handler))
     .route("/api/stats", get(stats_handler))
-    .route("/api/savetoarticle/:id", get(save_handler))
+    .route("/api/pads", get(pads_handler))
+    .merge(router_owned)
+    .route("/api/acl/:id", get(acl_list_handler).post(acl_grant_handler))
+    .route("/api/share/:id", post(share_handler))
     .with_state(state);
 
   router_ws
 }
 
+/// Query params of the pad endpoints.
+#[derive(Deserialize)]
+struct PadQuery {
+  /// share link token
+  token: Option<String>,
+}
+
+/// Resolve the access of a visitor on a document.
+///
+/// Notes are private to their author; other documents follow their ACL.
+async fn resolve_access(
+  pool: &SqlitePool,
+  id: &str,
+  uname: &str,
+  token: Option<&str>,
+) -> Access {
+  resolve_access_until(pool, id, uname, token).await.0
+}
+
+/// Resolve the access of a visitor on a document, along with the instant
+/// it ends if granted by a share token.
+async fn resolve_access_until(
+  pool: &SqlitePool,
+  id: &str,
+  uname: &str,
+  token: Option<&str>,
+) -> (Access, Option<Instant>) {
+  if id.starts_with("note_") {
+    if uname.is_empty() {
+      return (Access::None, None);
+    }
+    return match Note::load(pool, uname, id).await {
+      Ok(_) => (Access::Own, None),
+      Err(_) => (Access::None, None),
+    };
+  }
+  match AclEntry::access(pool, id, uname, token).await {
+    Ok((access, expire_at)) => {
+      let until = expire_at.map(|at| {
+        let left = at - chrono::Utc::now().timestamp();
+        Instant::now() + Duration::from_secs(left.max(0) as u64)
+      });
+      (access, until)
+    }
+    Err(_) => (Access::None, None),
+  }
+}
+
 /// Handler for the `/api/socket/:id` endpoint.
 async fn socket_handler(
   State(state): State<ServerState>,
   Path(id): Path<String>,
+  Query(query): Query<PadQuery>,
   ws: WebSocketUpgrade,
   check: ClaimCan<BASIC_PERMIT>,
 ) -> Result<impl IntoResponse, StatusCode> {
   if id.starts_with("note_") && !check.can() {
     return Err(StatusCode::UNAUTHORIZED);
   }
-  
+  let uname = check.claim.unwrap_or_default().uname;
+  let (access, until) =
+    resolve_access_until(&state.pool, &id, &uname, query.token.as_deref()).await;
+  if access == Access::None {
+    return Err(StatusCode::FORBIDDEN);
+  }
+
+  Ok(ws.protocols(Encoding::PROTOCOLS).on_upgrade(move |socket| async move {
+    let conn = Connection::websocket(socket).until(until);
+    serve_connection(state, id, conn, access, uname, String::new()).await
+  }))
+}
+
+/// Handler for the `/api/relay/:id` endpoint, a WebSocket relayed from
+/// another process, with the access it signed.
+///
+/// The messages of a relayed SSE client are posted to this process, the
+/// owner, under the session it got from the relaying one.
+async fn relay_handler(
+  State(state): State<ServerState>,
+  Path(id): Path<String>,
+  Query(query): Query<RelayQuery>,
+  ws: WebSocketUpgrade,
+) -> Result<impl IntoResponse, StatusCode> {
+  let access = match query.verify(&state.secret, &id) {
+    Some(access) if access != Access::None => access,
+    _ => return Err(StatusCode::FORBIDDEN),
+  };
+  // never relay further, the relaying process retries once leases settle
+  if owner(&state, &id).await? != Owner::Local {
+    return Err(StatusCode::CONFLICT);
+  }
+  let pad = get_pad(&state, &id, &query.uname).await?;
+  Ok(ws.on_upgrade(move |socket| async move {
+    let conn = Connection::websocket(socket);
+    if query.session.is_empty() {
+      return pad.on_connection(conn, access, query.uname).await;
+    }
+    let (sender, posted) = mpsc::channel(64);
+    let session = query.session;
+    state.sessions.insert(session.clone(), EventSession { doc_id: id, sender });
+    pad.on_connection(conn.with_incoming(posted), access, query.uname).await;
+    state.sessions.remove(&session);
+  }))
+}
+
+/// Serve a connection on the pad of a document, in this process if it owns
+/// the document, or else relayed to the owner, along with the SSE `session`
+/// if any.
+async fn serve_connection(
+  state: ServerState,
+  id: String,
+  conn: Connection,
+  access: Access,
+  uname: String,
+  session: String,
+) {
+  match state.coordinator.acquire(&id).await {
+    Ok(Owner::Local) => match get_pad(&state, &id, &uname).await {
+      Ok(pad) => pad.on_connection(conn, access, uname).await,
+      Err(_) => {
+        let mut conn = conn;
+        conn.close(String::from("failed to load the document")).await.ok();
+      }
+    },
+    Ok(Owner::Remote(node)) => {
+      let query = RelayQuery::new(&state.secret, &id, access, &uname, &session);
+      let url = cluster::relay_url(&node, &id, &query);
+      info!("relaying connection on {} to {}", id, node);
+      if let Err(e) = cluster::relay(conn, &url).await {
+        warn!("relay of {} to {} terminated: {}", id, node, e);
+      }
+    }
+    Err(e) => error!("when acquiring {}: {}", id, e),
+  }
+}
+
+/// Returns the process owning a document.
+async fn owner(state: &ServerState, id: &str) -> Result<Owner, StatusCode> {
+  state.coordinator.acquire(id).await.map_err(|e| {
+    error!("when acquiring {}: {}", id, e);
+    StatusCode::SERVICE_UNAVAILABLE
+  })
+}
+
+/// Middleware forwarding the requests on a document owned by another
+/// process to it.
+async fn forward_to_owner(
+  State(state): State<ServerState>,
+  Path(params): Path<HashMap<String, String>>,
+  req: Request<Body>,
+  next: Next<Body>,
+) -> Result<Response, StatusCode> {
+  let id = params.get("id").ok_or(StatusCode::NOT_FOUND)?;
+  match owner(&state, id).await? {
+    Owner::Local => Ok(next.run(req).await),
+    // forwarded already, the leases are changing hands
+    Owner::Remote(_) if req.headers().contains_key(FORWARDED_HEADER) => {
+      Err(StatusCode::SERVICE_UNAVAILABLE)
+    }
+    Owner::Remote(node) => cluster::forward(&node, req).await.map_err(|e| {
+      warn!("when forwarding request on {} to {}: {}", id, node, e);
+      StatusCode::BAD_GATEWAY
+    }),
+  }
+}
+
+/// Handler for the `/api/events/:id` endpoint, a Server-Sent Events stream
+/// for clients which cannot use WebSockets.
+///
+/// The first event, named `session`, carries the key to post the client
+/// messages to `/api/events/:id/:session`. Others are the messages as JSON.
+async fn events_handler(
+  State(state): State<ServerState>,
+  Path(id): Path<String>,
+  Query(query): Query<PadQuery>,
+  check: ClaimCan<BASIC_PERMIT>,
+) -> Result<impl IntoResponse, StatusCode> {
+  if id.starts_with("note_") && !check.can() {
+    return Err(StatusCode::UNAUTHORIZED);
+  }
+  let uname = check.claim.unwrap_or_default().uname;
+  let (access, until) =
+    resolve_access_until(&state.pool, &id, &uname, query.token.as_deref()).await;
+  if access == Access::None {
+    return Err(StatusCode::FORBIDDEN);
+  }
+
+  let (conn, sender, messages) = Connection::channel(64);
+  let conn = conn.until(until);
+  let session = nanoid!();
+  let doc_id = id.clone();
+  state.sessions.insert(session.clone(), EventSession { doc_id, sender });
+  let sessions = Arc::clone(&state.sessions);
+  let key = session.clone();
+  tokio::spawn(async move {
+    serve_connection(state, id, conn, access, uname, key.clone()).await;
+    sessions.remove(&key);
+  });
+
+  let first = Event::default().event("session").data(session);
+  let events = stream::once(future::ready(first))
+    .chain(messages.map(|out| match out {
+      Outgoing::Msg(msg) => Event::default()
+        .data(serde_json::to_string(&msg).expect("failed serialize")),
+      Outgoing::Close(reason) => Event::default().event("close").data(reason),
+    }))
+    .map(Ok::<_, Infallible>);
+
+  Ok(Sse::new(events).keep_alive(KeepAlive::default()))
+}
+
+/// Handler for the POST `/api/events/:id/:session` endpoint, a message from
+/// the client of an SSE connection, served by the owner of the document
+/// whichever process streams the events.
+async fn post_handler(
+  State(state): State<ServerState>,
+  Path((id, session)): Path<(String, String)>,
+  Json(msg): Json<ClientMsg>,
+) -> Result<impl IntoResponse, StatusCode> {
+  let mut sender = match state.sessions.get(&session) {
+    Some(value) if value.doc_id == id => value.sender.clone(),
+    _ => return Err(StatusCode::NOT_FOUND),
+  };
+  // the connection was closed, the client should reconnect
+  sender.send(msg).await.map_err(|_e| StatusCode::GONE)?;
+
+  Ok(StatusCode::OK)
+}
+
+/// Get the in-memory pad of a document, loading it if needed.
+async fn get_pad(
+  state: &ServerState,
+  id: &str,
+  uname: &str,
+) -> Result<Arc<Pad>, StatusCode> {
   use dashmap::mapref::entry::Entry;
 
-  let mut entry = match state.documents.entry(id.clone()) {
+  let mut entry = match state.documents.entry(id.to_owned()) {
     Entry::Occupied(e) => e.into_ref(),
     Entry::Vacant(e) => {
-      let pool = state.pool;
-
+      // a pad of the document dropped just before is flushed and released
+      // first, then the lease taken again for this one
+      let lock = Arc::clone(&state.persisting.entry(id.to_owned()).or_default());
+      let flushed = lock.lock_owned().await;
+      if owner(state, id).await? != Owner::Local {
+        return Err(StatusCode::CONFLICT);
+      }
+      let pool = state.pool.clone();
+      let id = id.to_owned();
+      let chats = StoreChat::load_recent(&pool, &id, CHAT_BACKLOG)
+        .await
+        .unwrap_or_default();
+      let comments = StoreComment::load(&pool, &id).await.unwrap_or_default();
       if id.starts_with("note_") {
-        let claim = check.claim.unwrap_or_default();
-        let uname = claim.clone().uname;
-        let pad = Arc::new(Note::load(&pool, &uname, &id)
+        let pad = Arc::new(Note::load(&pool, uname, &id)
           .await
           .map(Pad::from)
           .unwrap_or_default()
+          .with_limits(state.limits.clone())
         );
-        tokio::spawn(persister(id, uname, Arc::clone(&pad), pool.clone()));
-        e.insert(Document::new(pad))
-      } else {
-        let pad = Arc::new(StoreDoc::load(&pool, &id)
-          .await
-          .map(Pad::from)
-          .unwrap_or_default()
+        pad.load_chats(chats);
+        pad.load_comments(comments);
+        let owner = uname.to_owned();
+        let coordinator = Arc::clone(&state.coordinator);
+        tokio::spawn(
+          persister(id, owner, Arc::clone(&pad), pool, coordinator, flushed, 0)
         );
-        tokio::spawn(persister(id, String::new(), Arc::clone(&pad), pool.clone()));
-        e.insert(Document::new(pad))
+        e.insert(Document::new(pad, None))
+      } else {
+        let (pad, persisted, article_id) = load_pad(&pool, &id).await?;
+        let pad = Arc::new(pad.with_limits(state.limits.clone()));
+        pad.load_chats(chats);
+        pad.load_comments(comments);
+        let coordinator = Arc::clone(&state.coordinator);
+        tokio::spawn(persister(
+          id, String::new(), Arc::clone(&pad), pool, coordinator, flushed,
+          persisted,
+        ));
+        e.insert(Document::new(pad, article_id))
       }
     }
   };
 
   let value = entry.value_mut();
   value.last_accessed = Instant::now();
-  let pad = Arc::clone(&value.pad);
-  Ok(ws.on_upgrade(|socket| async move { pad.on_connection(socket).await }))
+  Ok(Arc::clone(&value.pad))
+}
+
+/// Load a pad with its operation history replayed.
+///
+/// Returns the pad, the number of operations already persisted and the id
+/// of the linked article. A history which does not replay is kept as is,
+/// and the pad not loaded, rather than losing it.
+async fn load_pad(
+  pool: &SqlitePool,
+  id: &str,
+) -> Result<(Pad, usize, Option<u32>), StatusCode> {
+  let document = match StoreDoc::load(pool, id).await {
+    Ok(document) => document,
+    Err(AppError::SqlxError(sqlx::Error::RowNotFound)) => {
+      return Ok((Pad::default(), 0, None))
+    }
+    Err(e) => {
+      error!("when loading document {}: {}", id, e);
+      return Err(StatusCode::INTERNAL_SERVER_ERROR);
+    }
+  };
+  let article_id = document.article_id;
+  let loaded = async {
+    let checkpoint = StoreCheckpoint::load(pool, id).await?;
+    let history = StoreOp::load_all(pool, id).await?;
+    Ok::<_, AppError>((checkpoint, history))
+  };
+  let (checkpoint, history) = loaded.await.map_err(|e| {
+    error!("when loading history of {}: {}", id, e);
+    StatusCode::INTERNAL_SERVER_ERROR
+  })?;
+  // a document saved before histories were persisted
+  if checkpoint.is_none() && history.is_empty() {
+    return Ok((Pad::from(document), 0, article_id));
+  }
+  match Pad::restore(document, checkpoint, history) {
+    Ok(pad) => {
+      let revision = pad.revision();
+      Ok((pad, revision, article_id))
+    }
+    Err(e) => {
+      error!("operation history of {} does not replay, kept as is: {}", id, e);
+      Err(StatusCode::INTERNAL_SERVER_ERROR)
+    }
+  }
 }
 
 /// Handler for the `/api/text/:id` endpoint.
 async fn text_handler(
   State(state): State<ServerState>,
   Path(id): Path<String>,
+  Query(query): Query<PadQuery>,
+  check: ClaimCan<BASIC_PERMIT>,
 ) -> Result<impl IntoResponse, StatusCode> {
+  let uname = check.claim.unwrap_or_default().uname;
+  let access =
+    resolve_access(&state.pool, &id, &uname, query.token.as_deref()).await;
+  if access == Access::None {
+    return Err(StatusCode::FORBIDDEN);
+  }
+
   Ok(match state.documents.get(&id) {
     Some(value) => value.pad.text(),
     None => {
@@ -177,6 +612,110 @@ async fn text_handler(
   })
 }
 
+/// Payload of the POST `/api/snapshot/:id` endpoint.
+#[derive(Deserialize)]
+struct SnapshotRequest {
+  name: String,
+}
+
+/// Handler for the GET `/api/snapshot/:id` endpoint, list the snapshots.
+async fn snapshot_list_handler(
+  State(state): State<ServerState>,
+  Path(id): Path<String>,
+  Query(query): Query<PadQuery>,
+  check: ClaimCan<BASIC_PERMIT>,
+) -> Result<impl IntoResponse, StatusCode> {
+  let uname = check.claim.unwrap_or_default().uname;
+  let access =
+    resolve_access(&state.pool, &id, &uname, query.token.as_deref()).await;
+  if access == Access::None {
+    return Err(StatusCode::FORBIDDEN);
+  }
+  let snapshots = StoreSnapshot::list(&state.pool, &id)
+    .await
+    .map_err(|_e| StatusCode::INTERNAL_SERVER_ERROR)?;
+
+  Ok(Json(snapshots))
+}
+
+/// Handler for the POST `/api/snapshot/:id` endpoint, take a named snapshot
+/// of the current text.
+async fn snapshot_handler(
+  State(state): State<ServerState>,
+  Path(id): Path<String>,
+  Query(query): Query<PadQuery>,
+  check: ClaimCan<BASIC_PERMIT>,
+  Json(payload): Json<SnapshotRequest>,
+) -> Result<impl IntoResponse, StatusCode> {
+  let uname = check.claim.unwrap_or_default().uname;
+  let access =
+    resolve_access(&state.pool, &id, &uname, query.token.as_deref()).await;
+  if !access.can_edit() {
+    return Err(StatusCode::FORBIDDEN);
+  }
+  let name = payload.name.trim();
+  if name.is_empty() || name.chars().count() > 64 {
+    return Err(StatusCode::BAD_REQUEST);
+  }
+  let text = match state.documents.get(&id) {
+    Some(value) => value.pad.text(),
+    None => StoreDoc::load(&state.pool, &id)
+      .await
+      .map(|document| document.text)
+      .map_err(|_e| StatusCode::NOT_FOUND)?,
+  };
+  let snapshot = StoreSnapshot::store(&state.pool, &id, name, &text, &uname)
+    .await
+    .map_err(|_e| StatusCode::INTERNAL_SERVER_ERROR)?;
+
+  Ok(Json(snapshot))
+}
+
+/// Handler for the GET `/api/snapshot/:id/:sid` endpoint, text of a snapshot.
+async fn snapshot_text_handler(
+  State(state): State<ServerState>,
+  Path((id, sid)): Path<(String, i64)>,
+  Query(query): Query<PadQuery>,
+  check: ClaimCan<BASIC_PERMIT>,
+) -> Result<impl IntoResponse, StatusCode> {
+  let uname = check.claim.unwrap_or_default().uname;
+  let access =
+    resolve_access(&state.pool, &id, &uname, query.token.as_deref()).await;
+  if access == Access::None {
+    return Err(StatusCode::FORBIDDEN);
+  }
+  StoreSnapshot::load_text(&state.pool, &id, sid)
+    .await
+    .map_err(|_e| StatusCode::NOT_FOUND)
+}
+
+/// Handler for the POST `/api/snapshot/:id/:sid/restore` endpoint.
+///
+/// The text is restored as an edit on the live pad, returns the revision.
+async fn restore_handler(
+  State(state): State<ServerState>,
+  Path((id, sid)): Path<(String, i64)>,
+  Query(query): Query<PadQuery>,
+  check: ClaimCan<BASIC_PERMIT>,
+) -> Result<impl IntoResponse, StatusCode> {
+  let uname = check.claim.unwrap_or_default().uname;
+  let access =
+    resolve_access(&state.pool, &id, &uname, query.token.as_deref()).await;
+  if !access.can_edit() {
+    return Err(StatusCode::FORBIDDEN);
+  }
+  let text = StoreSnapshot::load_text(&state.pool, &id, sid)
+    .await
+    .map_err(|_e| StatusCode::NOT_FOUND)?;
+  let pad = get_pad(&state, &id, &uname).await?;
+  let revision = pad.replace_text(&text).map_err(|e| {
+    warn!("failed to restore snapshot {} of {}: {}", sid, id, e);
+    StatusCode::BAD_REQUEST
+  })?;
+
+  Ok(Json(revision))
+}
+
 /// Handler for the `/api/stats` endpoint.
 async fn stats_handler(
   State(state): State<ServerState>,
@@ -193,22 +732,160 @@ async fn stats_handler(
   }))
 }
 
+/// Handler for the `/api/pads` endpoint, the activity on the live pads of
+/// this process, admin only.
+async fn pads_handler(
+  State(state): State<ServerState>,
+  check: ClaimCan<ADMIN_PERMIT>,
+) -> Result<impl IntoResponse, StatusCode> {
+  if !check.can() {
+    return Err(StatusCode::UNAUTHORIZED);
+  }
+  let pads = Pads(Arc::clone(&state.documents));
+
+  Ok(Json(pads.activity()))
+}
+
 /// Handler for the `/api/savetoarticle/:id` endpoint.
+///
+/// The merged text is applied to the pad. On conflict, the text with conflict
+/// markers is applied instead, and the number of conflicts returned with 409.
 async fn save_handler(
   State(state): State<ServerState>,
   Path(id): Path<String>,
   check: ClaimCan<CREATE_PERMIT>,
-) -> Result<impl IntoResponse, StatusCode> {
+) -> Result<Response, StatusCode> {
   if !check.can() {
     return Err(StatusCode::UNAUTHORIZED);
   }
   let claim = check.claim.unwrap_or_default();
   let uname = claim.clone().uname;
-  let article = Article::save_doc_to_article(&state.pool, &id, &uname)
+  // the author matching is checked on saving as well
+  if !resolve_access(&state.pool, &id, &uname, None).await.can_edit() {
+    return Err(StatusCode::FORBIDDEN);
+  }
+  let pad = get_pad(&state, &id, &uname).await?;
+  let (revision, text) = pad.revision_text();
+  let saved = Article::save_doc_to_article(&state.pool, &id, &uname, &text)
     .await
-    .map_err(|_e| StatusCode::BAD_REQUEST)?;
+    .map_err(|e| match e {
+      AppError::Moderated => StatusCode::LOCKED,
+      _ => StatusCode::BAD_REQUEST,
+    })?;
+
+  let (new_text, response) = match saved {
+    PadSave::Saved { article, text } => {
+      // the comment threads follow the document to its article
+      let (comments, _) = pad.comments();
+      let carried = async {
+        StoreComment::store_batch(&state.pool, &id, &comments).await?;
+        StoreComment::carry_to_article(&state.pool, &id, article.id).await
+      };
+      if let Err(e) = carried.await {
+        error!("when carrying comments of {} to article: {}", id, e);
+      }
+      (text, Json(article).into_response())
+    }
+    PadSave::Conflict { text, conflicts } => {
+      (text, (StatusCode::CONFLICT, Json(conflicts)).into_response())
+    }
+  };
+  if let Err(e) = pad.edit_text(revision, &text, &new_text) {
+    error!("when applying merged text to {}: {}", id, e);
+  }
   
-  return Ok(Json(article));
+  return Ok(response);
+}
+
+/// Handler for the GET `/api/acl/:id` endpoint, owner only.
+async fn acl_list_handler(
+  State(state): State<ServerState>,
+  Path(id): Path<String>,
+  check: ClaimCan<BASIC_PERMIT>,
+) -> Result<impl IntoResponse, StatusCode> {
+  let uname = check.claim.unwrap_or_default().uname;
+  if resolve_access(&state.pool, &id, &uname, None).await < Access::Own {
+    return Err(StatusCode::FORBIDDEN);
+  }
+  let entries = AclEntry::list(&state.pool, &id)
+    .await
+    .map_err(|_e| StatusCode::INTERNAL_SERVER_ERROR)?;
+
+  Ok(Json(entries))
+}
+
+/// Payload of the POST `/api/acl/:id` endpoint.
+#[derive(Deserialize)]
+struct AclGrant {
+  uname: String,
+  /// editor|viewer, or empty to revoke
+  role: String,
+}
+
+/// Handler for the POST `/api/acl/:id` endpoint, invite or revoke a member.
+async fn acl_grant_handler(
+  State(state): State<ServerState>,
+  Path(id): Path<String>,
+  check: ClaimCan<BASIC_PERMIT>,
+  Json(payload): Json<AclGrant>,
+) -> Result<impl IntoResponse, StatusCode> {
+  let uname = check.claim.unwrap_or_default().uname;
+  if id.starts_with("note_")
+    || resolve_access(&state.pool, &id, &uname, None).await < Access::Own
+  {
+    return Err(StatusCode::FORBIDDEN);
+  }
+  let access = Access::from_role(&payload.role);
+  // ownership is not transferable, and the owner cannot drop themselves
+  if access == Access::Own || payload.uname == uname {
+    return Err(StatusCode::BAD_REQUEST);
+  }
+  AclEntry::grant(&state.pool, &id, &payload.uname, access)
+    .await
+    .map_err(|_e| StatusCode::BAD_REQUEST)?;
+
+  Ok(StatusCode::OK)
+}
+
+/// Payload of the POST `/api/share/:id` endpoint.
+#[derive(Deserialize)]
+struct ShareRequest {
+  /// editor|viewer
+  role: String,
+  /// hours until the link expires
+  hours: Option<i64>,
+}
+
+/// Share link returned from the `/api/share/:id` endpoint.
+#[derive(Serialize)]
+struct ShareLink {
+  link: String,
+  expire_at: i64,
+}
+
+/// Handler for the POST `/api/share/:id` endpoint, create a share link.
+async fn share_handler(
+  State(state): State<ServerState>,
+  Path(id): Path<String>,
+  check: ClaimCan<BASIC_PERMIT>,
+  Json(payload): Json<ShareRequest>,
+) -> Result<impl IntoResponse, StatusCode> {
+  let uname = check.claim.unwrap_or_default().uname;
+  if id.starts_with("note_")
+    || resolve_access(&state.pool, &id, &uname, None).await < Access::Own
+  {
+    return Err(StatusCode::FORBIDDEN);
+  }
+  let hours = payload.hours.unwrap_or(24).clamp(1, 24 * 30);
+  let access = Access::from_role(&payload.role);
+  let share = ShareToken::new(&state.pool, &id, access, hours, &uname)
+    .await
+    .map_err(|_e| StatusCode::BAD_REQUEST)?;
+
+  Ok(Json(ShareLink {
+    link: format!("/app/editor?token={}#{}", share.token, id),
+    expire_at: share.expire_at,
+  }))
 }
 
 const HOUR: Duration = Duration::from_secs(3600);
@@ -227,6 +904,31 @@ async fn cleaner(state: ServerState, expiry_hours: u32) {
     for key in keys {
       state.documents.remove(&key);
     }
+    state.persisting.retain(|_, lock| Arc::strong_count(lock) > 1);
+  }
+}
+
+/// Renews the leases of the live pads, dropping those taken over by
+/// another process, e.g. after this one stalled.
+async fn lease_keeper(state: ServerState) {
+  loop {
+    time::sleep(LEASE_RENEW).await;
+    let ids: Vec<String> =
+      state.documents.iter().map(|entry| entry.key().clone()).collect();
+    if ids.is_empty() {
+      continue;
+    }
+    match state.coordinator.renew(&ids).await {
+      Ok(lost) => {
+        if !lost.is_empty() {
+          warn!("lost ownership of: {:?}", lost);
+        }
+        for id in lost {
+          state.documents.remove(&id);
+        }
+      }
+      Err(e) => error!("when renewing leases: {}", e),
+    }
   }
 }
 
@@ -234,28 +936,115 @@ const PERSIST_INTERVAL: Duration = Duration::from_secs(3);
 const PERSIST_INTERVAL_JITTER: Duration = Duration::from_secs(1);
 
 /// Persists changed documents after a fixed time interval.
-async fn persister(id: String, uname: String, pad: Arc<Pad>, db: SqlitePool) {
-  let mut last_revision = 0;
+///
+/// `last_revision` is the number of operations already persisted. Chat
+/// messages are persisted along, from those sent after the pad was loaded,
+/// and the comments whenever they changed.
+/// The document is released once its pad is dropped and flushed, and then
+/// `_flushed` unlocked for the next pad of the document.
+async fn persister(
+  id: String,
+  uname: String,
+  pad: Arc<Pad>,
+  db: SqlitePool,
+  coordinator: Arc<dyn Coordinator>,
+  _flushed: OwnedMutexGuard<()>,
+  mut last_revision: usize,
+) {
+  let fence = coordinator.fence();
+  let mut last_chat = pad.num_chats();
+  let mut last_comments = pad.comments_version();
   while !pad.killed() {
     let interval = PERSIST_INTERVAL
       + rand::thread_rng().gen_range(Duration::ZERO..=PERSIST_INTERVAL_JITTER);
-    time::sleep(interval).await;
+    tokio::select! {
+      _ = time::sleep(interval) => {}
+      _ = pad.until_killed() => {}
+    }
+    last_revision = persist(&id, &uname, &pad, &db, fence, last_revision).await;
+    last_chat = persist_chats(&id, &pad, &db, last_chat).await;
+    last_comments = persist_comments(&id, &pad, &db, last_comments).await;
+  }
+  // flush the edits made since the last tick before the pad is dropped
+  persist(&id, &uname, &pad, &db, fence, last_revision).await;
+  persist_chats(&id, &pad, &db, last_chat).await;
+  persist_comments(&id, &pad, &db, last_comments).await;
+  if let Err(e) = coordinator.release(&id).await {
+    error!("when releasing {}: {}", id, e);
+  }
+}
+
+/// Persists new chat messages, returning the number persisted so far.
+async fn persist_chats(
+  id: &str,
+  pad: &Pad,
+  db: &SqlitePool,
+  last_chat: usize,
+) -> usize {
+  let (chats, num_chats) = pad.chats_since(last_chat);
+  if chats.is_empty() {
+    return last_chat;
+  }
+  if let Err(e) = StoreChat::store_batch(db, id, &chats).await {
+    error!("when persisting chat of {}: {}", id, e);
+    return last_chat;
+  }
+  num_chats
+}
+
+/// Persists the comments if they changed, returning the persisted version.
+async fn persist_comments(
+  id: &str,
+  pad: &Pad,
+  db: &SqlitePool,
+  last_version: usize,
+) -> usize {
+  if pad.comments_version() == last_version {
+    return last_version;
+  }
+  let (comments, version) = pad.comments();
+  if let Err(e) = StoreComment::store_batch(db, id, &comments).await {
+    error!("when persisting comments of {}: {}", id, e);
+    return last_version;
+  }
+  version
+}
+
+/// Persists a pad if it has changed, returning the persisted revision.
+///
+/// The history is only written under the lease named by `fence`, if any.
+async fn persist(
+  id: &str,
+  uname: &str,
+  pad: &Pad,
+  db: &SqlitePool,
+  fence: Option<&str>,
+  last_revision: usize,
+) -> usize {
+  if pad.revision() <= last_revision {
+    return last_revision;
+  }
+  if id.starts_with("note_") {
     let revision = pad.revision();
-    if revision > last_revision {
-      info!("persisting revision {} for id = {}", revision, id);
-      if id.starts_with("note_") {
-        if let Err(e) = Note::store(&db, &uname, &id, &pad.snapshot().text).await {
-          error!("when persisting document {}: {}", id, e);
-        } else {
-          last_revision = revision;
-        }
-      } else {
-        if let Err(e) = StoreDoc::store(&db, &id, &pad.snapshot()).await {
-          error!("when persisting document {}: {}", id, e);
-        } else {
-          last_revision = revision;
-        }
-      }
+    info!("persisting revision {} for id = {}", revision, id);
+    if let Err(e) = Note::store(db, uname, id, &pad.snapshot().text).await {
+      error!("when persisting document {}: {}", id, e);
+      return last_revision;
+    }
+    revision
+  } else {
+    // the operations, text and checkpoint are taken under one lock, so
+    // that no compaction slips in between
+    let history = pad.snapshot_since(last_revision);
+    let revision = match history.operations.last() {
+      Some(op) => op.revision as usize + 1,
+      None => return last_revision,
+    };
+    info!("persisting revision {} for id = {}", revision, id);
+    if let Err(e) = history.store(db, id, fence).await {
+      error!("when persisting history of {}: {}", id, e);
+      return last_revision;
     }
+    revision
   }
 }
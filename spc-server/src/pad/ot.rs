@@ -21,3 +21,24 @@ pub fn transform_index(operation: &OperationSeq, position: u32) -> u32 {
   }
   new_index as u32
 }
+
+/// Return an operation turning `old` into `new`, replacing only the range
+/// between their common prefix and suffix.
+pub fn diff_operation(old: &str, new: &str) -> OperationSeq {
+  let old: Vec<char> = old.chars().collect();
+  let new: Vec<char> = new.chars().collect();
+  let prefix = old.iter().zip(&new).take_while(|(a, b)| a == b).count();
+  let suffix = old[prefix..]
+    .iter()
+    .rev()
+    .zip(new[prefix..].iter().rev())
+    .take_while(|(a, b)| a == b)
+    .count();
+
+  let mut operation = OperationSeq::default();
+  operation.retain(prefix as u64);
+  operation.delete((old.len() - prefix - suffix) as u64);
+  operation.insert(&new[prefix..new.len() - suffix].iter().collect::<String>());
+  operation.retain(suffix as u64);
+  operation
+}
@@ -0,0 +1,308 @@
+//! Transports carrying messages between pad clients and the server.
+
+use std::pin::Pin;
+
+use anyhow::{Context, Result};
+use axum::extract::ws::{close_code, CloseFrame, Message, WebSocket};
+use futures::channel::{mpsc, oneshot};
+use futures::prelude::*;
+use spc_util::wire;
+use tokio::time::{self, Instant};
+
+use super::mdpad::{ClientMsg, ServerMsg};
+
+/// Encoding of the messages sent over a WebSocket, negotiated per connection
+/// as its subprotocol. JSON unless the client asks otherwise.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Encoding {
+  /// JSON text messages
+  #[default]
+  Json,
+  /// binary messages, see `spc_util::wire`
+  Binary,
+}
+
+impl Encoding {
+  /// Subprotocols offered to the clients, by preference.
+  pub const PROTOCOLS: [&'static str; 2] = ["spc.binary", "spc.json"];
+
+  /// Encoding of the subprotocol selected on the upgrade.
+  pub fn from_protocol(protocol: Option<&str>) -> Self {
+    match protocol {
+      Some("spc.binary") => Encoding::Binary,
+      _ => Encoding::Json,
+    }
+  }
+
+  /// Encode a message to the client.
+  pub fn encode(self, msg: &ServerMsg) -> Result<Message> {
+    Ok(match self {
+      Encoding::Json => {
+        Message::Text(serde_json::to_string(msg).context("failed to serialize message")?)
+      }
+      Encoding::Binary => {
+        Message::Binary(wire::encode(msg).context("failed to serialize message")?)
+      }
+    })
+  }
+}
+
+/// What is sent to the client: a message, or the end of the connection.
+#[derive(Clone, Debug)]
+pub enum Outgoing {
+  /// A message to the client.
+  Msg(ServerMsg),
+  /// Closes the connection, telling the client why.
+  Close(String),
+}
+
+/// A connection with a client, independent of the transport: a sink of
+/// messages to the client and a stream of messages from the client.
+///
+/// The connection ends when the stream does.
+pub struct Connection {
+  sink: Pin<Box<dyn Sink<Outgoing, Error = anyhow::Error> + Send>>,
+  stream: Pin<Box<dyn Stream<Item = Result<ClientMsg>> + Send>>,
+}
+
+impl Connection {
+  /// Create a connection from a message sink and a message stream.
+  pub fn new(
+    sink: impl Sink<Outgoing, Error = anyhow::Error> + Send + 'static,
+    stream: impl Stream<Item = Result<ClientMsg>> + Send + 'static,
+  ) -> Self {
+    Self {
+      sink: Box::pin(sink),
+      stream: Box::pin(stream),
+    }
+  }
+
+  /// Create a connection over a WebSocket, encoding the messages to the
+  /// client as negotiated on the upgrade.
+  ///
+  /// Messages from the client are accepted in either encoding.
+  pub fn websocket(socket: WebSocket) -> Self {
+    let encoding =
+      Encoding::from_protocol(socket.protocol().and_then(|p| p.to_str().ok()));
+    let (sink, stream) = socket.split();
+    let sink = sink
+      .sink_map_err(anyhow::Error::from)
+      .with(move |out: Outgoing| {
+        future::ready(match out {
+          Outgoing::Msg(msg) => encoding.encode(&msg),
+          Outgoing::Close(reason) => Ok(Message::Close(Some(CloseFrame {
+            code: close_code::POLICY,
+            reason: reason.into(),
+          }))),
+        })
+      });
+    let stream = stream.filter_map(|message| async move {
+      match message {
+        Ok(Message::Text(text)) => Some(
+          serde_json::from_str(&text).context("failed to deserialize message"),
+        ),
+        Ok(Message::Binary(frame)) => Some(
+          wire::decode_with_limit(&frame, wire::MAX_CLIENT_INFLATED)
+            .context("failed to deserialize message"),
+        ),
+        Ok(_) => None, // Ignore ping, pong and close
+        Err(e) => Some(Err(e.into())),
+      }
+    });
+    Self::new(sink, stream)
+  }
+
+  /// Create a connection over a pair of channels, for transports made of
+  /// separate requests, like a Server-Sent Events stream along with POST
+  /// requests.
+  ///
+  /// Returns the connection, the sender of the messages from the client,
+  /// and the stream of what is sent to the client. The connection ends
+  /// once the stream is dropped.
+  pub fn channel(
+    buffer: usize,
+  ) -> (
+    Self,
+    mpsc::Sender<ClientMsg>,
+    impl Stream<Item = Outgoing> + Send + 'static,
+  ) {
+    let (server_tx, server_rx) = mpsc::channel(buffer);
+    let (client_tx, client_rx) = mpsc::channel(buffer);
+    let (mut alive_tx, alive_rx) = oneshot::channel::<()>();
+    let closed = future::poll_fn(move |cx| alive_tx.poll_canceled(cx));
+    let conn = Self::new(
+      server_tx.sink_map_err(anyhow::Error::from),
+      client_rx.map(Ok).take_until(closed),
+    );
+    let server_rx = server_rx.map(move |msg| {
+      let _alive = &alive_rx; // dropped along with the stream
+      msg
+    });
+    (conn, client_tx, server_rx)
+  }
+
+  /// Also receive the messages of `incoming`, posted by the client apart
+  /// from the transport, like those of an SSE client relayed from another
+  /// process. The connection still ends along with its own stream.
+  pub fn with_incoming(
+    self,
+    incoming: impl Stream<Item = ClientMsg> + Send + 'static,
+  ) -> Self {
+    let own = self.stream.map(Some).chain(stream::once(future::ready(None)));
+    let stream = stream::select(own, incoming.map(|msg| Some(Ok(msg))))
+      .take_while(|item| future::ready(item.is_some()))
+      .filter_map(future::ready);
+    Self {
+      sink: self.sink,
+      stream: Box::pin(stream),
+    }
+  }
+
+  /// End the connection at `deadline` if any, e.g. once the share link
+  /// granting its access expires.
+  pub fn until(self, deadline: Option<Instant>) -> Self {
+    let deadline = match deadline {
+      Some(deadline) => deadline,
+      None => return self,
+    };
+    Self {
+      sink: self.sink,
+      stream: Box::pin(self.stream.take_until(time::sleep_until(deadline))),
+    }
+  }
+
+  /// Send a message to the client.
+  pub async fn send(&mut self, msg: ServerMsg) -> Result<()> {
+    self.sink.send(Outgoing::Msg(msg)).await
+  }
+
+  /// Close the connection, telling the client why.
+  pub async fn close(&mut self, reason: String) -> Result<()> {
+    self.sink.send(Outgoing::Close(reason)).await?;
+    self.sink.close().await
+  }
+
+  /// Receive the next message from the client, `None` once disconnected.
+  pub async fn recv(&mut self) -> Option<Result<ClientMsg>> {
+    self.stream.next().await
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use std::sync::Arc;
+  use std::time::Duration;
+
+  use axum::extract::ws::WebSocketUpgrade;
+  use axum::routing::get;
+  use axum::Router;
+  use operational_transform::OperationSeq;
+  use tokio_tungstenite::tungstenite::Message as WsMessage;
+
+  use super::*;
+  use crate::pad::acl::Access;
+  use crate::pad::mdpad::Pad;
+
+  /// The revision a client reached, from the history it was sent.
+  fn caught_up(msg: &ServerMsg, revision: &mut usize) {
+    if let ServerMsg::History { start, operations } = msg {
+      *revision = (*revision).max(start + operations.len());
+    }
+  }
+
+  fn insert(text: &str) -> ClientMsg {
+    let mut operation = OperationSeq::default();
+    operation.insert(text);
+    ClientMsg::Edit { revision: 0, operation }
+  }
+
+  #[test]
+  fn test_encoding() {
+    assert_eq!(Encoding::from_protocol(None), Encoding::Json);
+    assert_eq!(Encoding::from_protocol(Some("spc.binary")), Encoding::Binary);
+    assert_eq!(Encoding::from_protocol(Some("other")), Encoding::Json);
+
+    let msg = ServerMsg::Snapshot {
+      revision: 42,
+      text: "# Title\n\n".repeat(100),
+    };
+    let json = match Encoding::Json.encode(&msg).unwrap() {
+      Message::Text(text) => text,
+      _ => panic!("expected a text message"),
+    };
+    let binary = match Encoding::Binary.encode(&msg).unwrap() {
+      Message::Binary(frame) => frame,
+      _ => panic!("expected a binary message"),
+    };
+    // the browser decodes binary frames to the same JSON
+    assert_eq!(wire::to_json(&binary).unwrap(), json);
+  }
+
+  #[tokio::test]
+  async fn test_sse_along_websocket() {
+    let pad = Arc::new(Pad::default());
+
+    // a WebSocket client, through a server
+    let served = Arc::clone(&pad);
+    let app = Router::new().route(
+      "/",
+      get(move |ws: WebSocketUpgrade| async move {
+        ws.on_upgrade(move |socket| async move {
+          let conn = Connection::websocket(socket);
+          served.on_connection(conn, Access::Edit, String::new()).await
+        })
+      }),
+    );
+    let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    let server = axum::Server::from_tcp(listener).unwrap();
+    tokio::spawn(server.serve(app.into_make_service()));
+    let (mut ws, _) = tokio_tungstenite::connect_async(format!("ws://{addr}/"))
+      .await
+      .unwrap();
+
+    // an SSE client, posting its messages apart
+    let (conn, mut posts, mut events) = Connection::channel(16);
+    let served = Arc::clone(&pad);
+    tokio::spawn(async move {
+      served.on_connection(conn, Access::Edit, String::new()).await
+    });
+
+    // both edit at once, from the same revision
+    let edit = serde_json::to_string(&insert("ws ")).unwrap();
+    ws.send(WsMessage::Text(edit)).await.unwrap();
+    posts.send(insert("sse ")).await.unwrap();
+
+    let synced = async {
+      let (mut ws_revision, mut sse_revision) = (0, 0);
+      while ws_revision < 2 {
+        if let WsMessage::Text(text) = ws.next().await.unwrap().unwrap() {
+          caught_up(&serde_json::from_str(&text).unwrap(), &mut ws_revision);
+        }
+      }
+      while sse_revision < 2 {
+        if let Outgoing::Msg(msg) = events.next().await.unwrap() {
+          caught_up(&msg, &mut sse_revision);
+        }
+      }
+    };
+    time::timeout(Duration::from_secs(5), synced).await.unwrap();
+    let text = pad.text();
+    assert!(text == "ws sse " || text == "sse ws ", "{text:?}");
+
+    // either client leaving ends its connection only
+    drop(events);
+    let mut operation = OperationSeq::default();
+    operation.retain(7);
+    operation.insert("!");
+    let edit = ClientMsg::Edit { revision: 2, operation };
+    ws.send(WsMessage::Text(serde_json::to_string(&edit).unwrap())).await.unwrap();
+    let edited = async {
+      while pad.revision() < 3 {
+        time::sleep(Duration::from_millis(10)).await;
+      }
+    };
+    time::timeout(Duration::from_secs(5), edited).await.unwrap();
+    assert!(pad.text().ends_with('!'));
+  }
+}
@@ -2,7 +2,10 @@
 //!
 use crate::{
   config::CONFIG,
-  pad::{ws_server, WsConfig},
+  pad::{
+    cluster::{Coordinator, SqliteLeases, Standalone},
+    ws_server, WsConfig,
+  },
   api::{
     feed::{
       fetch_feed, add_channel, get_sub_channels, get_feeds, 
@@ -13,17 +16,21 @@ use crate::{
     note::{
       new_note, get_note, get_notes, get_notes_by_folder, get_folders,
       move_note, del_note, update_note, rename_note
-    }
+    },
+    search::search,
   },
   ssr::{
     admin::{
       mod_user, save_site_config, site_config_view, user_list_page, 
-      channel_list_page, mod_channel
+      channel_list_page, mod_channel, pad_list_page, kill_pad, mod_content,
+      mod_log_page, merge_tags,
     },
     article::{
-      article_delete, article_view, gen_collaboration_link, 
-      edit_article_form, edit_article_page, explore_page, 
-      new_piece_form, piece_delete, tag_delete, tag_page, view_article_by_title, 
+      article_delete, article_history, article_rollback, article_view,
+      gen_collaboration_link, 
+      edit_article_form, edit_article_page, explore_page, wanted_page,
+      new_piece_form, piece_delete, tag_delete, tag_edit_form, tag_page,
+      view_article_by_title, 
     },
     auth::{
       change_psw_form, change_psw_page, sign_out, signin_form, signin_page,
@@ -34,7 +41,10 @@ use crate::{
       channel_preload_page, del_channel, feed_reader_page, mod_subscription,
       unsubscribe, refresh_scribled_feeds,
     },
+    graph::{graph_json, graph_page},
     handler_404,
+    search::search_page,
+    syndication::{site_feed, tag_feed, user_feed},
     home::{
       about_page, health_check, home_page, serve_dir, 
       static_js, static_style, favicon, manifest,
@@ -53,6 +63,7 @@ use axum::{
   BoxError,
   Router, response::Redirect,
 };
+use std::sync::Arc;
 use std::time::Duration;
 use tower::{timeout::TimeoutLayer, ServiceBuilder};
 use tower_http::{
@@ -75,9 +86,18 @@ pub async fn router(ctx: AppState) -> Router {
         .make_span_with(DefaultMakeSpan::new().level(Level::INFO)),
     );
 
+  let coordinator: Arc<dyn Coordinator> = if CONFIG.node_url.is_empty() {
+    Arc::new(Standalone)
+  } else {
+    Arc::new(SqliteLeases::new(ctx.pool.clone(), &CONFIG.node_url))
+  };
   let ws_config = WsConfig {
     expiry_hours: CONFIG.expiry_hours,
     pool: ctx.pool.clone(),
+    coordinator,
+    secret: CONFIG.secret_key.clone(),
+    limits: CONFIG.pad_limits.clone(),
+    pads: ctx.pads.clone(),
   };
 
   let ws_route = ws_server(ws_config).await;
@@ -108,12 +128,17 @@ pub async fn router(ctx: AppState) -> Router {
     .route("/api/get_folders", get(get_folders))
     .route("/api/move_note/:id/:folder", get(move_note))
     .route("/api/del_note/:id", get(del_note))
+    .route("/api/search", get(search))
     .route("/proxy/gethtml", get(get_html_proxy))
     .with_state(ctx.clone());
 
   let router_ssr = Router::new()
     .route("/", get(home_page))
     .route("/explore", get(explore_page))
+    .route("/search", get(search_page))
+    .route("/graph", get(graph_page))
+    .route("/graph.json", get(graph_json))
+    .route("/wanted", get(wanted_page))
     .route("/about", get(about_page))
     // auth and user
     .route("/signin", get(signin_page).post(signin_form))
@@ -124,6 +149,9 @@ pub async fn router(ctx: AppState) -> Router {
       get(change_psw_page).post(change_psw_form),
     )
     .route("/user/:uname", get(profile_page))
+    .route("/user/:uname/feed.xml", get(user_feed))
+    .route("/user/:uname/atom.xml", get(user_feed))
+    .route("/user/:uname/feed.json", get(user_feed))
     .route(
       "/user/:uname/setting",
       get(user_setting_view).post(user_setting_form),
@@ -138,15 +166,29 @@ pub async fn router(ctx: AppState) -> Router {
     )
     .route("/new", get(|| async { Redirect::permanent("/article/0/edit") }))
     .route("/article/:id/delete", get(article_delete))
+    .route("/article/:id/history", get(article_history))
+    .route("/article/:id/rollback/:rid", post(article_rollback))
     .route("/new_piece", post(new_piece_form))
     .route("/piece/:id/delete", get(piece_delete))
     .route("/tag/:tag", get(tag_page))
+    .route("/tag/:tag/edit", post(tag_edit_form))
+    .route("/tag/:tag/feed.xml", get(tag_feed))
+    .route("/tag/:tag/atom.xml", get(tag_feed))
+    .route("/tag/:tag/feed.json", get(tag_feed))
+    .route("/feed.xml", get(site_feed))
+    .route("/atom.xml", get(site_feed))
+    .route("/feed.json", get(site_feed))
     .route("/delete_tag/:id", get(tag_delete))
     // admin
     .route("/admin/user_list", get(user_list_page))
     .route("/admin/:uname/mod/:permission", get(mod_user))
     .route("/admin/channel_list", get(channel_list_page))
+    .route("/admin/pad_list", get(pad_list_page))
+    .route("/admin/kill_pad/:id", get(kill_pad))
     .route("/admin/mod_channel/:hidden", get(mod_channel))
+    .route("/admin/mod_content/:ty/:id", get(mod_content))
+    .route("/admin/mod_log", get(mod_log_page))
+    .route("/admin/merge_tags", get(merge_tags))
     .route("/siteconfig", get(site_config_view).post(save_site_config))
     // upload and media center
     .route(
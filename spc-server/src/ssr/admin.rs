@@ -3,20 +3,33 @@
 
 use super::{filters, into_response, PageData, QueryParams, ValidatedForm};
 use crate::{
-  config::{get_site_config, SiteConfig},
-  db::{user::{ClaimCan, PubUser, User, ADMIN_PERMIT, MOD_PERMIT}, feed::Channel},
+  config::{get_site_config, SiteConfig, CONFIG},
+  db::{
+    user::{ClaimCan, PubUser, User, ADMIN_PERMIT, MOD_PERMIT},
+    tag::{normalize_tname, Tag},
+    feed::Channel,
+    moderation::ModAction,
+  },
   error::{AppError, SsrError},
+  pad::{
+    cluster::{self, RemotePad, FORWARDED_HEADER},
+    PadActivity,
+  },
   AppState as Ctx,
 };
 use std::fs::File;
 use std::io::Write;
 use askama::Template;
 use axum::{
+  body::Body,
   extract::{Path, Query, State},
-  response::{IntoResponse, Redirect},
+  http::Request,
+  response::{IntoResponse, Redirect, Response},
 };
 // use axum_macros::debug_handler;
 use bincode::config::standard;
+use serde::Deserialize;
+use tracing::{info, warn};
 
 #[derive(Template)]
 #[template(path = "site_config.html")]
@@ -218,3 +231,214 @@ pub(crate) async fn mod_channel(
 
   Ok(Redirect::to("/admin/channel_list"))
 }
+
+/// Query params of `/admin/mod_content/:ty/:id`
+#[derive(Deserialize)]
+pub(crate) struct ModContentParams {
+  /// hide|unhide|lock|unlock
+  act: String,
+  reason: Option<String>,
+}
+
+/// MOD Content.
+/// `GET /admin/mod_content/:ty/:id?act={}&reason={}` hide or lock article
+/// or piece, recorded in the moderation log
+pub(crate) async fn mod_content(
+  State(ctx): State<Ctx>,
+  Path((ty, id)): Path<(String, u32)>,
+  Query(params): Query<ModContentParams>,
+  check: ClaimCan<MOD_PERMIT>,
+) -> Result<impl IntoResponse, SsrError> {
+  if !check.can() {
+    return Err(AppError::NoPermission.into());
+  }
+  let claim = check.claim;
+  let admin_uname = claim.clone().unwrap_or_default().uname;
+  // check the permission in server db
+  let admin = User::get(&ctx, &admin_uname).await?;
+  if admin.permission & MOD_PERMIT != MOD_PERMIT {
+    return Err(AppError::NoPermission.into());
+  }
+
+  let reason = params.reason.unwrap_or_default();
+  if reason.len() > 512 {
+    return Err(AppError::InvalidInput.into());
+  }
+  ModAction::apply(&ctx, &ty, id, &params.act, reason.trim(), &admin_uname)
+    .await?;
+  info!("{} {} {} {}: {}", admin_uname, params.act, ty, id, reason);
+
+  let target = if ty == "article" {
+    format!("/article/{}/view", id)
+  } else {
+    String::from("/admin/mod_log")
+  };
+  Ok(Redirect::to(&target))
+}
+
+/// Query params of `/admin/merge_tags`
+#[derive(Deserialize)]
+pub(crate) struct MergeTagsParams {
+  /// the tag to merge, by id, name or alias
+  from: String,
+  /// the tag to merge into, by id, name or alias
+  into: String,
+  /// rewrite the hashtags in articles and pieces too, if any
+  rewrite: Option<String>,
+}
+
+/// MERGE TAGS.
+/// `GET /admin/merge_tags?from={}&into={}&rewrite={}` merge a tag and its
+/// nested tags into another, the old names kept as aliases
+pub(crate) async fn merge_tags(
+  State(ctx): State<Ctx>,
+  Query(params): Query<MergeTagsParams>,
+  check: ClaimCan<MOD_PERMIT>,
+) -> Result<impl IntoResponse, SsrError> {
+  if !check.can() {
+    return Err(AppError::NoPermission.into());
+  }
+  let claim = check.claim;
+  let admin_uname = claim.clone().unwrap_or_default().uname;
+  // check the permission in server db
+  let admin = User::get(&ctx, &admin_uname).await?;
+  if admin.permission & MOD_PERMIT != MOD_PERMIT {
+    return Err(AppError::NoPermission.into());
+  }
+
+  let from = Tag::get(&ctx, &normalize_tname(&params.from)).await?;
+  let into = Tag::get(&ctx, &normalize_tname(&params.into)).await?;
+  let rewrite = params.rewrite.is_some();
+  let into = Tag::merge(&ctx, from.id, into.id, rewrite, &admin_uname).await?;
+  info!("{} merge tag {} into {}", admin_uname, from.tname, into.tname);
+
+  Ok(Redirect::to(&into.link()))
+}
+
+#[derive(Template)]
+#[template(path = "mod_log.html")]
+struct ModLogTmpl<'a> {
+  page_data: PageData<'a>,
+  actions: Vec<ModAction>,
+  admin: PubUser,
+  page: i64,
+}
+
+/// `GET /admin/mod_log` admin page, the moderation audit trail
+pub(crate) async fn mod_log_page(
+  State(ctx): State<Ctx>,
+  Query(params): Query<QueryParams>,
+  check: ClaimCan<MOD_PERMIT>,
+) -> Result<impl IntoResponse, SsrError> {
+  if !check.can() {
+    return Err(AppError::NoPermission.into());
+  }
+  let claim = check.claim;
+  let uname = claim.clone().unwrap_or_default().uname;
+  // check the permission in server db
+  let admin = User::get(&ctx, &uname).await?;
+  if admin.permission & MOD_PERMIT != MOD_PERMIT {
+    return Err(AppError::NoPermission.into());
+  }
+
+  let page = params.page.unwrap_or(1);
+  let perpage = params.perpage.unwrap_or(42);
+
+  let site_config = get_site_config(&ctx.sled).unwrap_or_default();
+  let page_data = PageData::new("Admin: mod log", &site_config, claim, false);
+  let actions = ModAction::get_list(&ctx, perpage, page).await?;
+  let mod_log_page = ModLogTmpl {
+    page_data,
+    actions,
+    admin: admin.into(),
+    page,
+  };
+
+  Ok(into_response(&mod_log_page, "html"))
+}
+
+#[derive(Template)]
+#[template(path = "pad_list.html")]
+struct PadListTmpl<'a> {
+  page_data: PageData<'a>,
+  pads: Vec<PadActivity>,
+  /// the pads live in other processes
+  remote_pads: Vec<RemotePad>,
+  admin: PubUser,
+}
+
+/// `GET /admin/pad_list` admin page, live collaborative pads
+pub(crate) async fn pad_list_page(
+  State(ctx): State<Ctx>,
+  check: ClaimCan<ADMIN_PERMIT>,
+) -> Result<impl IntoResponse, SsrError> {
+  if !check.can() {
+    return Err(AppError::NoPermission.into());
+  }
+  let claim = check.claim;
+  let uname = claim.clone().unwrap_or_default().uname;
+  // check the permission in server db
+  let admin = User::get(&ctx, &uname).await?;
+  if admin.permission & ADMIN_PERMIT != ADMIN_PERMIT {
+    return Err(AppError::NoPermission.into());
+  }
+
+  let site_config = get_site_config(&ctx.sled).unwrap_or_default();
+  let page_data = PageData::new("Admin: live pads", &site_config, claim, false);
+  let remote_pads = cluster::remote_pads(&ctx.pool, &CONFIG.node_url)
+    .await
+    .unwrap_or_default();
+  let padlist_page = PadListTmpl {
+    page_data,
+    pads: ctx.pads.activity(),
+    remote_pads,
+    admin: admin.into(),
+  };
+
+  Ok(into_response(&padlist_page, "html"))
+}
+
+/// KILL PAD.
+/// `GET /admin/kill_pad/:id` force-close a live pad, forwarded to the
+/// process owning it if another one
+pub(crate) async fn kill_pad(
+  State(ctx): State<Ctx>,
+  Path(id): Path<String>,
+  check: ClaimCan<ADMIN_PERMIT>,
+  req: Request<Body>,
+) -> Result<Response, SsrError> {
+  if !check.can() {
+    return Err(AppError::NoPermission.into());
+  }
+  let claim = check.claim;
+  let admin_uname = claim.unwrap_or_default().uname;
+  // check the permission in server db
+  let admin = User::get(&ctx, &admin_uname).await?;
+  if admin.permission & ADMIN_PERMIT != ADMIN_PERMIT {
+    return Err(AppError::NoPermission.into());
+  }
+
+  if ctx.pads.kill(&id) {
+    info!("pad {} killed by {}", id, admin_uname);
+    return Ok(Redirect::to("/admin/pad_list").into_response());
+  }
+
+  // forwarded already, the leases are changing hands
+  if req.headers().contains_key(FORWARDED_HEADER) {
+    return Err(AppError::NotOwnedHere.into());
+  }
+  let node = cluster::remote_owner(&ctx.pool, &CONFIG.node_url, &id)
+    .await
+    .unwrap_or_default()
+    .ok_or(AppError::NotOwnedHere)?;
+  match cluster::forward(&node, req).await {
+    Ok(res) => {
+      info!("kill of pad {} by {} forwarded to {}", id, admin_uname, node);
+      Ok(res)
+    }
+    Err(e) => {
+      warn!("when forwarding kill of pad {} to {}: {}", id, node, e);
+      Err(AppError::NotOwnedHere.into())
+    }
+  }
+}
@@ -8,13 +8,17 @@ use crate::db::feed::Feed;
 use crate::db::sled::{
   get_status_timestamp, increase_id, store_user_status, u32_to_ivec,
 };
+use crate::db::moderation::ModAction;
 use crate::db::user::{EIDT_PERMIT, MOD_PERMIT};
 use crate::error::SsrError;
-use crate::util::md::md2html;
+use crate::util::md::{md2html, md2html_with};
 use crate::{
   db::{
-    article::{Article, Entry, Piece, QueryArticles, QueryPieces},
-    tag::{Tag, TagEntry},
+    article::{
+      can_transit, Article, ArticleLinks, Entry, LinkedArticle, Piece, QueryArticles, QueryPieces, Revision,
+      Wanted, ARCHIVED, DRAFT, PUBLISHED, SCHEDULED,
+    },
+    tag::{normalize_tname, Tag, TagEntry},
     user::{ClaimCan, CREATE_PERMIT, READ_PERMIT},
   },
   error::AppError,
@@ -28,9 +32,10 @@ use axum::{
 };
 
 // use axum_macros::debug_handler;
-use chrono::Utc;
+use chrono::{NaiveDateTime, Utc};
 use serde::Deserialize;
-use spc_util::capture_element;
+use std::collections::BTreeSet;
+use spc_util::diff::{diff_lines, DiffLine};
 use validator::Validate;
 
 /// Page data: `article_form.html`
@@ -41,12 +46,20 @@ struct ArticleFormTmpl<'a> {
   article: Article,
 }
 
+/// Query of the article create page
+#[derive(Deserialize)]
+pub(crate) struct NewArticleParams {
+  /// the title to start with, from a wanted page
+  title: Option<String>,
+}
+
 /// `GET /article/:id/edit` article create/edit page
 ///
-/// if articleid is 0, then create a new article
+/// if articleid is 0, then create a new article, titled as `?title=`
 pub(crate) async fn edit_article_page(
   State(ctx): State<Ctx>,
   Path(articleid): Path<u32>,
+  Query(params): Query<NewArticleParams>,
   check: ClaimCan<CREATE_PERMIT>,
 ) -> Result<impl IntoResponse, SsrError> {
   if !check.can() {
@@ -58,7 +71,11 @@ pub(crate) async fn edit_article_page(
     let page_data = PageData::new("New Article", &site_config, claim, false);
     let article_new_page = ArticleFormTmpl {
       page_data,
-      article: Article::default(),
+      article: Article {
+        title: params.title.unwrap_or_default(),
+        state: DRAFT.to_string(),
+        ..Article::default()
+      },
     };
 
     Ok(into_response(&article_new_page, "html"))
@@ -68,6 +85,7 @@ pub(crate) async fn edit_article_page(
     if article.uname != claim.clone().unwrap_or_default().uname {
       return Err(SsrError::from(AppError::Unauthorized));
     }
+    article.check_unlocked()?;
 
     let page_data = PageData::new("Edit Article", &site_config, claim, false);
     let article_edit_page = ArticleFormTmpl { page_data, article };
@@ -85,6 +103,37 @@ pub(crate) struct ArticleForm {
   cover: String,
   #[validate(length(min = 1, max = 65535))]
   content: String,
+  #[serde(default = "default_state")]
+  state: String,
+  /// scheduled time, as `datetime-local` in UTC
+  #[serde(default)]
+  publish_at: String,
+}
+
+fn default_state() -> String {
+  PUBLISHED.to_string()
+}
+
+/// The state and publish time of an article set to `state`, which was
+/// published at `old_publish_at` if so; due scheduled ones are published.
+fn publish_state(
+  state: &str, publish_at: &str, old_publish_at: i64, now: i64,
+) -> Result<(&'static str, i64), AppError> {
+  let state = match state {
+    DRAFT => (DRAFT, 0),
+    SCHEDULED => {
+      let at = NaiveDateTime::parse_from_str(publish_at, "%Y-%m-%dT%H:%M")
+        .map_err(|_| AppError::InvalidInput)?
+        .timestamp();
+      if at > now { (SCHEDULED, at) } else { (PUBLISHED, now) }
+    }
+    PUBLISHED if old_publish_at > 0 => (PUBLISHED, old_publish_at),
+    PUBLISHED => (PUBLISHED, now),
+    ARCHIVED => (ARCHIVED, old_publish_at),
+    _ => return Err(AppError::InvalidInput),
+  };
+
+  Ok(state)
 }
 
 /// `POST /article/:id/edit` article create/edit page
@@ -119,34 +168,33 @@ pub(crate) async fn edit_article_form(
     return Err(AppError::WriteInterval.into());
   }
 
-  let (created_at, updated_at, old_title) = if articleid > 0 {
-    let old_article = Article::get(&ctx, articleid).await?;
-    if old_article.uname != uname && !claim.can(EIDT_PERMIT) {
-      return Err(AppError::NoPermission.into());
-    }
-
-    (old_article.created_at, now, old_article.title)
-  } else {
-    (now, now, String::new())
-  };
+  let (created_at, updated_at, old_title, old_state, old_publish_at) =
+    if articleid > 0 {
+      let old_article = Article::get(&ctx, articleid).await?;
+      if old_article.uname != uname && !claim.can(EIDT_PERMIT) {
+        return Err(AppError::NoPermission.into());
+      }
+      old_article.check_unlocked()?;
+      let old_publish_at = match old_article.state.as_str() {
+        PUBLISHED | ARCHIVED => old_article.publish_at,
+        _ => 0,
+      };
+
+      (
+        old_article.created_at,
+        now,
+        old_article.title,
+        old_article.state,
+        old_publish_at,
+      )
+    } else {
+      (now, now, String::new(), DRAFT.to_string(), 0)
+    };
 
-  // process wikilink, work with db to get linked article id
-  let wikilinks = capture_element(&content, "");
-  let mut includes: Vec<u32> = Vec::new();
-  for link in &wikilinks {
-    let linked_title = link.replace("[", "").replace("]", "");
-    if linked_title.trim().is_empty() {
-      continue;
-    }
-    let tar_title = linked_title.split_once("|").and_then(|s| {
-      let tar = s.1.trim();
-      if tar.len() > 0 { Some(tar) } else { None }
-    })
-    .unwrap_or(linked_title.trim());
-    // get article
-    if let Ok(link_article) = Article::get_by_id_or_title(&ctx, tar_title).await {
-      includes.push(link_article.id);
-    }
+  let (state, publish_at) =
+    publish_state(&form.state, &form.publish_at, old_publish_at, now)?;
+  if !can_transit(&old_state, state) {
+    return Err(AppError::InvalidInput.into());
   }
 
   // extact hashtags then save 
@@ -163,38 +211,33 @@ pub(crate) async fn edit_article_form(
     updated_at,
     is_locked: false,
     is_hidden: false,
+    state: state.to_string(),
+    publish_at,
+  };
+  let retitled = articleid > 0 && old_title.trim() != title;
+  // if change title, need to update the wikilinks. who link to me
+  let new_article = if retitled {
+    article.rename(&ctx, &old_title).await?
+  } else {
+    article.new(&ctx).await?
   };
-  let new_article = article.new(&ctx).await?;
 
   // record action: post new article
   if articleid == 0 {
     store_user_status(&ctx.sled, &uname, "post").unwrap_or(());
   }
 
-  // if change title, need to update the wikilinks. who link to me
-  if articleid > 0 && old_title.trim() != title {
-    tokio::spawn(
-      update_wikilinks(ctx.clone(), articleid, old_title, title.to_string())
-    );
-  }
-
   // save tags
   TagEntry::tag(&ctx, hashtags, "article", new_article.id).await?;
-  // save backlinks, link to who
-  for id in includes {
-    Article::include(&ctx, id, "article", new_article.id).await.unwrap_or(0);
+  // the links wanting this title are resolved now
+  if articleid == 0 || retitled {
+    Article::resolve_wanted(&ctx.pool, new_article.id, title).await?;
   }
 
   let target = format!("/article/{}/view", new_article.id);
   Ok(Redirect::to(&target))
 }
 
-async fn update_wikilinks(ctx: Ctx, id: u32, old_title: String, new_title: String) {
-  Article::update_wikilinks(&ctx, id, &old_title, &new_title)
-    .await
-    .unwrap_or(0);
-}
-
 /// Page data: `article.html`
 #[derive(Template)]
 #[template(path = "article.html", escape = "none")]
@@ -203,6 +246,13 @@ struct ArticleViewTmpl<'a> {
   article: Article,
   pageview: u32,
   is_author: bool,
+  is_mod: bool,
+  /// moderation trail, for the author and moderators
+  mod_actions: Vec<ModAction>,
+  /// the articles linking here
+  backlinks: Vec<LinkedArticle>,
+  /// the articles naming this one without a link
+  mentions: Vec<LinkedArticle>,
 }
 
 /// `GET /article/:id/view` Article page
@@ -214,13 +264,24 @@ pub(crate) async fn article_view(
   let claim = check.claim;
   let site_config = get_site_config(&ctx.sled).unwrap_or_default();
   let uname = claim.clone().unwrap_or_default().uname;
+  let is_mod = claim.as_ref().is_some_and(|c| c.can(MOD_PERMIT));
   let article: Article = Article::get(&ctx, articleid).await?;
+  if !article.visible_to(&uname) {
+    return Err(AppError::NotFound.into());
+  }
+  article.check_unhidden(&uname, is_mod)?;
   // let user: User = User::get(&ctx, &article.uname).await?;
   // let author = article.uname.clone();
   let is_author = uname == article.uname;
-  let content = article.content;
-
-  let content = md2html(&content, "articlepage", "tag");
+  let mod_actions = if is_author || is_mod {
+    ModAction::get_on(&ctx, "article", articleid).await?
+  } else {
+    vec![]
+  };
+  let backlinks = Article::backlinks(&ctx, articleid).await?;
+  let mentions = Article::unlinked_mentions(&ctx, articleid, &article.title).await?;
+  let links = ArticleLinks::load(&ctx, &article, &uname).await?;
+  let content = md2html_with(&article.content, "articlepage", "tag", links);
   let page_title = format!("{}", article.title);
 
   let article_view = Article { content, ..article };
@@ -240,17 +301,158 @@ pub(crate) async fn article_view(
     article: article_view,
     pageview,
     is_author,
+    is_mod,
+    mod_actions,
+    backlinks,
+    mentions,
   };
 
   Ok(into_response(&article_page, "html"))
 }
 
+/// unchanged lines kept around the changes in history diffs
+const DIFF_CONTEXT: usize = 2;
+
+/// A line in the diff of a revision, `kind` is one of `same`, `added`,
+/// `removed`, or `skip` for a run of unchanged lines left out.
+struct DiffLineView {
+  kind: &'static str,
+  text: String,
+}
+
+/// A revision and its changes from the previous one.
+struct RevisionView {
+  id: u32,
+  rev_by: String,
+  rev_at: i64,
+  is_current: bool,
+  lines: Vec<DiffLineView>,
+}
+
+/// Line diff of `old` to `new`, unchanged runs collapsed to some context.
+fn diff_view(old: &str, new: &str) -> Vec<DiffLineView> {
+  let diff = diff_lines(old, new);
+  let changed: Vec<usize> = diff
+    .iter()
+    .enumerate()
+    .filter(|(_, l)| !matches!(l, DiffLine::Same(_)))
+    .map(|(i, _)| i)
+    .collect();
+  let near = |i: usize| {
+    changed.iter().any(|&c| c.abs_diff(i) <= DIFF_CONTEXT)
+  };
+
+  let mut lines = Vec::new();
+  let mut skipped = 0;
+  for (i, line) in diff.into_iter().enumerate() {
+    let (kind, text) = match line {
+      DiffLine::Same(_) if !near(i) => {
+        skipped += 1;
+        continue;
+      }
+      DiffLine::Same(t) => ("same", t),
+      DiffLine::Added(t) => ("added", t),
+      DiffLine::Removed(t) => ("removed", t),
+    };
+    if skipped > 0 {
+      lines.push(DiffLineView {
+        kind: "skip",
+        text: format!("... {} unchanged lines", skipped),
+      });
+      skipped = 0;
+    }
+    lines.push(DiffLineView { kind, text: text.to_string() });
+  }
+  if skipped > 0 {
+    lines.push(DiffLineView {
+      kind: "skip",
+      text: format!("... {} unchanged lines", skipped),
+    });
+  }
+
+  lines
+}
+
+/// Page data: `article_history.html`
+#[derive(Template)]
+#[template(path = "article_history.html")]
+struct ArticleHistoryTmpl<'a> {
+  page_data: PageData<'a>,
+  article: Article,
+  revisions: Vec<RevisionView>,
+  can_rollback: bool,
+}
+
+/// `GET /article/:id/history` revisions of article with their changes
+pub(crate) async fn article_history(
+  State(ctx): State<Ctx>,
+  Path(articleid): Path<u32>,
+  check: ClaimCan<READ_PERMIT>,
+) -> Result<impl IntoResponse, SsrError> {
+  let claim = check.claim;
+  let site_config = get_site_config(&ctx.sled).unwrap_or_default();
+  let can_rollback = claim.as_ref().is_some_and(|c| c.can(EIDT_PERMIT));
+  let uname = claim.clone().unwrap_or_default().uname;
+  let is_mod = claim.as_ref().is_some_and(|c| c.can(MOD_PERMIT));
+  let article: Article = Article::get(&ctx, articleid).await?;
+  if !article.visible_to(&uname) {
+    return Err(AppError::NotFound.into());
+  }
+  article.check_unhidden(&uname, is_mod)?;
+  let revisions = Revision::list(&ctx, articleid).await?;
+
+  // newest first, each compared to the one before it
+  let revisions = revisions
+    .iter()
+    .enumerate()
+    .map(|(i, rev)| {
+      let older = revisions.get(i + 1).map_or("", |r| r.content.as_str());
+      RevisionView {
+        id: rev.id,
+        rev_by: rev.rev_by.clone(),
+        rev_at: rev.rev_at,
+        is_current: rev.is_current,
+        lines: diff_view(older, &rev.content),
+      }
+    })
+    .collect();
+
+  let page_title = format!("History: {}", article.title);
+  let page_data = PageData::new(&page_title, &site_config, claim, false);
+  let history_page = ArticleHistoryTmpl {
+    page_data,
+    article,
+    revisions,
+    can_rollback,
+  };
+
+  Ok(into_response(&history_page, "html"))
+}
+
+/// `POST /article/:id/rollback/:rid` restore article to a revision
+pub(crate) async fn article_rollback(
+  State(ctx): State<Ctx>,
+  Path((articleid, rid)): Path<(u32, u32)>,
+  check: ClaimCan<EIDT_PERMIT>,
+) -> Result<impl IntoResponse, SsrError> {
+  if !check.can() {
+    return Err(SsrError::from(AppError::Unauthorized));
+  }
+  let uname = check.claim.unwrap_or_default().uname;
+  Article::rollback(&ctx, articleid, rid, &uname).await?;
+
+  let target = format!("/article/{}/history", articleid);
+  Ok(Redirect::to(&target))
+}
+
 /// Page data: `article_not_found.html`
 #[derive(Template)]
 #[template(path = "article_not_found.html")]
 struct ArticleNotFoundTmpl<'a> {
   page_data: PageData<'a>,
   title: &'a str,
+  /// the title in the create link
+  encoded_title: String,
 }
 
 /// `GET /article/:encoded_title` Article page
@@ -262,18 +464,28 @@ pub(crate) async fn view_article_by_title(
   let claim = check.claim;
   let site_config = get_site_config(&ctx.sled).unwrap_or_default();
   let uname = claim.clone().unwrap_or_default().uname;
+  let is_mod = claim.as_ref().is_some_and(|c| c.can(MOD_PERMIT));
   let decoded_title = urlencoding::decode(&title).unwrap_or_default();
   let page_data = PageData::new(decoded_title.as_ref(), &site_config, claim, false);
 
-  if let Ok(article) = 
-    Article::get_by_id_or_title(&ctx, decoded_title.as_ref()).await 
-  {
+  let article = Article::get_by_id_or_title(&ctx, decoded_title.as_ref())
+    .await
+    .ok()
+    .filter(|a| a.visible_to(&uname));
+  if let Some(article) = article {
+    article.check_unhidden(&uname, is_mod)?;
     // let user: User = User::get(&ctx, &article.uname).await?;
     // let author = article.uname.clone();
     let is_author = uname == article.uname;
-    let content = article.content;
-    
-    let content = md2html(&content, "articlepage", "tag");
+    let mod_actions = if is_author || is_mod {
+      ModAction::get_on(&ctx, "article", article.id).await?
+    } else {
+      vec![]
+    };
+    let backlinks = Article::backlinks(&ctx, article.id).await?;
+    let mentions = Article::unlinked_mentions(&ctx, article.id, &article.title).await?;
+    let links = ArticleLinks::load(&ctx, &article, &uname).await?;
+    let content = md2html_with(&article.content, "articlepage", "tag", links);
 
     let article_view = Article { content, ..article };
 
@@ -291,6 +503,10 @@ pub(crate) async fn view_article_by_title(
       article: article_view,
       pageview,
       is_author,
+      is_mod,
+      mod_actions,
+      backlinks,
+      mentions,
     };
 
     Ok(into_response(&article_page, "html"))
@@ -298,6 +514,7 @@ pub(crate) async fn view_article_by_title(
     let not_found_page = ArticleNotFoundTmpl {
       page_data,
       title: decoded_title.as_ref(),
+      encoded_title: urlencoding::encode(decoded_title.as_ref()).into_owned(),
     };
 
     Ok(into_response(&not_found_page, "html"))
@@ -335,6 +552,7 @@ pub(crate) async fn article_delete(
 
   // check uname matched
   let article: Article = Article::get(&ctx, articleid).await?;
+  article.check_unlocked()?;
   if article.uname == uname || claim.can(EIDT_PERMIT) {
     Article::del(&ctx, articleid).await?;
   } else {
@@ -387,6 +605,7 @@ pub(crate) async fn new_piece_form(
     content,
     created_at,
     is_hidden: false,
+    is_locked: false,
   };
 
   let new_piece = piece.new(&ctx).await?;
@@ -414,6 +633,7 @@ pub(crate) async fn piece_delete(
 
   // check uid matched
   let piece: Piece = Piece::get(&ctx, id).await?;
+  piece.check_unlocked()?;
   if piece.uname == uname || claim.can(EIDT_PERMIT) {
     Piece::del(&ctx, id).await?;
   } else {
@@ -438,6 +658,57 @@ pub(crate) async fn tag_delete(
   Ok(Redirect::to("/explore"))
 }
 
+/// A missing title on the wanted page
+struct WantedView {
+  title: String,
+  refs: u32,
+  /// the title in the create link
+  encoded: String,
+}
+
+/// Page data: `wanted.html`
+#[derive(Template)]
+#[template(path = "wanted.html")]
+struct WantedTmpl<'a> {
+  page_data: PageData<'a>,
+  wanted: Vec<WantedView>,
+  can_create: bool,
+  page: i64,
+}
+
+/// `GET /wanted` titles linked to but missing, most linked first
+pub(crate) async fn wanted_page(
+  State(ctx): State<Ctx>,
+  Query(params): Query<QueryParams>,
+  check: ClaimCan<READ_PERMIT>,
+) -> Result<impl IntoResponse, SsrError> {
+  let claim = check.claim;
+  let site_config = get_site_config(&ctx.sled).unwrap_or_default();
+  let can_create = claim.as_ref().is_some_and(|c| c.can(CREATE_PERMIT));
+  let page = params.page.unwrap_or(1);
+  let perpage = params.perpage.unwrap_or(42);
+
+  let wanted = Wanted::get_list(&ctx, perpage, page)
+    .await?
+    .into_iter()
+    .map(|w| WantedView {
+      encoded: urlencoding::encode(&w.title).into_owned(),
+      title: w.title,
+      refs: w.refs,
+    })
+    .collect();
+
+  let page_data = PageData::new("Wanted", &site_config, claim, false);
+  let wanted_page = WantedTmpl {
+    page_data,
+    wanted,
+    can_create,
+    page,
+  };
+
+  Ok(into_response(&wanted_page, "html"))
+}
+
 #[derive(Template)]
 #[template(path = "explore.html")]
 struct ExploreTmpl<'a> {
@@ -456,7 +727,8 @@ pub(crate) async fn explore_page(
 ) -> Result<impl IntoResponse, SsrError> {
   let claim = check.claim;
   let site_config = get_site_config(&ctx.sled).unwrap_or_default();
-  let can_create = claim.clone().unwrap_or_default().can(CREATE_PERMIT);
+  let viewer = claim.clone().unwrap_or_default();
+  let can_create = viewer.can(CREATE_PERMIT);
 
   let ord = params.ord.unwrap_or(String::from("desc"));
   let page = params.page.unwrap_or(1);
@@ -465,7 +737,7 @@ pub(crate) async fn explore_page(
 
   let entries: Vec<Entry> = match tab.trim() {
     "posts" => {
-      let article_list = QueryArticles::Index(ord.clone(), perpage, page)
+      let article_list = QueryArticles::Index(ord.clone(), perpage, page, viewer.uname)
         .get(&ctx)
         .await?
         .0;
@@ -474,11 +746,10 @@ pub(crate) async fn explore_page(
       let mut posts: Vec<Entry> = article_list
         .into_iter()
         .map(|a| a.into())
-        .into_iter()
-        .chain(piece_list.into_iter().map(|p| p.into()).into_iter())
+        .chain(piece_list.into_iter().map(|p| p.into()))
         .collect();
-      // sort per created_at
-      posts.sort_by(|a, b| b.created_at.cmp(&a.created_at));
+      // sort per published_at
+      posts.sort_by(|a, b| b.published_at.cmp(&a.published_at));
       posts
     }
     "tags" => {
@@ -509,11 +780,19 @@ pub(crate) async fn explore_page(
 struct TagTmpl<'a> {
   page_data: PageData<'a>,
   tag: Tag,
+  /// the description, rendered
+  description: String,
+  /// the tags the tag is nested in, and itself: the name and link
+  crumbs: Vec<(String, String)>,
+  children: Vec<Tag>,
+  aliases: Vec<String>,
   entries: Vec<Entry>,
+  can_edit: bool,
+  is_mod: bool,
   page: i64,
 }
 
-/// `GET tag/:tname` tag page
+/// `GET tag/:tname` tag page, with the entries of the nested tags
 pub(crate) async fn tag_page(
   State(ctx): State<Ctx>,
   Path(tname): Path<String>,
@@ -527,13 +806,15 @@ pub(crate) async fn tag_page(
   let page = params.page.unwrap_or(1);
   let perpage = params.perpage.unwrap_or(42);
 
+  // by an alias too, the entries are under the tag name
   let tag = Tag::get(&ctx, &tname).await?;
 
-  let article_list = QueryArticles::Tag(tname.clone(), perpage, page)
+  let viewer = claim.clone().unwrap_or_default();
+  let article_list = QueryArticles::Tag(tag.tname.clone(), perpage, page, viewer.uname.clone())
     .get(&ctx)
     .await?
     .0;
-  let piece_list = QueryPieces::Tag(tname.clone(), perpage, page)
+  let piece_list = QueryPieces::Tag(tag.tname.clone(), perpage, page)
     .get(&ctx)
     .await?
     .0;
@@ -541,20 +822,130 @@ pub(crate) async fn tag_page(
   let mut entries: Vec<Entry> = article_list
     .into_iter()
     .map(|a| a.into())
-    .into_iter()
-    .chain(piece_list.into_iter().map(|p| p.into()).into_iter())
+    .chain(piece_list.into_iter().map(|p| p.into()))
     .collect();
-  // sort per created_at
-  entries.sort_by(|a, b| b.created_at.cmp(&a.created_at));
+  // sort per published_at
+  entries.sort_by(|a, b| b.published_at.cmp(&a.published_at));
 
-  let page_title = format!("Tag: {}", tname);
+  let crumbs = tag_crumbs(&tag.tname);
+  let children = Tag::children(&ctx, tag.id).await?;
+  let aliases = Tag::aliases(&ctx, tag.id).await?;
+  let description = md2html(&tag.content, "articlepage", "tag");
+
+  let page_title = format!("Tag: {}", tag.tname);
   let page_data = PageData::new(&page_title, &site_config, claim, false);
   let tag_page = TagTmpl {
     page_data,
     tag,
+    description,
+    crumbs,
+    children,
+    aliases,
     entries,
+    can_edit: viewer.can(EIDT_PERMIT),
+    is_mod: viewer.can(MOD_PERMIT),
     page,
   };
 
   Ok(into_response(&tag_page, "html"))
 }
+
+/// the name and link of each tag on the path to a nested tag: `a`, `a/b`
+fn tag_crumbs(tname: &str) -> Vec<(String, String)> {
+  tname
+    .match_indices('/')
+    .map(|(i, _)| &tname[..i])
+    .chain(std::iter::once(tname))
+    .map(|path| {
+      let name = path.rsplit('/').next().unwrap_or(path).to_string();
+      (name, format!("/tag/{}", urlencoding::encode(path)))
+    })
+    .collect()
+}
+
+/// Form data: `/tag/:tname/edit` tag edit form
+#[derive(Deserialize, Validate)]
+pub(crate) struct TagForm {
+  #[validate(length(max = 512))]
+  cover: String,
+  #[validate(length(max = 4096))]
+  content: String,
+  /// the other names, separated by commas
+  #[validate(length(max = 1024))]
+  aliases: String,
+}
+
+/// `POST /tag/:tname/edit` edit the description, cover and aliases
+pub(crate) async fn tag_edit_form(
+  State(ctx): State<Ctx>,
+  Path(tname): Path<String>,
+  check: ClaimCan<EIDT_PERMIT>,
+  ValidatedForm(form): ValidatedForm<TagForm>,
+) -> Result<impl IntoResponse, SsrError> {
+  if !check.can() {
+    return Err(AppError::NoPermission.into());
+  }
+
+  let tag = Tag::get(&ctx, &tname).await?;
+  let tag = Tag::update(&ctx, tag.id, form.content.trim(), form.cover.trim()).await?;
+  let aliases: BTreeSet<String> = form
+    .aliases
+    .split(',')
+    .map(normalize_tname)
+    .filter(|a| !a.is_empty() && *a != tag.tname)
+    .collect();
+  Tag::set_aliases(&ctx, tag.id, aliases).await?;
+
+  Ok(Redirect::to(&tag.link()))
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_diff_view() {
+    let old = "a\nb\nc\nd\ne\nf\ng\nh";
+    let new = "a\nb\nc\nd\ne\nf\nG\nh";
+    let kinds: Vec<&str> = diff_view(old, new).iter().map(|l| l.kind).collect();
+    assert_eq!(kinds, ["skip", "same", "same", "removed", "added", "same"]);
+    assert_eq!(diff_view(old, new)[0].text, "... 4 unchanged lines");
+    assert!(diff_view(old, old).iter().all(|l| l.kind == "skip"));
+  }
+
+  #[test]
+  fn test_publish_state() {
+    let now = 1_700_000_000;
+    assert_eq!(publish_state(DRAFT, "", 0, now).unwrap(), (DRAFT, 0));
+    assert_eq!(publish_state(PUBLISHED, "", 0, now).unwrap(), (PUBLISHED, now));
+    assert_eq!(publish_state(PUBLISHED, "", 42, now).unwrap(), (PUBLISHED, 42));
+    // 2023-11-15T00:00 is after now, 2023-11-14T00:00 before
+    assert_eq!(
+      publish_state(SCHEDULED, "2023-11-15T00:00", 0, now).unwrap(),
+      (SCHEDULED, 1_700_006_400)
+    );
+    assert_eq!(
+      publish_state(SCHEDULED, "2023-11-14T00:00", 0, now).unwrap(),
+      (PUBLISHED, now)
+    );
+    assert!(publish_state(SCHEDULED, "tomorrow", 0, now).is_err());
+    assert!(publish_state("deleted", "", 0, now).is_err());
+
+    assert!(can_transit(DRAFT, SCHEDULED));
+    assert!(can_transit(SCHEDULED, PUBLISHED));
+    assert!(can_transit(PUBLISHED, ARCHIVED));
+    assert!(!can_transit(DRAFT, ARCHIVED));
+    assert!(!can_transit(ARCHIVED, SCHEDULED));
+  }
+
+  #[test]
+  fn test_tag_crumbs() {
+    assert_eq!(
+      tag_crumbs("rust/async"),
+      vec![
+        ("rust".to_string(), "/tag/rust".to_string()),
+        ("async".to_string(), "/tag/rust%2Fasync".to_string()),
+      ]
+    );
+  }
+}
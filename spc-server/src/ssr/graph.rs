@@ -0,0 +1,56 @@
+//! ## Graph
+//! the published articles, their tags and the wikilinks between them:
+//! `/graph.json` nodes and edges, `/graph` the page drawing them.
+
+use askama::Template;
+use axum::{extract::State, response::IntoResponse, Json};
+
+use super::{into_response, PageData};
+use crate::config::get_site_config;
+use crate::db::graph::Graph;
+use crate::db::user::{ClaimCan, READ_PERMIT};
+use crate::error::SsrError;
+use crate::AppState as Ctx;
+
+/// Page data: `graph.html`
+#[derive(Template)]
+#[template(path = "graph.html")]
+struct GraphTmpl<'a> {
+  page_data: PageData<'a>,
+  /// the graph as JSON, safe in a script element
+  graph_json: String,
+  node_count: usize,
+  edge_count: usize,
+}
+
+/// `GET /graph` graph page
+pub(crate) async fn graph_page(
+  State(ctx): State<Ctx>,
+  check: ClaimCan<READ_PERMIT>,
+) -> Result<impl IntoResponse, SsrError> {
+  let claim = check.claim;
+  let site_config = get_site_config(&ctx.sled).unwrap_or_default();
+  let graph = Graph::get(&ctx).await?;
+  let graph_json = serde_json::to_string(&graph)
+    .unwrap_or_default()
+    .replace("</", "<\\/");
+
+  let page_data = PageData::new("Graph", &site_config, claim, false);
+  let graph_page = GraphTmpl {
+    page_data,
+    graph_json,
+    node_count: graph.nodes.len(),
+    edge_count: graph.edges.len(),
+  };
+
+  Ok(into_response(&graph_page, "html"))
+}
+
+/// `GET /graph.json` nodes and edges
+pub(crate) async fn graph_json(
+  State(ctx): State<Ctx>,
+  _check: ClaimCan<READ_PERMIT>,
+) -> Result<impl IntoResponse, SsrError> {
+  let graph = Graph::get(&ctx).await?;
+  Ok(Json(graph))
+}
@@ -19,7 +19,10 @@ pub mod admin;
 pub mod article;
 pub mod auth;
 pub mod feed;
+pub mod graph;
 pub mod home;
+pub mod search;
+pub mod syndication;
 pub mod upload;
 pub mod user;
 
@@ -197,6 +200,16 @@ pub(super) mod filters {
     Ok(formatted)
   }
 
+  /// timestamp as the value of a `datetime-local` input, in UTC
+  pub fn datetime_local(timestamp: &i64) -> TmplResult<String> {
+    let formatted = match NaiveDateTime::from_timestamp_opt(*timestamp, 0) {
+      Some(dt) if *timestamp > 0 => dt.format("%Y-%m-%dT%H:%M").to_string(),
+      _ => String::new(),
+    };
+
+    Ok(formatted)
+  }
+
   #[cfg(test)]
   mod tests {
     use super::*;
@@ -209,6 +222,15 @@ pub(super) mod filters {
       );
     }
 
+    #[test]
+    fn test_datetime_local() {
+      assert_eq!(
+        datetime_local(&1700006400).unwrap(),
+        String::from("2023-11-15T00:00")
+      );
+      assert_eq!(datetime_local(&0).unwrap(), String::new());
+    }
+
     #[test]
     fn test_num_unit() {
       assert_eq!(num_unit(&13976654).unwrap(), String::from("13M+"));
@@ -0,0 +1,61 @@
+//! ## Search
+//! full-text search over articles, pieces, notes, feeds and tags.
+
+use askama::Template;
+use axum::{
+  extract::{Query, State},
+  response::IntoResponse,
+};
+
+use super::{into_response, PageData};
+use crate::config::get_site_config;
+use crate::db::search::{SearchHit, SearchQuery};
+use crate::db::user::{ClaimCan, READ_PERMIT};
+use crate::error::SsrError;
+use crate::AppState as Ctx;
+
+/// Page data: `search.html`
+#[derive(Template)]
+#[template(path = "search.html")]
+struct SearchTmpl<'a> {
+  page_data: PageData<'a>,
+  query: SearchQuery,
+  hits: Vec<SearchHit>,
+  /// a full page of hits, maybe more
+  has_more: bool,
+  /// query string of the next page
+  next_page: String,
+}
+
+/// `GET /search?q=&ty=&tag=&author=&page=` search page
+pub(crate) async fn search_page(
+  State(ctx): State<Ctx>,
+  Query(query): Query<SearchQuery>,
+  check: ClaimCan<READ_PERMIT>,
+) -> Result<impl IntoResponse, SsrError> {
+  let claim = check.claim;
+  let site_config = get_site_config(&ctx.sled).unwrap_or_default();
+  let viewer = claim.clone().unwrap_or_default().uname;
+  let hits = query.get(&ctx, &viewer).await?;
+
+  let has_more = hits.len() as i64 >= query.perpage();
+  let next_page = format!(
+    "q={}&ty={}&tag={}&author={}&page={}",
+    urlencoding::encode(&query.q),
+    urlencoding::encode(query.ty.as_deref().unwrap_or_default()),
+    urlencoding::encode(query.tag.as_deref().unwrap_or_default()),
+    urlencoding::encode(query.author.as_deref().unwrap_or_default()),
+    query.page.unwrap_or(1) + 1,
+  );
+
+  let page_data = PageData::new("Search", &site_config, claim, false);
+  let search_page = SearchTmpl {
+    page_data,
+    query,
+    hits,
+    has_more,
+    next_page,
+  };
+
+  Ok(into_response(&search_page, "html"))
+}
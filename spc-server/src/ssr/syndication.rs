@@ -0,0 +1,454 @@
+//! ## Syndication: RSS, Atom and JSON Feed output
+//! of the site, a user or a tag, the latest articles and pieces.
+//!
+//! The format is told by the path: `feed.xml` RSS, `atom.xml` Atom and
+//! `feed.json` JSON Feed. Conditional GET by ETag or Last-Modified.
+
+use atom_syndication as atom;
+use axum::{
+  extract::{Path, State},
+  http::{header, HeaderMap, StatusCode, Uri},
+  response::{IntoResponse, Response},
+};
+use chrono::{DateTime, FixedOffset, TimeZone, Utc};
+use serde::Serialize;
+
+use crate::config::{get_site_config, SiteConfig};
+use crate::db::article::{Article, Piece, QueryArticles, QueryPieces};
+use crate::db::tag::Tag;
+use crate::db::user::User;
+use crate::error::SsrError;
+use crate::util::md::md2html;
+use crate::AppState as Ctx;
+
+/// number of items in a feed
+const FEED_SIZE: i64 = 20;
+
+/// Output format of a feed, told by the path.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Format {
+  Rss,
+  Atom,
+  Json,
+}
+
+impl Format {
+  fn from_path(path: &str) -> Self {
+    if path.ends_with("atom.xml") {
+      Format::Atom
+    } else if path.ends_with(".json") {
+      Format::Json
+    } else {
+      Format::Rss
+    }
+  }
+
+  fn content_type(self) -> &'static str {
+    match self {
+      Format::Rss => "application/rss+xml; charset=utf-8",
+      Format::Atom => "application/atom+xml; charset=utf-8",
+      Format::Json => "application/feed+json; charset=utf-8",
+    }
+  }
+}
+
+/// An article or piece in a feed, links absolute.
+struct FeedItem {
+  /// unique and permanent
+  id: String,
+  title: String,
+  link: String,
+  content: String,
+  author: String,
+  published: i64,
+  updated: i64,
+}
+
+impl FeedItem {
+  fn from_article(a: Article, domain: &str) -> Self {
+    let published = a.published_at();
+    let link = format!("{}/article/{}/view", domain, a.id);
+    FeedItem {
+      id: link.clone(),
+      title: a.title,
+      link,
+      content: absolute_links(&md2html(&a.content, "articlepage", "tag"), domain),
+      author: a.uname,
+      published,
+      updated: a.updated_at.max(published),
+    }
+  }
+
+  fn from_piece(p: Piece, domain: &str) -> Self {
+    let title: String = p.content.chars().take(64).collect();
+    // pieces have no page of their own, but on the profile
+    FeedItem {
+      id: format!("{}/piece/{}", domain, p.id),
+      title,
+      link: format!("{}/user/{}", domain, p.uname),
+      content: absolute_links(&md2html(&p.content, "articlepage", "tag"), domain),
+      author: p.uname,
+      published: p.created_at,
+      updated: p.created_at,
+    }
+  }
+}
+
+/// A feed to output, of the site, a user or a tag.
+struct FeedOut {
+  title: String,
+  description: String,
+  /// page of the feed on site
+  link: String,
+  /// the feed itself
+  self_link: String,
+  items: Vec<FeedItem>,
+}
+
+impl FeedOut {
+  fn new(
+    site_config: &SiteConfig,
+    scope: &str,
+    link: &str,
+    uri: &Uri,
+    articles: Vec<Article>,
+    pieces: Vec<Piece>,
+  ) -> Self {
+    let domain = site_config.domain.trim_end_matches('/');
+    let mut items: Vec<FeedItem> = articles
+      .into_iter()
+      .map(|a| FeedItem::from_article(a, domain))
+      .chain(pieces.into_iter().map(|p| FeedItem::from_piece(p, domain)))
+      .collect();
+    items.sort_by_key(|i| std::cmp::Reverse(i.published));
+    items.truncate(FEED_SIZE as usize);
+
+    let title = if scope.is_empty() {
+      site_config.site_name.clone()
+    } else {
+      format!("{} - {}", scope, site_config.site_name)
+    };
+    FeedOut {
+      title,
+      description: site_config.slogan.clone(),
+      link: format!("{}{}", domain, link),
+      self_link: format!("{}{}", domain, uri.path()),
+      items,
+    }
+  }
+
+  /// the last time any item changed, 0 if none.
+  fn last_modified(&self) -> i64 {
+    self.items.iter().map(|i| i.updated).max().unwrap_or(0)
+  }
+
+  fn to_rss(&self) -> String {
+    let items = self
+      .items
+      .iter()
+      .map(|i| rss::Item {
+        title: Some(i.title.clone()),
+        link: Some(i.link.clone()),
+        description: Some(i.content.clone()),
+        author: Some(i.author.clone()),
+        guid: Some(rss::Guid { value: i.id.clone(), permalink: i.id == i.link }),
+        pub_date: Some(to_datetime(i.published).to_rfc2822()),
+        ..Default::default()
+      })
+      .collect();
+    let channel = rss::Channel {
+      title: self.title.clone(),
+      link: self.link.clone(),
+      description: self.description.clone(),
+      last_build_date: Some(to_datetime(self.last_modified()).to_rfc2822()),
+      items,
+      ..Default::default()
+    };
+
+    channel.to_string()
+  }
+
+  fn to_atom(&self) -> String {
+    let entries = self
+      .items
+      .iter()
+      .map(|i| atom::Entry {
+        title: atom::Text::plain(i.title.clone()),
+        id: i.id.clone(),
+        updated: to_datetime(i.updated),
+        published: Some(to_datetime(i.published)),
+        authors: vec![atom::Person { name: i.author.clone(), ..Default::default() }],
+        links: vec![atom::Link { href: i.link.clone(), ..Default::default() }],
+        content: Some(atom::Content {
+          value: Some(i.content.clone()),
+          content_type: Some(String::from("html")),
+          ..Default::default()
+        }),
+        ..Default::default()
+      })
+      .collect();
+    let feed = atom::Feed {
+      title: atom::Text::plain(self.title.clone()),
+      id: self.self_link.clone(),
+      updated: to_datetime(self.last_modified()),
+      subtitle: Some(atom::Text::plain(self.description.clone())),
+      links: vec![
+        atom::Link { href: self.link.clone(), ..Default::default() },
+        atom::Link {
+          href: self.self_link.clone(),
+          rel: String::from("self"),
+          ..Default::default()
+        },
+      ],
+      entries,
+      ..Default::default()
+    };
+
+    feed.to_string()
+  }
+
+  fn to_json(&self) -> String {
+    let feed = JsonFeed {
+      version: "https://jsonfeed.org/version/1.1",
+      title: &self.title,
+      description: &self.description,
+      home_page_url: &self.link,
+      feed_url: &self.self_link,
+      items: self
+        .items
+        .iter()
+        .map(|i| JsonItem {
+          id: &i.id,
+          url: &i.link,
+          title: &i.title,
+          content_html: &i.content,
+          date_published: to_datetime(i.published).to_rfc3339(),
+          date_modified: to_datetime(i.updated).to_rfc3339(),
+          authors: vec![JsonAuthor { name: &i.author }],
+        })
+        .collect(),
+    };
+
+    serde_json::to_string(&feed).unwrap_or_default()
+  }
+
+  /// respond in `format`, or Not Modified if the client has it already.
+  fn into_response(self, format: Format, headers: &HeaderMap) -> Response {
+    let body = match format {
+      Format::Rss => self.to_rss(),
+      Format::Atom => self.to_atom(),
+      Format::Json => self.to_json(),
+    };
+    let etag = format!("\"{}\"", &content_hash(&body)[..32]);
+    let last_modified = to_datetime(self.last_modified())
+      .format("%a, %d %b %Y %H:%M:%S GMT")
+      .to_string();
+
+    if not_modified(headers, &etag, self.last_modified()) {
+      return (
+        StatusCode::NOT_MODIFIED,
+        [(header::ETAG, etag), (header::LAST_MODIFIED, last_modified)],
+      )
+        .into_response();
+    }
+
+    (
+      [
+        (header::CONTENT_TYPE, format.content_type().to_string()),
+        (header::ETAG, etag),
+        (header::LAST_MODIFIED, last_modified),
+      ],
+      body,
+    )
+      .into_response()
+  }
+}
+
+/// JSON Feed, version 1.1
+#[derive(Serialize)]
+struct JsonFeed<'a> {
+  version: &'static str,
+  title: &'a str,
+  description: &'a str,
+  home_page_url: &'a str,
+  feed_url: &'a str,
+  items: Vec<JsonItem<'a>>,
+}
+
+#[derive(Serialize)]
+struct JsonItem<'a> {
+  id: &'a str,
+  url: &'a str,
+  title: &'a str,
+  content_html: &'a str,
+  date_published: String,
+  date_modified: String,
+  authors: Vec<JsonAuthor<'a>>,
+}
+
+#[derive(Serialize)]
+struct JsonAuthor<'a> {
+  name: &'a str,
+}
+
+fn to_datetime(timestamp: i64) -> DateTime<FixedOffset> {
+  Utc
+    .timestamp_opt(timestamp, 0)
+    .single()
+    .unwrap_or_default()
+    .into()
+}
+
+fn content_hash(content: &str) -> String {
+  ring::digest::digest(&ring::digest::SHA256, content.as_bytes())
+    .as_ref()
+    .iter()
+    .map(|b| format!("{b:02x}"))
+    .collect()
+}
+
+/// make the site links in html absolute, as feed readers have no base.
+fn absolute_links(html: &str, domain: &str) -> String {
+  html
+    .replace("href=\"/", &format!("href=\"{domain}/"))
+    .replace("src=\"/", &format!("src=\"{domain}/"))
+}
+
+/// if the client has the feed already, by ETag first, else Last-Modified.
+fn not_modified(headers: &HeaderMap, etag: &str, last_modified: i64) -> bool {
+  if let Some(if_none_match) = headers.get(header::IF_NONE_MATCH) {
+    let if_none_match = if_none_match.to_str().unwrap_or_default();
+    return if_none_match
+      .split(',')
+      .any(|tag| {
+        let tag = tag.trim();
+        tag == "*" || tag.trim_start_matches("W/") == etag
+      });
+  }
+  headers
+    .get(header::IF_MODIFIED_SINCE)
+    .and_then(|since| since.to_str().ok())
+    .and_then(|since| DateTime::parse_from_rfc2822(since).ok())
+    .is_some_and(|since| last_modified <= since.timestamp())
+}
+
+/// `GET /feed.xml`, `/atom.xml` or `/feed.json` site feed
+pub(crate) async fn site_feed(
+  State(ctx): State<Ctx>,
+  uri: Uri,
+  headers: HeaderMap,
+) -> Result<impl IntoResponse, SsrError> {
+  let site_config = get_site_config(&ctx.sled).unwrap_or_default();
+  // as seen by anyone: published and not hidden only
+  let viewer = String::new();
+  let articles =
+    QueryArticles::Index(String::from("desc"), FEED_SIZE, 1, viewer.clone())
+      .get(&ctx)
+      .await?
+      .0;
+  let pieces = QueryPieces::Index(String::from("desc"), FEED_SIZE, 1)
+    .get(&ctx)
+    .await?
+    .0;
+
+  let feed = FeedOut::new(&site_config, "", "/explore", &uri, articles, pieces);
+  Ok(feed.into_response(Format::from_path(uri.path()), &headers))
+}
+
+/// `GET /user/:uname/feed.xml`, `atom.xml` or `feed.json` user feed
+pub(crate) async fn user_feed(
+  State(ctx): State<Ctx>,
+  Path(uname): Path<String>,
+  uri: Uri,
+  headers: HeaderMap,
+) -> Result<impl IntoResponse, SsrError> {
+  let site_config = get_site_config(&ctx.sled).unwrap_or_default();
+  let user = User::get(&ctx, &uname).await?;
+  let viewer = String::new();
+  let articles =
+    QueryArticles::User(uname.clone(), 1, FEED_SIZE, 1, viewer.clone())
+      .get(&ctx)
+      .await?
+      .0;
+  let pieces = QueryPieces::User(uname.clone(), 1, FEED_SIZE, 1, viewer)
+    .get(&ctx)
+    .await?
+    .0;
+
+  let scope = format!("@{}", user.username);
+  let link = format!("/user/{}", user.username);
+  let feed = FeedOut::new(&site_config, &scope, &link, &uri, articles, pieces);
+  Ok(feed.into_response(Format::from_path(uri.path()), &headers))
+}
+
+/// `GET /tag/:tag/feed.xml`, `atom.xml` or `feed.json` tag feed
+pub(crate) async fn tag_feed(
+  State(ctx): State<Ctx>,
+  Path(tname): Path<String>,
+  uri: Uri,
+  headers: HeaderMap,
+) -> Result<impl IntoResponse, SsrError> {
+  let site_config = get_site_config(&ctx.sled).unwrap_or_default();
+  let tag = Tag::get(&ctx, &tname).await?;
+  let viewer = String::new();
+  let articles = QueryArticles::Tag(tag.tname.clone(), FEED_SIZE, 1, viewer)
+    .get(&ctx)
+    .await?
+    .0;
+  let pieces = QueryPieces::Tag(tag.tname.clone(), FEED_SIZE, 1)
+    .get(&ctx)
+    .await?
+    .0;
+
+  let scope = format!("#{}", tag.tname);
+  let link = tag.link();
+  let feed = FeedOut::new(&site_config, &scope, &link, &uri, articles, pieces);
+  Ok(feed.into_response(Format::from_path(uri.path()), &headers))
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use axum::http::HeaderValue;
+
+  #[test]
+  fn test_absolute_links() {
+    assert_eq!(
+      absolute_links(r#"<a href="/tag/x">x</a><img src="/a.png">"#, "https://s.io"),
+      r#"<a href="https://s.io/tag/x">x</a><img src="https://s.io/a.png">"#
+    );
+    assert_eq!(
+      absolute_links(r#"<a href="https://o.io/">o</a>"#, "https://s.io"),
+      r#"<a href="https://o.io/">o</a>"#
+    );
+  }
+
+  #[test]
+  fn test_not_modified() {
+    let mut headers = HeaderMap::new();
+    assert!(!not_modified(&headers, "\"abc\"", 1700000000));
+
+    headers.insert(
+      header::IF_MODIFIED_SINCE,
+      HeaderValue::from_static("Tue, 14 Nov 2023 22:13:20 GMT"),
+    );
+    assert!(not_modified(&headers, "\"abc\"", 1700000000));
+    assert!(!not_modified(&headers, "\"abc\"", 1700000001));
+
+    // the ETag wins over the date
+    headers.insert(header::IF_NONE_MATCH, HeaderValue::from_static("\"xyz\""));
+    assert!(!not_modified(&headers, "\"abc\"", 1700000000));
+    headers.insert(
+      header::IF_NONE_MATCH,
+      HeaderValue::from_static("\"xyz\", W/\"abc\""),
+    );
+    assert!(not_modified(&headers, "\"abc\"", 1700000001));
+  }
+
+  #[test]
+  fn test_format() {
+    assert_eq!(Format::from_path("/feed.xml"), Format::Rss);
+    assert_eq!(Format::from_path("/user/x/atom.xml"), Format::Atom);
+    assert_eq!(Format::from_path("/tag/x/feed.json"), Format::Json);
+  }
+}
@@ -57,23 +57,24 @@ pub(crate) async fn profile_page(
 
   let user = User::get(&ctx, &uname).await?;
 
-  let article_list = QueryArticles::User(uname.clone(), 1, perpage, page)
-    .get(&ctx)
-    .await?
-    .0;
-  let piece_list = QueryPieces::User(uname.clone(), 1, perpage, page)
-    .get(&ctx)
-    .await?
-    .0;
+  let article_list =
+    QueryArticles::User(uname.clone(), 1, perpage, page, claim_uname.clone())
+      .get(&ctx)
+      .await?
+      .0;
+  let piece_list =
+    QueryPieces::User(uname.clone(), 1, perpage, page, claim_uname.clone())
+      .get(&ctx)
+      .await?
+      .0;
 
   let mut entries: Vec<Entry> = article_list
     .into_iter()
     .map(|a| a.into())
-    .into_iter()
-    .chain(piece_list.into_iter().map(|p| p.into()).into_iter())
+    .chain(piece_list.into_iter().map(|p| p.into()))
     .collect();
-  // sort per created_at
-  entries.sort_by(|a, b| b.created_at.cmp(&a.created_at));
+  // sort per published_at
+  entries.sort_by(|a, b| b.published_at.cmp(&a.published_at));
 
   // get status
   let tree = ctx
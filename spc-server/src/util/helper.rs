@@ -3,7 +3,7 @@
 use once_cell::sync::Lazy;
 use regex::Regex;
 
-pub use spc_util::{extract_element, capture_element};
+pub use spc_util::extract_element;
 
 // let fail in test
 static RE_HTTP: Lazy<Regex> = Lazy::new(|| Regex::new(r"https?://").unwrap()); 
@@ -1,4 +1,4 @@
 //! ## Process Markdown
 //! render Markdown(with latex) to HTML, pre-process math block or inline math
 
-pub use spc_util::md2html;
+pub use spc_util::{md2html, md2html_with};
@@ -0,0 +1,162 @@
+//! ## Text documents as a CRDT, built with the `crdt` feature.
+//! Edited offline by the browser and merged later by the server.
+//!
+//! A document is created once, by the server, from the current text; the
+//! replicas are all loaded from its bytes, so that they share the same text
+//! object. Positions are counted in Unicode code points, as in the pads.
+
+use automerge::transaction::Transactable;
+use automerge::{
+  ActorId, AutoCommit, ChangeHash, LoadOptions, ObjId, ObjType, ReadDoc,
+  TextEncoding, ROOT,
+};
+
+/// key of the text object in the root map
+const TEXT_KEY: &str = "text";
+
+/// Error on a CRDT document.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CrdtError(String);
+
+impl std::fmt::Display for CrdtError {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    write!(f, "crdt error: {}", self.0)
+  }
+}
+
+impl std::error::Error for CrdtError {}
+
+impl From<automerge::AutomergeError> for CrdtError {
+  fn from(e: automerge::AutomergeError) -> Self {
+    CrdtError(e.to_string())
+  }
+}
+
+/// A text document as a CRDT.
+#[derive(Debug, Clone)]
+pub struct CrdtText {
+  doc: AutoCommit,
+  text: ObjId,
+}
+
+impl CrdtText {
+  /// create a document holding `text`.
+  pub fn new(text: &str) -> Result<Self, CrdtError> {
+    let mut doc = AutoCommit::new_with_encoding(TextEncoding::UnicodeCodePoint);
+    let obj = doc.put_object(ROOT, TEXT_KEY, ObjType::Text)?;
+    doc.splice_text(&obj, 0, 0, text)?;
+    doc.commit();
+    Ok(Self { doc, text: obj })
+  }
+
+  /// load a document from its saved bytes.
+  pub fn load(bytes: &[u8]) -> Result<Self, CrdtError> {
+    let options = LoadOptions::new().text_encoding(TextEncoding::UnicodeCodePoint);
+    let doc = AutoCommit::load_with_options(bytes, options)?;
+    let text = match doc.get(ROOT, TEXT_KEY)? {
+      Some((_, obj)) if doc.object_type(&obj) == Ok(ObjType::Text) => obj,
+      _ => return Err(CrdtError(String::from("no text in document"))),
+    };
+    Ok(Self { doc, text })
+  }
+
+  /// set the actor of the following edits, unique to each replica.
+  pub fn set_actor(&mut self, actor: &[u8]) {
+    self.doc.set_actor(ActorId::from(actor));
+  }
+
+  /// save the whole document.
+  pub fn save(&mut self) -> Vec<u8> {
+    self.doc.save()
+  }
+
+  /// the heads of the document, hex encoded, to save the changes after.
+  pub fn heads(&mut self) -> Vec<String> {
+    self.doc.get_heads().iter().map(|h| h.to_string()).collect()
+  }
+
+  /// save the changes made after the given heads.
+  pub fn save_after(&mut self, heads: &[String]) -> Result<Vec<u8>, CrdtError> {
+    let heads = heads
+      .iter()
+      .map(|h| h.parse::<ChangeHash>())
+      .collect::<Result<Vec<_>, _>>()
+      .map_err(|e| CrdtError(e.to_string()))?;
+    Ok(self.doc.save_after(&heads))
+  }
+
+  /// the current text.
+  pub fn text(&self) -> String {
+    self.doc.text(&self.text).unwrap_or_default()
+  }
+
+  /// delete `del` characters at `pos` and insert `insert` there.
+  pub fn splice(&mut self, pos: usize, del: usize, insert: &str) -> Result<(), CrdtError> {
+    self.doc.splice_text(&self.text, pos, del as isize, insert)?;
+    Ok(())
+  }
+
+  /// replace the text by `new`, editing only the differing range.
+  ///
+  /// For texts edited outside of the CRDT, e.g. on a live pad.
+  pub fn update(&mut self, new: &str) -> Result<(), CrdtError> {
+    let old = self.text();
+    if old == new {
+      return Ok(());
+    }
+    let old: Vec<char> = old.chars().collect();
+    let new: Vec<char> = new.chars().collect();
+    let prefix = old.iter().zip(&new).take_while(|(a, b)| a == b).count();
+    let suffix = old[prefix..]
+      .iter()
+      .rev()
+      .zip(new[prefix..].iter().rev())
+      .take_while(|(a, b)| a == b)
+      .count();
+    let insert: String = new[prefix..new.len() - suffix].iter().collect();
+    self.splice(prefix, old.len() - prefix - suffix, &insert)
+  }
+
+  /// commit the pending edits, returns if there were any.
+  pub fn commit(&mut self) -> bool {
+    self.doc.commit().is_some()
+  }
+
+  /// merge the changes of a saved document, or saved after some heads.
+  pub fn merge(&mut self, bytes: &[u8]) -> Result<(), CrdtError> {
+    self.doc.commit();
+    self.doc.load_incremental(bytes)?;
+    Ok(())
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_offline_merge() {
+    let mut server = CrdtText::new("hello world").unwrap();
+    let bytes = server.save();
+
+    // two replicas edit offline
+    let mut alice = CrdtText::load(&bytes).unwrap();
+    alice.set_actor(b"alice");
+    let heads = alice.heads();
+    alice.splice(0, 5, "howdy").unwrap();
+    alice.commit();
+    let mut bob = CrdtText::load(&bytes).unwrap();
+    bob.set_actor(b"bob");
+    bob.update("hello world!").unwrap();
+    bob.commit();
+
+    // and the server merges them in any order
+    server.merge(&bob.save()).unwrap();
+    server.merge(&alice.save_after(&heads).unwrap()).unwrap();
+    assert_eq!(server.text(), "howdy world!");
+    alice.merge(&server.save()).unwrap();
+    assert_eq!(alice.text(), "howdy world!");
+
+    assert!(CrdtText::load(b"not a document").is_err());
+  }
+}
@@ -0,0 +1,198 @@
+//! ## Line based diff and three-way merge.
+
+/// Skip the middle of a diff above this many line pairs, keep it quadratic.
+const MAX_CELLS: usize = 4_000_000;
+
+/// A line in the diff of two texts, line ending included.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DiffLine<'a> {
+  Same(&'a str),
+  Added(&'a str),
+  Removed(&'a str),
+}
+
+/// Result of a three-way merge.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Merge {
+  /// merged text, with conflict markers if any
+  pub text: String,
+  /// number of conflicting hunks
+  pub conflicts: usize,
+}
+
+/// split text into lines, keep the line ending
+fn lines(s: &str) -> Vec<&str> {
+  s.split_inclusive('\n').collect()
+}
+
+/// pairs of indices of the common lines, i.e. longest common subsequence,
+/// increasing on both sides.
+fn matches(a: &[&str], b: &[&str]) -> Vec<(usize, usize)> {
+  let prefix = a.iter().zip(b).take_while(|(x, y)| x == y).count();
+  let suffix = a[prefix..]
+    .iter()
+    .rev()
+    .zip(b[prefix..].iter().rev())
+    .take_while(|(x, y)| x == y)
+    .count();
+  let mut pairs: Vec<(usize, usize)> = (0..prefix).map(|i| (i, i)).collect();
+
+  let mid_a = &a[prefix..a.len() - suffix];
+  let mid_b = &b[prefix..b.len() - suffix];
+  let (n, m) = (mid_a.len(), mid_b.len());
+  // too large to diff, as if the middle was rewritten
+  if n > 0 && m > 0 && (n + 1) * (m + 1) <= MAX_CELLS {
+    // lcs[i][j]: length of the lcs of mid_a[i..] and mid_b[j..]
+    let mut lcs = vec![0u32; (n + 1) * (m + 1)];
+    for i in (0..n).rev() {
+      for j in (0..m).rev() {
+        lcs[i * (m + 1) + j] = if mid_a[i] == mid_b[j] {
+          lcs[(i + 1) * (m + 1) + j + 1] + 1
+        } else {
+          lcs[(i + 1) * (m + 1) + j].max(lcs[i * (m + 1) + j + 1])
+        };
+      }
+    }
+    let (mut i, mut j) = (0, 0);
+    while i < n && j < m {
+      if mid_a[i] == mid_b[j] {
+        pairs.push((prefix + i, prefix + j));
+        i += 1;
+        j += 1;
+      } else if lcs[(i + 1) * (m + 1) + j] >= lcs[i * (m + 1) + j + 1] {
+        i += 1;
+      } else {
+        j += 1;
+      }
+    }
+  }
+
+  pairs.extend((0..suffix).map(|k| (a.len() - suffix + k, b.len() - suffix + k)));
+  pairs
+}
+
+/// diff two texts line by line.
+pub fn diff_lines<'a>(old: &'a str, new: &'a str) -> Vec<DiffLine<'a>> {
+  let (a, b) = (lines(old), lines(new));
+  let mut diff = Vec::with_capacity(a.len().max(b.len()));
+  let (mut i, mut j) = (0, 0);
+  for (mi, mj) in matches(&a, &b).into_iter().chain([(a.len(), b.len())]) {
+    diff.extend(a[i..mi].iter().map(|l| DiffLine::Removed(l)));
+    diff.extend(b[j..mj].iter().map(|l| DiffLine::Added(l)));
+    if mi < a.len() {
+      diff.push(DiffLine::Same(a[mi]));
+    }
+    i = mi + 1;
+    j = mj + 1;
+  }
+  diff
+}
+
+/// three-way merge `ours` and `theirs`, both changed from `base`.
+///
+/// The hunks changed differently on both sides are kept with conflict
+/// markers, labelled by `labels`.
+pub fn merge3(
+  base: &str,
+  ours: &str,
+  theirs: &str,
+  labels: (&str, &str),
+) -> Merge {
+  let (b, o, t) = (lines(base), lines(ours), lines(theirs));
+  let mut to_ours = vec![None; b.len()];
+  for (i, j) in matches(&b, &o) {
+    to_ours[i] = Some(j);
+  }
+  let mut to_theirs = vec![None; b.len()];
+  for (i, j) in matches(&b, &t) {
+    to_theirs[i] = Some(j);
+  }
+
+  let mut text = String::with_capacity(ours.len().max(theirs.len()));
+  let mut conflicts = 0;
+  let (mut ib, mut io, mut it) = (0, 0, 0);
+  loop {
+    // the next base line kept on both sides
+    let stable = (ib..b.len()).find_map(|k| match (to_ours[k], to_theirs[k]) {
+      (Some(ko), Some(kt)) => Some((k, ko, kt)),
+      _ => None,
+    });
+    let (kb, ko, kt) = stable.unwrap_or((b.len(), o.len(), t.len()));
+    if (kb, ko, kt) == (ib, io, it) {
+      if stable.is_none() {
+        break;
+      }
+      text.push_str(b[kb]);
+      ib += 1;
+      io += 1;
+      it += 1;
+      continue;
+    }
+
+    let (hunk_b, hunk_o, hunk_t) = (&b[ib..kb], &o[io..ko], &t[it..kt]);
+    if hunk_o == hunk_b {
+      hunk_t.iter().for_each(|l| text.push_str(l));
+    } else if hunk_t == hunk_b || hunk_o == hunk_t {
+      hunk_o.iter().for_each(|l| text.push_str(l));
+    } else {
+      conflicts += 1;
+      push_marker(&mut text, &format!("<<<<<<< {}", labels.0));
+      hunk_o.iter().for_each(|l| text.push_str(l));
+      push_marker(&mut text, "=======");
+      hunk_t.iter().for_each(|l| text.push_str(l));
+      push_marker(&mut text, &format!(">>>>>>> {}", labels.1));
+    }
+    (ib, io, it) = (kb, ko, kt);
+  }
+
+  Merge { text, conflicts }
+}
+
+/// push a conflict marker on its own line
+fn push_marker(text: &mut String, marker: &str) {
+  if !text.is_empty() && !text.ends_with('\n') {
+    text.push('\n');
+  }
+  text.push_str(marker);
+  text.push('\n');
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_diff_lines() {
+    assert_eq!(
+      diff_lines("a\nb\nc\n", "a\nx\nc\nd"),
+      vec![
+        DiffLine::Same("a\n"),
+        DiffLine::Removed("b\n"),
+        DiffLine::Added("x\n"),
+        DiffLine::Same("c\n"),
+        DiffLine::Added("d"),
+      ]
+    );
+    assert_eq!(diff_lines("", ""), vec![]);
+  }
+
+  #[test]
+  fn test_merge3() {
+    let base = "title\n\none\ntwo\nthree\n";
+    // changed on different lines
+    let merged = merge3(base, "title\n\nONE\ntwo\nthree\n", "title\n\none\ntwo\nthree\nfour\n", ("pad", "article"));
+    assert_eq!(merged.text, "title\n\nONE\ntwo\nthree\nfour\n");
+    assert_eq!(merged.conflicts, 0);
+    // changed the same way
+    let merged = merge3(base, "title\n\none\n2\nthree\n", "title\n\none\n2\nthree\n", ("pad", "article"));
+    assert_eq!(merged.text, "title\n\none\n2\nthree\n");
+    assert_eq!(merged.conflicts, 0);
+    // changed the same line differently
+    let merged = merge3(base, "title\n\none\n2\nthree", "title\n\none\nII\nthree", ("pad", "article"));
+    assert_eq!(
+      merged.text,
+      "title\n\none\n<<<<<<< pad\n2\nthree\n=======\nII\nthree\n>>>>>>> article\n"
+    );
+    assert_eq!(merged.conflicts, 1);
+  }
+}
@@ -4,11 +4,17 @@
 use std::collections::BTreeSet;
 use regex::Regex;
 use once_cell::sync::Lazy;
-use pulldown_cmark::{html, CodeBlockKind, Event, Options, Tag};
+use pulldown_cmark::{html, CodeBlockKind, Event, LinkType, Options, Tag};
 use syntect::{
   highlighting::ThemeSet, html::highlighted_html_for_string, parsing::SyntaxSet,
 };
 
+pub mod diff;
+pub mod wikilink;
+pub mod wire;
+#[cfg(feature = "crdt")]
+pub mod crdt;
+
 /// generate a new id with expiration time that is hex encoded.
 /// format: "hex-timestamp_id"
 pub fn gen_expirable_id(seconds: i64, key: &str) -> String {
@@ -46,6 +52,25 @@ pub fn extract_element(input: &str, re: &str, pat: &str) -> BTreeSet<String> {
   extracted
 }
 
+/// rewrite the hashtag `#from` into `#into`, whole tags only: `#from/sub`
+/// and `#from2` are other tags; none if no `#from`.
+pub fn rename_hashtag(input: &str, from: &str, into: &str) -> Option<String> {
+  let mut renamed = false;
+  let output = HASHTAG.replace_all(input, |caps: &regex::Captures| {
+    if &caps[2] == from {
+      renamed = true;
+      format!("{}#{}", &caps[1], into)
+    } else {
+      caps[0].to_string()
+    }
+  });
+  if renamed {
+    Some(output.into_owned())
+  } else {
+    None
+  }
+}
+
 /// capture element from string via regex.
 ///
 pub fn capture_element(input: &str, re: &str) -> BTreeSet<String> {
@@ -97,6 +122,9 @@ fn is_inline_latex(s: &str) -> bool {
   s.len() > 1 && [s[0], s[s.len() - 1]] == [b'$', b'$']
 }
 
+/// a hashtag as in `extract_element`
+static HASHTAG: Lazy<Regex> = Lazy::new(|| Regex::new(r"(\s)#([^\s#]+)").unwrap());
+
 static THEME_SET: Lazy<syntect::highlighting::ThemeSet> =
   Lazy::new(ThemeSet::load_defaults);
 static SYNTAX_SET: Lazy<SyntaxSet> = Lazy::new(SyntaxSet::load_defaults_newlines);
@@ -119,6 +147,17 @@ impl<'a, I: Iterator<Item = Event<'a>>> Iterator for SyntaxPreprocessor<'a, I> {
           .into(),
         ));
       }
+      Event::Start(Tag::Link(LinkType::Inline, dest, title))
+        if title.as_ref() == WIKILINK_MISSING_CLASS =>
+      {
+        return Some(Event::Html(
+          format!(
+            "<a class=\"{WIKILINK_MISSING_CLASS}\" href=\"{}\">",
+            escape_html(&dest)
+          )
+          .into(),
+        ));
+      }
       Event::Html(h) => {
         code.push_str(&h);
         "html".into()
@@ -191,17 +230,204 @@ const OPTIONS: Options = Options::all();
 // You should have received a copy of the GNU General Public License
 // along with cmark-syntax. If not, see <http://www.gnu.org/licenses/>
 pub fn md2html(md: &str, wikilink_base: &str, tag_base: &str) -> String {
-  let md = pre_process_md(md, wikilink_base, tag_base);
+  md2html_with(md, wikilink_base, tag_base, |_: &str| true)
+}
+
+/// class of the wikilinks to missing pages
+pub const WIKILINK_MISSING_CLASS: &str = "wikilink-missing";
+
+/// class of the transcluded content of `![[title]]`
+pub const TRANSCLUSION_CLASS: &str = "transclusion";
+
+/// the deepest transclusion: an embed in an embed in ... the page
+pub const MAX_EMBED_DEPTH: usize = 3;
+
+/// marks an embed link, made in `pre_process_md`, by its link title
+const EMBED_MARKER: &str = "spc-embed";
+
+/// Resolve the wikilinks when rendering.
+pub trait LinkResolver {
+  /// if the target title or id of a wikilink exists
+  fn exists(&self, target: &str) -> bool;
+
+  /// the markdown to transclude for `![[target]]`, none if missing
+  fn embed(&self, _target: &str) -> Option<String> {
+    None
+  }
+
+  /// the names of the page rendered, its title and id: embedding it is a cycle
+  fn page_names(&self) -> Vec<String> {
+    Vec::new()
+  }
+}
+
+impl<F: Fn(&str) -> bool> LinkResolver for F {
+  fn exists(&self, target: &str) -> bool {
+    self(target)
+  }
+}
+
+/// convert like [`md2html`], with a link resolver: the unresolved wikilinks
+/// get the class [`WIKILINK_MISSING_CLASS`], the embeds are transcluded.
+pub fn md2html_with<R: LinkResolver>(
+  md: &str, wikilink_base: &str, tag_base: &str, resolver: R,
+) -> String {
+  let mut embedding = resolver.page_names();
+  render_md(md, wikilink_base, tag_base, &resolver, &mut embedding)
+}
+
+/// render, `embedding` the pages this markdown is embedded in, the page first
+fn render_md(
+  md: &str, wikilink_base: &str, tag_base: &str,
+  resolver: &dyn LinkResolver, embedding: &mut Vec<String>,
+) -> String {
+  let md = pre_process_md(md, wikilink_base, tag_base, resolver);
   let parser = pulldown_cmark::Parser::new_ext(&md, OPTIONS);
-  let processed = SyntaxPreprocessor::new(parser);
+  let events: Vec<Event> = SyntaxPreprocessor::new(parser).collect();
+  let events = anchor_headings(events);
+  let events = transclude(events, wikilink_base, tag_base, resolver, embedding);
   let mut html_output = String::with_capacity(md.len() * 2);
-  html::push_html(&mut html_output, processed);
+  html::push_html(&mut html_output, events.into_iter());
   html_output
 }
 
+/// the anchor of a heading: lowercase words joined by `-`
+pub fn heading_slug(text: &str) -> String {
+  let mut slug = String::with_capacity(text.len());
+  for c in text.chars() {
+    if c.is_alphanumeric() {
+      slug.extend(c.to_lowercase());
+    } else if (c.is_whitespace() || c == '-' || c == '_') && !slug.ends_with('-') {
+      slug.push('-');
+    }
+  }
+  let slug = slug.trim_matches('-');
+  if slug.is_empty() {
+    String::from("section")
+  } else {
+    slug.to_owned()
+  }
+}
+
+/// give the headings without an explicit `{#id}` an id from their text,
+/// made unique by a `-n` suffix.
+fn anchor_headings(events: Vec<Event<'_>>) -> Vec<Event<'_>> {
+  let mut used: BTreeSet<String> = BTreeSet::new();
+  let mut anchored = Vec::with_capacity(events.len());
+  for (i, event) in events.iter().enumerate() {
+    match event {
+      Event::Start(Tag::Heading(level, None, classes)) => {
+        let text: String = events[i + 1..]
+          .iter()
+          .take_while(|e| !matches!(e, Event::End(Tag::Heading(..))))
+          .filter_map(|e| match e {
+            Event::Text(t) | Event::Code(t) => Some(t.as_ref()),
+            _ => None,
+          })
+          .collect();
+        let slug = heading_slug(&text);
+        let mut id = slug.clone();
+        let mut n = 0;
+        while used.contains(&id) {
+          n += 1;
+          id = format!("{slug}-{n}");
+        }
+        used.insert(id.clone());
+
+        let class = if classes.is_empty() {
+          String::new()
+        } else {
+          format!(" class=\"{}\"", escape_html(&classes.join(" ")))
+        };
+        anchored.push(Event::Html(format!("<{level} id=\"{id}\"{class}>").into()));
+      }
+      other => anchored.push(other.clone()),
+    }
+  }
+  anchored
+}
+
+/// replace the embed links with the rendered markdown of their targets;
+/// an embed alone in a paragraph replaces the paragraph.
+fn transclude<'a>(
+  events: Vec<Event<'a>>, wikilink_base: &str, tag_base: &str,
+  resolver: &dyn LinkResolver, embedding: &mut Vec<String>,
+) -> Vec<Event<'a>> {
+  let mut transcluded = Vec::with_capacity(events.len());
+  let mut iter = events.into_iter().peekable();
+  while let Some(event) = iter.next() {
+    let dest = match event {
+      Event::Start(Tag::Link(LinkType::Inline, dest, title))
+        if title.as_ref() == EMBED_MARKER =>
+      {
+        dest
+      }
+      other => {
+        transcluded.push(other);
+        continue;
+      }
+    };
+    // the link text is the target again
+    for e in iter.by_ref() {
+      if matches!(e, Event::End(Tag::Link(..))) {
+        break;
+      }
+    }
+    let alone = matches!(transcluded.last(), Some(Event::Start(Tag::Paragraph)))
+      && matches!(iter.peek(), Some(Event::End(Tag::Paragraph)));
+    if alone {
+      transcluded.pop();
+      iter.next();
+    }
+
+    let encoded = dest.rsplit('/').next().unwrap_or_default();
+    let target = urlencoding::decode(encoded).unwrap_or_default();
+    let html = embed_html(&target, &dest, wikilink_base, tag_base, resolver, embedding);
+    transcluded.push(Event::Html(html.into()));
+  }
+  transcluded
+}
+
+/// the html of `![[target]]`, a note in place of a cycle or too deep embed
+fn embed_html(
+  target: &str, dest: &str, wikilink_base: &str, tag_base: &str,
+  resolver: &dyn LinkResolver, embedding: &mut Vec<String>,
+) -> String {
+  let title = escape_html(target);
+  let dest = escape_html(dest);
+  let error = |note: &str| {
+    format!(
+      "<div class=\"{TRANSCLUSION_CLASS} {TRANSCLUSION_CLASS}-error\">\
+      {note}: <a href=\"{dest}\">{title}</a></div>"
+    )
+  };
+  if embedding.iter().any(|name| name == target) {
+    return error("Embedding itself");
+  }
+  // the page is not an embed
+  if embedding.len().saturating_sub(resolver.page_names().len()) >= MAX_EMBED_DEPTH {
+    return error("Embedded too deep");
+  }
+
+  match resolver.embed(target) {
+    Some(md) => {
+      embedding.push(target.to_owned());
+      let html = render_md(&md, wikilink_base, tag_base, resolver, embedding);
+      embedding.pop();
+      format!(
+        "<div class=\"{TRANSCLUSION_CLASS}\"><a class=\"{TRANSCLUSION_CLASS}-src\" \
+        href=\"{dest}\">{title}</a>{html}</div>"
+      )
+    }
+    None => format!("<a class=\"{WIKILINK_MISSING_CLASS}\" href=\"{dest}\">{title}</a>"),
+  }
+}
+
 /// unify the block format for math
 /// maybe do more pre-process in the future 
-fn pre_process_md(md: &str, wikilink_base: &str, tag_base: &str) -> String {
+fn pre_process_md(
+  md: &str, wikilink_base: &str, tag_base: &str, resolver: &dyn LinkResolver,
+) -> String {
   let mut content = md.to_string();
   // process inline math code $math$
   let inline_maths = capture_element(&content, r"[\s]+\$[^$]+\$[\s]+");
@@ -218,40 +444,46 @@ fn pre_process_md(md: &str, wikilink_base: &str, tag_base: &str) -> String {
     content = content.replace(math, &math_block);
   }
 
-  // Process tags
-  let hashtags = extract_element(&content, "", "#");
-  for tag in &hashtags {
-    let tag_link = format!("[#{tag}](/{tag_base}/{tag})");
-    content = content.replace(&format!("#{tag}"), &tag_link);
-  }
-
-  let wikilinks = capture_element(&content, "");
-  for link in &wikilinks {
-    let title = link.replace("[", "").replace("]", "");
-    if title.trim().is_empty() {
-      continue;
-    }
-    
-    // custom wikilink title:  src_title and target_title
-    let parts = title.split_once("|");
-    let src_title = parts.and_then(|s| {
-      let src = s.0.trim();
-      if src.len() > 0 { Some(src) } else { None }
-    })
-    .unwrap_or(title.trim());
-
-    let tar_title = parts.and_then(|s| {
-      let tar = s.1.trim();
-      if tar.len() > 0 { Some(tar) } else { None }
-    })
-    .unwrap_or(title.trim());
-    
-    let encoded_title = urlencoding::encode(tar_title);
-    let wiki_link = format!("[{src_title}](/{wikilink_base}/{})", encoded_title);
-    content = content.replace(link, &wiki_link);
+  // Process tags, only where extracted: after a space, not in `[[a#b]]`
+  let content = HASHTAG.replace_all(&content, |caps: &regex::Captures| {
+    let tname = urlencoding::encode(&caps[2]);
+    format!("{}[#{}](/{tag_base}/{})", &caps[1], &caps[2], tname)
+  });
+
+  let mut linked = String::with_capacity(content.len());
+  let mut last = 0;
+  for link in wikilink::parse_wikilinks(&content) {
+    linked.push_str(&content[last..link.span.start]);
+    last = link.span.end;
+
+    let anchor = link
+      .heading
+      .map(|h| format!("#{}", heading_slug(h)))
+      .unwrap_or_default();
+    let src_title = link.text;
+    let wiki_link = if link.embed && !link.target.is_empty() {
+      // marked by the link title, transcluded in `transclude`
+      format!(
+        "[{}](/{wikilink_base}/{} \"{EMBED_MARKER}\")",
+        link.target,
+        urlencoding::encode(link.target)
+      )
+    } else if link.target.is_empty() {
+      format!("[{src_title}]({anchor})")
+    } else if resolver.exists(link.target) {
+      format!("[{src_title}](/{wikilink_base}/{}{anchor})", urlencoding::encode(link.target))
+    } else {
+      // marked by the link title, given the class in `SyntaxPreprocessor`
+      format!(
+        "[{src_title}](/{wikilink_base}/{}{anchor} \"{WIKILINK_MISSING_CLASS}\")",
+        urlencoding::encode(link.target)
+      )
+    };
+    linked.push_str(&wiki_link);
   }
+  linked.push_str(&content[last..]);
 
-  return content;
+  linked
 }
 
 
@@ -377,6 +609,19 @@ mod tests {
     );
   }
 
+  #[test]
+  fn test_rename_hashtag() {
+    assert_eq!(
+      rename_hashtag("on #js, #js/react #jsx\n#js", "js", "javascript"),
+      Some("on #js, #js/react #jsx\n#javascript".to_string())
+    );
+    assert_eq!(
+      rename_hashtag("a #js b", "js", "javascript"),
+      Some("a #javascript b".to_string())
+    );
+    assert_eq!(rename_hashtag("#js", "js", "javascript"), None);
+  }
+
   #[test]
   fn test_capture_element() {
     assert_eq!(
@@ -384,4 +629,71 @@ mod tests {
       BTreeSet::from(["[[just got]]".to_string()])
     );
   }
+
+  #[test]
+  fn test_md2html_with() {
+    let md = "[[Here]] and [[the gone|Gone Page]]";
+    let html = md2html_with(md, "articlepage", "tag", |t: &str| t == "Here");
+    assert!(html.contains(r#"<a href="/articlepage/Here">Here</a>"#));
+    assert!(html.contains(
+      r#"<a class="wikilink-missing" href="/articlepage/Gone%20Page">the gone</a>"#
+    ));
+    assert!(!md2html(md, "articlepage", "tag").contains("wikilink-missing"));
+  }
+
+  #[test]
+  fn test_md2html_heading_link() {
+    let html = md2html("[[Rust#Intro]] on #Intro `[[Code]]`", "articlepage", "tag");
+    assert!(html.contains(r#"<a href="/articlepage/Rust#intro">Rust#Intro</a>"#));
+    assert!(html.contains(r#"<a href="/tag/Intro">#Intro</a>"#));
+    assert!(html.contains("<code>[[Code]]</code>"));
+    let html = md2html("on #rust/async", "articlepage", "tag");
+    assert!(html.contains(r#"<a href="/tag/rust%2Fasync">#rust/async</a>"#));
+  }
+
+  #[test]
+  fn test_heading_ids() {
+    assert_eq!(heading_slug(" Hello, `World` -- 2 "), "hello-world-2");
+    assert_eq!(heading_slug("??"), "section");
+    let html = md2html("# Intro\n\n## Intro\n\n## Own {#own}", "articlepage", "tag");
+    assert!(html.contains(r#"<h1 id="intro">Intro</h1>"#));
+    assert!(html.contains(r#"<h2 id="intro-1">Intro</h2>"#));
+    assert!(html.contains(r#"<h2 id="own">Own</h2>"#));
+  }
+
+  struct Pages(Vec<(&'static str, &'static str)>);
+
+  impl LinkResolver for Pages {
+    fn exists(&self, target: &str) -> bool {
+      self.0.iter().any(|(t, _)| *t == target)
+    }
+
+    fn embed(&self, target: &str) -> Option<String> {
+      self.0.iter().find(|(t, _)| *t == target).map(|(_, md)| md.to_string())
+    }
+
+    fn page_names(&self) -> Vec<String> {
+      vec!["Page".to_string()]
+    }
+  }
+
+  #[test]
+  fn test_transclusion() {
+    let pages = Pages(vec![
+      ("Page", "![[Snippet]]"),
+      ("Snippet", "**shared** and ![[Page]]"),
+    ]);
+    let html = md2html_with("intro\n\n![[Snippet]]\n\n![[Gone]]", "articlepage", "tag", pages);
+    assert!(html.contains(
+      r#"<div class="transclusion"><a class="transclusion-src" href="/articlepage/Snippet">Snippet</a><p><strong>shared</strong> and "#
+    ));
+    assert!(html.contains("Embedding itself: <a href=\"/articlepage/Page\">Page</a>"));
+    assert!(html.contains(r#"<a class="wikilink-missing" href="/articlepage/Gone">Gone</a>"#));
+    assert!(!html.contains("<p><div"));
+
+    let pages = Pages(vec![("A", "![[B]]"), ("B", "![[C]]"), ("C", "![[D]]"), ("D", "deep")]);
+    let html = md2html_with("![[A]]", "articlepage", "tag", pages);
+    assert!(html.contains("Embedded too deep: <a href=\"/articlepage/D\">D</a>"));
+    assert!(!html.contains("deep</p>"));
+  }
 }
@@ -0,0 +1,246 @@
+//! ## Wikilinks: `[[target]]`, `[[text|target]]` and `[[target#heading]]`,
+//! and embeds: `![[target]]`.
+//!
+//! Links in fenced code blocks and inline code spans are left alone.
+
+use std::ops::Range;
+
+/// A wikilink in a text, spans are byte ranges in the text.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct WikiLink<'a> {
+  /// the whole `[[...]]`, or `![[...]]`
+  pub span: Range<usize>,
+  /// `![[...]]`, to transclude the target
+  pub embed: bool,
+  /// the text shown: the alias, or all within the brackets
+  pub text: &'a str,
+  /// the title or id linked to, empty for a heading on the same page
+  pub target: &'a str,
+  /// where the target is, to rewrite it
+  pub target_span: Range<usize>,
+  /// the heading after `#` in the target
+  pub heading: Option<&'a str>,
+}
+
+/// the wikilinks in `input`, in order.
+pub fn parse_wikilinks(input: &str) -> Vec<WikiLink<'_>> {
+  let mut links = Vec::new();
+  // the char and length of the open code fence
+  let mut fence: Option<(u8, usize)> = None;
+  let mut offset = 0;
+  for line in input.split_inclusive('\n') {
+    let start = offset;
+    offset += line.len();
+
+    if let Some((ch, len)) = fence_marker(line.trim_start()) {
+      match fence {
+        None => {
+          fence = Some((ch, len));
+          continue;
+        }
+        Some((open_ch, open_len)) if open_ch == ch && len >= open_len => {
+          fence = None;
+          continue;
+        }
+        _ => {}
+      }
+    }
+    if fence.is_none() {
+      scan_line(input, start, start + line.len(), &mut links);
+    }
+  }
+
+  links
+}
+
+/// the targets of the wikilinks in `input`, in order, without repeats.
+pub fn wikilink_targets(input: &str) -> Vec<&str> {
+  let mut targets: Vec<&str> = Vec::new();
+  for link in parse_wikilinks(input) {
+    if !link.target.is_empty() && !targets.contains(&link.target) {
+      targets.push(link.target);
+    }
+  }
+  targets
+}
+
+/// rewrite the links to `old` into links to `new`, keeping their texts
+/// and headings; none if nothing links to `old`.
+pub fn rename_wikilinks(input: &str, old: &str, new: &str) -> Option<String> {
+  let old = old.trim();
+  let new = new.trim();
+  let spans: Vec<Range<usize>> = parse_wikilinks(input)
+    .into_iter()
+    .filter(|link| !old.is_empty() && link.target == old)
+    .map(|link| link.target_span)
+    .collect();
+  if spans.is_empty() {
+    return None;
+  }
+
+  let mut output = String::with_capacity(input.len());
+  let mut last = 0;
+  for span in spans {
+    output.push_str(&input[last..span.start]);
+    output.push_str(new);
+    last = span.end;
+  }
+  output.push_str(&input[last..]);
+
+  Some(output)
+}
+
+/// the char and length of a code fence opening the line
+fn fence_marker(line: &str) -> Option<(u8, usize)> {
+  let ch = *line.as_bytes().first()?;
+  if ch != b'`' && ch != b'~' {
+    return None;
+  }
+  let len = line.bytes().take_while(|b| *b == ch).count();
+  if len >= 3 {
+    Some((ch, len))
+  } else {
+    None
+  }
+}
+
+/// collect the links in `input[start..end]`, a line outside code blocks
+fn scan_line<'a>(input: &'a str, start: usize, end: usize, links: &mut Vec<WikiLink<'a>>) {
+  let bytes = input.as_bytes();
+  let mut i = start;
+  while i < end {
+    // skip inline code spans, closed by a run of as many backticks
+    if bytes[i] == b'`' {
+      let run = bytes[i..end].iter().take_while(|b| **b == b'`').count();
+      i += run;
+      let mut j = i;
+      while j < end {
+        let close = bytes[j..end].iter().take_while(|b| **b == b'`').count();
+        if close == run {
+          i = j + close;
+          break;
+        }
+        j += close.max(1);
+      }
+      continue;
+    }
+
+    if bytes[i..end].starts_with(b"[[") {
+      let inner_start = i + 2;
+      let inner_end = inner_start
+        + bytes[inner_start..end]
+          .iter()
+          .take_while(|b| !matches!(b, b'[' | b']' | b'\n'))
+          .count();
+      if inner_end > inner_start && bytes[inner_end..end].starts_with(b"]]") {
+        if let Some(mut link) = to_link(input, inner_start, inner_end) {
+          if i > start && bytes[i - 1] == b'!' {
+            link.embed = true;
+            link.span.start -= 1;
+          }
+          links.push(link);
+          i = inner_end + 2;
+          continue;
+        }
+      }
+    }
+    i += 1;
+  }
+}
+
+/// the link within the brackets at `input[start..end]`, none if empty
+fn to_link(input: &str, start: usize, end: usize) -> Option<WikiLink<'_>> {
+  let inner = &input[start..end];
+  let (text, target_start) = match inner.find('|') {
+    Some(bar) => (inner[..bar].trim(), start + bar + 1),
+    None => (inner.trim(), start),
+  };
+  let mut target_part = trimmed(input, target_start, end);
+  if target_part.is_empty() {
+    // `[[text|]]` links to the text
+    target_part = trimmed(input, start, target_start.saturating_sub(1).max(start));
+  }
+
+  let (target_span, heading) = match input[target_part.clone()].find('#') {
+    Some(hash) => {
+      let heading = input[target_part.start + hash + 1..target_part.end].trim();
+      let target = trimmed(input, target_part.start, target_part.start + hash);
+      (target, Some(heading).filter(|h| !h.is_empty()))
+    }
+    None => (target_part, None),
+  };
+  let target = &input[target_span.clone()];
+  if target.is_empty() && heading.is_none() {
+    return None;
+  }
+
+  Some(WikiLink {
+    span: start - 2..end + 2,
+    embed: false,
+    text: if text.is_empty() { inner.trim() } else { text },
+    target,
+    target_span,
+    heading,
+  })
+}
+
+/// the range of `input[start..end]` without the whitespace around
+fn trimmed(input: &str, start: usize, end: usize) -> Range<usize> {
+  let s = &input[start..end];
+  let lead = s.len() - s.trim_start().len();
+  let trail = s.len() - s.trim_end().len();
+  if lead == s.len() {
+    return start..start;
+  }
+  start + lead..end - trail
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_parse_wikilinks() {
+    let text = "a [[Rust]] b [[the book | Rust Book#Ch 1]] `[[code]]` [[ ]] [[[x]]\n\
+      ```\n[[fenced]]\n```\n[[#Top]] [[Bar|]]";
+    let links = parse_wikilinks(text);
+    let got: Vec<(&str, &str, Option<&str>)> =
+      links.iter().map(|l| (l.text, l.target, l.heading)).collect();
+    assert_eq!(
+      got,
+      vec![
+        ("Rust", "Rust", None),
+        ("the book", "Rust Book", Some("Ch 1")),
+        ("x", "x", None),
+        ("#Top", "", Some("Top")),
+        ("Bar", "Bar", None),
+      ]
+    );
+    assert_eq!(&text[links[0].span.clone()], "[[Rust]]");
+    assert_eq!(&text[links[1].target_span.clone()], "Rust Book");
+    assert_eq!(wikilink_targets(text), vec!["Rust", "Rust Book", "x", "Bar"]);
+  }
+
+  #[test]
+  fn test_parse_embeds() {
+    let text = "![[Snippet]] and [[Link]]!";
+    let links = parse_wikilinks(text);
+    assert!(links[0].embed && !links[1].embed);
+    assert_eq!(&text[links[0].span.clone()], "![[Snippet]]");
+    assert_eq!(&text[links[1].span.clone()], "[[Link]]");
+  }
+
+  #[test]
+  fn test_rename_wikilinks() {
+    let text = "[[Bar]], [[Foo Bar]], [[b|Bar#h]], [[a.*|Bar]] `[[Bar]]`";
+    assert_eq!(
+      rename_wikilinks(text, "Bar", "Baz").unwrap(),
+      "[[Baz]], [[Foo Bar]], [[b|Baz#h]], [[a.*|Baz]] `[[Bar]]`"
+    );
+    assert_eq!(
+      rename_wikilinks("[[a (b)]] [[x|a (b)]] [[a (b)|x]]", "a (b)", "c").unwrap(),
+      "[[c]] [[x|c]] [[a (b)|x]]"
+    );
+    assert_eq!(rename_wikilinks("[[Foo Bar]]", "Bar", "Baz"), None);
+  }
+}
@@ -0,0 +1,122 @@
+//! ## Binary wire format of pad messages.
+//! MessagePack, deflated when large, shared by the server and the browser.
+//!
+//! A frame is a flag byte followed by the payload, the flag tells whether
+//! the payload is deflated.
+
+use serde::{de::DeserializeOwned, Serialize};
+
+/// flag of a payload sent as is
+const RAW: u8 = 0;
+/// flag of a deflated payload
+const DEFLATED: u8 = 1;
+/// deflate payloads larger than this, smaller ones barely shrink
+const COMPRESS_ABOVE: usize = 512;
+/// refuse to inflate a payload beyond this
+const MAX_INFLATED: usize = 64 << 20;
+/// refuse to inflate a payload from a client beyond this, an edit is at
+/// most 100 KB of text
+pub const MAX_CLIENT_INFLATED: usize = 128 << 10;
+
+/// Error while encoding or decoding a frame.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct WireError(String);
+
+impl std::fmt::Display for WireError {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    write!(f, "wire error: {}", self.0)
+  }
+}
+
+impl std::error::Error for WireError {}
+
+/// encode a message into a frame.
+///
+/// Fields are named, so a frame decodes to the same shape as the JSON.
+pub fn encode<T: Serialize + ?Sized>(msg: &T) -> Result<Vec<u8>, WireError> {
+  let payload = rmp_serde::to_vec_named(msg).map_err(|e| WireError(e.to_string()))?;
+  if payload.len() <= COMPRESS_ABOVE {
+    let mut frame = Vec::with_capacity(payload.len() + 1);
+    frame.push(RAW);
+    frame.extend_from_slice(&payload);
+    return Ok(frame);
+  }
+  let mut frame = vec![DEFLATED];
+  frame.extend_from_slice(&miniz_oxide::deflate::compress_to_vec(&payload, 6));
+  Ok(frame)
+}
+
+/// decode a frame into a message.
+pub fn decode<T: DeserializeOwned>(frame: &[u8]) -> Result<T, WireError> {
+  decode_with_limit(frame, MAX_INFLATED)
+}
+
+/// decode a frame into a message, refusing to inflate beyond `limit`.
+pub fn decode_with_limit<T: DeserializeOwned>(
+  frame: &[u8],
+  limit: usize,
+) -> Result<T, WireError> {
+  let inflated;
+  let payload = match frame.split_first() {
+    Some((&RAW, payload)) => payload,
+    Some((&DEFLATED, deflated)) => {
+      inflated = miniz_oxide::inflate::decompress_to_vec_with_limit(deflated, limit)
+        .map_err(|e| WireError(format!("failed to inflate: {e:?}")))?;
+      &inflated[..]
+    }
+    Some((flag, _)) => return Err(WireError(format!("unknown flag {flag}"))),
+    None => return Err(WireError("empty frame".into())),
+  };
+  rmp_serde::from_slice(payload).map_err(|e| WireError(e.to_string()))
+}
+
+/// decode a frame into JSON text, for clients handling JSON messages.
+pub fn to_json(frame: &[u8]) -> Result<String, WireError> {
+  let value: serde_json::Value = decode(frame)?;
+  Ok(value.to_string())
+}
+
+/// encode JSON text into a frame.
+pub fn from_json(json: &str) -> Result<Vec<u8>, WireError> {
+  let value: serde_json::Value =
+    serde_json::from_str(json).map_err(|e| WireError(e.to_string()))?;
+  encode(&value)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use serde::Deserialize;
+
+  #[derive(Debug, PartialEq, Serialize, Deserialize)]
+  enum Msg {
+    Snapshot { revision: usize, text: String },
+    Ops(Vec<i64>),
+  }
+
+  #[test]
+  fn test_roundtrip() {
+    let small = Msg::Snapshot { revision: 3, text: "hello".into() };
+    let frame = encode(&small).unwrap();
+    assert_eq!(frame[0], RAW);
+    assert_eq!(decode::<Msg>(&frame).unwrap(), small);
+
+    let large = Msg::Snapshot { revision: 7, text: "lorem ipsum ".repeat(200) };
+    let frame = encode(&large).unwrap();
+    assert_eq!(frame[0], DEFLATED);
+    assert!(frame.len() < 200);
+    assert_eq!(decode::<Msg>(&frame).unwrap(), large);
+
+    assert!(decode_with_limit::<Msg>(&frame, 256).is_err());
+    assert!(decode::<Msg>(&[]).is_err());
+    assert!(decode::<Msg>(&[9, 1, 2]).is_err());
+  }
+
+  #[test]
+  fn test_json() {
+    let json = r#"{"Ops":[5,-2,3]}"#;
+    let frame = from_json(json).unwrap();
+    assert_eq!(decode::<Msg>(&frame).unwrap(), Msg::Ops(vec![5, -2, 3]));
+    assert_eq!(to_json(&frame).unwrap(), json);
+  }
+}
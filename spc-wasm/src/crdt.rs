@@ -0,0 +1,79 @@
+//! CRDT documents edited offline on frontend, with the `crdt` feature.
+
+use operational_transform::Operation;
+use wasm_bindgen::prelude::*;
+use spc_util::crdt::CrdtText;
+
+use crate::OpSeq;
+
+/// A document fetched from the server, edited offline and merged back.
+#[wasm_bindgen]
+pub struct CrdtDoc(CrdtText);
+
+#[wasm_bindgen]
+impl CrdtDoc {
+  /// load a document saved by the server, or by `save`
+  pub fn load(bytes: &[u8]) -> Result<CrdtDoc, JsError> {
+    CrdtText::load(bytes)
+      .map(CrdtDoc)
+      .map_err(|e| JsError::new(&e.to_string()))
+  }
+
+  /// the current text
+  pub fn text(&self) -> String {
+    self.0.text()
+  }
+
+  /// apply an edit of the editor, as sent to the pads
+  pub fn apply(&mut self, operation: &OpSeq) -> Result<(), JsError> {
+    let mut index = 0;
+    for op in operation.0.ops() {
+      match op {
+        Operation::Retain(n) => index += *n as usize,
+        Operation::Delete(n) => self.splice(index, *n as usize, "")?,
+        Operation::Insert(s) => {
+          self.splice(index, 0, s)?;
+          index += bytecount::num_chars(s.as_bytes());
+        }
+      }
+    }
+    Ok(())
+  }
+
+  /// delete `del` characters at `pos` and insert `insert` there
+  pub fn splice(&mut self, pos: usize, del: usize, insert: &str) -> Result<(), JsError> {
+    self
+      .0
+      .splice(pos, del, insert)
+      .map_err(|e| JsError::new(&e.to_string()))
+  }
+
+  /// commit the pending edits, returns if there were any
+  pub fn commit(&mut self) -> bool {
+    self.0.commit()
+  }
+
+  /// save the whole document, e.g. to keep it offline
+  pub fn save(&mut self) -> Vec<u8> {
+    self.0.save()
+  }
+
+  /// the heads of the document, as a JSON array
+  pub fn heads(&mut self) -> String {
+    serde_json::to_string(&self.0.heads()).unwrap_or_default()
+  }
+
+  /// save the changes after the heads, a JSON array, to send to the server
+  pub fn save_after(&mut self, heads: &str) -> Result<Vec<u8>, JsError> {
+    let heads: Vec<String> = serde_json::from_str(heads)?;
+    self
+      .0
+      .save_after(&heads)
+      .map_err(|e| JsError::new(&e.to_string()))
+  }
+
+  /// merge a document returned by the server
+  pub fn merge(&mut self, bytes: &[u8]) -> Result<(), JsError> {
+    self.0.merge(bytes).map_err(|e| JsError::new(&e.to_string()))
+  }
+}
@@ -9,6 +9,9 @@ use wasm_bindgen::prelude::*;
 
 pub mod utils;
 pub mod md;
+pub mod wire;
+#[cfg(feature = "crdt")]
+pub mod crdt;
 
 /// This is an wrapper around `operational_transform::OperationSeq`, which is
 /// necessary for Wasm compatibility through `wasm-bindgen`.
@@ -0,0 +1,22 @@
+//! Decode and encode the binary pad messages on frontend.
+
+use serde::Serialize;
+use wasm_bindgen::prelude::*;
+use spc_util::wire;
+
+/// decode a binary message from the server into an object, of the same
+/// shape as the JSON message
+#[wasm_bindgen]
+pub fn decode_message(frame: &[u8]) -> Result<JsValue, JsError> {
+  let value: serde_json::Value =
+    wire::decode(frame).map_err(|e| JsError::new(&e.to_string()))?;
+  value
+    .serialize(&serde_wasm_bindgen::Serializer::json_compatible())
+    .map_err(|e| JsError::new(&e.to_string()))
+}
+
+/// encode a JSON message to the server into binary
+#[wasm_bindgen]
+pub fn encode_message(json: &str) -> Result<Vec<u8>, JsError> {
+  wire::from_json(json).map_err(|e| JsError::new(&e.to_string()))
+}
@@ -11,10 +11,16 @@ import padRaw from "../../spc-server/src/pad/mdpad.rs?raw";
 import sample from "../../README.md?raw";
 import languages from "./lib/languages.json";
 import names from "./lib/bands.json";
-import Pad, { UserInfo } from "./lib/mdpad";
+import Pad, {
+  ChatMessage, Comment, Suggestion, UserInfo,
+} from "./lib/mdpad";
 import useHash from "../useHash";
+import Chat from "./components/Chat";
+import Comments from "./components/Comments";
+import Suggestions from "./components/Suggestions";
 import ConnectionStatus from "./components/ConnectionStatus";
 import Footer from "./components/Footer";
+import Snapshots from "./components/Snapshots";
 import User from "./components/User";
 import SplitEditor from "./SplitEditor";
 
@@ -22,10 +28,14 @@ function getWsUri(id: string) {
   return (
     (window.location.origin.startsWith("https") ? "wss://" : "ws://") +
     window.location.host +
-    `/api/socket/${id}`
+    `/api/socket/${id}${window.location.search}`
   );
 }
 
+function getEventsUri(id: string) {
+  return `${window.location.origin}/api/events/${id}${window.location.search}`;
+}
+
 function generateName() {
   return names[Math.floor(Math.random() * names.length)];
 }
@@ -41,6 +51,10 @@ export default function PadPage() {
     "connected" | "disconnected" | "desynchronized"
   >("disconnected");
   const [users, setUsers] = useState<Record<number, UserInfo>>({});
+  const [chats, setChats] = useState<ChatMessage[]>([]);
+  const [comments, setComments] = useState<Record<string, Comment>>({});
+  const [suggestions, setSuggestions] = useState<Suggestion[]>([]);
+  const [suggesting, setSuggesting] = useState(false);
   const [name, setName] = useStorage("name", generateName);
   const [hue, setHue] = useStorage("hue", generateHue);
   const [editor, setEditor] = useState<editor.IStandaloneCodeEditor>();
@@ -55,8 +69,12 @@ export default function PadPage() {
       model.setEOL(0); // LF
       pad.current = new Pad({
         uri: getWsUri(id),
+        fallbackUri: getEventsUri(id),
         editor,
-        onConnected: () => setConnection("connected"),
+        onConnected: () => {
+          setConnection("connected");
+          setChats([]); // the backlog is sent again on connection
+        },
         onDisconnected: () => setConnection("disconnected"),
         onDesynchronized: () => {
           setConnection("desynchronized");
@@ -73,6 +91,10 @@ export default function PadPage() {
           }
         },
         onChangeUsers: setUsers,
+        onChat: (chat) => setChats((chats) => [...chats, chat]),
+        onComment: (comment) =>
+          setComments((comments) => ({ ...comments, [comment.key]: comment })),
+        onSuggestions: setSuggestions,
       });
       return () => {
         pad.current?.dispose();
@@ -300,6 +322,46 @@ export default function PadPage() {
               <User key={id} info={info} darkMode={darkMode} />
             ))}
           </Stack>
+          <Heading mt={4} mb={1.5} size="sm">
+            Snapshots
+          </Heading>
+          <Snapshots id={id} darkMode={darkMode} />
+          <Heading mt={4} mb={1.5} size="sm">
+            Suggestions
+          </Heading>
+          <Suggestions
+            suggestions={suggestions}
+            suggesting={suggesting}
+            onSuggesting={(on) => {
+              const done = pad.current?.setSuggesting(on) ?? false;
+              if (done) setSuggesting(on);
+              return done;
+            }}
+            onAccept={(key) => pad.current?.accept(key) ?? false}
+            onReject={(key) => pad.current?.reject(key) ?? false}
+            darkMode={darkMode}
+          />
+          <Heading mt={4} mb={1.5} size="sm">
+            Comments
+          </Heading>
+          <Comments
+            comments={Object.values(comments)}
+            onAdd={(text) => pad.current?.addComment(text) ?? false}
+            onReply={(parent, text) => pad.current?.reply(parent, text) ?? false}
+            onResolve={(key, resolved) =>
+              pad.current?.resolve(key, resolved) ?? false
+            }
+            onReveal={(key) => pad.current?.revealComment(key)}
+            darkMode={darkMode}
+          />
+          <Heading mt={4} mb={1.5} size="sm">
+            Chat
+          </Heading>
+          <Chat
+            chats={chats}
+            onSend={(text) => pad.current?.sendChat(text) ?? false}
+            darkMode={darkMode}
+          />
           <Button
             size="sm"
             w="full"
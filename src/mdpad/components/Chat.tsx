@@ -0,0 +1,58 @@
+import { Box, Input, Stack, Text } from "@chakra-ui/react";
+import { KeyboardEvent, useEffect, useRef, useState } from "react";
+import { ChatMessage } from "../lib/mdpad";
+
+type ChatProps = {
+  chats: ChatMessage[];
+  onSend: (text: string) => boolean;
+  darkMode: boolean;
+};
+
+export default function Chat({ chats, onSend, darkMode }: ChatProps) {
+  const [text, setText] = useState("");
+  const bottomRef = useRef<HTMLDivElement>(null);
+
+  useEffect(() => {
+    bottomRef.current?.scrollIntoView({ block: "nearest" });
+  }, [chats]);
+
+  function handleKeyDown(event: KeyboardEvent<HTMLInputElement>) {
+    if (event.key === "Enter" && text.trim() && onSend(text)) {
+      setText("");
+    }
+  }
+
+  return (
+    <Box>
+      <Stack spacing={1} mb={1.5} fontSize="sm" maxH="xs" overflowY="auto">
+        {chats.map((chat, i) => (
+          <Box key={i}>
+            <Text
+              as="span"
+              fontWeight="medium"
+              fontStyle={chat.uname ? "normal" : "italic"}
+            >
+              {chat.name}
+            </Text>
+            <Text as="span" color={darkMode ? "gray.400" : "gray.500"} ml={1}>
+              {new Date(chat.time * 1000).toLocaleTimeString()}
+            </Text>
+            <Text whiteSpace="pre-wrap">{chat.text}</Text>
+          </Box>
+        ))}
+        <div ref={bottomRef} />
+      </Stack>
+      <Input
+        size="sm"
+        variant="outline"
+        placeholder="Say something..."
+        maxLength={2000}
+        bgColor={darkMode ? "#3c3c3c" : "white"}
+        borderColor={darkMode ? "#3c3c3c" : "white"}
+        value={text}
+        onChange={(event) => setText(event.target.value)}
+        onKeyDown={handleKeyDown}
+      />
+    </Box>
+  );
+}
@@ -0,0 +1,150 @@
+import { Box, Button, Input, Stack, Text } from "@chakra-ui/react";
+import { KeyboardEvent, useState } from "react";
+import { Comment } from "../lib/mdpad";
+
+type CommentsProps = {
+  comments: Comment[];
+  onAdd: (text: string) => boolean;
+  onReply: (parent: string, text: string) => boolean;
+  onResolve: (key: string, resolved: boolean) => boolean;
+  onReveal: (key: string) => unknown;
+  darkMode: boolean;
+};
+
+export default function Comments({
+  comments,
+  onAdd,
+  onReply,
+  onResolve,
+  onReveal,
+  darkMode,
+}: CommentsProps) {
+  const [text, setText] = useState("");
+  const [showResolved, setShowResolved] = useState(false);
+
+  const threads = comments
+    .filter((c) => c.parent === null && (showResolved || !c.resolved))
+    .sort((a, b) => a.start - b.start);
+  const numResolved = comments.filter((c) => c.parent === null && c.resolved)
+    .length;
+
+  function handleKeyDown(event: KeyboardEvent<HTMLInputElement>) {
+    if (event.key === "Enter" && text.trim() && onAdd(text)) {
+      setText("");
+    }
+  }
+
+  return (
+    <Box>
+      <Stack spacing={2} mb={1.5} fontSize="sm" maxH="sm" overflowY="auto">
+        {threads.map((thread) => (
+          <Thread
+            key={thread.key}
+            thread={thread}
+            replies={comments.filter((c) => c.parent === thread.key)}
+            onReply={onReply}
+            onResolve={onResolve}
+            onReveal={onReveal}
+            darkMode={darkMode}
+          />
+        ))}
+      </Stack>
+      <Input
+        size="sm"
+        variant="outline"
+        placeholder="Comment on the selection..."
+        maxLength={2000}
+        bgColor={darkMode ? "#3c3c3c" : "white"}
+        borderColor={darkMode ? "#3c3c3c" : "white"}
+        value={text}
+        onChange={(event) => setText(event.target.value)}
+        onKeyDown={handleKeyDown}
+      />
+      {numResolved > 0 && (
+        <Button
+          size="xs"
+          variant="link"
+          mt={1}
+          onClick={() => setShowResolved(!showResolved)}
+        >
+          {showResolved ? "Hide" : "Show"} {numResolved} resolved
+        </Button>
+      )}
+    </Box>
+  );
+}
+
+type ThreadProps = {
+  thread: Comment;
+  replies: Comment[];
+  onReply: (parent: string, text: string) => boolean;
+  onResolve: (key: string, resolved: boolean) => boolean;
+  onReveal: (key: string) => unknown;
+  darkMode: boolean;
+};
+
+function Thread({
+  thread,
+  replies,
+  onReply,
+  onResolve,
+  onReveal,
+  darkMode,
+}: ThreadProps) {
+  const [text, setText] = useState("");
+
+  function handleKeyDown(event: KeyboardEvent<HTMLInputElement>) {
+    if (event.key === "Enter" && text.trim() && onReply(thread.key, text)) {
+      setText("");
+    }
+  }
+
+  return (
+    <Box
+      p={1.5}
+      rounded="sm"
+      bgColor={darkMode ? "#2d2d2d" : "gray.50"}
+      opacity={thread.resolved ? 0.6 : 1}
+    >
+      {[thread, ...replies].map((comment) => (
+        <Box
+          key={comment.key}
+          cursor={comment === thread ? "pointer" : undefined}
+          onClick={comment === thread ? () => onReveal(thread.key) : undefined}
+        >
+          <Text
+            as="span"
+            fontWeight="medium"
+            fontStyle={comment.uname ? "normal" : "italic"}
+          >
+            {comment.name}
+          </Text>
+          <Text as="span" color={darkMode ? "gray.400" : "gray.500"} ml={1}>
+            {new Date(comment.time * 1000).toLocaleString()}
+          </Text>
+          <Text whiteSpace="pre-wrap">{comment.text}</Text>
+        </Box>
+      ))}
+      <Stack direction="row" spacing={1} mt={1}>
+        <Input
+          size="xs"
+          variant="outline"
+          placeholder="Reply..."
+          maxLength={2000}
+          bgColor={darkMode ? "#3c3c3c" : "white"}
+          borderColor={darkMode ? "#3c3c3c" : "white"}
+          value={text}
+          onChange={(event) => setText(event.target.value)}
+          onKeyDown={handleKeyDown}
+        />
+        <Button
+          size="xs"
+          flexShrink={0}
+          onClick={() => onResolve(thread.key, !thread.resolved)}
+        >
+          {thread.resolved ? "Reopen" : "Resolve"}
+        </Button>
+      </Stack>
+    </Box>
+  );
+}
@@ -0,0 +1,98 @@
+import { Button, HStack, Input, Stack, Text, useToast } from "@chakra-ui/react";
+import { useCallback, useEffect, useState } from "react";
+
+/** A named snapshot of the document. */
+type Snapshot = {
+  id: number;
+  name: string;
+  rev_by: string;
+  rev_at: number;
+};
+
+type SnapshotsProps = {
+  id: string;
+  darkMode: boolean;
+};
+
+export default function Snapshots({ id, darkMode }: SnapshotsProps) {
+  const toast = useToast();
+  const [name, setName] = useState("");
+  const [snapshots, setSnapshots] = useState<Snapshot[]>([]);
+  const search = window.location.search;
+
+  const refresh = useCallback(async () => {
+    const resp = await fetch(`/api/snapshot/${id}${search}`);
+    if (resp.ok) {
+      setSnapshots(await resp.json());
+    }
+  }, [id, search]);
+
+  useEffect(() => {
+    refresh();
+  }, [refresh]);
+
+  async function handleTake() {
+    const resp = await fetch(`/api/snapshot/${id}${search}`, {
+      method: "POST",
+      headers: { "Content-Type": "application/json" },
+      body: JSON.stringify({ name }),
+    });
+    if (resp.ok) {
+      setName("");
+      refresh();
+    } else {
+      toast({
+        title: "Failed to take snapshot",
+        status: "error",
+        duration: 2000,
+        isClosable: true,
+      });
+    }
+  }
+
+  async function handleRestore(snapshot: Snapshot) {
+    if (!window.confirm(`Restore "${snapshot.name}" for everyone?`)) return;
+    const resp = await fetch(
+      `/api/snapshot/${id}/${snapshot.id}/restore${search}`,
+      { method: "POST" }
+    );
+    toast({
+      title: resp.ok ? "Restored!" : "Failed to restore snapshot",
+      status: resp.ok ? "success" : "error",
+      duration: 2000,
+      isClosable: true,
+    });
+  }
+
+  return (
+    <Stack spacing={1} mb={1.5} fontSize="sm">
+      <HStack>
+        <Input
+          size="sm"
+          placeholder="Snapshot name"
+          maxLength={64}
+          bgColor={darkMode ? "#3c3c3c" : "white"}
+          borderColor={darkMode ? "#3c3c3c" : "white"}
+          value={name}
+          onChange={(event) => setName(event.target.value)}
+        />
+        <Button size="sm" isDisabled={!name.trim()} onClick={handleTake}>
+          Take
+        </Button>
+      </HStack>
+      {snapshots.map((snapshot) => (
+        <HStack key={snapshot.id} justifyContent="space-between">
+          <Text noOfLines={1} title={`by ${snapshot.rev_by}`}>
+            {snapshot.name}
+            <Text as="span" color={darkMode ? "gray.400" : "gray.500"} ml={1}>
+              {new Date(snapshot.rev_at * 1000).toLocaleString()}
+            </Text>
+          </Text>
+          <Button size="xs" onClick={() => handleRestore(snapshot)}>
+            Restore
+          </Button>
+        </HStack>
+      ))}
+    </Stack>
+  );
+}
@@ -0,0 +1,76 @@
+import {
+  Box,
+  Button,
+  FormControl,
+  FormLabel,
+  Stack,
+  Switch,
+  Text,
+} from "@chakra-ui/react";
+import { Suggestion } from "../lib/mdpad";
+
+type SuggestionsProps = {
+  suggestions: Suggestion[];
+  suggesting: boolean;
+  onSuggesting: (on: boolean) => boolean;
+  onAccept: (key: string) => boolean;
+  onReject: (key: string) => boolean;
+  darkMode: boolean;
+};
+
+export default function Suggestions({
+  suggestions,
+  suggesting,
+  onSuggesting,
+  onAccept,
+  onReject,
+  darkMode,
+}: SuggestionsProps) {
+  return (
+    <Box>
+      <FormControl display="flex" alignItems="center" mb={1.5}>
+        <FormLabel htmlFor="suggesting" mb={0} fontSize="sm">
+          Suggest edits
+        </FormLabel>
+        <Switch
+          id="suggesting"
+          size="sm"
+          isChecked={suggesting}
+          onChange={(event) => onSuggesting(event.target.checked)}
+        />
+      </FormControl>
+      <Stack spacing={1} fontSize="sm" maxH="xs" overflowY="auto">
+        {suggestions.map((suggestion) => (
+          <Stack key={suggestion.key} direction="row" align="center">
+            <Box flex={1}>
+              <Text
+                as="span"
+                fontWeight="medium"
+                fontStyle={suggestion.uname ? "normal" : "italic"}
+              >
+                {suggestion.name}
+              </Text>
+              <Text
+                as="span"
+                color={darkMode ? "gray.400" : "gray.500"}
+                ml={1}
+              >
+                {new Date(suggestion.time * 1000).toLocaleTimeString()}
+              </Text>
+            </Box>
+            <Button
+              size="xs"
+              colorScheme="green"
+              onClick={() => onAccept(suggestion.key)}
+            >
+              Accept
+            </Button>
+            <Button size="xs" onClick={() => onReject(suggestion.key)}>
+              Reject
+            </Button>
+          </Stack>
+        ))}
+      </Stack>
+    </Box>
+  );
+}
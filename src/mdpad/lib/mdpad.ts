@@ -1,4 +1,4 @@
-import { OpSeq } from "spc-wasm";
+import { OpSeq, decode_message } from "spc-wasm";
 import type {
   editor, IDisposable, IPosition,
 } from "monaco-editor/esm/vs/editor/editor.api";
@@ -7,12 +7,21 @@ import debounce from "lodash.debounce";
 /** Options passed in to the constructor. */
 export type PadOptions = {
   readonly uri: string;
+  /** URI of the Server-Sent Events fallback, used if WebSockets fail. */
+  readonly fallbackUri?: string;
+  /** Opt in to binary messages over the WebSocket, JSON by default. */
+  readonly binary?: boolean;
   readonly editor: editor.IStandaloneCodeEditor;
   readonly onConnected?: () => unknown;
   readonly onDisconnected?: () => unknown;
   readonly onDesynchronized?: () => unknown;
   readonly onChangeLanguage?: (language: string) => unknown;
   readonly onChangeUsers?: (users: Record<number, UserInfo>) => unknown;
+  readonly onChat?: (chat: ChatMessage) => unknown;
+  /** Called with each new or changed comment, anchors kept up to date. */
+  readonly onComment?: (comment: Comment) => unknown;
+  /** Called with the pending suggestions whenever they change. */
+  readonly onSuggestions?: (suggestions: Suggestion[]) => unknown;
   readonly reconnectInterval?: number;
 };
 
@@ -22,15 +31,55 @@ export type UserInfo = {
   readonly hue: number;
 };
 
+/** A chat message sent by a user on the document. */
+export type ChatMessage = {
+  readonly id: number;
+  /** Username of the sender if signed in, empty for anonymous. */
+  readonly uname: string;
+  readonly name: string;
+  readonly text: string;
+  readonly time: number;
+};
+
+/** A comment on the document, the first of a thread or a reply in it. */
+export type Comment = {
+  readonly key: string;
+  /** Key of the first comment of the thread, null for the first itself. */
+  readonly parent: string | null;
+  /** Anchored range in Unicode codepoints, for the first comment. */
+  start: number;
+  end: number;
+  /** Username of the author if signed in, empty for anonymous. */
+  readonly uname: string;
+  readonly name: string;
+  readonly text: string;
+  readonly time: number;
+  readonly resolved: boolean;
+};
+
+/** An edit proposed in suggesting mode, pending until accepted or rejected. */
+export type Suggestion = {
+  readonly key: string;
+  /** Connection id of the author. */
+  readonly id: number;
+  /** Username of the author if signed in, empty for anonymous. */
+  readonly uname: string;
+  readonly name: string;
+  readonly time: number;
+};
+
 /** Browser client. */
 class Pad {
-  private ws?: WebSocket;
+  private ws?: Socket;
   private connecting?: boolean;
   private recentFailures: number = 0;
+  private openFailures: number = 0;
+  private useFallback: boolean = false;
   private readonly model: editor.ITextModel;
   private readonly onChangeHandle: IDisposable;
   private readonly onCursorHandle: IDisposable;
   private readonly onSelectionHandle: IDisposable;
+  private readonly onKeyDownHandle: IDisposable;
   private readonly beforeUnload: (event: BeforeUnloadEvent) => void;
   private readonly tryConnectId: number;
   private readonly resetFailuresId: number;
@@ -40,8 +89,13 @@ class Pad {
   private revision: number = 0;
   private outstanding?: OpSeq;
   private buffer?: OpSeq;
+  private pendingUndo: string[] = [];
   private users: Record<number, UserInfo> = {};
   private userCursors: Record<number, CursorData> = {};
+  private comments: Record<string, Comment> = {};
+  private suggestions: Record<string, Suggestion & { op: OpSeq }> = {};
+  private suggesting: boolean = false;
+  private readOnly: boolean = false;
   private myInfo?: UserInfo;
   private cursorData: CursorData = { cursors: [], selections: [] };
 
@@ -64,6 +118,19 @@ class Pad {
       this.onSelection(e);
       cursorUpdate();
     });
+    // undo on the server, so that edits of others are kept
+    this.onKeyDownHandle = options.editor.onKeyDown((e) => {
+      if (!(e.ctrlKey || e.metaKey) || !this.ws) return;
+      const redo = e.code === "KeyY" || (e.code === "KeyZ" && e.shiftKey);
+      if (e.code !== "KeyZ" && !redo) return;
+      e.preventDefault();
+      e.stopPropagation();
+      if (redo) {
+        this.redo();
+      } else {
+        this.undo();
+      }
+    });
     this.beforeUnload = (event: BeforeUnloadEvent) => {
       if (this.outstanding) {
         event.preventDefault();
@@ -90,6 +157,7 @@ class Pad {
     this.onSelectionHandle.dispose();
     this.onCursorHandle.dispose();
     this.onChangeHandle.dispose();
+    this.onKeyDownHandle.dispose();
     window.removeEventListener("beforeunload", this.beforeUnload);
     this.ws?.close();
   }
@@ -100,6 +168,87 @@ class Pad {
     return this.ws !== undefined;
   }
 
+  /** Try to send a chat message, if connected. */
+  sendChat(text: string): boolean {
+    this.ws?.send(`{"Chat":${JSON.stringify(text)}}`);
+    return this.ws !== undefined;
+  }
+
+  /**
+   * Try to start a comment thread on the selected text, or at the cursor,
+   * if connected.
+   */
+  addComment(text: string): boolean {
+    const selection = this.options.editor.getSelection();
+    if (!selection) return false;
+    const start = unicodeOffset(this.model, selection.getStartPosition());
+    const end = unicodeOffset(this.model, selection.getEndPosition());
+    this.ws?.send(`{"Comment":${JSON.stringify({ start, end, text })}}`);
+    return this.ws !== undefined;
+  }
+
+  /** Try to reply in a comment thread, if connected. */
+  reply(parent: string, text: string): boolean {
+    this.ws?.send(`{"Reply":${JSON.stringify({ parent, text })}}`);
+    return this.ws !== undefined;
+  }
+
+  /** Try to resolve, or reopen, a comment thread, if connected. */
+  resolve(key: string, resolved: boolean): boolean {
+    this.ws?.send(`{"Resolve":${JSON.stringify({ key, resolved })}}`);
+    return this.ws !== undefined;
+  }
+
+  /** Reveal and select the text a comment thread is anchored to. */
+  revealComment(key: string) {
+    const comment = this.comments[key];
+    if (!comment) return;
+    const start = unicodePosition(this.model, comment.start);
+    const end = unicodePosition(this.model, comment.end);
+    const range = {
+      startLineNumber: start.lineNumber,
+      startColumn: start.column,
+      endLineNumber: end.lineNumber,
+      endColumn: end.column,
+    };
+    this.options.editor.setSelection(range);
+    this.options.editor.revealRangeInCenter(range);
+  }
+
+  /**
+   * Try to turn suggesting mode on or off, if connected and all local edits
+   * were acknowledged. Edits are then only proposed to the owner.
+   */
+  setSuggesting(on: boolean): boolean {
+    if (!this.ws || this.outstanding) return false;
+    this.ws.send(`{"Suggesting":${on}}`);
+    this.suggesting = on;
+    this.options.editor.updateOptions({ readOnly: this.readOnly && !on });
+    return true;
+  }
+
+  /** Try to accept a suggestion, as the owner, if connected. */
+  accept(key: string): boolean {
+    this.ws?.send(`{"Accept":${JSON.stringify(key)}}`);
+    return this.ws !== undefined;
+  }
+
+  /** Try to reject a suggestion, as the owner or its author, if connected. */
+  reject(key: string): boolean {
+    this.ws?.send(`{"Reject":${JSON.stringify(key)}}`);
+    return this.ws !== undefined;
+  }
+
+  /** Try to undo the user's last edit, if connected. */
+  undo(): boolean {
+    return this.sendUndo(`"Undo"`);
+  }
+
+  /** Try to redo the user's last undone edit, if connected. */
+  redo(): boolean {
+    return this.sendUndo(`"Redo"`);
+  }
+
   /** Set the user's information. */
   setInfo(info: UserInfo) {
     this.myInfo = info;
@@ -107,7 +256,7 @@ class Pad {
   }
 
   /**
-   * Attempts a WebSocket connection.
+   * Attempts a WebSocket connection, or an SSE one as the fallback.
    *
    * Safety Invariant: Until this WebSocket connection is closed, no other
    * connections will be attempted because either `this.ws` or
@@ -120,20 +269,33 @@ class Pad {
   private tryConnect() {
     if (this.connecting || this.ws) return;
     this.connecting = true;
-    const ws = new WebSocket(this.options.uri);
+    const ws: Socket =
+      this.useFallback && this.options.fallbackUri
+        ? new EventSocket(this.options.fallbackUri)
+        : this.openWebSocket();
     ws.onopen = () => {
       this.connecting = false;
+      this.openFailures = 0;
+      this.pendingUndo = [];
+      this.suggestions = {}; // sent again on connection
       this.ws = ws;
       this.options.onConnected?.();
       this.users = {};
       this.options.onChangeUsers?.(this.users);
+      this.updateSuggestions();
       this.sendInfo();
       this.sendCursorData();
+      if (this.suggesting) {
+        ws.send(`{"Suggesting":true}`);
+      }
       if (this.outstanding) {
         this.sendOperation(this.outstanding);
       }
     };
-    ws.onclose = () => {
+    ws.onclose = ({ code, reason }) => {
+      if (code === 1008) {
+        console.warn(`Disconnected by the server: ${reason}`);
+      }
       if (this.ws) {
         this.ws = undefined;
         this.options.onDisconnected?.();
@@ -145,18 +307,54 @@ class Pad {
         }
       } else {
         this.connecting = false;
+        // WebSocket upgrades may be blocked by proxies, switch to SSE
+        if (++this.openFailures >= 3 && this.options.fallbackUri) {
+          this.useFallback = true;
+        }
       }
     };
     ws.onmessage = ({ data }) => {
       if (typeof data === "string") {
         this.handleMessage(JSON.parse(data));
+      } else if (data instanceof ArrayBuffer) {
+        this.handleMessage(decode_message(new Uint8Array(data)));
       }
     };
   }
 
+  /** Open a WebSocket, negotiating the encoding of the server messages. */
+  private openWebSocket(): WebSocket {
+    if (!this.options.binary) {
+      return new WebSocket(this.options.uri);
+    }
+    const ws = new WebSocket(this.options.uri, ["spc.binary", "spc.json"]);
+    ws.binaryType = "arraybuffer";
+    return ws;
+  }
+
   private handleMessage(msg: ServerMsg) {
     if (msg.Identity !== undefined) {
       this.me = msg.Identity;
+    } else if (msg.ReadOnly !== undefined) {
+      this.readOnly = msg.ReadOnly;
+      this.options.editor.updateOptions({
+        readOnly: msg.ReadOnly && !this.suggesting,
+      });
+    } else if (msg.Snapshot !== undefined) {
+      const { revision, text } = msg.Snapshot;
+      if (revision > this.revision) {
+        if (this.outstanding) {
+          // Local edits are based on history the server no longer keeps.
+          this.dispose();
+          this.options.onDesynchronized?.();
+          return;
+        }
+        const operation = OpSeq.new();
+        operation.delete(unicodeLength(this.lastValue));
+        operation.insert(text);
+        this.applyOperation(operation);
+        this.revision = revision;
+      }
     } else if (msg.History !== undefined) {
       const { start, operations } = msg.History;
       if (start > this.revision) {
@@ -174,6 +372,30 @@ class Pad {
           this.applyServer(operation);
         }
       }
+    } else if (msg.Chat !== undefined) {
+      this.options.onChat?.(msg.Chat);
+    } else if (msg.Comment !== undefined) {
+      const comment = { ...msg.Comment };
+      this.comments[comment.key] = comment;
+      this.updateCursors();
+      this.options.onComment?.({ ...comment });
+    } else if (msg.Suggestion !== undefined) {
+      // sent at our revision, rebase it on the local edits
+      const { key, id, uname, name, time, operation } = msg.Suggestion;
+      let op: OpSeq | undefined = OpSeq.from_str(JSON.stringify(operation));
+      if (op && this.outstanding) {
+        op = op.transform(this.outstanding)?.first();
+      }
+      if (op && this.buffer) {
+        op = op.transform(this.buffer)?.first();
+      }
+      if (op) {
+        this.suggestions[key] = { key, id, uname, name, time, op };
+        this.updateSuggestions();
+      }
+    } else if (msg.SuggestionDone !== undefined) {
+      delete this.suggestions[msg.SuggestionDone.key];
+      this.updateSuggestions();
     } else if (msg.Language !== undefined) {
       this.options.onChangeLanguage?.(msg.Language);
     } else if (msg.UserInfo !== undefined) {
@@ -208,6 +430,9 @@ class Pad {
     if (this.outstanding) {
       this.sendOperation(this.outstanding);
     }
+    for (const msg of this.pendingUndo.splice(0)) {
+      this.ws?.send(msg);
+    }
   }
 
   private applyServer(operation: OpSeq) {
@@ -241,6 +466,17 @@ class Pad {
     this.ws?.send(`{"Edit":{"revision":${this.revision},"operation":${op}}}`);
   }
 
+  private sendUndo(msg: string): boolean {
+    if (!this.ws) return false;
+    // the server undoes the edits it got, send after the buffered one
+    if (this.buffer) {
+      this.pendingUndo.push(msg);
+    } else {
+      this.ws.send(msg);
+    }
+    return true;
+  }
+
   private sendInfo() {
     if (this.myInfo) {
       this.ws?.send(`{"ClientInfo":${JSON.stringify(this.myInfo)}}`);
@@ -322,7 +558,42 @@ class Pad {
         operation.transform_index(e),
       ]);
     }
+    for (const [key, suggestion] of Object.entries(this.suggestions)) {
+      const op = suggestion.op.transform(operation)?.first();
+      if (op) {
+        suggestion.op = op;
+      } else {
+        delete this.suggestions[key];
+      }
+    }
+    const moved: Comment[] = [];
+    for (const comment of Object.values(this.comments)) {
+      if (comment.parent !== null) continue;
+      const start = operation.transform_index(comment.start);
+      const end = operation.transform_index(comment.end);
+      if (start !== comment.start || end !== comment.end) {
+        comment.start = start;
+        comment.end = end;
+        moved.push(comment);
+      }
+    }
+    this.updateCursors();
+    for (const comment of moved) {
+      this.options.onComment?.({ ...comment });
+    }
+  }
+
+  private updateSuggestions() {
     this.updateCursors();
+    this.options.onSuggestions?.(
+      Object.values(this.suggestions).map(({ key, id, uname, name, time }) => ({
+        key,
+        id,
+        uname,
+        name,
+        time,
+      }))
+    );
   }
 
   private updateCursors() {
@@ -372,6 +643,75 @@ class Pad {
       }
     }
 
+    for (const comment of Object.values(this.comments)) {
+      if (comment.parent !== null || comment.resolved) continue;
+      generateCommentStyles();
+      const position = unicodePosition(this.model, comment.start);
+      const positionEnd = unicodePosition(this.model, comment.end);
+      decorations.push({
+        options: {
+          className: "comment-range",
+          hoverMessage: {
+            value: `**${comment.name}**: ${comment.text}`,
+          },
+          stickiness: 1,
+          zIndex: 0,
+        },
+        range: {
+          startLineNumber: position.lineNumber,
+          startColumn: position.column,
+          endLineNumber: positionEnd.lineNumber,
+          endColumn: positionEnd.column,
+        },
+      });
+    }
+
+    for (const suggestion of Object.values(this.suggestions)) {
+      generateSuggestionStyles();
+      const ops: (string | number)[] = JSON.parse(suggestion.op.to_string());
+      const hoverMessage = { value: `Suggested by **${suggestion.name}**` };
+      let index = 0;
+      for (const op of ops) {
+        if (typeof op === "string") {
+          // Insert, shown after the text it would be inserted at
+          const pos = unicodePosition(this.model, index);
+          decorations.push({
+            options: {
+              after: { content: op, inlineClassName: "suggestion-insert" },
+              hoverMessage,
+            },
+            range: {
+              startLineNumber: pos.lineNumber,
+              startColumn: pos.column,
+              endLineNumber: pos.lineNumber,
+              endColumn: pos.column,
+            },
+          });
+        } else if (op >= 0) {
+          // Retain
+          index += op;
+        } else {
+          // Delete
+          const from = unicodePosition(this.model, index);
+          const to = unicodePosition(this.model, index - op);
+          index -= op;
+          decorations.push({
+            options: {
+              className: "suggestion-delete",
+              hoverMessage,
+              stickiness: 1,
+            },
+            range: {
+              startLineNumber: from.lineNumber,
+              startColumn: from.column,
+              endLineNumber: to.lineNumber,
+              endColumn: to.column,
+            },
+          });
+        }
+      }
+    }
+
     this.oldDecorations = this.model.deltaDecorations(
       this.oldDecorations,
       decorations
@@ -406,6 +746,14 @@ class Pad {
         operation = operation.compose(changeOp)!;
         offset += changeOp.target_len() - changeOp.base_len();
       }
+      if (this.suggesting) {
+        // only proposed, the edit is reverted until the owner accepts it
+        const op = operation.to_string();
+        this.ws?.send(`{"Edit":{"revision":${this.revision},"operation":${op}}}`);
+        this.transformCursors(operation);
+        this.applyOperation(operation.invert(content));
+        return;
+      }
       this.applyClient(operation);
       this.lastValue = this.model.getValue();
     }
@@ -425,6 +773,69 @@ class Pad {
   }
 }
 
+/** The part of the WebSocket interface used by the client. */
+type Socket = {
+  onopen: ((event: Event) => unknown) | null;
+  onclose: ((event: CloseEvent) => unknown) | null;
+  onmessage: ((event: MessageEvent) => unknown) | null;
+  send(data: string): void;
+  close(): void;
+};
+
+/**
+ * A socket over a Server-Sent Events stream, with messages to the server
+ * posted in order to the session given by the first event.
+ */
+class EventSocket implements Socket {
+  onopen: ((event: Event) => unknown) | null = null;
+  onclose: ((event: CloseEvent) => unknown) | null = null;
+  onmessage: ((event: MessageEvent) => unknown) | null = null;
+  private readonly source: EventSource;
+  private postUri?: string;
+  private queue: Promise<unknown> = Promise.resolve();
+  private closed: boolean = false;
+
+  constructor(readonly uri: string) {
+    this.source = new EventSource(uri);
+    this.source.addEventListener("session", (event) => {
+      const url = new URL(uri, window.location.href);
+      url.pathname += `/${(event as MessageEvent).data}`;
+      url.search = "";
+      this.postUri = url.toString();
+      this.onopen?.(new Event("open"));
+    });
+    this.source.onmessage = ({ data }) => {
+      this.onmessage?.(new MessageEvent("message", { data }));
+    };
+    // closed by the server, e.g. on exceeding a rate limit
+    this.source.addEventListener("close", (event) => {
+      this.close(1008, (event as MessageEvent).data);
+    });
+    // the pad reconnects on its own, never let EventSource retry
+    this.source.onerror = () => this.close();
+  }
+
+  send(data: string) {
+    const uri = this.postUri;
+    if (!uri || this.closed) return;
+    this.queue = this.queue.then(async () => {
+      const resp = await fetch(uri, {
+        method: "POST",
+        headers: { "Content-Type": "application/json" },
+        body: data,
+      }).catch(() => undefined);
+      if (!resp?.ok) this.close();
+    });
+  }
+
+  close(code?: number, reason?: string) {
+    if (this.closed) return;
+    this.closed = true;
+    this.source.close();
+    this.onclose?.(new CloseEvent("close", { code, reason }));
+  }
+}
+
 type UserOperation = {
   id: number;
   operation: any;
@@ -437,6 +848,11 @@ type CursorData = {
 
 type ServerMsg = {
   Identity?: number;
+  ReadOnly?: boolean;
+  Snapshot?: {
+    revision: number;
+    text: string;
+  };
   History?: {
     start: number;
     operations: UserOperation[];
@@ -450,6 +866,16 @@ type ServerMsg = {
     id: number;
     data: CursorData;
   };
+  Chat?: ChatMessage;
+  Comment?: Comment;
+  Suggestion?: Suggestion & {
+    revision: number;
+    operation: (string | number)[];
+  };
+  SuggestionDone?: {
+    key: string;
+    accepted: boolean;
+  };
 };
 
 /** Returns the number of Unicode codepoints in a string. */
@@ -502,4 +928,40 @@ function generateCssStyles(hue: number) {
   }
 }
 
+/** Add the CSS style of the text commented on, once. */
+function generateCommentStyles() {
+  if (!generatedStyles.has(-1)) {
+    generatedStyles.add(-1);
+    const css = `
+      .monaco-editor .comment-range {
+        background-color: hsla(48, 100%, 60%, 0.3);
+        border-bottom: 2px solid hsl(48, 100%, 45%);
+      }
+    `;
+    const element = document.createElement("style");
+    element.appendChild(document.createTextNode(css));
+    document.head.appendChild(element);
+  }
+}
+
+/** Add the CSS styles of suggested insertions and deletions, once. */
+function generateSuggestionStyles() {
+  if (!generatedStyles.has(-2)) {
+    generatedStyles.add(-2);
+    const css = `
+      .monaco-editor .suggestion-insert {
+        color: hsl(140, 60%, 35%);
+        text-decoration: underline;
+      }
+      .monaco-editor .suggestion-delete {
+        color: hsl(0, 70%, 45%);
+        text-decoration: line-through;
+      }
+    `;
+    const element = document.createElement("style");
+    element.appendChild(document.createTextNode(css));
+    document.head.appendChild(element);
+  }
+}
+
 export default Pad;